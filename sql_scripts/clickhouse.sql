@@ -0,0 +1,31 @@
+CREATE DATABASE IF NOT EXISTS {database};
+
+-- edits insert a new row, ReplacingMergeTree keeps the latest version of each message
+CREATE TABLE IF NOT EXISTS {database}.messages
+(
+    id           UInt64,
+    channel_id   UInt64,
+    guild_id     UInt64,
+    author_id    UInt64,
+    created_at   DateTime64(3, 'UTC'),
+    edited_at    Nullable(DateTime64(3, 'UTC')),
+    content      String,
+    message_type Int32,
+    attachments  UInt16,
+    language     LowCardinality(Nullable(String)),
+    tags         Array(LowCardinality(String)),
+    version      UInt64
+)
+ENGINE = ReplacingMergeTree(version)
+PARTITION BY toYYYYMM(created_at)
+ORDER BY (guild_id, channel_id, id);
+
+CREATE TABLE IF NOT EXISTS {database}.message_deletes
+(
+    id         UInt64,
+    channel_id UInt64,
+    guild_id   UInt64,
+    deleted_at DateTime64(3, 'UTC')
+)
+ENGINE = ReplacingMergeTree
+ORDER BY (guild_id, channel_id, id);
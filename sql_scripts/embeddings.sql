@@ -0,0 +1,12 @@
+CREATE EXTENSION IF NOT EXISTS vector;
+
+CREATE TABLE IF NOT EXISTS message_embeddings
+(
+    message_id BIGINT PRIMARY KEY REFERENCES messages (id) ON DELETE CASCADE,
+    model      TEXT                NOT NULL,
+    embedding  VECTOR({dimensions}) NOT NULL,
+    created_at TIMESTAMPTZ         NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_message_embeddings_hnsw
+    ON message_embeddings USING hnsw (embedding vector_cosine_ops);
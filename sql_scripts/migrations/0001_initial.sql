@@ -0,0 +1,368 @@
+-- Schema before versioned migrations, every statement is idempotent so databases
+-- created by the former setup.sql are brought up to date by this migration too.
+
+CREATE TABLE IF NOT EXISTS users
+(
+    id           BIGINT PRIMARY KEY,
+    username     TEXT NOT NULL,
+    global_name  TEXT,
+    avatar       TEXT,
+    bot          BOOLEAN NOT NULL DEFAULT FALSE,
+    banner       TEXT,
+    accent_color INTEGER,
+    flags        INTEGER,
+    premium_type INTEGER,
+    public_flags INTEGER,
+    guilds       BIGINT[] NOT NULL DEFAULT ARRAY []::BIGINT[],
+    avatar_path  TEXT,
+    banner_path  TEXT
+);
+
+CREATE INDEX IF NOT EXISTS idx_users_id ON users (id);
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS avatar_path TEXT;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS banner_path TEXT;
+
+CREATE TABLE IF NOT EXISTS user_history
+(
+    id         BIGSERIAL PRIMARY KEY,
+    user_id    BIGINT      NOT NULL,
+    field      TEXT        NOT NULL,
+    old_value  TEXT,
+    new_value  TEXT,
+    changed_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_user_history_user ON user_history (user_id);
+
+CREATE TABLE IF NOT EXISTS messages
+(
+    id                    BIGINT PRIMARY KEY,
+    channel_id            BIGINT      NOT NULL,
+    author_id             BIGINT      NOT NULL REFERENCES users (id),
+    guild_id              BIGINT,
+    content               TEXT,
+    edited_at             TIMESTAMPTZ,
+    message_type          INT         NOT NULL,
+    flags                 BIGINT      NOT NULL DEFAULT 0,
+    referenced_message_id BIGINT REFERENCES messages (id),
+    attachments           JSONB       NOT NULL DEFAULT '[]'::JSONB,
+    deleted_at            TIMESTAMPTZ          DEFAULT NULL,
+    tags                  TEXT[]      NOT NULL DEFAULT '{}',
+    pinned                BOOLEAN     NOT NULL DEFAULT FALSE,
+    pinned_at             TIMESTAMPTZ          DEFAULT NULL,
+    sample_rate           DOUBLE PRECISION NOT NULL DEFAULT 1.0,
+    language              TEXT,
+    embeds                JSONB       NOT NULL DEFAULT '[]'::JSONB,
+    UNIQUE (id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_messages_channel ON messages (channel_id);
+CREATE INDEX IF NOT EXISTS idx_messages_guild ON messages (guild_id);
+
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS tags TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS pinned BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS pinned_at TIMESTAMPTZ DEFAULT NULL;
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS sample_rate DOUBLE PRECISION NOT NULL DEFAULT 1.0;
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS language TEXT;
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS embeds JSONB NOT NULL DEFAULT '[]'::JSONB;
+
+CREATE INDEX IF NOT EXISTS idx_messages_language ON messages (language);
+
+CREATE INDEX IF NOT EXISTS idx_messages_tags ON messages USING GIN (tags);
+
+ALTER TABLE messages
+    ADD COLUMN IF NOT EXISTS content_tsv TSVECTOR
+        GENERATED ALWAYS AS (to_tsvector('simple', COALESCE(content, ''))) STORED;
+
+CREATE INDEX IF NOT EXISTS idx_messages_content_tsv ON messages USING GIN (content_tsv);
+
+CREATE TABLE IF NOT EXISTS message_revisions
+(
+    id                 BIGSERIAL PRIMARY KEY,
+    message_id         BIGINT      NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+    content            TEXT,
+    previous_edited_at TIMESTAMPTZ,
+    edited_at          TIMESTAMPTZ,
+    diff               JSONB       NOT NULL DEFAULT '[]'::JSONB,
+    removed_chars      INTEGER     NOT NULL DEFAULT 0,
+    added_chars        INTEGER     NOT NULL DEFAULT 0,
+    recorded_at        TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_message_revisions_message ON message_revisions (message_id);
+
+CREATE TABLE IF NOT EXISTS message_snapshots
+(
+    message_id        BIGINT      NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+    position          INTEGER     NOT NULL,
+    source_message_id BIGINT,
+    source_channel_id BIGINT,
+    source_guild_id   BIGINT,
+    content           TEXT,
+    created_at        TIMESTAMPTZ,
+    edited_at         TIMESTAMPTZ,
+    flags             BIGINT      NOT NULL DEFAULT 0,
+    attachments       JSONB       NOT NULL DEFAULT '[]'::JSONB,
+    embeds            JSONB       NOT NULL DEFAULT '[]'::JSONB,
+    PRIMARY KEY (message_id, position)
+);
+
+CREATE INDEX IF NOT EXISTS idx_message_snapshots_source ON message_snapshots (source_message_id);
+
+CREATE TABLE IF NOT EXISTS polls
+(
+    message_id        BIGINT PRIMARY KEY,
+    channel_id        BIGINT  NOT NULL,
+    guild_id          BIGINT,
+    question          TEXT,
+    allow_multiselect BOOLEAN NOT NULL DEFAULT FALSE,
+    layout_type       INTEGER,
+    expires_at        TIMESTAMPTZ
+);
+
+CREATE TABLE IF NOT EXISTS poll_answers
+(
+    message_id BIGINT  NOT NULL REFERENCES polls (message_id) ON DELETE CASCADE,
+    answer_id  INTEGER NOT NULL,
+    text       TEXT,
+    emoji      TEXT,
+    PRIMARY KEY (message_id, answer_id)
+);
+
+CREATE TABLE IF NOT EXISTS poll_votes
+(
+    message_id BIGINT      NOT NULL,
+    answer_id  INTEGER     NOT NULL,
+    user_id    BIGINT      NOT NULL,
+    voted_at   TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    removed_at TIMESTAMPTZ          DEFAULT NULL,
+    PRIMARY KEY (message_id, answer_id, user_id)
+);
+
+CREATE TABLE IF NOT EXISTS attachments
+(
+    id            BIGINT PRIMARY KEY,
+    message_id    BIGINT      NOT NULL,
+    filename      TEXT        NOT NULL,
+    content_type  TEXT,
+    size          BIGINT,
+    path          TEXT,
+    downloaded_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_attachments_message ON attachments (message_id);
+
+CREATE TABLE IF NOT EXISTS guilds
+(
+    id                       BIGINT PRIMARY KEY,
+    name                     TEXT,
+    icon                     TEXT,
+    banner                   TEXT,
+    splash                   TEXT,
+    region                   TEXT,
+    owner_id                 BIGINT,
+    member_count             INTEGER,
+    features                 TEXT[],
+    premium_tier             INTEGER,
+    vanity_url_code          TEXT
+);
+
+CREATE INDEX IF NOT EXISTS idx_guilds_id ON guilds (id);
+
+ALTER TABLE guilds ADD COLUMN IF NOT EXISTS banner TEXT;
+ALTER TABLE guilds ADD COLUMN IF NOT EXISTS splash TEXT;
+ALTER TABLE guilds ADD COLUMN IF NOT EXISTS vanity_url_code TEXT;
+
+CREATE TABLE IF NOT EXISTS guild_discovery
+(
+    guild_id            BIGINT PRIMARY KEY REFERENCES guilds (id) ON DELETE CASCADE,
+    description         TEXT,
+    vanity_url_code     TEXT,
+    primary_category    TEXT,
+    categories          TEXT[]      NOT NULL DEFAULT '{}',
+    keywords            TEXT[]      NOT NULL DEFAULT '{}',
+    approximate_members BIGINT,
+    approximate_online  BIGINT,
+    listed              BOOLEAN     NOT NULL DEFAULT TRUE,
+    fetched_at          TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS guild_history
+(
+    id          BIGSERIAL PRIMARY KEY,
+    guild_id    BIGINT      NOT NULL REFERENCES guilds (id) ON DELETE CASCADE,
+    field       TEXT        NOT NULL,
+    value       TEXT,
+    path        TEXT,
+    recorded_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_guild_history_guild ON guild_history (guild_id, field);
+
+CREATE TABLE IF NOT EXISTS roles
+(
+    id                  BIGINT,
+    guild_id            BIGINT NOT NULL REFERENCES guilds (id) ON DELETE CASCADE,
+    name                TEXT,
+    color               INTEGER,
+    hoist               BOOLEAN,
+    position            INTEGER,
+    permissions         TEXT,
+    flags               BIGINT,
+    icon                TEXT,
+    unicode_emoji       TEXT,
+    description         TEXT,
+    PRIMARY KEY (id, guild_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_roles_guild ON roles (guild_id);
+
+CREATE TABLE IF NOT EXISTS role_icon_history
+(
+    id            BIGSERIAL PRIMARY KEY,
+    role_id       BIGINT      NOT NULL,
+    guild_id      BIGINT      NOT NULL,
+    icon          TEXT,
+    unicode_emoji TEXT,
+    path          TEXT,
+    recorded_at   TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_role_icon_history_role ON role_icon_history (role_id, recorded_at);
+
+CREATE TABLE IF NOT EXISTS channels
+(
+    id                          BIGINT PRIMARY KEY,
+    guild_id                    BIGINT REFERENCES guilds (id) ON DELETE CASCADE,
+    type                        INTEGER NOT NULL,
+    name                        TEXT,
+    topic                       TEXT,
+    nsfw                        BOOLEAN,
+    position                    INTEGER,
+    parent_id                   BIGINT,
+    flags                       BIGINT,
+    permission_overwrites       JSONB DEFAULT '[]'::JSONB
+);
+
+CREATE INDEX IF NOT EXISTS idx_channels_guild ON channels (guild_id);
+CREATE INDEX IF NOT EXISTS idx_channels_parent ON channels (parent_id);
+
+CREATE TABLE IF NOT EXISTS emojis
+(
+    id         BIGINT PRIMARY KEY,
+    guild_id   BIGINT  NOT NULL,
+    name       TEXT,
+    animated   BOOLEAN NOT NULL DEFAULT FALSE,
+    available  BOOLEAN,
+    creator_id BIGINT,
+    deleted_at TIMESTAMPTZ DEFAULT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_emojis_guild ON emojis (guild_id);
+
+CREATE TABLE IF NOT EXISTS stickers
+(
+    id          BIGINT PRIMARY KEY,
+    guild_id    BIGINT  NOT NULL,
+    name        TEXT    NOT NULL,
+    description TEXT,
+    tags        TEXT,
+    format_type INTEGER NOT NULL,
+    available   BOOLEAN,
+    creator_id  BIGINT,
+    deleted_at  TIMESTAMPTZ DEFAULT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_stickers_guild ON stickers (guild_id);
+
+CREATE TABLE IF NOT EXISTS invites
+(
+    code              TEXT PRIMARY KEY,
+    guild_id          BIGINT,
+    channel_id        BIGINT,
+    inviter_id        BIGINT,
+    guild_name        TEXT,
+    guild_description TEXT,
+    member_count      BIGINT,
+    presence_count    BIGINT,
+    max_age           INTEGER,
+    max_uses          INTEGER,
+    uses              INTEGER,
+    temporary         BOOLEAN,
+    created_at        TIMESTAMPTZ,
+    expires_at        TIMESTAMPTZ,
+    source_guild_id   BIGINT,
+    first_message_id  BIGINT,
+    invalid           BOOLEAN     NOT NULL DEFAULT FALSE,
+    first_seen_at     TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    resolved_at       TIMESTAMPTZ          DEFAULT NULL,
+    deleted_at        TIMESTAMPTZ          DEFAULT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_invites_guild ON invites (guild_id);
+
+CREATE TABLE IF NOT EXISTS audit_logs
+(
+    id          BIGINT PRIMARY KEY,
+    guild_id    BIGINT      NOT NULL,
+    user_id     BIGINT,
+    target_id   BIGINT,
+    action_type INTEGER     NOT NULL,
+    changes     JSONB,
+    options     JSONB,
+    reason      TEXT,
+    fetched_at  TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_audit_logs_guild ON audit_logs (guild_id);
+CREATE INDEX IF NOT EXISTS idx_audit_logs_action ON audit_logs (guild_id, action_type);
+
+CREATE TABLE IF NOT EXISTS scheduled_events
+(
+    id            BIGINT PRIMARY KEY,
+    guild_id      BIGINT      NOT NULL,
+    channel_id    BIGINT,
+    creator_id    BIGINT,
+    name          TEXT        NOT NULL,
+    description   TEXT,
+    start_time    TIMESTAMPTZ NOT NULL,
+    end_time      TIMESTAMPTZ,
+    privacy_level SMALLINT    NOT NULL,
+    status        SMALLINT    NOT NULL,
+    entity_type   SMALLINT    NOT NULL,
+    entity_id     BIGINT,
+    location      TEXT,
+    user_count    INTEGER,
+    image         TEXT,
+    created_at    TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at    TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    deleted_at    TIMESTAMPTZ          DEFAULT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_scheduled_events_guild ON scheduled_events (guild_id);
+
+CREATE TABLE IF NOT EXISTS stage_instances
+(
+    id                 BIGINT PRIMARY KEY,
+    guild_id           BIGINT      NOT NULL,
+    channel_id         BIGINT      NOT NULL,
+    topic              TEXT        NOT NULL,
+    privacy_level      SMALLINT    NOT NULL,
+    scheduled_event_id BIGINT,
+    started_at         TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    ended_at           TIMESTAMPTZ          DEFAULT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_stage_instances_guild ON stage_instances (guild_id);
+
+CREATE TABLE IF NOT EXISTS raw_events
+(
+    id            BIGSERIAL PRIMARY KEY,
+    event_type    TEXT        NOT NULL,
+    account_index INTEGER     NOT NULL,
+    payload       JSONB       NOT NULL,
+    received_at   TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_raw_events_type ON raw_events (event_type);
@@ -0,0 +1,11 @@
+-- users, roles and @everyone/@here mentioned by a message, target_id is the user or role id,
+-- or the guild id for @everyone (the id of the everyone role)
+CREATE TABLE IF NOT EXISTS message_mentions
+(
+    message_id   BIGINT NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+    mention_type TEXT   NOT NULL,
+    target_id    BIGINT NOT NULL,
+    PRIMARY KEY (message_id, mention_type, target_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_message_mentions_target ON message_mentions (mention_type, target_id);
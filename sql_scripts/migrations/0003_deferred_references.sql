@@ -0,0 +1,14 @@
+-- replies keep the id of their parent even when it isn't stored yet, `repair-references`
+-- fetches the missing parents
+ALTER TABLE messages DROP CONSTRAINT IF EXISTS messages_referenced_message_id_fkey;
+
+CREATE INDEX IF NOT EXISTS idx_messages_referenced ON messages (referenced_message_id);
+
+-- parents that can't be fetched (deleted, no access) or were skipped by sampling
+CREATE TABLE IF NOT EXISTS unavailable_messages
+(
+    id         BIGINT PRIMARY KEY,
+    channel_id BIGINT      NOT NULL,
+    reason     TEXT        NOT NULL,
+    checked_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
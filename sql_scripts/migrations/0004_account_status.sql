@@ -0,0 +1,15 @@
+-- last known state of each token, keyed by the first part of the token (the base64 user id)
+-- so the token itself is never stored
+CREATE TABLE IF NOT EXISTS account_status
+(
+    token_id       TEXT PRIMARY KEY,
+    label          TEXT,
+    user_id        BIGINT,
+    username       TEXT,
+    status         TEXT        NOT NULL,
+    email_verified BOOLEAN,
+    phone_verified BOOLEAN,
+    guild_count    INTEGER,
+    error          TEXT,
+    checked_at     TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
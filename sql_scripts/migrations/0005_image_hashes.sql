@@ -0,0 +1,10 @@
+-- perceptual hashes of the downloaded images, close hashes mean visually similar images
+CREATE TABLE IF NOT EXISTS image_hashes
+(
+    attachment_id BIGINT PRIMARY KEY,
+    phash         BIGINT      NOT NULL,
+    dhash         BIGINT      NOT NULL,
+    computed_at   TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_image_hashes_phash ON image_hashes (phash);
@@ -0,0 +1,6 @@
+-- read from the downloaded files when `extract_media_metadata` is enabled
+ALTER TABLE attachments
+    ADD COLUMN IF NOT EXISTS width    INTEGER,
+    ADD COLUMN IF NOT EXISTS height   INTEGER,
+    ADD COLUMN IF NOT EXISTS duration DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS exif     JSONB;
@@ -0,0 +1,3 @@
+-- JPEG previews created when `generate_thumbnails` is enabled
+ALTER TABLE attachments
+    ADD COLUMN IF NOT EXISTS thumbnail_path TEXT;
@@ -0,0 +1,6 @@
+-- antivirus scan of the downloaded file, when `scan` is configured
+ALTER TABLE attachments
+    ADD COLUMN IF NOT EXISTS scan_status TEXT,
+    -- signature found, or the scan error
+    ADD COLUMN IF NOT EXISTS scan_detail TEXT,
+    ADD COLUMN IF NOT EXISTS scanned_at  TIMESTAMPTZ;
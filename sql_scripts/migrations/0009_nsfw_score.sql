@@ -0,0 +1,3 @@
+-- from 0.0 (safe) to 1.0 (explicit), set when the `nsfw` classifier is configured
+ALTER TABLE attachments
+    ADD COLUMN IF NOT EXISTS nsfw_score REAL;
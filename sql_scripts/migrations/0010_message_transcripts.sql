@@ -0,0 +1,12 @@
+-- speech to text of voice messages, when `transcription` is configured
+CREATE TABLE IF NOT EXISTS message_transcripts
+(
+    attachment_id  BIGINT PRIMARY KEY,
+    message_id     BIGINT      NOT NULL,
+    text           TEXT        NOT NULL,
+    text_tsv       TSVECTOR GENERATED ALWAYS AS (to_tsvector('simple', text)) STORED,
+    transcribed_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_message_transcripts_message_id ON message_transcripts (message_id);
+CREATE INDEX IF NOT EXISTS idx_message_transcripts_text_tsv ON message_transcripts USING GIN (text_tsv);
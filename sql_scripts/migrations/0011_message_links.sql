@@ -0,0 +1,12 @@
+-- URLs written in messages, position is the offset of the URL in the content in characters
+CREATE TABLE IF NOT EXISTS message_links
+(
+    message_id BIGINT  NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+    position   INTEGER NOT NULL,
+    url        TEXT    NOT NULL,
+    -- lowercase host, without www.
+    domain     TEXT    NOT NULL,
+    PRIMARY KEY (message_id, position)
+);
+
+CREATE INDEX IF NOT EXISTS idx_message_links_domain ON message_links (domain);
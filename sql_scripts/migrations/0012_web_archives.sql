@@ -0,0 +1,15 @@
+-- snapshots of the linked pages, saved as <download_dir>/web/<hash prefix>/<url hash>.<ext>
+-- failed attempts are kept with their error so they aren't retried
+CREATE TABLE IF NOT EXISTS web_archives
+(
+    url          TEXT PRIMARY KEY,
+    -- SHA-256 of the URL, in hex
+    url_hash     TEXT        NOT NULL,
+    path         TEXT,
+    status_code  INTEGER,
+    content_type TEXT,
+    error        TEXT,
+    archived_at  TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_web_archives_url_hash ON web_archives (url_hash);
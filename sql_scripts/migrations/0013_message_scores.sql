@@ -0,0 +1,10 @@
+-- set by `slurpslurp score-messages` when `scoring` is configured, null when the model isn't
+CREATE TABLE IF NOT EXISTS message_scores
+(
+    message_id BIGINT PRIMARY KEY REFERENCES messages (id) ON DELETE CASCADE,
+    -- from 0.0 to 1.0
+    toxicity   REAL,
+    -- from -1.0 (negative) to 1.0 (positive)
+    sentiment  REAL,
+    scored_at  TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
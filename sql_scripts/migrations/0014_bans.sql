@@ -0,0 +1,14 @@
+-- bans seen in sniff mode, replaces the banned_user_<guild>.txt files
+CREATE TABLE IF NOT EXISTS bans
+(
+    id          BIGSERIAL PRIMARY KEY,
+    guild_id    BIGINT      NOT NULL,
+    user_id     BIGINT      NOT NULL,
+    username    TEXT        NOT NULL,
+    -- the banned user is one of the sniffing accounts
+    own_account BOOLEAN     NOT NULL DEFAULT FALSE,
+    banned_at   TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_bans_guild ON bans (guild_id);
+CREATE INDEX IF NOT EXISTS idx_bans_user ON bans (user_id);
@@ -0,0 +1,11 @@
+-- paging of the recent member searches of sniff mode, one row per guild
+CREATE TABLE IF NOT EXISTS member_search_progress
+(
+    guild_id           BIGINT PRIMARY KEY,
+    -- user id of the last member received, where the next search resumes
+    continuation_token BIGINT,
+    members_seen       BIGINT      NOT NULL DEFAULT 0,
+    -- every member was listed once, the guild only gets recent joins from now on
+    completed_at       TIMESTAMPTZ,
+    updated_at         TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,17 @@
+-- friends, blocked users and pending requests of each sniffing account
+CREATE TABLE IF NOT EXISTS relationships
+(
+    account_id        BIGINT      NOT NULL,
+    user_id           BIGINT      NOT NULL,
+    -- 1 friend, 2 blocked, 3 incoming request, 4 outgoing request, 5 implicit
+    relationship_type SMALLINT    NOT NULL,
+    nickname          TEXT,
+    since             TIMESTAMPTZ,
+    first_seen        TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at        TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    -- the relationship ended, the row is kept for the history
+    removed_at        TIMESTAMPTZ,
+    PRIMARY KEY (account_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_relationships_user ON relationships (user_id);
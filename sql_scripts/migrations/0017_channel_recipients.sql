@@ -0,0 +1,10 @@
+-- participants of DMs and group DMs, the sniffing account included
+CREATE TABLE IF NOT EXISTS channel_recipients
+(
+    channel_id BIGINT      NOT NULL REFERENCES channels (id) ON DELETE CASCADE,
+    user_id    BIGINT      NOT NULL,
+    first_seen TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (channel_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_channel_recipients_user ON channel_recipients (user_id);
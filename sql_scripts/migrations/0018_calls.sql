@@ -0,0 +1,18 @@
+-- DM and group DM calls seen in sniff mode, one row per call
+CREATE TABLE IF NOT EXISTS calls
+(
+    id           BIGSERIAL PRIMARY KEY,
+    channel_id   BIGINT      NOT NULL,
+    -- the call message posted in the channel
+    message_id   BIGINT      NOT NULL,
+    region       TEXT,
+    -- every user rung during the call
+    ringing      BIGINT[]    NOT NULL DEFAULT '{}',
+    -- every user who was in the call
+    participants BIGINT[]    NOT NULL DEFAULT '{}',
+    started_at   TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    ended_at     TIMESTAMPTZ,
+    UNIQUE (channel_id, message_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_calls_channel ON calls (channel_id);
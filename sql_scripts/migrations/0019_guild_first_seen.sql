@@ -0,0 +1,2 @@
+-- when the guild was first stored, guilds known before this migration get its date
+ALTER TABLE guilds ADD COLUMN IF NOT EXISTS first_seen TIMESTAMPTZ NOT NULL DEFAULT NOW();
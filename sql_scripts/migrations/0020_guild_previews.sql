@@ -0,0 +1,20 @@
+-- public data of guilds seen through invites but never joined by the accounts
+CREATE TABLE IF NOT EXISTS guild_previews
+(
+    guild_id            BIGINT PRIMARY KEY,
+    name                TEXT,
+    description         TEXT,
+    icon                TEXT,
+    splash              TEXT,
+    features            TEXT[]      NOT NULL DEFAULT '{}',
+    approximate_members BIGINT,
+    approximate_online  BIGINT,
+    emoji_count         INTEGER,
+    sticker_count       INTEGER,
+    -- from the widget, when the guild enabled it
+    instant_invite      TEXT,
+    widget_channels     JSONB,
+    -- neither the preview nor the widget is public
+    available           BOOLEAN     NOT NULL DEFAULT TRUE,
+    fetched_at          TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,285 @@
+use crate::BoxedResult;
+use crate::alerts;
+use crate::database::{AccountStatus, lock_db, mark_account_dead, save_account_status};
+use crate::status::{self, ConnectionState};
+use discord_client_rest::rest::RestClient;
+use log::{error, info, warn};
+use serde::Deserialize;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio_postgres::Client;
+
+const ACCOUNTS_FILE: &str = "tokens.toml";
+const LEGACY_TOKENS_FILE: &str = "tokens.txt";
+// delay between two token checks
+const CHECK_DELAY: Duration = Duration::from_millis(1500);
+
+/// An account listed in `tokens.toml`
+#[derive(Debug, Deserialize, Clone)]
+pub struct Account {
+    pub token: String,
+    /// Name shown in the logs instead of the account index
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Guilds the account subscribes to and stores, empty means all of them
+    #[serde(default)]
+    pub guilds: Vec<u64>,
+    #[serde(default)]
+    pub capability: Capability,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Capability {
+    #[default]
+    Any,
+    /// Only connected in sniff mode
+    SniffOnly,
+    /// Only used by scrapes
+    ScrapeOnly,
+}
+
+#[derive(Deserialize)]
+struct AccountsFile {
+    #[serde(default)]
+    accounts: Vec<Account>,
+}
+
+impl Account {
+    fn from_token(token: String) -> Account {
+        Account {
+            token,
+            label: None,
+            enabled: true,
+            guilds: Vec::new(),
+            capability: Capability::Any,
+        }
+    }
+
+    pub fn can_sniff(&self) -> bool {
+        self.enabled && self.capability != Capability::ScrapeOnly
+    }
+
+    pub fn can_scrape(&self) -> bool {
+        self.enabled && self.capability != Capability::SniffOnly
+    }
+
+    /// Whether the guild is in the allowlist of the account. DMs are always allowed.
+    pub fn allows_guild(&self, guild_id: Option<u64>) -> bool {
+        match guild_id {
+            Some(guild_id) => self.guilds.is_empty() || self.guilds.contains(&guild_id),
+            None => true,
+        }
+    }
+
+    /// Label of the account, or its index when it has none
+    pub fn name(&self, index: usize) -> String {
+        match &self.label {
+            Some(label) => label.clone(),
+            None => index.to_string(),
+        }
+    }
+}
+
+/// Loads the accounts of `tokens.toml`, falling back to the one token per line `tokens.txt`.
+/// Tokens listed twice are only kept once.
+pub fn load() -> BoxedResult<Vec<Account>> {
+    let accounts = if Path::new(ACCOUNTS_FILE).exists() {
+        let content = std::fs::read_to_string(ACCOUNTS_FILE)
+            .map_err(|e| format!("Error reading {}: {}", ACCOUNTS_FILE, e))?;
+        let file: AccountsFile = toml::from_str(&content)
+            .map_err(|e| format!("Error parsing {}: {}", ACCOUNTS_FILE, e))?;
+        file.accounts
+    } else if Path::new(LEGACY_TOKENS_FILE).exists() {
+        warn!(
+            "{} is deprecated, move your tokens to {} (see tokens_example.toml)",
+            LEGACY_TOKENS_FILE, ACCOUNTS_FILE
+        );
+        let content = std::fs::read_to_string(LEGACY_TOKENS_FILE)
+            .map_err(|e| format!("Error reading {}: {}", LEGACY_TOKENS_FILE, e))?;
+        content
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| Account::from_token(line.to_string()))
+            .collect()
+    } else {
+        return Err(format!("No {} found", ACCOUNTS_FILE).into());
+    };
+
+    let mut unique: Vec<Account> = Vec::new();
+    for account in accounts {
+        if unique.iter().any(|known| known.token == account.token) {
+            warn!("Skipping a token listed twice in {}", ACCOUNTS_FILE);
+            continue;
+        }
+        unique.push(account);
+    }
+
+    Ok(unique)
+}
+
+/// First part of the token, the base64 encoded user id, used to refer to a token without storing it
+pub fn token_id(token: &str) -> String {
+    token.split('.').next().unwrap_or_default().to_string()
+}
+
+/// Connects every account of `tokens.toml` over REST, prints what it can see of the account
+/// and saves the result in `account_status` when a database is given
+pub async fn check(db: Option<&Client>) -> BoxedResult<()> {
+    let accounts = load()?;
+    if accounts.is_empty() {
+        info!("No account to check");
+        return Ok(());
+    }
+
+    let mut valid = 0;
+    for (index, account) in accounts.iter().enumerate() {
+        let status = check_account(account).await;
+
+        match status.status {
+            "valid" => {
+                valid += 1;
+                println!(
+                    "{} : {} ({}) email verified: {}, phone verified: {}, {} guilds{}",
+                    account.name(index),
+                    status.username.as_deref().unwrap_or_default(),
+                    status.user_id.unwrap_or_default(),
+                    status.email_verified.unwrap_or(false),
+                    status.phone_verified.unwrap_or(false),
+                    status.guild_count.unwrap_or_default(),
+                    if account.enabled { "" } else { " (disabled)" }
+                );
+            }
+            _ => println!(
+                "{} : {} ({})",
+                account.name(index),
+                status.status.to_uppercase(),
+                status.error.as_deref().unwrap_or_default()
+            ),
+        }
+
+        if let Some(db) = db
+            && let Err(e) = save_account_status(&status, db).await
+        {
+            error!("Failed to save the status of account {}: {}", index, e);
+        }
+
+        tokio::time::sleep(CHECK_DELAY).await;
+    }
+
+    info!("{}/{} tokens are valid", valid, accounts.len());
+
+    Ok(())
+}
+
+async fn check_account(account: &Account) -> AccountStatus {
+    let mut status = AccountStatus {
+        token_id: token_id(&account.token),
+        label: account.label.clone(),
+        user_id: None,
+        username: None,
+        status: "valid",
+        email_verified: None,
+        phone_verified: None,
+        guild_count: None,
+        error: None,
+    };
+
+    let rest_client = match RestClient::connect(account.token.clone(), Some(9), None).await {
+        Ok(rest_client) => rest_client,
+        Err(e) => {
+            status.status = error_status(&e.to_string());
+            status.error = Some(e.to_string());
+            return status;
+        }
+    };
+
+    let user = match rest_client.user(None).get_current_user().await {
+        Ok(user) => user,
+        Err(e) => {
+            status.status = error_status(&e.to_string());
+            status.error = Some(e.to_string());
+            return status;
+        }
+    };
+
+    status.user_id = Some(user.id);
+    status.username = Some(user.username.clone());
+    status.email_verified = user.verified;
+    status.phone_verified = Some(user.phone.is_some());
+
+    match rest_client.user(None).get_guilds().await {
+        Ok(guilds) => status.guild_count = Some(guilds.len()),
+        Err(e) => {
+            status.status = error_status(&e.to_string());
+            status.error = Some(e.to_string());
+        }
+    }
+
+    status
+}
+
+/// "invalid" for rejected tokens, "locked" for accounts that have to be verified again
+fn error_status(error: &str) -> &'static str {
+    if error.contains("401") {
+        "invalid"
+    } else if error.contains("40002") || error.contains("403") {
+        "locked"
+    } else {
+        "error"
+    }
+}
+
+/// Whether the gateway or REST error means the token won't ever work again: rejected token,
+/// or disabled or locked account
+pub fn is_dead_token_error(error: &str) -> bool {
+    const DEAD_TOKEN_ERRORS: &[&str] = &[
+        // gateway close code 4004
+        "Authentication failed",
+        "401 Unauthorized",
+        "You need to verify your account",
+        "account has been disabled",
+    ];
+
+    DEAD_TOKEN_ERRORS
+        .iter()
+        .any(|message| error.to_lowercase().contains(&message.to_lowercase()))
+}
+
+/// Marks the token as dead so it isn't connected again until `tokens check` finds it valid
+pub async fn quarantine(
+    account: &Account,
+    account_index: usize,
+    error: &str,
+    db_client: &Option<Arc<Mutex<Client>>>,
+) {
+    let name = account.name(account_index);
+    status::set_account_state(account_index, ConnectionState::Dead);
+    error!(
+        "Account {} : Token is dead, quarantining it: {}",
+        name, error
+    );
+
+    if let Some(db) = db_client
+        && let Err(e) = mark_account_dead(
+            &token_id(&account.token),
+            account.label.as_deref(),
+            error,
+            &*lock_db(db).await,
+        )
+        .await
+    {
+        error!("Failed to mark account {} as dead: {}", name, e);
+    }
+
+    alerts::send(&format!("Token of account {} is dead: {}", name, error)).await;
+}
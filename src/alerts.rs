@@ -0,0 +1,158 @@
+use crate::BoxedResult;
+use crate::config::{Config, SmtpConfig, SmtpSecurity};
+use crate::mirror::execute_webhook;
+use lazy_static::lazy_static;
+use lettre::message::header::ContentType;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use log::{error, warn};
+use serde_json::json;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+// max length of a webhook message
+const WEBHOOK_CONTENT_LIMIT: usize = 2000;
+
+lazy_static! {
+    static ref PENDING: Mutex<Vec<String>> = Mutex::new(Vec::new());
+}
+
+// set while the batching task runs, alerts are sent right away otherwise
+static BATCHING: AtomicBool = AtomicBool::new(false);
+
+fn has_target() -> bool {
+    let config = Config::get();
+    config.alert_webhook_url.is_some() || config.alert_smtp.is_some()
+}
+
+/// Sends the alert to `alert_webhook_url` and `alert_smtp`, when set. In sniff mode alerts are
+/// batched and sent every `alert_batch_interval` seconds.
+pub async fn send(message: &str) {
+    if !has_target() {
+        return;
+    }
+
+    if BATCHING.load(Ordering::Relaxed) {
+        PENDING.lock().unwrap().push(message.to_string());
+        return;
+    }
+
+    deliver(&[message.to_string()]).await;
+}
+
+/// Sends the pending alerts every `alert_batch_interval` seconds
+pub fn spawn_alert_task() {
+    let interval = Config::get().alert_batch_interval;
+    if !has_target() || interval == 0 {
+        return;
+    }
+    BATCHING.store(true, Ordering::Relaxed);
+
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+
+            let alerts = std::mem::take(&mut *PENDING.lock().unwrap());
+            if !alerts.is_empty() {
+                deliver(&alerts).await;
+            }
+        }
+    });
+}
+
+// identical alerts are merged, with their count
+fn digest(alerts: &[String]) -> Vec<String> {
+    let mut lines: Vec<(&str, usize)> = Vec::new();
+    for alert in alerts {
+        match lines.iter_mut().find(|(line, _)| *line == alert) {
+            Some((_, count)) => *count += 1,
+            None => lines.push((alert, 1)),
+        }
+    }
+
+    lines
+        .into_iter()
+        .map(|(line, count)| match count {
+            1 => line.to_string(),
+            count => format!("{} (x{})", line, count),
+        })
+        .collect()
+}
+
+async fn deliver(alerts: &[String]) {
+    let lines = digest(alerts);
+    let config = Config::get();
+
+    if let Some(url) = config.alert_webhook_url.as_deref() {
+        send_webhook(url, &lines).await;
+    }
+
+    if let Some(smtp) = &config.alert_smtp {
+        let subject = match alerts.len() {
+            1 => "slurpslurp: 1 alert".to_string(),
+            count => format!("slurpslurp: {} alerts", count),
+        };
+        if let Err(e) = send_email(smtp, &subject, &lines.join("\n")).await {
+            error!("Failed to send alert email: {}", e);
+        }
+    }
+}
+
+async fn send_webhook(url: &str, lines: &[String]) {
+    let client = rquest::Client::new();
+
+    // one message per chunk of lines fitting in the content limit
+    let mut chunks: Vec<String> = Vec::new();
+    for line in lines {
+        let line: String = line.chars().take(WEBHOOK_CONTENT_LIMIT).collect();
+        match chunks.last_mut() {
+            Some(chunk) if chunk.chars().count() + line.chars().count() < WEBHOOK_CONTENT_LIMIT => {
+                chunk.push('\n');
+                chunk.push_str(&line);
+            }
+            _ => chunks.push(line),
+        }
+    }
+
+    for chunk in chunks {
+        let payload = json!({
+            "content": chunk,
+            "username": "slurpslurp",
+            "allowed_mentions": { "parse": [] },
+        });
+
+        match execute_webhook(&client, url, &payload).await {
+            Ok(None) => (),
+            Ok(Some(_)) => warn!("Alert webhook rate limited, dropping alert: {}", chunk),
+            Err(e) => error!("Failed to send alert: {}", e),
+        }
+    }
+}
+
+async fn send_email(smtp: &SmtpConfig, subject: &str, body: &str) -> BoxedResult<()> {
+    let mut builder = Message::builder()
+        .from(smtp.from.parse()?)
+        .subject(subject)
+        .header(ContentType::TEXT_PLAIN);
+    for to in &smtp.to {
+        builder = builder.to(to.parse()?);
+    }
+    let email = builder.body(body.to_string())?;
+
+    let mut transport = match smtp.security {
+        SmtpSecurity::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp.host)?,
+        SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&smtp.host)?,
+        SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&smtp.host),
+    };
+    if let Some(port) = smtp.port {
+        transport = transport.port(port);
+    }
+    if let (Some(username), Some(password)) = (&smtp.username, &smtp.password) {
+        transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+
+    transport.build().send(email).await?;
+
+    Ok(())
+}
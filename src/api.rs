@@ -0,0 +1,217 @@
+use crate::database::{
+    ChannelSummary, GuildSummary, MessageAttachment, MessageFilter, MessageMatch,
+    get_guild_channels, get_guilds, get_message_attachments, lock_db, search_messages,
+};
+use crate::downloader;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio_postgres::Client;
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 500;
+
+type Db = Arc<Mutex<Client>>;
+type ApiResult<T> = Result<Json<T>, (StatusCode, String)>;
+
+// messages are returned newest first, `before` and `after` are message ids
+#[derive(Debug, Deserialize)]
+struct PageQuery {
+    before: Option<u64>,
+    after: Option<u64>,
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchQuery {
+    q: Option<String>,
+    author_id: Option<u64>,
+    channel_id: Option<u64>,
+    before: Option<u64>,
+    after: Option<u64>,
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct ApiMessage {
+    #[serde(flatten)]
+    message: MessageMatch,
+    attachments: Vec<MessageAttachment>,
+}
+
+fn limit(limit: Option<i64>) -> i64 {
+    limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+}
+
+fn internal_error(e: impl std::fmt::Display) -> (StatusCode, String) {
+    error!("API error: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Database error".to_string(),
+    )
+}
+
+// message ids are stored as BIGINT, bigger ones are rejected instead of wrapping around
+fn message_id(id: Option<u64>) -> Result<Option<i64>, (StatusCode, String)> {
+    id.map(|id| {
+        i64::try_from(id).map_err(|_| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid message id {}", id),
+            )
+        })
+    })
+    .transpose()
+}
+
+// `after` is exclusive
+fn min_id(after: Option<u64>) -> Result<Option<u64>, (StatusCode, String)> {
+    Ok(message_id(after)?.map(|id| id.saturating_add(1) as u64))
+}
+
+fn max_id(before: Option<u64>) -> Result<Option<u64>, (StatusCode, String)> {
+    Ok(message_id(before)?.map(|id| id as u64))
+}
+
+// paths of the downloaded files are relative to `download_dir`, as served by the web UI
+fn relative_path(path: Option<String>) -> Option<String> {
+    let root = format!("{}/", downloader::download_root());
+    path.map(|path| path.strip_prefix(&root).map(str::to_string).unwrap_or(path))
+}
+
+async fn query_messages(filter: MessageFilter, db: &Db) -> ApiResult<Vec<ApiMessage>> {
+    let db = lock_db(db).await;
+    let messages = search_messages(&filter, &db)
+        .await
+        .map_err(internal_error)?;
+
+    let ids: Vec<i64> = messages.iter().map(|message| message.id).collect();
+    let mut attachments: HashMap<i64, Vec<MessageAttachment>> = HashMap::new();
+    for attachment in get_message_attachments(&ids, &db)
+        .await
+        .map_err(internal_error)?
+    {
+        attachments
+            .entry(attachment.message_id)
+            .or_default()
+            .push(MessageAttachment {
+                path: relative_path(attachment.path),
+                thumbnail_path: relative_path(attachment.thumbnail_path),
+                ..attachment
+            });
+    }
+
+    let messages = messages
+        .into_iter()
+        .map(|message| ApiMessage {
+            attachments: attachments.remove(&message.id).unwrap_or_default(),
+            message,
+        })
+        .collect();
+
+    Ok(Json(messages))
+}
+
+async fn guilds(State(db): State<Db>) -> ApiResult<Vec<GuildSummary>> {
+    let db = lock_db(&db).await;
+    get_guilds(&db).await.map(Json).map_err(internal_error)
+}
+
+async fn guild_channels(
+    State(db): State<Db>,
+    Path(guild_id): Path<u64>,
+) -> ApiResult<Vec<ChannelSummary>> {
+    let db = lock_db(&db).await;
+    get_guild_channels(guild_id, &db)
+        .await
+        .map(Json)
+        .map_err(internal_error)
+}
+
+async fn channel_messages(
+    State(db): State<Db>,
+    Path(channel_id): Path<u64>,
+    Query(page): Query<PageQuery>,
+) -> ApiResult<Vec<ApiMessage>> {
+    let filter = MessageFilter {
+        text: None,
+        author_id: None,
+        channel_id: Some(channel_id),
+        min_id: min_id(page.after)?,
+        max_id: max_id(page.before)?,
+        limit: limit(page.limit),
+    };
+
+    query_messages(filter, &db).await
+}
+
+async fn user_messages(
+    State(db): State<Db>,
+    Path(user_id): Path<u64>,
+    Query(page): Query<PageQuery>,
+) -> ApiResult<Vec<ApiMessage>> {
+    let filter = MessageFilter {
+        text: None,
+        author_id: Some(user_id),
+        channel_id: None,
+        min_id: min_id(page.after)?,
+        max_id: max_id(page.before)?,
+        limit: limit(page.limit),
+    };
+
+    query_messages(filter, &db).await
+}
+
+async fn search(
+    State(db): State<Db>,
+    Query(search): Query<SearchQuery>,
+) -> ApiResult<Vec<ApiMessage>> {
+    let filter = MessageFilter {
+        text: search.q.filter(|text| !text.trim().is_empty()),
+        author_id: search.author_id,
+        channel_id: search.channel_id,
+        min_id: min_id(search.after)?,
+        max_id: max_id(search.before)?,
+        limit: limit(search.limit),
+    };
+
+    query_messages(filter, &db).await
+}
+
+/// Read only HTTP API over the archive
+pub fn router(db: Db) -> Router {
+    Router::new()
+        .route("/guilds", get(guilds))
+        .route("/guilds/{id}/channels", get(guild_channels))
+        .route("/channels/{id}/messages", get(channel_messages))
+        .route("/users/{id}/messages", get(user_messages))
+        .route("/search", get(search))
+        .with_state(db)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn min_id_excludes_the_after_id() {
+        assert_eq!(min_id(None), Ok(None));
+        assert_eq!(min_id(Some(10)), Ok(Some(11)));
+        assert_eq!(min_id(Some(i64::MAX as u64)), Ok(Some(i64::MAX as u64)));
+    }
+
+    #[test]
+    fn ids_above_bigint_are_rejected() {
+        assert_eq!(max_id(Some(i64::MAX as u64)), Ok(Some(i64::MAX as u64)));
+        for id in [i64::MAX as u64 + 1, u64::MAX] {
+            assert_eq!(min_id(Some(id)).unwrap_err().0, StatusCode::BAD_REQUEST);
+            assert_eq!(max_id(Some(id)).unwrap_err().0, StatusCode::BAD_REQUEST);
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use crate::BoxedResult;
+use crate::database::{
+    bulk_insert_audit_logs, bulk_upsert_users, get_latest_audit_log_id, lock_db,
+};
+use discord_client_rest::rest::RestClient;
+use log::{debug, info};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio_postgres::Client;
+
+// max entries per page allowed by discord
+const PAGE_LIMIT: u32 = 100;
+
+/// Fetches the audit log entries newer than the latest stored one, going back from the newest.
+/// Returns the amount of new entries.
+pub async fn fetch_audit_logs(
+    rest_client: &RestClient,
+    guild_id: u64,
+    db_client: &Arc<Mutex<Client>>,
+) -> BoxedResult<usize> {
+    let latest_id = {
+        let db = lock_db(db_client).await;
+        get_latest_audit_log_id(guild_id, &db).await?
+    };
+
+    let guild_rest = rest_client.guild(Some(guild_id));
+    let mut before = None;
+    let mut count = 0;
+
+    loop {
+        let audit_log = guild_rest
+            .get_audit_logs(before, None, Some(PAGE_LIMIT))
+            .await
+            .map_err(|e| format!("Error fetching audit logs of guild {}: {}", guild_id, e))?;
+
+        let page_size = audit_log.audit_log_entries.len();
+        let entries: Vec<_> = audit_log
+            .audit_log_entries
+            .into_iter()
+            .filter(|entry| latest_id.is_none_or(|latest| entry.id > latest))
+            .collect();
+
+        if entries.is_empty() {
+            break;
+        }
+
+        {
+            let db = lock_db(db_client).await;
+            bulk_upsert_users(&audit_log.users, &db).await?;
+            bulk_insert_audit_logs(&entries, guild_id, &db).await?;
+        }
+
+        count += entries.len();
+        before = entries.iter().map(|entry| entry.id).min();
+
+        // reached the already stored entries or the end of the audit log
+        if entries.len() < page_size || page_size < PAGE_LIMIT as usize {
+            break;
+        }
+    }
+
+    if count > 0 {
+        info!("Saved {} audit log entries for guild {}", count, guild_id);
+    } else {
+        debug!("No new audit log entries for guild {}", guild_id);
+    }
+
+    Ok(count)
+}
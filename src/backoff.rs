@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+/// Exponential backoff with jitter, so accounts disconnected together don't all
+/// reconnect at the same time
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Backoff {
+        Backoff {
+            base,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// Delay before the next attempt, between half and all of `base * 2^attempt`, capped to `max`
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .base
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+
+        delay / 2 + delay.mul_f64(rand::random::<f64>() / 2.0)
+    }
+
+    /// Called once connected, the next failure starts from the base delay again
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_double_with_jitter_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(8));
+
+        for ceiling in [1, 2, 4, 8, 8, 8] {
+            let ceiling = Duration::from_secs(ceiling);
+            let delay = backoff.next_delay();
+            assert!(
+                delay >= ceiling / 2 && delay <= ceiling,
+                "{:?} not within {:?}",
+                delay,
+                ceiling
+            );
+        }
+    }
+
+    #[test]
+    fn reset_starts_from_base() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+        for _ in 0..5 {
+            backoff.next_delay();
+        }
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn many_attempts_dont_overflow() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(30));
+        for _ in 0..100 {
+            assert!(backoff.next_delay() <= Duration::from_secs(30));
+        }
+    }
+}
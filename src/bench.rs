@@ -0,0 +1,154 @@
+use crate::BoxedResult;
+use crate::database::{delete_author_data, lock_db};
+use crate::downloader;
+use crate::event_processor::message::process_message_common;
+use discord_client_structs::structs::message::Message;
+use log::{error, info};
+use serde_json::json;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio::time::MissedTickBehavior;
+use tokio_postgres::Client;
+
+// fake ids used by the synthetic messages, low enough to never collide with real snowflakes
+const BENCH_AUTHOR_ID: u64 = 1;
+const BENCH_CHANNEL_ID: u64 = 2;
+const BENCH_GUILD_ID: u64 = 3;
+// milliseconds between the unix epoch and the discord epoch (2015-01-01)
+const DISCORD_EPOCH: u64 = 1_420_070_400_000;
+
+pub async fn run_bench(
+    rate: u32,
+    duration: u64,
+    download_url: Option<String>,
+    db_client: Option<Arc<Mutex<Client>>>,
+) -> BoxedResult<()> {
+    if rate == 0 {
+        return Err("Rate must be greater than 0".into());
+    }
+
+    info!(
+        "Generating {} messages/s for {}s{}",
+        rate,
+        duration,
+        if db_client.is_some() {
+            " into the database"
+        } else {
+            " without database"
+        }
+    );
+
+    let mut ticker = tokio::time::interval(Duration::from_secs(1) / rate);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Burst);
+
+    let mut message_latencies = Vec::new();
+    let mut download_latencies = Vec::new();
+    let start = Instant::now();
+    let end = start + Duration::from_secs(duration);
+    let mut sequence = 0u64;
+
+    while Instant::now() < end {
+        ticker.tick().await;
+        sequence += 1;
+
+        let message = synthetic_message(sequence)?;
+        let started = Instant::now();
+        if let Err(e) = process_message_common(
+            &message,
+            &message.author,
+            Some(BENCH_GUILD_ID),
+            &db_client,
+            false,
+        )
+        .await
+        {
+            error!("Failed to process synthetic message: {}", e);
+        }
+        message_latencies.push(started.elapsed());
+
+        if let Some(url) = &download_url {
+            // unique query so the downloader cache doesn't skip it
+            let url = format!("{}?bench={}", url, sequence);
+            let started = Instant::now();
+            if let Err(e) = downloader::download_url(&url, "/dev/null").await {
+                error!("Failed to download {}: {}", url, e);
+            }
+            download_latencies.push(started.elapsed());
+        }
+    }
+
+    let elapsed = start.elapsed();
+
+    println!(
+        "Processed {} messages in {:.1}s ({:.1} messages/s, target {})",
+        message_latencies.len(),
+        elapsed.as_secs_f64(),
+        message_latencies.len() as f64 / elapsed.as_secs_f64(),
+        rate
+    );
+    print_percentiles("Message latency", &mut message_latencies);
+    if !download_latencies.is_empty() {
+        print_percentiles("Download latency", &mut download_latencies);
+    }
+
+    if let Some(db) = &db_client {
+        let db = lock_db(db).await;
+        delete_author_data(BENCH_AUTHOR_ID, &db).await?;
+        info!("Synthetic messages removed from the database");
+    }
+
+    Ok(())
+}
+
+fn synthetic_message(sequence: u64) -> Result<Message, serde_json::Error> {
+    let now = chrono::Utc::now();
+    let id = ((now.timestamp_millis() as u64 - DISCORD_EPOCH) << 22) | (sequence & 0x3F_FFFF);
+
+    serde_json::from_value(json!({
+        "id": id.to_string(),
+        "channel_id": BENCH_CHANNEL_ID.to_string(),
+        "guild_id": BENCH_GUILD_ID.to_string(),
+        "author": {
+            "id": BENCH_AUTHOR_ID.to_string(),
+            "username": "slurpslurp-bench",
+            "discriminator": "0",
+            "global_name": null,
+            "avatar": null,
+            "bot": false
+        },
+        "content": format!("Synthetic bench message #{} with some text to store", sequence),
+        "timestamp": now.to_rfc3339(),
+        "edited_timestamp": null,
+        "tts": false,
+        "mention_everyone": false,
+        "mentions": [],
+        "mention_roles": [],
+        "attachments": [],
+        "embeds": [],
+        "pinned": false,
+        "type": 0,
+        "flags": 0
+    }))
+}
+
+fn print_percentiles(label: &str, latencies: &mut [Duration]) {
+    if latencies.is_empty() {
+        return;
+    }
+
+    latencies.sort();
+    let percentile = |p: f64| {
+        let index = ((latencies.len() - 1) as f64 * p).round() as usize;
+        latencies[index].as_secs_f64() * 1000.0
+    };
+
+    println!(
+        "{}: p50 {:.2}ms, p90 {:.2}ms, p99 {:.2}ms, max {:.2}ms",
+        label,
+        percentile(0.5),
+        percentile(0.9),
+        percentile(0.99),
+        percentile(1.0)
+    );
+}
@@ -1,4 +1,9 @@
-use crate::scraper::ScrapeType;
+use crate::export::ExportFormat;
+use crate::logging::LogFormat;
+use crate::progress::ProgressFormat;
+use crate::scraper::{GuildStrategy, ScrapeOptions, ScrapeType, ScrapeWindow};
+use crate::timezone;
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
 use clap::{Parser, Subcommand};
 
 #[derive(Parser, Debug)]
@@ -9,17 +14,542 @@ pub struct Cli {
 
     #[arg(long, short)]
     pub help: bool,
+
+    /// Config file to load instead of `config.toml`
+    #[arg(long, global = true)]
+    pub config: Option<String>,
+
+    /// IANA timezone used when displaying dates, overrides the config value
+    #[arg(long, global = true)]
+    pub timezone: Option<String>,
+
+    /// Log lines format
+    #[arg(long, global = true, value_enum, default_value_t)]
+    pub log_format: LogFormat,
+
+    /// Log levels per module, like `slurpslurp=info,slurpslurp::downloader=debug`, overrides RUST_LOG
+    #[arg(long, global = true)]
+    pub log_filter: Option<String>,
 }
 
 #[derive(Subcommand, Debug)]
 pub enum Mode {
-    Sniff,
+    Sniff {
+        /// Show a live dashboard of the accounts instead of the logs, quit with q
+        #[arg(long)]
+        dashboard: bool,
+        /// Address serving the /healthz health check, like 127.0.0.1:8081
+        #[arg(long)]
+        health_bind: Option<String>,
+    },
+    /// Sniff, stream the captured events over a WebSocket and serve the archive over HTTP
+    Serve {
+        /// Address the server listens on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        bind: String,
+        /// Only serve the API, without connecting any account
+        #[arg(long)]
+        no_sniff: bool,
+        /// Also serve a web UI to browse the archive, and the downloaded files under /files
+        #[arg(long)]
+        ui: bool,
+    },
     Scrape {
-        #[clap(value_enum)]
-        target_type: ScrapeType,
+        #[clap(subcommand)]
+        target: ScrapeTarget,
+        /// Only estimate the messages, requests, time and storage of the scrape
+        #[arg(long, global = true)]
+        dry_run: bool,
+        /// How the progress is reported
+        #[arg(long, global = true, value_enum, default_value_t)]
+        progress: ProgressFormat,
+    },
+    FindMedia {
+        /// Filename pattern, `*` and `?` wildcards are supported
+        #[arg(long)]
+        name: Option<String>,
+        /// MIME type, e.g. `image/png` or `video/*`
+        #[arg(long)]
+        mime: Option<String>,
+        /// Minimum file size, e.g. `500K`, `1M` or `2G`
+        #[arg(long, value_parser = parse_size)]
+        min_size: Option<u64>,
+        /// Only media from messages carrying this tag
+        #[arg(long)]
+        tag: Option<String>,
+        /// Minimum width in pixels, needs `extract_media_metadata`
+        #[arg(long)]
+        min_width: Option<u32>,
+        /// Minimum height in pixels, needs `extract_media_metadata`
+        #[arg(long)]
+        min_height: Option<u32>,
+        /// Minimum duration of videos and audio in seconds, needs `extract_media_metadata`
+        #[arg(long)]
+        min_duration: Option<f64>,
+        #[arg(long, default_value_t = 100)]
+        limit: i64,
+    },
+    /// Manage the accounts of tokens.toml
+    Tokens {
+        #[clap(subcommand)]
+        action: TokensAction,
+    },
+    /// Find the stored images that look like an image file or a pHash
+    FindSimilar {
+        /// Image file, or 16 hex digits pHash
+        target: String,
+        /// Max amount of differing bits between two hashes, out of 64
+        #[arg(long, default_value_t = 10)]
+        max_distance: i32,
+        #[arg(long, default_value_t = 50)]
+        limit: i64,
+    },
+    /// Compute the perceptual hashes of images downloaded before hashing existed
+    HashImages {
+        /// Max amount of images to hash, all of them by default
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Score the images downloaded before NSFW classification was enabled
+    ClassifyImages {
+        /// Max amount of images to classify, all of them by default
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Transcribe the voice messages downloaded before transcription was enabled
+    Transcribe {
+        /// Max amount of audio files to transcribe, all of them by default
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Save the pages linked in messages that weren't archived yet, see `web_archive`
+    ArchiveLinks {
+        #[arg(long, default_value_t = 1000)]
+        limit: i64,
+    },
+    /// Search the stored messages
+    Query {
+        /// Full-text search, supports quotes, `or` and `-word`
+        text: Option<String>,
+        #[arg(long)]
+        author: Option<u64>,
+        #[arg(long)]
+        channel: Option<u64>,
+        /// Only messages sent on or after this day (YYYY-MM-DD)
+        #[arg(long)]
+        after: Option<NaiveDate>,
+        /// Only messages sent on or before this day (YYYY-MM-DD)
+        #[arg(long)]
+        before: Option<NaiveDate>,
+        #[arg(long, default_value_t = 50)]
+        limit: i64,
+        /// Rank by meaning using the stored embeddings instead of matching words
+        #[arg(long)]
+        semantic: bool,
+        /// Print the results as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Compute the embeddings of stored messages for semantic search
+    Embed {
+        /// Max amount of messages to embed, all of them by default
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Score the toxicity and sentiment of the stored messages, see `scoring`
+    ScoreMessages {
+        /// Max amount of messages to score, all of them by default
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Push the stored messages to the configured search engine
+    Index {
+        /// Resume after this message id
+        #[arg(long, default_value_t = 0)]
+        after: u64,
+    },
+    /// Detect the language of stored messages saved before language detection existed
+    DetectLanguages,
+    /// Export stored data
+    Export {
+        #[arg(long, value_enum)]
+        format: ExportFormat,
+        /// Channel to export, only restricts the messages for parquet
+        #[arg(long)]
+        channel: Option<u64>,
+        /// Output file for html (defaults to `export_<channel>.html`),
+        /// output directory for parquet (defaults to `export`)
+        #[arg(long)]
+        output: Option<String>,
+        /// Replace users with pseudonyms, drop attachments and scrub links, emails and phone numbers
+        #[arg(long, requires = "salt")]
+        anonymize: bool,
+        /// Secret keying the pseudonyms of --anonymize, the same salt gives the same pseudonyms
+        #[arg(long, requires = "anonymize")]
+        salt: Option<String>,
+    },
+    /// Look up the guilds behind invite codes seen in messages
+    ResolveInvites {
+        #[clap(value_parser)]
+        token: String,
+        #[arg(long, default_value_t = 500)]
+        limit: i64,
+    },
+    /// Fetch the parents of stored replies that were never captured
+    RepairReferences {
+        #[clap(value_parser)]
+        token: String,
+        #[arg(long, default_value_t = 500)]
+        limit: i64,
+    },
+    /// Store the members of a guild listed in the member sidebar of a channel
+    ScrapeMembers {
+        #[clap(value_parser)]
+        guild_id: u64,
+        #[clap(value_parser)]
+        token: String,
+        /// Channel whose member sidebar is read, the first text channel of the guild by default
+        #[arg(long)]
+        channel: Option<u64>,
+    },
+    /// Fetch the discovery metadata (description, categories, vanity URL, counts) of discoverable guilds
+    EnrichDiscovery {
+        #[clap(value_parser)]
+        token: String,
+        #[arg(long, default_value_t = 100)]
+        limit: i64,
+    },
+    /// Fetch the public preview and widget of guilds seen through invites but not joined
+    PreviewGuilds {
+        #[clap(value_parser)]
+        token: String,
+        #[arg(long, default_value_t = 100)]
+        limit: i64,
+    },
+    /// Push synthetic messages through the pipeline and report throughput and latency
+    Bench {
+        /// Messages generated per second
+        #[arg(long, default_value_t = 100)]
+        rate: u32,
+        /// Duration of the bench in seconds
+        #[arg(long, default_value_t = 30)]
+        duration: u64,
+        /// Also download this URL for every message, discarding the content
+        #[arg(long)]
+        download_url: Option<String>,
+    },
+    /// Print aggregate counts of the archive, or of a guild, channel or user
+    Stats {
+        #[arg(long)]
+        guild: Option<u64>,
+        #[arg(long)]
+        channel: Option<u64>,
+        #[arg(long)]
+        user: Option<u64>,
+        /// Only print aggregate counts without identifiers, suppressing small counts. Not
+        /// available for a single user, whose activity profile would identify them
+        #[arg(long, conflicts_with = "user")]
+        publishable: bool,
+        /// Smallest count printed in publishable mode
+        #[arg(long, default_value_t = 10)]
+        min_count: i64,
+        /// Print the counts as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Permanently remove stored messages, their attachments and downloaded files
+    Prune {
+        /// Only messages older than this, e.g. 90d, 12w or 36h
+        #[arg(long, value_parser = parse_age)]
+        older_than: Option<TimeDelta>,
+        #[arg(long)]
+        guild: Option<u64>,
+        #[arg(long)]
+        channel: Option<u64>,
+        /// Only messages sent by bots
+        #[arg(long)]
+        only_bots: bool,
+        /// Also remove the downloaded files of messages that aren't stored anymore
+        #[arg(long)]
+        orphaned_files: bool,
+        /// Only count what would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ScrapeTarget {
+    /// Scrape the history of a channel
+    Channel {
         #[clap(value_parser)]
         id: u64,
+        /// Tokens to scrape with, the scrape accounts of tokens.toml by default
         #[clap(value_parser)]
         tokens: Vec<String>,
+        /// Only scrape the messages newer than the newest stored one
+        #[arg(long)]
+        since_db: bool,
+        /// Only scrape the messages around this one
+        #[arg(long, conflicts_with_all = ["before", "after", "since_db"])]
+        around: Option<u64>,
+        /// Only scrape the messages before this message id, or ISO date or time
+        #[arg(long, value_parser = parse_message_bound)]
+        before: Option<MessageBound>,
+        /// Only scrape the messages after this message id, or ISO date or time
+        #[arg(long, value_parser = parse_message_bound, conflicts_with = "since_db")]
+        after: Option<MessageBound>,
     },
+    /// Scrape every message of a guild
+    Guild {
+        #[clap(value_parser)]
+        id: u64,
+        /// Tokens to scrape with, the scrape accounts of tokens.toml by default
+        #[clap(value_parser)]
+        tokens: Vec<String>,
+        /// How the messages are gone through
+        #[arg(long, value_enum, default_value_t)]
+        strategy: GuildStrategy,
+        /// Only scrape the messages newer than the newest stored one
+        #[arg(long)]
+        since_db: bool,
+    },
+    /// Scrape every message a user sent in a guild, found with the guild search
+    User {
+        #[clap(value_parser)]
+        guild_id: u64,
+        #[clap(value_parser)]
+        user_id: u64,
+        /// Tokens to scrape with, the scrape accounts of tokens.toml by default
+        #[clap(value_parser)]
+        tokens: Vec<String>,
+    },
+    /// Scrape the guild search results of a query
+    Search {
+        #[clap(value_parser)]
+        guild_id: u64,
+        /// Tokens to scrape with, the scrape accounts of tokens.toml by default
+        #[clap(value_parser)]
+        tokens: Vec<String>,
+        /// Words to search, like in the Discord search bar
+        #[arg(long)]
+        query: String,
+        /// Only the messages of this user
+        #[arg(long)]
+        author: Option<u64>,
+        /// Only the messages of this channel
+        #[arg(long)]
+        channel: Option<u64>,
+        /// Only the messages mentioning this user
+        #[arg(long)]
+        mentions: Option<u64>,
+        /// Only the messages before this message id, or ISO date or time
+        #[arg(long, value_parser = parse_message_bound)]
+        before: Option<MessageBound>,
+        /// Only the messages after this message id, or ISO date or time
+        #[arg(long, value_parser = parse_message_bound)]
+        after: Option<MessageBound>,
+    },
+}
+
+impl ScrapeTarget {
+    /// Type and id of the scraped target, its tokens and what to scrape of it
+    pub fn into_parts(self) -> (ScrapeType, u64, Vec<String>, ScrapeOptions) {
+        match self {
+            ScrapeTarget::Channel {
+                id,
+                tokens,
+                since_db,
+                around,
+                before,
+                after,
+            } => {
+                let window = ScrapeWindow {
+                    around,
+                    before: before.map(MessageBound::to_snowflake),
+                    after: after.map(MessageBound::to_snowflake),
+                };
+                let options = ScrapeOptions {
+                    since_db,
+                    window,
+                    ..Default::default()
+                };
+                (ScrapeType::Channel, id, tokens, options)
+            }
+            ScrapeTarget::Guild {
+                id,
+                tokens,
+                strategy,
+                since_db,
+            } => {
+                let options = ScrapeOptions {
+                    strategy,
+                    since_db,
+                    ..Default::default()
+                };
+                (ScrapeType::Guild, id, tokens, options)
+            }
+            ScrapeTarget::User {
+                guild_id,
+                user_id,
+                tokens,
+            } => {
+                let options = ScrapeOptions {
+                    author_id: Some(user_id),
+                    ..Default::default()
+                };
+                (ScrapeType::Guild, guild_id, tokens, options)
+            }
+            ScrapeTarget::Search {
+                guild_id,
+                tokens,
+                query,
+                author,
+                channel,
+                mentions,
+                before,
+                after,
+            } => {
+                let window = ScrapeWindow {
+                    around: None,
+                    before: before.map(MessageBound::to_snowflake),
+                    after: after.map(MessageBound::to_snowflake),
+                };
+                let options = ScrapeOptions {
+                    window,
+                    author_id: author,
+                    content: Some(query),
+                    channel_id: channel,
+                    mentions,
+                    ..Default::default()
+                };
+                (ScrapeType::Guild, guild_id, tokens, options)
+            }
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum TokensAction {
+    /// Connect every token and report its account, verification status and guild count
+    Check,
+}
+
+/// Message id, or date or time standing for the first message id at that moment
+#[derive(Debug, Clone, Copy)]
+pub enum MessageBound {
+    Id(u64),
+    Date(NaiveDate),
+    Time(DateTime<Utc>),
+}
+
+impl MessageBound {
+    /// Dates start at midnight in the display timezone, it isn't known yet while parsing
+    pub fn to_snowflake(self) -> u64 {
+        match self {
+            MessageBound::Id(id) => id,
+            MessageBound::Date(date) => timezone::date_to_snowflake(date),
+            MessageBound::Time(time) => timezone::time_to_snowflake(time),
+        }
+    }
+}
+
+/// Parses a message id, a `YYYY-MM-DD` date or an RFC 3339 time
+fn parse_message_bound(value: &str) -> Result<MessageBound, String> {
+    let value = value.trim();
+    if let Ok(id) = value.parse::<u64>() {
+        return Ok(MessageBound::Id(id));
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(MessageBound::Date(date));
+    }
+
+    DateTime::parse_from_rfc3339(value)
+        .map(|time| MessageBound::Time(time.with_timezone(&Utc)))
+        .map_err(|_| format!("Invalid message id or date: {}", value))
+}
+
+/// Parses an age such as `90d`, in seconds, minutes, hours, days or weeks
+pub fn parse_age(value: &str) -> Result<TimeDelta, String> {
+    let value = value.trim();
+    let Some((unit_index, unit)) = value.char_indices().last() else {
+        return Err("Empty age".to_string());
+    };
+    let number = value[..unit_index]
+        .trim()
+        .parse::<i64>()
+        .map_err(|_| format!("Invalid age: {}", value))?;
+
+    match unit.to_ascii_lowercase() {
+        's' => Ok(TimeDelta::seconds(number)),
+        'm' => Ok(TimeDelta::minutes(number)),
+        'h' => Ok(TimeDelta::hours(number)),
+        'd' => Ok(TimeDelta::days(number)),
+        'w' => Ok(TimeDelta::weeks(number)),
+        _ => Err(format!(
+            "Invalid age unit in {}, expected s, m, h, d or w",
+            value
+        )),
+    }
+}
+
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, multiplier) = match value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&value[..value.len() - 1], 1024),
+        Some('M') => (&value[..value.len() - 1], 1024 * 1024),
+        Some('G') => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+
+    number
+        .trim()
+        .parse::<u64>()
+        .map(|n| n * multiplier)
+        .map_err(|_| format!("Invalid size: {}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_message_bound_reads_ids_dates_and_times() {
+        assert!(matches!(
+            parse_message_bound("1234567890123456789"),
+            Ok(MessageBound::Id(1234567890123456789))
+        ));
+        assert!(matches!(
+            parse_message_bound(" 2024-05-01 "),
+            Ok(MessageBound::Date(date)) if date == NaiveDate::from_ymd_opt(2024, 5, 1).unwrap()
+        ));
+
+        let Ok(MessageBound::Time(time)) = parse_message_bound("2024-05-01T18:30:00+02:00") else {
+            panic!("expected a time");
+        };
+        assert_eq!(time.to_rfc3339(), "2024-05-01T16:30:00+00:00");
+    }
+
+    #[test]
+    fn parse_message_bound_rejects_garbage() {
+        for value in ["", "yesterday", "2024-13-01", "-12", "2024-05-01 18:30"] {
+            assert!(parse_message_bound(value).is_err(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn parse_age_reads_every_unit() {
+        assert_eq!(parse_age("90s"), Ok(TimeDelta::seconds(90)));
+        assert_eq!(parse_age("15m"), Ok(TimeDelta::minutes(15)));
+        assert_eq!(parse_age("36h"), Ok(TimeDelta::hours(36)));
+        assert_eq!(parse_age("90d"), Ok(TimeDelta::days(90)));
+        assert_eq!(parse_age(" 12W "), Ok(TimeDelta::weeks(12)));
+    }
+
+    #[test]
+    fn parse_age_rejects_garbage() {
+        for value in ["", "d", "90", "90y", "ten days", "1.5d"] {
+            assert!(parse_age(value).is_err(), "{:?}", value);
+        }
+    }
 }
@@ -0,0 +1,206 @@
+use crate::BoxedResult;
+use crate::config::{ClickHouseConfig, Config};
+use crate::database::message_type_id;
+use crate::language;
+use crate::tagging;
+use crate::timezone;
+use chrono::{DateTime, Utc};
+use discord_client_structs::structs::message::Message;
+use log::{debug, error, info, warn};
+use serde_json::json;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+
+static SENDER: OnceLock<UnboundedSender<Row>> = OnceLock::new();
+
+// failed batches are retried, up to this many batches per table
+const MAX_PENDING_BATCHES: usize = 10;
+
+enum Row {
+    Message(String),
+    Delete(String),
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%d %H:%M:%S%.3f").to_string()
+}
+
+/// Sends a statement, and rows in JSONEachRow format when given, to the HTTP interface
+async fn execute(
+    client: &rquest::Client,
+    config: &ClickHouseConfig,
+    query: &str,
+    body: Option<String>,
+) -> BoxedResult<()> {
+    let url = format!(
+        "{}/?query={}",
+        config.url.trim_end_matches('/'),
+        urlencoding::encode(query)
+    );
+
+    let mut request = client
+        .post(&url)
+        .header("X-ClickHouse-User", &config.user)
+        .body(body.unwrap_or_default());
+    if let Some(password) = &config.password {
+        request = request.header("X-ClickHouse-Key", password);
+    }
+
+    let response = request.send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        let text = response.text().await?;
+        return Err(format!("ClickHouse returned {}: {}", status, text).into());
+    }
+
+    Ok(())
+}
+
+/// Creates the tables and starts batching rows, only run when ClickHouse is configured
+pub async fn init() -> BoxedResult<()> {
+    let Some(config) = &Config::get().clickhouse else {
+        return Ok(());
+    };
+
+    let client = rquest::Client::new();
+    let script =
+        include_str!("../sql_scripts/clickhouse.sql").replace("{database}", &config.database);
+
+    // the HTTP interface runs a single statement per request
+    for statement in script.split(';') {
+        let statement: String = statement
+            .lines()
+            .filter(|line| !line.trim_start().starts_with("--"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        if statement.trim().is_empty() {
+            continue;
+        }
+
+        execute(&client, config, &statement, None)
+            .await
+            .map_err(|e| format!("Error executing ClickHouse setup script: {}", e))?;
+    }
+
+    let (sender, receiver) = unbounded_channel();
+    tokio::spawn(run_batcher(client, config, receiver));
+    let _ = SENDER.set(sender);
+
+    info!("Batching messages into ClickHouse at {}", config.url);
+
+    Ok(())
+}
+
+async fn flush(
+    client: &rquest::Client,
+    config: &ClickHouseConfig,
+    table: &str,
+    rows: &mut Vec<String>,
+) {
+    if rows.is_empty() {
+        return;
+    }
+
+    let query = format!(
+        "INSERT INTO {}.{} FORMAT JSONEachRow",
+        config.database, table
+    );
+    match execute(client, config, &query, Some(rows.join("\n"))).await {
+        Ok(()) => {
+            debug!("Inserted {} rows into ClickHouse {}", rows.len(), table);
+            rows.clear();
+        }
+        Err(e) => {
+            error!("Failed to insert into ClickHouse {}: {}", table, e);
+
+            let max_rows = config.batch_size * MAX_PENDING_BATCHES;
+            if rows.len() > max_rows {
+                let dropped = rows.len() - max_rows;
+                rows.drain(..dropped);
+                warn!("Dropped {} ClickHouse {} rows", dropped, table);
+            }
+        }
+    }
+}
+
+async fn run_batcher(
+    client: rquest::Client,
+    config: &'static ClickHouseConfig,
+    mut receiver: UnboundedReceiver<Row>,
+) {
+    let mut messages = Vec::new();
+    let mut deletes = Vec::new();
+    let mut interval = tokio::time::interval(Duration::from_secs(config.flush_interval));
+
+    loop {
+        tokio::select! {
+            row = receiver.recv() => {
+                match row {
+                    Some(Row::Message(row)) => messages.push(row),
+                    Some(Row::Delete(row)) => deletes.push(row),
+                    None => break,
+                }
+
+                if messages.len() >= config.batch_size {
+                    flush(&client, config, "messages", &mut messages).await;
+                }
+                if deletes.len() >= config.batch_size {
+                    flush(&client, config, "message_deletes", &mut deletes).await;
+                }
+            }
+            _ = interval.tick() => {
+                flush(&client, config, "messages", &mut messages).await;
+                flush(&client, config, "message_deletes", &mut deletes).await;
+            }
+        }
+    }
+}
+
+fn send(row: Row) {
+    if let Some(sender) = SENDER.get()
+        && sender.send(row).is_err()
+    {
+        error!("ClickHouse batcher is closed");
+    }
+}
+
+pub fn push_message(msg: &Message, guild_id: Option<u64>) {
+    if SENDER.get().is_none() {
+        return;
+    }
+
+    let row = json!({
+        "id": msg.id,
+        "channel_id": msg.channel_id,
+        "guild_id": guild_id.unwrap_or_default(),
+        "author_id": msg.author.id,
+        "created_at": format_time(timezone::snowflake_time(msg.id).with_timezone(&Utc)),
+        "edited_at": msg.edited_timestamp.map(format_time),
+        "content": msg.content.as_deref().unwrap_or_default(),
+        "message_type": message_type_id(&msg.r#type),
+        "attachments": msg.attachments.len(),
+        "language": msg.content.as_deref().and_then(language::detect_language),
+        "tags": tagging::tags_for(msg, guild_id),
+        "version": Utc::now().timestamp_millis(),
+    });
+
+    send(Row::Message(row.to_string()));
+}
+
+pub fn push_deletes(message_ids: &[u64], channel_id: u64, guild_id: Option<u64>) {
+    if SENDER.get().is_none() {
+        return;
+    }
+
+    let deleted_at = format_time(Utc::now());
+    for id in message_ids {
+        let row = json!({
+            "id": id,
+            "channel_id": channel_id,
+            "guild_id": guild_id.unwrap_or_default(),
+            "deleted_at": deleted_at,
+        });
+        send(Row::Delete(row.to_string()));
+    }
+}
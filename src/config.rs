@@ -1,34 +1,604 @@
+use crate::cli::parse_age;
+use crate::downloader::PATH_TEMPLATE_PLACEHOLDERS;
 use log::{error, info};
 use serde::Deserialize;
+use serde::de::{self, DeserializeSeed, Deserializer, IntoDeserializer, MapAccess, Visitor};
 use std::error::Error;
+use std::path::Path;
 use std::process::exit;
 use std::sync::OnceLock;
+use toml::{Table, Value};
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
     pub skip_bot_messages: bool,
     pub download_files: bool,
+    /// Root folder of the downloaded files
+    #[serde(default = "default_download_dir")]
+    pub download_dir: String,
+    /// Path of the attachments inside `download_dir`, with `{guild_id}`, `{channel_id}`,
+    /// `{message_id}`, `{attachment_id}`, `{date}`, `{mime}`, `{filename}` and `{extension}`
+    #[serde(default = "default_download_path_template")]
+    pub download_path_template: String,
+    /// MIME types downloaded, e.g. ["image/*", "video/mp4"]. Empty means all of them.
+    #[serde(default)]
+    pub download_allowed_mime: Vec<String>,
+    /// Files bigger than this are never downloaded
+    #[serde(default)]
+    pub download_max_bytes: Option<u64>,
+    /// Store the size, duration and EXIF of downloaded media, videos and audio need ffprobe
+    #[serde(default)]
+    pub extract_media_metadata: bool,
+    /// Create small JPEG previews of downloaded images and videos in `download_dir/thumbs`,
+    /// videos need ffmpeg
+    #[serde(default)]
+    pub generate_thumbnails: bool,
+    /// Max width and height of the thumbnails, in pixels
+    #[serde(default = "default_thumbnail_size")]
+    pub thumbnail_size: u32,
+    /// Antivirus scan of the downloaded attachments
+    #[serde(default)]
+    pub scan: Option<ScanConfig>,
+    #[serde(default)]
+    pub nsfw: Option<NsfwConfig>,
+    #[serde(default)]
+    pub transcription: Option<TranscriptionConfig>,
+    #[serde(default)]
+    pub web_archive: Option<WebArchiveConfig>,
     pub use_db: bool,
     pub db_url: String,
+    #[serde(default)]
+    pub download_avatars: bool,
+    #[serde(default)]
+    pub download_role_icons: bool,
+    /// IANA timezone used when displaying dates, e.g. "Europe/Paris". Defaults to UTC.
+    #[serde(default)]
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub download_collision_strategy: CollisionStrategy,
+    #[serde(default)]
+    pub tag_rules: Vec<TagRule>,
+    /// Minutes between two audit log fetches in sniff mode, 0 disables it
+    #[serde(default)]
+    pub audit_log_interval: u64,
+    /// Minutes between two archived thread discoveries in sniff mode, 0 disables it
+    #[serde(default)]
+    pub thread_discovery_interval: u64,
+    /// Seconds between two recent member searches in sniff mode, each one asks for the next
+    /// page of members of one guild. 0 disables it
+    #[serde(default = "default_member_search_interval")]
+    pub member_search_interval: u64,
+    /// Max amount of message events kept while the database is unreachable
+    #[serde(default = "default_db_buffer_limit")]
+    pub db_buffer_limit: usize,
+    /// Store the JSON of gateway events that aren't processed in the `raw_events` table
+    #[serde(default)]
+    pub store_raw_events: bool,
+    #[serde(default)]
+    pub sampling_rules: Vec<SamplingRule>,
+    #[serde(default)]
+    pub embeddings: Option<EmbeddingsConfig>,
+    #[serde(default)]
+    pub scoring: Option<ScoringConfig>,
+    #[serde(default)]
+    pub mirrors: Vec<MirrorRule>,
+    #[serde(default)]
+    pub stream: Option<StreamConfig>,
+    #[serde(default)]
+    pub clickhouse: Option<ClickHouseConfig>,
+    #[serde(default)]
+    pub search_index: Option<SearchIndexConfig>,
+    /// Heavy background jobs only run during these windows, none means anytime
+    #[serde(default)]
+    pub maintenance_windows: Vec<MaintenanceWindow>,
+    /// Hours between two runs of the retention rules in sniff mode, 0 disables them
+    #[serde(default)]
+    pub retention_interval: u64,
+    #[serde(default)]
+    pub retention_rules: Vec<RetentionRule>,
+    /// Also remove the downloaded files of messages that aren't stored anymore
+    #[serde(default)]
+    pub retention_remove_orphaned_files: bool,
+    /// Max seconds between two gateway reconnection attempts of an account
+    #[serde(default = "default_reconnect_max_delay")]
+    pub reconnect_max_delay: u64,
+    /// Discord webhook notified of events needing attention, such as dead tokens
+    #[serde(default)]
+    pub alert_webhook_url: Option<String>,
+    /// Mailbox notified of the same events as `alert_webhook_url`
+    #[serde(default)]
+    pub alert_smtp: Option<SmtpConfig>,
+    /// Seconds during which alerts are gathered before being sent together in sniff mode,
+    /// 0 to send them right away
+    #[serde(default = "default_alert_batch_interval")]
+    pub alert_batch_interval: u64,
+    /// Failed downloads in a row raising an alert, 0 to disable
+    #[serde(default = "default_alert_download_failures")]
+    pub alert_download_failures: u32,
+    #[serde(default)]
+    pub guild_hygiene: Option<GuildHygieneConfig>,
+}
+
+/// Guilds the accounts leave by themselves in sniff mode, to free room under the guild limit
+#[derive(Debug, Deserialize, Clone)]
+pub struct GuildHygieneConfig {
+    /// Hours between two checks of the guilds of each account
+    #[serde(default = "default_guild_hygiene_interval")]
+    pub interval: u64,
+    /// Guilds left whatever their activity
+    #[serde(default)]
+    pub blocked_guilds: Vec<u64>,
+    /// Guilds never left, even when a rule matches
+    #[serde(default)]
+    pub kept_guilds: Vec<u64>,
+    /// Leave the guilds with fewer members
+    #[serde(default)]
+    pub min_member_count: Option<u64>,
+    /// Leave the guilds with fewer stored messages per day over the last `activity_days`
+    #[serde(default)]
+    pub min_messages_per_day: Option<f64>,
+    #[serde(default = "default_guild_hygiene_activity_days")]
+    pub activity_days: u64,
+    /// Only log the guilds that would be left
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+fn default_guild_hygiene_interval() -> u64 {
+    24
+}
+
+fn default_guild_hygiene_activity_days() -> u64 {
+    7
+}
+
+/// Messages permanently removed by the retention policy, every filter set must match
+#[derive(Debug, Deserialize, Clone)]
+pub struct RetentionRule {
+    /// Age of the messages, e.g. "90d", "12w" or "36h"
+    pub older_than: String,
+    #[serde(default)]
+    pub guild_id: Option<u64>,
+    #[serde(default)]
+    pub channel_id: Option<u64>,
+    #[serde(default)]
+    pub only_bots: bool,
+}
+
+/// Daily time range in the configured timezone, may span midnight
+#[derive(Debug, Deserialize, Clone)]
+pub struct MaintenanceWindow {
+    /// "HH:MM"
+    pub start: String,
+    /// "HH:MM", before `start` when the window ends the next day
+    pub end: String,
+    /// Days the window starts on, e.g. ["sat", "sun"]. Empty means every day.
+    #[serde(default)]
+    pub days: Vec<String>,
+}
+
+/// Publishes the messages, edits and deletes seen in sniff mode to a broker
+#[derive(Debug, Deserialize, Clone)]
+pub struct StreamConfig {
+    pub backend: StreamBackend,
+    /// Kafka bootstrap servers or NATS server URL
+    pub url: String,
+    /// Events are published to `<prefix>.message_create`, `<prefix>.message_update`
+    /// and `<prefix>.message_delete`
+    #[serde(default = "default_topic_prefix")]
+    pub topic_prefix: String,
+}
+
+fn default_topic_prefix() -> String {
+    "slurpslurp".to_string()
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum StreamBackend {
+    Kafka,
+    Nats,
+}
+
+/// Copies the stored messages and deletes to ClickHouse, in batches
+#[derive(Debug, Deserialize, Clone)]
+pub struct ClickHouseConfig {
+    /// URL of the HTTP interface, e.g. "http://localhost:8123"
+    pub url: String,
+    #[serde(default = "default_clickhouse_database")]
+    pub database: String,
+    #[serde(default = "default_clickhouse_user")]
+    pub user: String,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Rows sent in a single insert
+    #[serde(default = "default_clickhouse_batch_size")]
+    pub batch_size: usize,
+    /// Max seconds rows wait before being sent
+    #[serde(default = "default_clickhouse_flush_interval")]
+    pub flush_interval: u64,
+}
+
+fn default_clickhouse_database() -> String {
+    "slurpslurp".to_string()
+}
+
+fn default_clickhouse_user() -> String {
+    "default".to_string()
+}
+
+fn default_clickhouse_batch_size() -> usize {
+    10_000
+}
+
+fn default_clickhouse_flush_interval() -> u64 {
+    5
+}
+
+/// Pushes the stored messages to a search engine, for typo tolerant search
+#[derive(Debug, Deserialize, Clone)]
+pub struct SearchIndexConfig {
+    pub backend: SearchBackend,
+    pub url: String,
+    #[serde(default)]
+    pub api_key: Option<String>,
+    #[serde(default = "default_search_index")]
+    pub index: String,
+    /// Documents sent in a single request
+    #[serde(default = "default_search_batch_size")]
+    pub batch_size: usize,
+    /// Max seconds documents wait before being sent
+    #[serde(default = "default_search_flush_interval")]
+    pub flush_interval: u64,
+}
+
+fn default_search_index() -> String {
+    "messages".to_string()
+}
+
+fn default_search_batch_size() -> usize {
+    1000
+}
+
+fn default_search_flush_interval() -> u64 {
+    5
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SearchBackend {
+    Meilisearch,
+    Elasticsearch,
+}
+
+/// Forwards the messages created in a channel to a webhook in sniff mode
+#[derive(Debug, Deserialize, Clone)]
+pub struct MirrorRule {
+    pub channel_id: u64,
+    pub webhook_url: String,
+}
+
+/// OpenAI-compatible embedding endpoint used for semantic search
+#[derive(Debug, Deserialize, Clone)]
+pub struct EmbeddingsConfig {
+    /// Base URL of the API, e.g. "http://localhost:11434/v1"
+    pub endpoint: String,
+    pub model: String,
+    #[serde(default)]
+    pub api_key: Option<String>,
+    /// Size of the vectors returned by the model
+    pub dimensions: u32,
+    #[serde(default = "default_embeddings_batch_size")]
+    pub batch_size: usize,
+}
+
+fn default_embeddings_batch_size() -> usize {
+    64
+}
+
+/// Scans downloaded attachments with clamd or a command, infected files are moved away
+#[derive(Debug, Deserialize, Clone)]
+pub struct ScanConfig {
+    /// "127.0.0.1:3310", or the path of the clamd unix socket
+    #[serde(default)]
+    pub clamd_address: Option<String>,
+    /// Used instead of clamd, e.g. ["clamscan", "--no-summary", "{path}"]. Exit code 0 means
+    /// clean and 1 infected.
+    #[serde(default)]
+    pub command: Option<Vec<String>>,
+    #[serde(default = "default_quarantine_dir")]
+    pub quarantine_dir: String,
+}
+
+fn default_quarantine_dir() -> String {
+    "quarantine".to_string()
+}
+
+/// SMTP server sending the alert emails
+#[derive(Debug, Deserialize, Clone)]
+pub struct SmtpConfig {
+    pub host: String,
+    /// Defaults to 587 with starttls, 465 with tls and 25 without encryption
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    #[default]
+    Starttls,
+    Tls,
+    None,
+}
+
+/// ONNX image classifier giving downloaded images an explicitness score, needs the nsfw
+/// cargo feature. The defaults fit the 5 classes model of GantMan/nsfw_model.
+#[derive(Debug, Deserialize, Clone)]
+pub struct NsfwConfig {
+    /// Path of the .onnx model
+    pub model: String,
+    /// Side of the square input of the model, in pixels
+    #[serde(default = "default_nsfw_input_size")]
+    pub input_size: u32,
+    #[serde(default)]
+    pub layout: TensorLayout,
+    /// Pixels are scaled to 0.0..1.0, then normalized per channel with `(value - mean) / std`
+    #[serde(default)]
+    pub mean: [f32; 3],
+    #[serde(default = "default_nsfw_std")]
+    pub std: [f32; 3],
+    /// For models returning logits instead of probabilities
+    #[serde(default)]
+    pub softmax: bool,
+    /// Indexes of the output classes summed into the score
+    #[serde(default = "default_nsfw_explicit_outputs")]
+    pub explicit_outputs: Vec<usize>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TensorLayout {
+    /// Channels last, usual for TensorFlow models
+    #[default]
+    Nhwc,
+    /// Channels first, usual for PyTorch models
+    Nchw,
+}
+
+fn default_nsfw_input_size() -> u32 {
+    224
+}
+
+fn default_nsfw_std() -> [f32; 3] {
+    [1.0; 3]
+}
+
+// drawings, hentai, neutral, porn, sexy
+fn default_nsfw_explicit_outputs() -> Vec<usize> {
+    vec![1, 3, 4]
+}
+
+/// Speech to text of downloaded voice messages, with an OpenAI-compatible endpoint or a command
+#[derive(Debug, Deserialize, Clone)]
+pub struct TranscriptionConfig {
+    /// Base URL of the API, e.g. "http://localhost:8000/v1"
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    #[serde(default = "default_transcription_model")]
+    pub model: String,
+    #[serde(default)]
+    pub api_key: Option<String>,
+    /// Used instead of the endpoint, prints the transcript, e.g.
+    /// ["whisper-cli", "-m", "ggml-base.bin", "-nt", "-np", "-f", "{path}"]
+    #[serde(default)]
+    pub command: Option<Vec<String>>,
+    /// ISO 639-1 code, detected by the model when not set
+    #[serde(default)]
+    pub language: Option<String>,
+    /// Also transcribe audio files that aren't voice messages
+    #[serde(default)]
+    pub all_audio: bool,
+}
+
+fn default_transcription_model() -> String {
+    "whisper-1".to_string()
+}
+
+/// Saves the pages linked in messages under `download_dir/web`
+#[derive(Debug, Deserialize, Clone)]
+pub struct WebArchiveConfig {
+    /// Minutes between two archiving runs in sniff mode, 0 only archives with `archive-links`
+    #[serde(default)]
+    pub interval: u64,
+    /// Only archive links to these domains and their subdomains, empty means all of them
+    #[serde(default)]
+    pub domains: Vec<String>,
+    #[serde(default = "default_web_archive_exclude_domains")]
+    pub exclude_domains: Vec<String>,
+    /// Pages bigger than this are skipped
+    #[serde(default = "default_web_archive_max_bytes")]
+    pub max_bytes: u64,
+    /// Renders the page instead of fetching it, with `{url}` and `{path}` placeholders. The
+    /// command writes `{path}` or prints the page, e.g. ["monolith", "{url}", "-o", "{path}"]
+    #[serde(default)]
+    pub command: Option<Vec<String>>,
+}
+
+// links to Discord itself are already stored or need a token
+fn default_web_archive_exclude_domains() -> Vec<String> {
+    [
+        "discord.com",
+        "discord.gg",
+        "discordapp.com",
+        "discordapp.net",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+fn default_web_archive_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+/// Hugging Face text classification endpoints scoring the toxicity and sentiment of messages
+#[derive(Debug, Deserialize, Clone)]
+pub struct ScoringConfig {
+    /// URL of a toxicity model, e.g. unitary/toxic-bert
+    #[serde(default)]
+    pub toxicity_endpoint: Option<String>,
+    /// Labels of the toxicity model summed into the toxicity score
+    #[serde(default = "default_toxic_labels")]
+    pub toxic_labels: Vec<String>,
+    /// URL of a sentiment model, e.g. cardiffnlp/twitter-roberta-base-sentiment-latest
+    #[serde(default)]
+    pub sentiment_endpoint: Option<String>,
+    #[serde(default = "default_positive_label")]
+    pub positive_label: String,
+    #[serde(default = "default_negative_label")]
+    pub negative_label: String,
+    #[serde(default)]
+    pub api_key: Option<String>,
+    #[serde(default = "default_scoring_batch_size")]
+    pub batch_size: usize,
+}
+
+fn default_toxic_labels() -> Vec<String> {
+    vec!["toxic".to_string()]
+}
+
+fn default_positive_label() -> String {
+    "positive".to_string()
+}
+
+fn default_negative_label() -> String {
+    "negative".to_string()
+}
+
+fn default_scoring_batch_size() -> usize {
+    32
+}
+
+/// Only stores a fraction of the messages of a guild
+#[derive(Debug, Deserialize, Clone)]
+pub struct SamplingRule {
+    pub guild_id: u64,
+    /// Between 0.0 and 1.0, e.g. 0.1 stores 10% of the messages
+    pub rate: f64,
+}
+
+fn default_download_dir() -> String {
+    "downloads".to_string()
+}
+
+fn default_download_path_template() -> String {
+    "{mime}/{attachment_id}_{filename}".to_string()
+}
+
+fn default_thumbnail_size() -> u32 {
+    256
+}
+
+fn default_reconnect_max_delay() -> u64 {
+    300
+}
+
+fn default_alert_batch_interval() -> u64 {
+    60
+}
+
+fn default_alert_download_failures() -> u32 {
+    10
+}
+
+fn default_db_buffer_limit() -> usize {
+    10_000
+}
+
+fn default_member_search_interval() -> u64 {
+    600
+}
+
+/// Tags a message when every filter that is set matches. Empty lists match anything.
+#[derive(Debug, Deserialize, Clone)]
+pub struct TagRule {
+    pub tag: String,
+    #[serde(default)]
+    pub guild_ids: Vec<u64>,
+    #[serde(default)]
+    pub channel_ids: Vec<u64>,
+    #[serde(default)]
+    pub author_ids: Vec<u64>,
+    /// Regex matched against the message content
+    #[serde(default)]
+    pub pattern: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum CollisionStrategy {
+    /// Never touch a file that already exists
+    Skip,
+    /// Download again when the existing file size doesn't match the remote one
+    #[default]
+    OverwriteIfSizeDiffers,
+    /// Keep the existing file and save the new one with a `_v<n>` suffix
+    VersionSuffix,
 }
 
 static CONFIG: OnceLock<Config> = OnceLock::new();
 
+lazy_static::lazy_static! {
+    static ref PLACEHOLDER_REGEX: regex::Regex = regex::Regex::new(r"\{([^}]*)\}").unwrap();
+}
+
+// environment variables overriding config fields, nested fields are separated by `__`,
+// e.g. SLURP_DB_URL or SLURP_CLICKHOUSE__URL
+const ENV_PREFIX: &str = "SLURP_";
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
 impl Config {
-    pub fn init() -> Result<(), Box<dyn Error>> {
-        if !std::path::Path::new("config.toml").exists() {
-            if std::path::Path::new("config_example.toml").exists() {
-                error!(
-                    "Please rename 'config_example.toml' to 'config.toml' and fill in the required fields."
-                );
-            } else {
-                error!("Configuration file 'config.toml' is missing.");
+    /// Loads the config file, `config.toml` by default, then applies the `SLURP_*` overrides.
+    /// The file can be left out when the environment sets every required field.
+    pub fn init(path: Option<&str>) -> Result<(), Box<dyn Error>> {
+        let overrides = env_overrides();
+
+        let mut table = match path {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .map_err(|e| format!("Error reading {}: {}", path, e))?;
+                toml::from_str::<Table>(&content)?
             }
-            exit(1);
-        }
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                toml::from_str::<Table>(&std::fs::read_to_string(DEFAULT_CONFIG_PATH)?)?
+            }
+            None if !overrides.is_empty() => Table::new(),
+            None => {
+                if Path::new("config_example.toml").exists() {
+                    error!(
+                        "Please rename 'config_example.toml' to 'config.toml' and fill in the required fields."
+                    );
+                } else {
+                    error!("Configuration file 'config.toml' is missing.");
+                }
+                exit(1);
+            }
+        };
 
-        let config_content = std::fs::read_to_string("config.toml")?;
-        let config: Config = toml::from_str(&config_content)?;
+        let config = deserialize(table, &overrides)?;
+
+        let problems = config.validate();
+        if !problems.is_empty() {
+            return Err(format!("Invalid configuration:\n  - {}", problems.join("\n  - ")).into());
+        }
 
         CONFIG
             .set(config)
@@ -42,3 +612,664 @@ impl Config {
         CONFIG.get().expect("Configuration not initialized")
     }
 }
+
+impl Config {
+    /// Every problem of the config, checked before anything uses it
+    fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.use_db {
+            if let Err(e) = self.db_url.parse::<tokio_postgres::Config>() {
+                problems.push(format!("db_url is not a valid PostgreSQL URL: {}", e));
+            }
+        } else {
+            // these read or write the database
+            let needs_db = [
+                ("store_raw_events", self.store_raw_events),
+                ("transcription", self.transcription.is_some()),
+                ("web_archive", self.web_archive.is_some()),
+                ("embeddings", self.embeddings.is_some()),
+                ("scoring", self.scoring.is_some()),
+                ("retention_interval", self.retention_interval > 0),
+                ("audit_log_interval", self.audit_log_interval > 0),
+                (
+                    "thread_discovery_interval",
+                    self.thread_discovery_interval > 0,
+                ),
+                (
+                    "guild_hygiene member and activity rules",
+                    self.guild_hygiene.as_ref().is_some_and(|hygiene| {
+                        hygiene.min_member_count.is_some() || hygiene.min_messages_per_day.is_some()
+                    }),
+                ),
+            ];
+            for (option, enabled) in needs_db {
+                if enabled {
+                    problems.push(format!("{} needs use_db to be enabled", option));
+                }
+            }
+        }
+
+        if Path::new(&self.download_dir).is_file() {
+            problems.push(format!(
+                "download_dir: {} is a file, not a folder",
+                self.download_dir
+            ));
+        }
+        for placeholder in PLACEHOLDER_REGEX.captures_iter(&self.download_path_template) {
+            if !PATH_TEMPLATE_PLACEHOLDERS.contains(&&placeholder[1]) {
+                problems.push(format!(
+                    "download_path_template: unknown placeholder {}, expected one of {}",
+                    &placeholder[0],
+                    PATH_TEMPLATE_PLACEHOLDERS.join(", ")
+                ));
+            }
+        }
+        // two attachments must never get the same path
+        if !self.download_path_template.contains("{attachment_id}") {
+            problems.push("download_path_template must contain {attachment_id}".to_string());
+        }
+        if self.download_path_template.starts_with('/')
+            || self
+                .download_path_template
+                .split('/')
+                .any(|part| part == "..")
+        {
+            problems.push("download_path_template must stay inside download_dir".to_string());
+        }
+
+        if self.generate_thumbnails && self.thumbnail_size == 0 {
+            problems.push("thumbnail_size must be greater than 0".to_string());
+        }
+
+        if let Some(scan) = &self.scan {
+            match (&scan.clamd_address, &scan.command) {
+                (None, None) => problems.push("scan needs clamd_address or command".to_string()),
+                (Some(_), Some(_)) => problems
+                    .push("scan: clamd_address and command can't be used together".to_string()),
+                (None, Some(command)) if command.is_empty() => {
+                    problems.push("scan.command is empty".to_string())
+                }
+                _ => {}
+            }
+            if Path::new(&scan.quarantine_dir).is_file() {
+                problems.push(format!(
+                    "scan.quarantine_dir: {} is a file, not a folder",
+                    scan.quarantine_dir
+                ));
+            }
+        }
+
+        if let Some(nsfw) = &self.nsfw {
+            if !Path::new(&nsfw.model).is_file() {
+                problems.push(format!("nsfw.model: {} doesn't exist", nsfw.model));
+            }
+            if nsfw.input_size == 0 {
+                problems.push("nsfw.input_size must be greater than 0".to_string());
+            }
+            if nsfw.std.contains(&0.0) {
+                problems.push("nsfw.std can't contain 0".to_string());
+            }
+            if nsfw.explicit_outputs.is_empty() {
+                problems.push("nsfw.explicit_outputs is empty".to_string());
+            }
+        }
+
+        if let Some(hygiene) = &self.guild_hygiene {
+            if hygiene.interval == 0 {
+                problems.push("guild_hygiene.interval must be greater than 0".to_string());
+            }
+            if hygiene.activity_days == 0 {
+                problems.push("guild_hygiene.activity_days must be greater than 0".to_string());
+            }
+        }
+
+        if let Some(transcription) = &self.transcription {
+            match (&transcription.endpoint, &transcription.command) {
+                (None, None) => {
+                    problems.push("transcription needs an endpoint or a command".to_string())
+                }
+                (Some(_), Some(_)) => problems
+                    .push("transcription: endpoint and command can't be used together".to_string()),
+                (None, Some(command)) if command.is_empty() => {
+                    problems.push("transcription.command is empty".to_string())
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(web_archive) = &self.web_archive
+            && web_archive
+                .command
+                .as_ref()
+                .is_some_and(|command| command.is_empty())
+        {
+            problems.push("web_archive.command is empty".to_string());
+        }
+
+        for pattern in &self.download_allowed_mime {
+            let valid = match pattern.split_once('/') {
+                Some((kind, subtype)) => !kind.is_empty() && !subtype.is_empty(),
+                None => pattern == "*",
+            };
+            if !valid {
+                problems.push(format!(
+                    "download_allowed_mime: invalid MIME type '{}', expected e.g. image/* or video/mp4",
+                    pattern
+                ));
+            }
+        }
+
+        if let Some(timezone) = &self.timezone
+            && timezone.parse::<chrono_tz::Tz>().is_err()
+        {
+            problems.push(format!("timezone: unknown timezone '{}'", timezone));
+        }
+
+        for rule in &self.tag_rules {
+            if let Some(pattern) = &rule.pattern
+                && let Err(e) = regex::Regex::new(pattern)
+            {
+                problems.push(format!("tag_rules '{}': invalid pattern: {}", rule.tag, e));
+            }
+        }
+
+        for rule in &self.sampling_rules {
+            if !(0.0..=1.0).contains(&rule.rate) {
+                problems.push(format!(
+                    "sampling_rules of guild {}: rate {} is not between 0.0 and 1.0",
+                    rule.guild_id, rule.rate
+                ));
+            }
+        }
+
+        for window in &self.maintenance_windows {
+            for time in [&window.start, &window.end] {
+                if chrono::NaiveTime::parse_from_str(time, "%H:%M").is_err() {
+                    problems.push(format!(
+                        "maintenance_windows: invalid time '{}', expected HH:MM",
+                        time
+                    ));
+                }
+            }
+            if window.start == window.end {
+                problems.push(format!(
+                    "maintenance_windows: window starts and ends at {}",
+                    window.start
+                ));
+            }
+            for day in &window.days {
+                if day.parse::<chrono::Weekday>().is_err() {
+                    problems.push(format!("maintenance_windows: invalid day '{}'", day));
+                }
+            }
+        }
+
+        for rule in &self.retention_rules {
+            if let Err(e) = parse_age(&rule.older_than) {
+                problems.push(format!("retention_rules: {}", e));
+            }
+        }
+        if self.retention_interval > 0 && self.retention_rules.is_empty() {
+            problems.push("retention_interval is set but there are no retention_rules".to_string());
+        }
+
+        for mirror in &self.mirrors {
+            if !mirror.webhook_url.starts_with("https://") {
+                problems.push(format!(
+                    "mirrors of channel {}: webhook_url must be an https URL",
+                    mirror.channel_id
+                ));
+            }
+        }
+        if let Some(url) = &self.alert_webhook_url
+            && !url.starts_with("https://")
+        {
+            problems.push("alert_webhook_url must be an https URL".to_string());
+        }
+        if let Some(smtp) = &self.alert_smtp {
+            if smtp.to.is_empty() {
+                problems.push("alert_smtp.to is empty".to_string());
+            }
+            if smtp.username.is_some() != smtp.password.is_some() {
+                problems.push("alert_smtp needs both username and password".to_string());
+            }
+        }
+
+        if let Some(embeddings) = &self.embeddings {
+            if embeddings.dimensions == 0 {
+                problems.push("embeddings.dimensions must be greater than 0".to_string());
+            }
+            if embeddings.batch_size == 0 {
+                problems.push("embeddings.batch_size must be greater than 0".to_string());
+            }
+        }
+        if let Some(scoring) = &self.scoring {
+            if scoring.toxicity_endpoint.is_none() && scoring.sentiment_endpoint.is_none() {
+                problems
+                    .push("scoring needs a toxicity_endpoint or a sentiment_endpoint".to_string());
+            }
+            if scoring.batch_size == 0 {
+                problems.push("scoring.batch_size must be greater than 0".to_string());
+            }
+        }
+        if let Some(clickhouse) = &self.clickhouse
+            && clickhouse.batch_size == 0
+        {
+            problems.push("clickhouse.batch_size must be greater than 0".to_string());
+        }
+        if let Some(search_index) = &self.search_index
+            && search_index.batch_size == 0
+        {
+            problems.push("search_index.batch_size must be greater than 0".to_string());
+        }
+
+        #[cfg(not(feature = "kafka"))]
+        if self
+            .stream
+            .as_ref()
+            .is_some_and(|stream| stream.backend == StreamBackend::Kafka)
+        {
+            problems.push("stream: the kafka backend needs the kafka cargo feature".to_string());
+        }
+        #[cfg(not(feature = "nats"))]
+        if self
+            .stream
+            .as_ref()
+            .is_some_and(|stream| stream.backend == StreamBackend::Nats)
+        {
+            problems.push("stream: the nats backend needs the nats cargo feature".to_string());
+        }
+
+        #[cfg(not(feature = "nsfw"))]
+        if self.nsfw.is_some() {
+            problems.push("nsfw needs the nsfw cargo feature".to_string());
+        }
+
+        problems
+    }
+}
+
+/// `SLURP_*` variables as (lowercase key, raw value) pairs
+fn env_overrides() -> Vec<(String, String)> {
+    std::env::vars()
+        .filter_map(|(name, raw)| {
+            let key = name.strip_prefix(ENV_PREFIX)?.to_lowercase();
+            Some((key, raw))
+        })
+        .collect()
+}
+
+/// Value of an override for a field that isn't a string: parsed as TOML when it can be,
+/// e.g. `true`, `10` or `[1, 2]`, kept as a string otherwise
+fn parse_override(raw: &str) -> Value {
+    toml::from_str::<Table>(&format!("value = {}", raw))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.to_string()))
+}
+
+/// Applies the overrides to the table of the config file and deserializes it. Each override is
+/// read as the type of the field it sets, so a digit-only password stays a string while
+/// `SLURP_USE_DB=true` is a boolean.
+fn deserialize(
+    mut table: Table,
+    overrides: &[(String, String)],
+) -> Result<Config, toml::de::Error> {
+    let mut env = Table::new();
+    for (key, raw) in overrides {
+        set_path(&mut table, key, Value::String(raw.clone()));
+        set_path(&mut env, key, Value::String(raw.clone()));
+    }
+
+    let env = Value::Table(env);
+    Config::deserialize(Overridden {
+        value: Value::Table(table),
+        env: Some(&env),
+    })
+}
+
+// a value of the config with the overrides at the same path, a string for an overridden field
+struct Overridden<'a> {
+    value: Value,
+    env: Option<&'a Value>,
+}
+
+impl Overridden<'_> {
+    fn raw(&self) -> Option<&str> {
+        self.env.and_then(Value::as_str)
+    }
+
+    // what a field that isn't a string gets
+    fn parsed(self) -> Value {
+        match self.raw() {
+            Some(raw) => parse_override(raw),
+            None => self.value,
+        }
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident),*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                self.parsed().$method(visitor)
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for Overridden<'_> {
+    type Error = toml::de::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match (self.value, self.env) {
+            (Value::Table(table), Some(Value::Table(env))) => visitor.visit_map(OverriddenTable {
+                entries: table.into_iter(),
+                env,
+                value: None,
+            }),
+            (value, env) => Overridden { value, env }.parsed().deserialize_any(visitor),
+        }
+    }
+
+    deserialize_parsed!(
+        deserialize_bool,
+        deserialize_i8,
+        deserialize_i16,
+        deserialize_i32,
+        deserialize_i64,
+        deserialize_u8,
+        deserialize_u16,
+        deserialize_u32,
+        deserialize_u64,
+        deserialize_f32,
+        deserialize_f64,
+        deserialize_bytes,
+        deserialize_byte_buf,
+        deserialize_seq,
+        deserialize_unit
+    );
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.raw() {
+            Some(raw) => visitor.visit_str(raw),
+            None => self.value.deserialize_string(visitor),
+        }
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.parsed().deserialize_unit_struct(name, visitor)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.parsed().deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.parsed().deserialize_tuple_struct(name, len, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_any(visitor)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_any(visitor)
+    }
+
+    // the variants are strings, the override is taken as it is
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.value.deserialize_enum(name, variants, visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_unit()
+    }
+}
+
+struct OverriddenTable<'a> {
+    entries: toml::map::IntoIter,
+    env: &'a Table,
+    value: Option<Overridden<'a>>,
+}
+
+impl<'de> MapAccess<'de> for OverriddenTable<'_> {
+    type Error = toml::de::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Self::Error> {
+        let Some((key, value)) = self.entries.next() else {
+            return Ok(None);
+        };
+        self.value = Some(Overridden {
+            env: self.env.get(&key),
+            value,
+        });
+        seed.deserialize(key.into_deserializer()).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, Self::Error> {
+        let Some(value) = self.value.take() else {
+            return Err(de::Error::custom("value asked before its key"));
+        };
+        seed.deserialize(value)
+    }
+}
+
+fn set_path(table: &mut Table, key: &str, value: Value) {
+    match key.split_once("__") {
+        Some((head, rest)) => {
+            let entry = table
+                .entry(head.to_string())
+                .or_insert_with(|| Value::Table(Table::new()));
+            if !entry.is_table() {
+                *entry = Value::Table(Table::new());
+            }
+            if let Value::Table(nested) = entry {
+                set_path(nested, rest, value);
+            }
+        }
+        None => {
+            table.insert(key.to_string(), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn required_fields() -> Table {
+        toml::from_str(
+            r#"
+            skip_bot_messages = false
+            download_files = false
+            use_db = false
+            "#,
+        )
+        .unwrap()
+    }
+
+    // overrides as given by env_overrides, without touching the environment
+    fn apply(vars: &[(&str, &str)]) -> Result<Config, toml::de::Error> {
+        let overrides: Vec<(String, String)> = vars
+            .iter()
+            .map(|(key, raw)| (key.to_string(), raw.to_string()))
+            .collect();
+
+        deserialize(required_fields(), &overrides)
+    }
+
+    #[test]
+    fn parse_override_reads_toml_values() {
+        assert_eq!(parse_override("true"), Value::Boolean(true));
+        assert_eq!(parse_override("10"), Value::Integer(10));
+        assert_eq!(
+            parse_override("[1, 2]"),
+            Value::Array(vec![Value::Integer(1), Value::Integer(2)])
+        );
+        assert_eq!(
+            parse_override("postgres://localhost/slurpslurp"),
+            Value::String("postgres://localhost/slurpslurp".to_string())
+        );
+        assert_eq!(parse_override(""), Value::String(String::new()));
+    }
+
+    #[test]
+    fn set_path_creates_nested_tables() {
+        let mut table = Table::new();
+        set_path(
+            &mut table,
+            "db_url",
+            Value::String("postgres://db".to_string()),
+        );
+        set_path(
+            &mut table,
+            "clickhouse__url",
+            Value::String("http://ch".to_string()),
+        );
+        set_path(&mut table, "clickhouse__batch_size", Value::Integer(10));
+
+        assert_eq!(table["db_url"].as_str(), Some("postgres://db"));
+        assert_eq!(table["clickhouse"]["url"].as_str(), Some("http://ch"));
+        assert_eq!(table["clickhouse"]["batch_size"].as_integer(), Some(10));
+    }
+
+    #[test]
+    fn set_path_replaces_values_in_the_way() {
+        let mut table = Table::new();
+        set_path(&mut table, "alert_smtp", Value::String("oops".to_string()));
+        set_path(
+            &mut table,
+            "alert_smtp__host",
+            Value::String("smtp".to_string()),
+        );
+
+        assert_eq!(table["alert_smtp"]["host"].as_str(), Some("smtp"));
+    }
+
+    #[test]
+    fn overrides_apply_to_typed_fields() {
+        let config = apply(&[
+            ("db_url", "postgres://db/slurpslurp"),
+            ("use_db", "true"),
+            ("db_buffer_limit", "50"),
+        ])
+        .unwrap();
+
+        assert_eq!(config.db_url, "postgres://db/slurpslurp");
+        assert!(config.use_db);
+        assert_eq!(config.db_buffer_limit, 50);
+    }
+
+    #[test]
+    fn numeric_overrides_of_text_fields_stay_strings() {
+        let config = apply(&[
+            ("db_url", "1234"),
+            ("alert_smtp__host", "smtp.example.com"),
+            ("alert_smtp__port", "2525"),
+            ("alert_smtp__password", "123456"),
+            ("alert_smtp__from", "slurp@example.com"),
+            ("alert_smtp__to", r#"["me@example.com"]"#),
+        ])
+        .unwrap();
+
+        assert_eq!(config.db_url, "1234");
+        let smtp = config.alert_smtp.unwrap();
+        assert_eq!(smtp.port, Some(2525));
+        assert_eq!(smtp.password.as_deref(), Some("123456"));
+    }
+
+    #[test]
+    fn overrides_follow_the_type_of_nested_fields() {
+        let config = apply(&[
+            ("db_url", "true"),
+            ("download_collision_strategy", "version-suffix"),
+            ("guild_hygiene__blocked_guilds", "[1, 2]"),
+            ("guild_hygiene__min_messages_per_day", "1"),
+        ])
+        .unwrap();
+
+        assert_eq!(config.db_url, "true");
+        assert_eq!(
+            config.download_collision_strategy,
+            CollisionStrategy::VersionSuffix
+        );
+        let hygiene = config.guild_hygiene.unwrap();
+        assert_eq!(hygiene.blocked_guilds, vec![1, 2]);
+        assert_eq!(hygiene.min_messages_per_day, Some(1.0));
+    }
+
+    #[test]
+    fn overrides_replace_file_values() {
+        let mut table = required_fields();
+        table.insert(
+            "db_url".to_string(),
+            Value::String("postgres://file".to_string()),
+        );
+        table.insert("db_buffer_limit".to_string(), Value::Integer(10));
+        let overrides = vec![("db_buffer_limit".to_string(), "20".to_string())];
+
+        let config = deserialize(table, &overrides).unwrap();
+        assert_eq!(config.db_url, "postgres://file");
+        assert_eq!(config.db_buffer_limit, 20);
+    }
+
+    #[test]
+    fn invalid_overrides_are_still_rejected() {
+        assert!(apply(&[("db_url", "postgres://db"), ("db_buffer_limit", "lots")]).is_err());
+    }
+}
@@ -0,0 +1,216 @@
+use log::{debug, info};
+use std::collections::{HashMap, HashSet};
+use tokio::sync::Mutex;
+
+/// Gives each guild a single primary account subscribing to it and processing its messages,
+/// so accounts sharing guilds don't receive and store the same events several times. Channels
+/// the primary account can't see are processed by another account of the guild receiving them.
+#[derive(Default)]
+struct Coordinator {
+    // guilds each connected account is in
+    members: HashMap<usize, HashSet<u64>>,
+    owners: HashMap<u64, usize>,
+    // account processing the messages of each guild channel, with the guild of the channel
+    channel_owners: HashMap<u64, (u64, usize)>,
+    // guilds handed over to an account that it still has to subscribe to
+    pending_subscriptions: HashMap<usize, Vec<u64>>,
+}
+
+lazy_static::lazy_static! {
+    static ref COORDINATOR: Mutex<Coordinator> = Mutex::new(Coordinator::default());
+}
+
+impl Coordinator {
+    fn owned_count(&self, account_index: usize) -> usize {
+        self.owners
+            .values()
+            .filter(|owner| **owner == account_index)
+            .count()
+    }
+
+    // connected account in the guild owning the fewest guilds
+    fn pick_owner(&self, guild_id: u64) -> Option<usize> {
+        self.members
+            .iter()
+            .filter(|(_, guilds)| guilds.contains(&guild_id))
+            .map(|(account_index, _)| *account_index)
+            .min_by_key(|account_index| (self.owned_count(*account_index), *account_index))
+    }
+
+    // whether the account owns the guild, a guild joined after READY goes to the first account
+    // receiving its events
+    fn claim_guild(&mut self, account_index: usize, guild_id: u64) -> bool {
+        match self.owners.get(&guild_id) {
+            Some(owner) => *owner == account_index,
+            None => {
+                self.owners.insert(guild_id, account_index);
+                if let Some(guilds) = self.members.get_mut(&account_index) {
+                    guilds.insert(guild_id);
+                }
+                true
+            }
+        }
+    }
+}
+
+/// Registers the guilds of an account after READY and takes the guilds nobody owns,
+/// returns the guilds the account is primary for, the ones it should subscribe to
+pub async fn register_account(account_index: usize, guild_ids: &[u64]) -> Vec<u64> {
+    let mut coordinator = COORDINATOR.lock().await;
+    coordinator
+        .members
+        .insert(account_index, guild_ids.iter().copied().collect());
+    coordinator.pending_subscriptions.remove(&account_index);
+
+    let mut owned = Vec::new();
+    for guild_id in guild_ids {
+        let owner = *coordinator.owners.entry(*guild_id).or_insert(account_index);
+        if owner == account_index {
+            owned.push(*guild_id);
+        }
+    }
+
+    debug!(
+        "Account {} is primary for {}/{} guilds",
+        account_index,
+        owned.len(),
+        guild_ids.len()
+    );
+
+    owned
+}
+
+/// Guilds handed over to the account since the last call, after another account disconnected
+pub async fn take_pending_subscriptions(account_index: usize) -> Vec<u64> {
+    COORDINATOR
+        .lock()
+        .await
+        .pending_subscriptions
+        .remove(&account_index)
+        .unwrap_or_default()
+}
+
+/// Hands the guilds of a disconnected account over to other accounts in them
+pub async fn unregister_account(account_index: usize) {
+    let mut coordinator = COORDINATOR.lock().await;
+    if coordinator.members.remove(&account_index).is_none() {
+        return;
+    }
+    coordinator
+        .channel_owners
+        .retain(|_, (_, owner)| *owner != account_index);
+
+    let orphaned: Vec<u64> = coordinator
+        .owners
+        .iter()
+        .filter(|(_, owner)| **owner == account_index)
+        .map(|(guild_id, _)| *guild_id)
+        .collect();
+
+    let mut moved = 0;
+    for guild_id in &orphaned {
+        match coordinator.pick_owner(*guild_id) {
+            Some(new_owner) => {
+                coordinator.owners.insert(*guild_id, new_owner);
+                coordinator
+                    .pending_subscriptions
+                    .entry(new_owner)
+                    .or_default()
+                    .push(*guild_id);
+                moved += 1;
+            }
+            None => {
+                coordinator.owners.remove(guild_id);
+            }
+        }
+    }
+
+    if !orphaned.is_empty() {
+        info!(
+            "Account {} disconnected, {} of its {} guilds moved to other accounts",
+            account_index,
+            moved,
+            orphaned.len()
+        );
+    }
+}
+
+/// Removes a guild the account was banned from or left, hands it over to another account in it
+pub async fn leave_guild(account_index: usize, guild_id: u64) {
+    let mut coordinator = COORDINATOR.lock().await;
+    if let Some(guilds) = coordinator.members.get_mut(&account_index) {
+        guilds.remove(&guild_id);
+    }
+    coordinator
+        .channel_owners
+        .retain(|_, (guild, owner)| *guild != guild_id || *owner != account_index);
+    if coordinator.owners.get(&guild_id) != Some(&account_index) {
+        return;
+    }
+
+    match coordinator.pick_owner(guild_id) {
+        Some(new_owner) => {
+            coordinator.owners.insert(guild_id, new_owner);
+            coordinator
+                .pending_subscriptions
+                .entry(new_owner)
+                .or_default()
+                .push(guild_id);
+            info!(
+                "Account {} left guild {}, moved to account {}",
+                account_index, guild_id, new_owner
+            );
+        }
+        None => {
+            coordinator.owners.remove(&guild_id);
+        }
+    }
+}
+
+/// Whether the account should process the messages of the guild. DMs are always processed.
+pub async fn is_owner(account_index: usize, guild_id: Option<u64>) -> bool {
+    let Some(guild_id) = guild_id else {
+        return true;
+    };
+
+    COORDINATOR
+        .lock()
+        .await
+        .claim_guild(account_index, guild_id)
+}
+
+/// Whether the account should process a message event of the channel. The primary account of
+/// the guild processes the channels it receives messages from, the other accounts only the ones
+/// the primary account hasn't shown it can see, first come first served. DMs are always processed.
+pub async fn is_channel_owner(
+    account_index: usize,
+    guild_id: Option<u64>,
+    channel_id: Option<u64>,
+) -> bool {
+    let Some(guild_id) = guild_id else {
+        return true;
+    };
+    // the guild and the channel are claimed under the same lock, so another account can't
+    // take the channel in between
+    let mut coordinator = COORDINATOR.lock().await;
+    let primary = coordinator.claim_guild(account_index, guild_id);
+    let Some(channel_id) = channel_id else {
+        return primary;
+    };
+
+    match coordinator.channel_owners.get(&channel_id) {
+        Some((_, owner)) if *owner == account_index => true,
+        // the primary account can see the channel after all, it takes it back
+        Some(_) if !primary => false,
+        _ => {
+            debug!(
+                "Account {} processes channel {} of guild {}",
+                account_index, channel_id, guild_id
+            );
+            coordinator
+                .channel_owners
+                .insert(channel_id, (guild_id, account_index));
+            true
+        }
+    }
+}
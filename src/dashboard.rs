@@ -0,0 +1,225 @@
+use crate::BoxedResult;
+use crate::config::Config;
+use crate::database::is_db_available;
+use crate::logging;
+use crate::status::{self, AccountActivity, ConnectionState};
+use crate::timezone;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, Paragraph, Row, Sparkline, Table};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use tracing::Level;
+
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+// events/sec samples drawn by the sparkline
+const HISTORY_LENGTH: usize = 240;
+
+/// Draws the state of the sniffing accounts until `q` or `Esc` is pressed. The logs stop being
+/// printed, the warnings and errors are listed by the dashboard instead.
+pub async fn run() -> BoxedResult<()> {
+    logging::silence();
+
+    tokio::task::spawn_blocking(|| {
+        let mut terminal = ratatui::init();
+        let result = draw_loop(&mut terminal);
+        ratatui::restore();
+        result
+    })
+    .await??;
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct Rates {
+    last_sample: Option<Instant>,
+    last_counts: HashMap<usize, u64>,
+    per_account: HashMap<usize, f64>,
+    total: f64,
+    history: VecDeque<u64>,
+}
+
+impl Rates {
+    fn sample(&mut self, accounts: &[(usize, AccountActivity)]) {
+        let now = Instant::now();
+        let elapsed = self
+            .last_sample
+            .map(|last| now.duration_since(last).as_secs_f64())
+            .unwrap_or(0.0);
+
+        self.total = 0.0;
+        for (index, account) in accounts {
+            let previous = self
+                .last_counts
+                .insert(*index, account.events)
+                .unwrap_or(account.events);
+            let rate = if elapsed > 0.0 {
+                (account.events - previous) as f64 / elapsed
+            } else {
+                0.0
+            };
+            self.per_account.insert(*index, rate);
+            self.total += rate;
+        }
+
+        if self.history.len() >= HISTORY_LENGTH {
+            self.history.pop_front();
+        }
+        self.history.push_back(self.total.round() as u64);
+        self.last_sample = Some(now);
+    }
+}
+
+fn draw_loop(terminal: &mut DefaultTerminal) -> std::io::Result<()> {
+    let mut rates = Rates::default();
+    let mut next_sample = Instant::now();
+
+    loop {
+        let accounts = status::accounts();
+        if Instant::now() >= next_sample {
+            rates.sample(&accounts);
+            next_sample = Instant::now() + REFRESH_INTERVAL;
+        }
+
+        terminal.draw(|frame| draw(frame, &accounts, &rates))?;
+
+        let timeout = next_sample.saturating_duration_since(Instant::now());
+        if event::poll(timeout)?
+            && let Event::Key(key) = event::read()?
+            && key.kind == KeyEventKind::Press
+            && matches!(key.code, KeyCode::Char('q') | KeyCode::Esc)
+        {
+            return Ok(());
+        }
+    }
+}
+
+fn draw(frame: &mut Frame, accounts: &[(usize, AccountActivity)], rates: &Rates) {
+    let [header, accounts_area, chart, errors] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Length(accounts.len() as u16 + 3),
+        Constraint::Length(6),
+        Constraint::Min(5),
+    ])
+    .areas(frame.area());
+
+    frame.render_widget(header_line(), header);
+    frame.render_widget(accounts_table(accounts, rates), accounts_area);
+
+    let history: Vec<u64> = rates.history.iter().copied().collect();
+    // newest samples on the right, cut to the width of the chart
+    let width = chart.width.saturating_sub(2) as usize;
+    let visible = &history[history.len().saturating_sub(width)..];
+    let sparkline = Sparkline::default()
+        .block(Block::bordered().title(format!("Events/sec ({:.1})", rates.total)))
+        .data(visible)
+        .style(Style::default().fg(Color::Cyan));
+    frame.render_widget(sparkline, chart);
+
+    frame.render_widget(error_list(), errors);
+}
+
+fn header_line() -> Paragraph<'static> {
+    let uptime = status::uptime().as_secs();
+    let database = if !Config::get().use_db {
+        Span::raw("disabled")
+    } else if is_db_available() {
+        Span::styled("up", Style::default().fg(Color::Green))
+    } else {
+        Span::styled("down", Style::default().fg(Color::Red))
+    };
+
+    let line = Line::from(vec![
+        Span::raw(format!(
+            "Uptime {}h{:02}m{:02}s | Database ",
+            uptime / 3600,
+            uptime / 60 % 60,
+            uptime % 60
+        )),
+        database,
+        Span::raw(format!(
+            " | {} buffered events | {} downloads in progress | q to quit",
+            status::buffered_events(),
+            status::downloads_in_progress()
+        )),
+    ]);
+
+    Paragraph::new(line).block(Block::bordered().title("slurpslurp"))
+}
+
+fn accounts_table<'a>(accounts: &'a [(usize, AccountActivity)], rates: &Rates) -> Table<'a> {
+    let rows = accounts.iter().map(|(index, account)| {
+        let color = match account.state {
+            ConnectionState::Connected => Color::Green,
+            ConnectionState::Connecting | ConnectionState::Reconnecting => Color::Yellow,
+            ConnectionState::Dead => Color::Red,
+            ConnectionState::Stopped => Color::DarkGray,
+        };
+        let last_event = account
+            .last_event
+            .map(|time| timezone::to_local(time).format("%H:%M:%S").to_string())
+            .unwrap_or_else(|| "-".to_string());
+
+        Row::new(vec![
+            Span::raw(account.name.as_str()),
+            Span::styled(account.state.as_str(), Style::default().fg(color)),
+            Span::raw(account.guilds.to_string()),
+            Span::raw(account.events.to_string()),
+            Span::raw(format!(
+                "{:.1}",
+                rates.per_account.get(index).copied().unwrap_or(0.0)
+            )),
+            Span::raw(last_event),
+        ])
+    });
+
+    let widths = [
+        Constraint::Min(20),
+        Constraint::Length(12),
+        Constraint::Length(8),
+        Constraint::Length(12),
+        Constraint::Length(10),
+        Constraint::Length(10),
+    ];
+
+    Table::new(rows, widths)
+        .header(
+            Row::new(vec![
+                "Account",
+                "State",
+                "Guilds",
+                "Events",
+                "Events/s",
+                "Last event",
+            ])
+            .bold(),
+        )
+        .block(Block::bordered().title("Accounts"))
+}
+
+fn error_list() -> List<'static> {
+    let items = status::recent_errors().into_iter().map(|entry| {
+        let color = if entry.level == Level::ERROR {
+            Color::Red
+        } else {
+            Color::Yellow
+        };
+        ListItem::new(Line::from(vec![
+            Span::raw(format!(
+                "{} ",
+                timezone::to_local(entry.time).format("%H:%M:%S")
+            )),
+            Span::styled(
+                format!("{:<5} ", entry.level.as_str()),
+                Style::default().fg(color),
+            ),
+            Span::raw(entry.message),
+        ]))
+    });
+
+    List::new(items).block(Block::bordered().title("Recent errors"))
+}
@@ -1,15 +1,40 @@
 use crate::BoxedResult;
+use crate::alerts;
 use crate::config::Config;
+use crate::diff;
+use crate::downloader::user_asset_path;
+use crate::language;
+use crate::links;
+use crate::media_metadata::MediaMetadata;
+use crate::sampling;
+use crate::scoring;
+use crate::tagging;
+use crate::timezone;
+use chrono::{DateTime, Utc};
+use discord_client_gateway::events::structs::invite::InviteCreateEvent;
+use discord_client_structs::structs::audit_log::AuditLogEntry;
 use discord_client_structs::structs::channel::Channel;
-use discord_client_structs::structs::guild::GatewayGuild;
+use discord_client_structs::structs::emoji::Emoji;
 use discord_client_structs::structs::guild::role::Role;
+use discord_client_structs::structs::guild::{DiscoverableGuild, GatewayGuild};
+use discord_client_structs::structs::invite::Invite;
+use discord_client_structs::structs::message::attachment::Attachment;
 use discord_client_structs::structs::message::{Message, MessageType};
+use discord_client_structs::structs::scheduled_event::GuildScheduledEvent;
+use discord_client_structs::structs::stage_instance::StageInstance;
+use discord_client_structs::structs::sticker::Sticker;
 use discord_client_structs::structs::user::User;
-use log::debug;
+use log::{debug, error, info, warn};
+use serde::{Serialize, Serializer};
 use serde_json;
 use std::error::Error;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::{Mutex, MutexGuard, Notify};
 use tokio_postgres::types::ToSql;
 use tokio_postgres::{Client, NoTls};
+use tracing::Instrument;
 
 pub async fn connect_db() -> BoxedResult<Client> {
     let (client, connection) =
@@ -24,31 +49,135 @@ pub async fn connect_db() -> BoxedResult<Client> {
     Ok(client)
 }
 
-pub async fn upsert_message(
-    msg: &Message,
-    guild_id: Option<u64>,
-    db: &Client,
-) -> Result<(), Box<dyn Error>> {
-    let msg_id: i64 = msg.id as i64;
-    let channel_id: i64 = msg.channel_id as i64;
-    let author_id: i64 = msg.author.id as i64;
-    let flags: i64 = msg.flags as i64;
-    let guild_id: Option<i64> = guild_id.map(|id| id as i64);
+// cleared while the database connection is down
+static DB_AVAILABLE: AtomicBool = AtomicBool::new(true);
+// woken once the client is replaced after an outage, the permit is kept until the replay task
+// waits for it
+static DB_RESTORED: Notify = Notify::const_new();
 
-    let referenced_id: Option<i64> = if let Some(ref_msg) = &msg.referenced_message {
-        let id = ref_msg.id as i64;
-        let exists: bool = db
-            .query_one(
-                "SELECT EXISTS(SELECT 1 FROM messages WHERE id = $1)",
-                &[&id],
-            )
-            .await?
-            .get(0);
-        exists.then_some(id)
-    } else {
-        None
+// delay between two checks of the database connection
+const DB_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const DB_MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Locks the shared client, the time spent waiting for it shows up as a `db_lock` span
+pub async fn lock_db(db: &Mutex<Client>) -> MutexGuard<'_, Client> {
+    db.lock().instrument(tracing::info_span!("db_lock")).await
+}
+
+pub fn is_db_available() -> bool {
+    DB_AVAILABLE.load(Ordering::Relaxed)
+}
+
+/// Checks the connection after a failed write, so the next events are buffered right away
+/// instead of after the next check of the reconnect task
+pub async fn is_connection_lost(db: &Mutex<Client>) -> bool {
+    if !lock_db(db).await.is_closed() {
+        return false;
+    }
+
+    DB_AVAILABLE.store(false, Ordering::Relaxed);
+    true
+}
+
+/// Waits until the database is reachable again after an outage
+pub async fn wait_db_restored() {
+    DB_RESTORED.notified().await;
+}
+
+/// Watches the database connection and replaces the client once the database is reachable again
+pub fn spawn_reconnect_task(db: Arc<Mutex<Client>>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(DB_CHECK_INTERVAL).await;
+
+            if !lock_db(&db).await.is_closed() {
+                continue;
+            }
+
+            DB_AVAILABLE.store(false, Ordering::Relaxed);
+            error!("Database connection lost, reconnecting...");
+            alerts::send("Database connection lost, reconnecting").await;
+            let lost_at = std::time::Instant::now();
+
+            let mut delay = DB_CHECK_INTERVAL;
+            let client = loop {
+                match connect_db().await {
+                    Ok(client) => break client,
+                    Err(e) => {
+                        warn!(
+                            "Database reconnection failed, retrying in {:?}: {}",
+                            delay, e
+                        );
+                        tokio::time::sleep(delay).await;
+                        delay = (delay * 2).min(DB_MAX_RECONNECT_DELAY);
+                    }
+                }
+            };
+
+            *lock_db(&db).await = client;
+            DB_AVAILABLE.store(true, Ordering::Relaxed);
+            DB_RESTORED.notify_one();
+            info!("Database connection restored");
+            alerts::send(&format!(
+                "Database connection restored after {}s",
+                lost_at.elapsed().as_secs()
+            ))
+            .await;
+        }
+    });
+}
+
+/// Keeps the previous content of an edited message along with the diff to the new one
+async fn record_message_revision(msg: &Message, db: &Client) -> Result<(), Box<dyn Error>> {
+    let msg_id = msg.id as i64;
+    let Some(row) = db
+        .query_opt(
+            "SELECT content, edited_at FROM messages WHERE id = $1",
+            &[&msg_id],
+        )
+        .await?
+    else {
+        return Ok(());
     };
-    let message_type = match msg.r#type {
+
+    // updates without content (e.g. embeds resolving) don't change the text
+    let Some(new_content) = msg.content.as_deref() else {
+        return Ok(());
+    };
+    let old_content: Option<String> = row.get(0);
+    let old_content = old_content.unwrap_or_default();
+    if old_content == new_content {
+        return Ok(());
+    }
+
+    let previous_edited_at: Option<DateTime<Utc>> = row.get(1);
+    let diff = diff::word_diff(&old_content, new_content);
+    let (removed_chars, added_chars) = diff::change_size(&diff);
+
+    db.execute(
+        "INSERT INTO message_revisions
+            (message_id, content, previous_edited_at, edited_at, diff, removed_chars, added_chars)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        &[
+            &msg_id,
+            &old_content,
+            &previous_edited_at,
+            &msg.edited_timestamp,
+            &diff,
+            &removed_chars,
+            &added_chars,
+        ],
+    )
+    .await?;
+
+    debug!("Recorded revision of message {}", msg.id);
+
+    Ok(())
+}
+
+/// Numeric message type, as sent by Discord
+pub fn message_type_id(message_type: &MessageType) -> i32 {
+    let id = match *message_type {
         MessageType::Default => 0,
         MessageType::RecipientAdd => 1,
         MessageType::RecipientRemove => 2,
@@ -87,23 +216,49 @@ pub async fn upsert_message(
         MessageType::PurchaseNotification => 44,
         MessageType::PollResult => 46,
         MessageType::Unknown(i) => i,
-    } as i32;
+    };
+    id as i32
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn upsert_message(
+    msg: &Message,
+    guild_id: Option<u64>,
+    db: &Client,
+) -> Result<(), Box<dyn Error>> {
+    record_message_revision(msg, db).await?;
+
+    let msg_id: i64 = msg.id as i64;
+    let channel_id: i64 = msg.channel_id as i64;
+    let author_id: i64 = msg.author.id as i64;
+    let flags: i64 = msg.flags as i64;
+    let guild_id: Option<i64> = guild_id.map(|id| id as i64);
+
+    // kept even when the parent isn't stored yet, see `repair-references`
+    let referenced_id: Option<i64> = msg.referenced_message.as_ref().map(|m| m.id as i64);
+    let message_type = message_type_id(&msg.r#type);
 
     db.execute(
         "INSERT INTO messages (
          id, channel_id, author_id, guild_id, content,
          edited_at, message_type, flags,
-         referenced_message_id, attachments
+         referenced_message_id, attachments, tags, pinned, sample_rate,
+         language, embeds
      ) VALUES (
          $1, $2, $3, $4, $5,
          $6, $7, $8, $9,
-         $10
+         $10, $11, $12, $13,
+         $14, $15
      )
      ON CONFLICT (id) DO UPDATE SET
-         content   = EXCLUDED.content,
+         content   = COALESCE(EXCLUDED.content, messages.content),
          edited_at = EXCLUDED.edited_at,
          flags     = EXCLUDED.flags,
-         attachments = EXCLUDED.attachments",
+         attachments = EXCLUDED.attachments,
+         tags      = EXCLUDED.tags,
+         pinned    = EXCLUDED.pinned,
+         language  = EXCLUDED.language,
+         embeds    = EXCLUDED.embeds",
         &[
             &msg_id,
             &channel_id,
@@ -115,6 +270,11 @@ pub async fn upsert_message(
             &flags,
             &referenced_id,
             &serde_json::to_value(&msg.attachments)?,
+            &tagging::tags_for(msg, guild_id.map(|id| id as u64)),
+            &msg.pinned,
+            &sampling::sample_rate(guild_id.map(|id| id as u64)),
+            &msg.content.as_deref().and_then(language::detect_language),
+            &serde_json::to_value(&msg.embeds)?,
         ],
     )
     .await?;
@@ -122,433 +282,3628 @@ pub async fn upsert_message(
     Ok(())
 }
 
-pub async fn delete_message(msg_id: &u64, db: &Client) -> Result<(), Box<dyn Error>> {
-    let msg_id = *msg_id as i64;
+/// Applies a partial `MessageUpdate`, only the fields it carries are written. Embeds are
+/// replaced when present, as Discord sends them once resolved. `pinned` defaults to false when
+/// missing, so pins are left to `sync_channel_pins`.
+pub async fn update_partial_message(
+    msg: &Message,
+    db: &Client,
+) -> Result<u64, Box<dyn Error + Send + Sync>> {
+    let embeds = (!msg.embeds.is_empty())
+        .then(|| serde_json::to_value(&msg.embeds))
+        .transpose()?;
+    let attachments = (!msg.attachments.is_empty())
+        .then(|| serde_json::to_value(&msg.attachments))
+        .transpose()?;
+
+    let updated = db
+        .execute(
+            "UPDATE messages SET
+                embeds      = COALESCE($2, embeds),
+                attachments = COALESCE($3, attachments),
+                edited_at   = COALESCE($4, edited_at)
+            WHERE id = $1",
+            &[
+                &(msg.id as i64),
+                &embeds,
+                &attachments,
+                &msg.edited_timestamp,
+            ],
+        )
+        .await?;
+
+    Ok(updated)
+}
+
+/// Marks the given messages as the pinned ones of the channel and unpins the others.
+/// Pin timestamps are kept for messages that were already pinned.
+pub async fn sync_channel_pins(
+    channel_id: u64,
+    pinned_ids: &[u64],
+    pinned_at: Option<DateTime<Utc>>,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let pinned_ids: Vec<i64> = pinned_ids.iter().map(|id| *id as i64).collect();
+
     db.execute(
-        "UPDATE messages SET deleted_at = NOW() WHERE id = $1 AND deleted_at IS NULL",
-        &[&msg_id],
+        "UPDATE messages SET pinned = TRUE, pinned_at = COALESCE(pinned_at, $2, NOW())
+        WHERE id = ANY($1)",
+        &[&pinned_ids, &pinned_at],
     )
     .await?;
+
+    db.execute(
+        "UPDATE messages SET pinned = FALSE, pinned_at = NULL
+        WHERE channel_id = $1 AND pinned AND NOT (id = ANY($2))",
+        &[&(channel_id as i64), &pinned_ids],
+    )
+    .await?;
+
     Ok(())
 }
 
-pub async fn bulk_delete_messages(msg_ids: &[u64], db: &Client) -> Result<(), Box<dyn Error>> {
-    if msg_ids.is_empty() {
+pub async fn get_guild_channel_ids(
+    guild_id: u64,
+    db: &Client,
+) -> Result<Vec<u64>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            "SELECT id FROM channels WHERE guild_id = $1",
+            &[&(guild_id as i64)],
+        )
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| row.get::<_, i64>(0) as u64)
+        .collect())
+}
+
+/// Channels of the guild with a message history: text, voice, announcement and stage channels,
+/// and threads
+pub async fn get_guild_message_channel_ids(
+    guild_id: u64,
+    db: &Client,
+) -> Result<Vec<u64>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            "SELECT id FROM channels WHERE guild_id = $1 AND type IN (0, 2, 5, 10, 11, 12, 13)",
+            &[&(guild_id as i64)],
+        )
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| row.get::<_, i64>(0) as u64)
+        .collect())
+}
+
+/// Text, announcement, forum and media channels of the guild, the ones that can have threads
+pub async fn get_guild_thread_parent_ids(
+    guild_id: u64,
+    db: &Client,
+) -> Result<Vec<u64>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            "SELECT id FROM channels WHERE guild_id = $1 AND type IN (0, 5, 15, 16)",
+            &[&(guild_id as i64)],
+        )
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| row.get::<_, i64>(0) as u64)
+        .collect())
+}
+
+/// Ids among the given ones that are stored as a channel or have stored messages
+pub async fn get_known_channel_ids(
+    channel_ids: &[u64],
+    db: &Client,
+) -> Result<Vec<u64>, Box<dyn Error + Send + Sync>> {
+    let ids: Vec<i64> = channel_ids.iter().map(|id| *id as i64).collect();
+    let rows = db
+        .query(
+            "SELECT id FROM channels WHERE id = ANY($1)
+            UNION
+            SELECT DISTINCT channel_id FROM messages WHERE channel_id = ANY($1)",
+            &[&ids],
+        )
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| row.get::<_, i64>(0) as u64)
+        .collect())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn upsert_message_snapshots(
+    msg: &Message,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let Some(snapshots) = &msg.message_snapshots else {
         return Ok(());
+    };
+
+    let reference = msg.message_reference.as_ref();
+    let source_message_id = reference.and_then(|r| r.message_id).map(|id| id as i64);
+    let source_channel_id = reference.and_then(|r| r.channel_id).map(|id| id as i64);
+    let source_guild_id = reference.and_then(|r| r.guild_id).map(|id| id as i64);
+
+    for (position, snapshot) in snapshots.iter().enumerate() {
+        let snapshot = &snapshot.message;
+
+        db.execute(
+            "INSERT INTO message_snapshots (
+                message_id, position, source_message_id, source_channel_id, source_guild_id,
+                content, created_at, edited_at, flags, attachments, embeds
+            ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            ON CONFLICT (message_id, position) DO UPDATE SET
+                content     = EXCLUDED.content,
+                edited_at   = EXCLUDED.edited_at,
+                flags       = EXCLUDED.flags,
+                attachments = EXCLUDED.attachments,
+                embeds      = EXCLUDED.embeds",
+            &[
+                &(msg.id as i64),
+                &(position as i32),
+                &source_message_id,
+                &source_channel_id,
+                &source_guild_id,
+                &snapshot.content,
+                &snapshot.timestamp,
+                &snapshot.edited_timestamp,
+                &(snapshot.flags.unwrap_or(0) as i64),
+                &serde_json::to_value(&snapshot.attachments)?,
+                &serde_json::to_value(&snapshot.embeds)?,
+            ],
+        )
+        .await?;
     }
 
-    let mut sql_ids: Vec<i64> = msg_ids.iter().map(|&id| id as i64).collect();
-    sql_ids.sort_unstable();
+    Ok(())
+}
 
+/// Replaces the links of a message with the URLs in its content, along with their domain and
+/// position, edits can add or remove some
+pub async fn replace_message_links(
+    msg: &Message,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
     db.execute(
-        "UPDATE messages SET deleted_at = NOW() WHERE id = ANY($1) AND deleted_at IS NULL",
-        &[&sql_ids],
+        "DELETE FROM message_links WHERE message_id = $1",
+        &[&(msg.id as i64)],
     )
     .await?;
 
-    Ok(())
-}
+    let links = msg
+        .content
+        .as_deref()
+        .map(links::extract_links)
+        .unwrap_or_default();
+    if links.is_empty() {
+        return Ok(());
+    }
 
-pub async fn upsert_user(
-    user: &User,
-    db: &Client,
-    guild_id: Option<u64>,
-) -> Result<(), Box<dyn Error>> {
-    let query = r#"
-        INSERT INTO users (id, username, global_name, avatar, bot, banner, accent_color, flags, premium_type, public_flags, guilds)
-        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, 
-                CASE WHEN $11::BIGINT IS NOT NULL THEN ARRAY[$11::BIGINT] ELSE ARRAY[]::BIGINT[] END)
-        ON CONFLICT (id) DO UPDATE SET
-            username = EXCLUDED.username,
-            global_name = EXCLUDED.global_name,
-            avatar = EXCLUDED.avatar,
-            bot = EXCLUDED.bot,
-            banner = EXCLUDED.banner,
-            accent_color = EXCLUDED.accent_color,
-            flags = EXCLUDED.flags,
-            premium_type = EXCLUDED.premium_type,
-            public_flags = EXCLUDED.public_flags,
-            guilds = CASE 
-                WHEN $11::BIGINT IS NOT NULL AND NOT ($11::BIGINT = ANY(users.guilds)) THEN 
-                    array_append(users.guilds, $11::BIGINT)
-                ELSE 
-                    users.guilds
-            END
-    "#;
+    let positions: Vec<i32> = links.iter().map(|link| link.position).collect();
+    let urls: Vec<&str> = links.iter().map(|link| link.url.as_str()).collect();
+    let domains: Vec<&str> = links.iter().map(|link| link.domain.as_str()).collect();
 
     db.execute(
-        query,
-        &[
-            &(user.id as i64),
-            &user.username,
-            &user.global_name,
-            &user.avatar,
-            &user.bot.unwrap_or(false),
-            &user.banner,
-            &user.accent_color.map(|v| v as i32),
-            &user.flags.map(|v| v as i32),
-            &user.premium_type.map(|v| v as i32),
-            &user.public_flags.map(|v| v as i32),
-            &guild_id.map(|id| id as i64),
-        ],
+        "INSERT INTO message_links (message_id, position, url, domain)
+        SELECT $1, position, url, domain
+        FROM UNNEST($2::INT[], $3::TEXT[], $4::TEXT[]) AS l(position, url, domain)
+        ON CONFLICT DO NOTHING",
+        &[&(msg.id as i64), &positions, &urls, &domains],
     )
     .await?;
 
     Ok(())
 }
 
-pub async fn bulk_upsert_users(
-    users: &[User],
+/// Replaces the user, role and @everyone mentions of a message, edits can add or remove some
+pub async fn replace_message_mentions(
+    msg: &Message,
+    guild_id: Option<u64>,
     db: &Client,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    if users.is_empty() {
-        return Ok(());
-    }
-
-    let mut user_data = Vec::new();
+    let mut types = Vec::new();
+    let mut target_ids = Vec::new();
 
-    for user in users {
-        user_data.push((
-            user.id as i64,
-            user.username.clone(),
-            user.global_name.clone(),
-            user.avatar.clone(),
-            user.bot.unwrap_or(false),
-            user.banner.clone(),
-            user.accent_color.map(|v| v as i32),
-            user.flags.map(|v| v as i32),
-            user.premium_type.map(|v| v as i32),
-            user.public_flags.map(|v| v as i32),
-        ));
+    for user in msg.mentions.iter().flatten() {
+        types.push("user");
+        target_ids.push(user.id as i64);
+    }
+    for role_id in msg.mention_roles.iter().flatten() {
+        types.push("role");
+        target_ids.push(*role_id as i64);
+    }
+    // the everyone role shares its id with the guild
+    if msg.mention_everyone
+        && let Some(guild_id) = guild_id
+    {
+        types.push("everyone");
+        target_ids.push(guild_id as i64);
     }
 
-    let mut placeholders = Vec::new();
-    let mut values: Vec<&(dyn ToSql + Sync)> = Vec::new();
-    let mut param_index = 1;
-
-    for data in &user_data {
-        placeholders.push(format!(
-            "(${}, ${}, ${}, ${}, ${}, ${}, ${}, ${}, ${}, ${})",
-            param_index,
-            param_index + 1,
-            param_index + 2,
-            param_index + 3,
-            param_index + 4,
-            param_index + 5,
-            param_index + 6,
-            param_index + 7,
-            param_index + 8,
-            param_index + 9
-        ));
-
-        values.extend_from_slice(&[
-            &data.0, &data.1, &data.2, &data.3, &data.4, &data.5, &data.6, &data.7, &data.8,
-            &data.9,
-        ]);
+    db.execute(
+        "DELETE FROM message_mentions WHERE message_id = $1",
+        &[&(msg.id as i64)],
+    )
+    .await?;
 
-        param_index += 10;
+    if types.is_empty() {
+        return Ok(());
     }
 
-    let query = format!(
-        r#"INSERT INTO users (id, username, global_name, avatar, bot, banner, accent_color, flags, premium_type, public_flags)
-        VALUES {}
-        ON CONFLICT (id) DO UPDATE SET
-            username = EXCLUDED.username,
-            global_name = EXCLUDED.global_name,
-            avatar = EXCLUDED.avatar,
-            bot = EXCLUDED.bot,
-            banner = EXCLUDED.banner,
-            accent_color = EXCLUDED.accent_color,
-            flags = EXCLUDED.flags,
-            premium_type = EXCLUDED.premium_type,
-            public_flags = EXCLUDED.public_flags"#,
-        placeholders.join(", ")
-    );
+    db.execute(
+        "INSERT INTO message_mentions (message_id, mention_type, target_id)
+        SELECT $1, mention_type, target_id FROM UNNEST($2::TEXT[], $3::BIGINT[]) AS m(mention_type, target_id)
+        ON CONFLICT DO NOTHING",
+        &[&(msg.id as i64), &types, &target_ids],
+    )
+    .await?;
 
-    db.execute(&query, &values).await?;
+    Ok(())
+}
 
-    debug!("Bulk upserted {} users", users.len());
+pub async fn record_invite_codes(
+    codes: &[String],
+    message_id: u64,
+    guild_id: Option<u64>,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    for code in codes {
+        db.execute(
+            "INSERT INTO invites (code, source_guild_id, first_message_id)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (code) DO NOTHING",
+            &[code, &guild_id.map(|id| id as i64), &(message_id as i64)],
+        )
+        .await?;
+    }
 
     Ok(())
 }
 
-pub async fn upsert_guild(
-    guild: &GatewayGuild,
+#[tracing::instrument(skip_all)]
+pub async fn upsert_invite_create(
+    invite: &InviteCreateEvent,
     db: &Client,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let guild_id = guild.id as i64;
+    let expires_at = (invite.max_age > 0)
+        .then(|| invite.created_at + chrono::Duration::seconds(invite.max_age as i64));
 
-    if let Some(props) = &guild.properties {
-        let name = &props.name;
-        let icon = &props.icon;
-        let region = &props.region;
-        let owner_id = props.owner_id as i64;
-        let member_count = guild.member_count.map(|count| count as i32);
-        let features = props.features.clone();
-        let premium_tier = Some(props.premium_tier as i32);
+    db.execute(
+        "INSERT INTO invites (
+            code, guild_id, channel_id, inviter_id, max_age, max_uses, uses,
+            temporary, created_at, expires_at
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+        ON CONFLICT (code) DO UPDATE SET
+            guild_id   = EXCLUDED.guild_id,
+            channel_id = EXCLUDED.channel_id,
+            inviter_id = EXCLUDED.inviter_id,
+            max_age    = EXCLUDED.max_age,
+            max_uses   = EXCLUDED.max_uses,
+            uses       = EXCLUDED.uses,
+            temporary  = EXCLUDED.temporary,
+            created_at = EXCLUDED.created_at,
+            expires_at = EXCLUDED.expires_at,
+            deleted_at = NULL",
+        &[
+            &invite.code,
+            &invite.guild_id.map(|id| id as i64),
+            &(invite.channel_id as i64),
+            &invite.inviter.as_ref().map(|user| user.id as i64),
+            &(invite.max_age as i32),
+            &(invite.max_uses as i32),
+            &(invite.uses as i32),
+            &invite.temporary,
+            &invite.created_at,
+            &expires_at,
+        ],
+    )
+    .await?;
 
-        db.execute(
-            "INSERT INTO guilds (
-                id, name, icon, region, owner_id, member_count, features, premium_tier
-            ) VALUES (
-                $1, $2, $3, $4, $5, $6, $7, $8
+    Ok(())
+}
+
+pub async fn mark_invite_deleted(
+    code: &str,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    db.execute(
+        "UPDATE invites SET deleted_at = NOW() WHERE code = $1",
+        &[&code],
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_unresolved_invites(
+    limit: i64,
+    db: &Client,
+) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            "SELECT code FROM invites
+            WHERE resolved_at IS NULL AND NOT invalid
+            ORDER BY code LIMIT $1",
+            &[&limit],
+        )
+        .await?;
+
+    Ok(rows.into_iter().map(|row| row.get(0)).collect())
+}
+
+/// Parents of stored replies that aren't stored themselves, as (id, channel id, guild id)
+pub async fn get_missing_reply_parents(
+    limit: i64,
+    db: &Client,
+) -> Result<Vec<(u64, u64, Option<u64>)>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            "SELECT DISTINCT ON (m.referenced_message_id)
+                m.referenced_message_id, m.channel_id, m.guild_id
+            FROM messages m
+            WHERE m.referenced_message_id IS NOT NULL
+              AND NOT EXISTS (SELECT 1 FROM messages p WHERE p.id = m.referenced_message_id)
+              AND NOT EXISTS (SELECT 1 FROM unavailable_messages u WHERE u.id = m.referenced_message_id)
+            ORDER BY m.referenced_message_id
+            LIMIT $1",
+            &[&limit],
+        )
+        .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| {
+            (
+                row.get::<_, i64>(0) as u64,
+                row.get::<_, i64>(1) as u64,
+                row.get::<_, Option<i64>>(2).map(|id| id as u64),
             )
-            ON CONFLICT (id) DO UPDATE SET
-                name = EXCLUDED.name,
-                icon = EXCLUDED.icon,
-                region = EXCLUDED.region,
-                owner_id = EXCLUDED.owner_id,
-                member_count = EXCLUDED.member_count,
-                features = EXCLUDED.features,
-                premium_tier = EXCLUDED.premium_tier",
+        })
+        .collect())
+}
+
+/// Ids among the given ones that are stored, or known to be unavailable
+pub async fn get_known_message_ids(
+    ids: &[u64],
+    db: &Client,
+) -> Result<Vec<u64>, Box<dyn Error + Send + Sync>> {
+    let ids: Vec<i64> = ids.iter().map(|id| *id as i64).collect();
+    let rows = db
+        .query(
+            "SELECT id FROM messages WHERE id = ANY($1)
+            UNION
+            SELECT id FROM unavailable_messages WHERE id = ANY($1)",
+            &[&ids],
+        )
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| row.get::<_, i64>(0) as u64)
+        .collect())
+}
+
+/// Records a message that can't be stored, so it isn't fetched again
+pub async fn mark_message_unavailable(
+    id: u64,
+    channel_id: u64,
+    reason: &str,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    db.execute(
+        "INSERT INTO unavailable_messages (id, channel_id, reason) VALUES ($1, $2, $3)
+        ON CONFLICT (id) DO UPDATE SET reason = EXCLUDED.reason, checked_at = NOW()",
+        &[&(id as i64), &(channel_id as i64), &reason],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Discoverable guilds whose discovery metadata is missing or older than a day
+pub async fn get_guilds_for_discovery(
+    limit: i64,
+    db: &Client,
+) -> Result<Vec<u64>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            "SELECT g.id FROM guilds g
+            LEFT JOIN guild_discovery d ON d.guild_id = g.id
+            WHERE 'DISCOVERABLE' = ANY(g.features)
+              AND (d.fetched_at IS NULL OR d.fetched_at < NOW() - INTERVAL '1 day')
+            ORDER BY d.fetched_at NULLS FIRST
+            LIMIT $1",
+            &[&limit],
+        )
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| row.get::<_, i64>(0) as u64)
+        .collect())
+}
+
+/// Guilds behind resolved invites that no account joined, not previewed in the last day
+pub async fn get_guilds_for_preview(
+    limit: i64,
+    db: &Client,
+) -> Result<Vec<u64>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            "SELECT i.guild_id FROM invites i
+            LEFT JOIN guilds g ON g.id = i.guild_id
+            LEFT JOIN guild_previews p ON p.guild_id = i.guild_id
+            WHERE i.guild_id IS NOT NULL AND g.id IS NULL
+              AND (p.fetched_at IS NULL OR p.fetched_at < NOW() - INTERVAL '1 day')
+            GROUP BY i.guild_id, p.fetched_at
+            ORDER BY p.fetched_at NULLS FIRST
+            LIMIT $1",
+            &[&limit],
+        )
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| row.get::<_, i64>(0) as u64)
+        .collect())
+}
+
+/// Stores the preview and the widget of a guild, either may be missing when it isn't public
+pub async fn save_guild_preview(
+    guild_id: u64,
+    preview: Option<&serde_json::Value>,
+    widget: Option<&serde_json::Value>,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let preview = preview.cloned().unwrap_or_default();
+    let widget = widget.cloned().unwrap_or_default();
+    let text = |value: &serde_json::Value| value.as_str().map(str::to_string);
+    let count = |value: &serde_json::Value| value.as_array().map(|items| items.len() as i32);
+
+    let name = text(&preview["name"]).or_else(|| text(&widget["name"]));
+    let features: Vec<String> = preview["features"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(text)
+        .collect();
+    let available = !preview.is_null() || !widget.is_null();
+
+    db.execute(
+        "INSERT INTO guild_previews (
+            guild_id, name, description, icon, splash, features, approximate_members,
+            approximate_online, emoji_count, sticker_count, instant_invite, widget_channels,
+            available, fetched_at
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, NOW())
+        ON CONFLICT (guild_id) DO UPDATE SET
+            name                = COALESCE(EXCLUDED.name, guild_previews.name),
+            description         = EXCLUDED.description,
+            icon                = EXCLUDED.icon,
+            splash              = EXCLUDED.splash,
+            features            = EXCLUDED.features,
+            approximate_members = EXCLUDED.approximate_members,
+            approximate_online  = EXCLUDED.approximate_online,
+            emoji_count         = EXCLUDED.emoji_count,
+            sticker_count       = EXCLUDED.sticker_count,
+            instant_invite      = EXCLUDED.instant_invite,
+            widget_channels     = EXCLUDED.widget_channels,
+            available           = EXCLUDED.available,
+            fetched_at          = NOW()",
+        &[
+            &(guild_id as i64),
+            &name,
+            &text(&preview["description"]),
+            &text(&preview["icon"]),
+            &text(&preview["splash"]),
+            &features,
+            &preview["approximate_member_count"].as_i64(),
+            &preview["approximate_presence_count"]
+                .as_i64()
+                .or_else(|| widget["presence_count"].as_i64()),
+            &count(&preview["emojis"]),
+            &count(&preview["stickers"]),
+            &text(&widget["instant_invite"]),
+            &Some(widget["channels"].clone()).filter(|channels| !channels.is_null()),
+            &available,
+        ],
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn save_guild_discovery(
+    guild: &DiscoverableGuild,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let primary_category = guild
+        .categories
+        .iter()
+        .find(|category| category.is_primary)
+        .map(|category| category.name.clone());
+    let categories: Vec<String> = guild
+        .categories
+        .iter()
+        .map(|category| category.name.clone())
+        .collect();
+
+    db.execute(
+        "INSERT INTO guild_discovery (
+            guild_id, description, vanity_url_code, primary_category, categories, keywords,
+            approximate_members, approximate_online, listed, fetched_at
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, TRUE, NOW())
+        ON CONFLICT (guild_id) DO UPDATE SET
+            description         = EXCLUDED.description,
+            vanity_url_code     = EXCLUDED.vanity_url_code,
+            primary_category    = EXCLUDED.primary_category,
+            categories          = EXCLUDED.categories,
+            keywords            = EXCLUDED.keywords,
+            approximate_members = EXCLUDED.approximate_members,
+            approximate_online  = EXCLUDED.approximate_online,
+            listed              = TRUE,
+            fetched_at          = NOW()",
+        &[
+            &(guild.id as i64),
+            &guild.description,
+            &guild.vanity_url_code,
+            &primary_category,
+            &categories,
+            &guild.keywords.clone().unwrap_or_default(),
+            &guild.approximate_member_count.map(|count| count as i64),
+            &guild.approximate_presence_count.map(|count| count as i64),
+        ],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Records that the guild isn't listed in discovery anymore, so it isn't retried before a day
+pub async fn mark_guild_unlisted(
+    guild_id: u64,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    db.execute(
+        "INSERT INTO guild_discovery (guild_id, listed, fetched_at) VALUES ($1, FALSE, NOW())
+        ON CONFLICT (guild_id) DO UPDATE SET listed = FALSE, fetched_at = NOW()",
+        &[&(guild_id as i64)],
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn save_resolved_invite(
+    invite: &Invite,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let guild = invite.guild.as_ref();
+
+    db.execute(
+        "UPDATE invites SET
+            guild_id         = COALESCE($2, guild_id),
+            channel_id       = COALESCE($3, channel_id),
+            inviter_id       = COALESCE($4, inviter_id),
+            guild_name       = $5,
+            guild_description = $6,
+            member_count     = $7,
+            presence_count   = $8,
+            expires_at       = COALESCE($9, expires_at),
+            resolved_at      = NOW()
+        WHERE code = $1",
+        &[
+            &invite.code,
+            &guild.map(|guild| guild.id as i64),
+            &invite.channel.as_ref().map(|channel| channel.id as i64),
+            &invite.inviter.as_ref().map(|user| user.id as i64),
+            &guild.map(|guild| guild.name.clone()),
+            &guild.and_then(|guild| guild.description.clone()),
+            &invite.approximate_member_count.map(|count| count as i64),
+            &invite.approximate_presence_count.map(|count| count as i64),
+            &invite.expires_at,
+        ],
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn mark_invite_invalid(
+    code: &str,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    db.execute(
+        "UPDATE invites SET invalid = TRUE, resolved_at = NOW() WHERE code = $1",
+        &[&code],
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn upsert_poll(
+    msg: &Message,
+    guild_id: Option<u64>,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let Some(poll) = &msg.poll else {
+        return Ok(());
+    };
+
+    let message_id = msg.id as i64;
+
+    db.execute(
+        "INSERT INTO polls (
+            message_id, channel_id, guild_id, question, allow_multiselect, layout_type, expires_at
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7)
+        ON CONFLICT (message_id) DO UPDATE SET
+            question = EXCLUDED.question,
+            allow_multiselect = EXCLUDED.allow_multiselect,
+            expires_at = EXCLUDED.expires_at",
+        &[
+            &message_id,
+            &(msg.channel_id as i64),
+            &guild_id.map(|id| id as i64),
+            &poll.question.text,
+            &poll.allow_multiselect,
+            &(poll.layout_type as i32),
+            &poll.expiry,
+        ],
+    )
+    .await?;
+
+    for answer in &poll.answers {
+        let emoji = answer.poll_media.emoji.as_ref().and_then(|emoji| {
+            emoji
+                .id
+                .map(|id| id.to_string())
+                .or_else(|| emoji.name.clone())
+        });
+
+        db.execute(
+            "INSERT INTO poll_answers (message_id, answer_id, text, emoji)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (message_id, answer_id) DO UPDATE SET
+                text = EXCLUDED.text,
+                emoji = EXCLUDED.emoji",
             &[
-                &guild_id,
-                &name,
-                &icon,
-                &region,
-                &owner_id,
-                &member_count,
-                &features,
-                &premium_tier,
+                &message_id,
+                &(answer.answer_id as i32),
+                &answer.poll_media.text,
+                &emoji,
             ],
         )
         .await?;
-    } else {
-        // Fallback to using the GatewayGuild fields
-        let name = &guild.name;
-        let icon = &guild.icon;
-        let region = &guild.region;
-        let owner_id = 0i64;
-        let member_count = guild.member_count.map(|count| count as i32);
-        let features: Option<Vec<String>> = guild.features.clone();
-        let premium_tier: Option<i32> = None;
+    }
+
+    Ok(())
+}
+
+pub async fn add_poll_vote(
+    message_id: u64,
+    answer_id: u32,
+    user_id: u64,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    db.execute(
+        "INSERT INTO poll_votes (message_id, answer_id, user_id)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (message_id, answer_id, user_id) DO UPDATE SET
+            voted_at = NOW(),
+            removed_at = NULL",
+        &[&(message_id as i64), &(answer_id as i32), &(user_id as i64)],
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn remove_poll_vote(
+    message_id: u64,
+    answer_id: u32,
+    user_id: u64,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    db.execute(
+        "UPDATE poll_votes SET removed_at = NOW()
+        WHERE message_id = $1 AND answer_id = $2 AND user_id = $3 AND removed_at IS NULL",
+        &[&(message_id as i64), &(answer_id as i32), &(user_id as i64)],
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn upsert_attachment(
+    attachment: &Attachment,
+    message_id: u64,
+    mime_type: &str,
+    path: &str,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    db.execute(
+        "INSERT INTO attachments (id, message_id, filename, content_type, size, path)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (id) DO UPDATE SET
+            content_type = EXCLUDED.content_type,
+            size = EXCLUDED.size,
+            path = EXCLUDED.path",
+        &[
+            &(attachment.id as i64),
+            &(message_id as i64),
+            &attachment.filename,
+            &mime_type,
+            &(attachment.size as i64),
+            &path,
+        ],
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+pub struct MessageFilter {
+    pub text: Option<String>,
+    pub author_id: Option<u64>,
+    pub channel_id: Option<u64>,
+    pub min_id: Option<u64>,
+    pub max_id: Option<u64>,
+    pub limit: i64,
+}
+
+// snowflakes don't fit in a javascript number, the API hands them out as strings
+fn id_string<S: Serializer>(id: &i64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(id)
+}
+
+fn opt_id_string<S: Serializer>(id: &Option<i64>, serializer: S) -> Result<S::Ok, S::Error> {
+    match id {
+        Some(id) => serializer.collect_str(id),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct MessageMatch {
+    #[serde(serialize_with = "id_string")]
+    pub id: i64,
+    #[serde(serialize_with = "id_string")]
+    pub channel_id: i64,
+    #[serde(serialize_with = "opt_id_string")]
+    pub guild_id: Option<i64>,
+    #[serde(serialize_with = "id_string")]
+    pub author_id: i64,
+    pub username: String,
+    pub content: Option<String>,
+    pub edited_at: Option<DateTime<Utc>>,
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+impl MessageMatch {
+    fn from_row(row: &tokio_postgres::Row) -> Self {
+        MessageMatch {
+            id: row.get(0),
+            channel_id: row.get(1),
+            guild_id: row.get(2),
+            author_id: row.get(3),
+            username: row.get(4),
+            content: row.get(5),
+            edited_at: row.get(6),
+            deleted_at: row.get(7),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct GuildSummary {
+    #[serde(serialize_with = "id_string")]
+    pub id: i64,
+    pub name: Option<String>,
+    pub icon: Option<String>,
+    pub member_count: Option<i32>,
+    pub features: Option<Vec<String>>,
+    pub vanity_url_code: Option<String>,
+}
+
+pub async fn get_guilds(db: &Client) -> Result<Vec<GuildSummary>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            "SELECT id, name, icon, member_count, features, vanity_url_code
+            FROM guilds
+            ORDER BY id",
+            &[],
+        )
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| GuildSummary {
+            id: row.get(0),
+            name: row.get(1),
+            icon: row.get(2),
+            member_count: row.get(3),
+            features: row.get(4),
+            vanity_url_code: row.get(5),
+        })
+        .collect())
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChannelSummary {
+    #[serde(serialize_with = "id_string")]
+    pub id: i64,
+    #[serde(rename = "type")]
+    pub kind: i32,
+    pub name: Option<String>,
+    #[serde(serialize_with = "opt_id_string")]
+    pub parent_id: Option<i64>,
+    pub position: Option<i32>,
+}
+
+pub async fn get_guild_channels(
+    guild_id: u64,
+    db: &Client,
+) -> Result<Vec<ChannelSummary>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            "SELECT id, type, name, parent_id, position
+            FROM channels
+            WHERE guild_id = $1
+            ORDER BY position NULLS LAST, id",
+            &[&(guild_id as i64)],
+        )
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| ChannelSummary {
+            id: row.get(0),
+            kind: row.get(1),
+            name: row.get(2),
+            parent_id: row.get(3),
+            position: row.get(4),
+        })
+        .collect())
+}
+
+/// Attachment of a stored message, `path` and `thumbnail_path` are set once downloaded
+#[derive(Debug, Serialize)]
+pub struct MessageAttachment {
+    #[serde(skip)]
+    pub message_id: i64,
+    pub id: String,
+    pub filename: String,
+    pub content_type: Option<String>,
+    pub path: Option<String>,
+    pub thumbnail_path: Option<String>,
+}
+
+/// Attachments of the messages, infected files are listed without their path
+pub async fn get_message_attachments(
+    message_ids: &[i64],
+    db: &Client,
+) -> Result<Vec<MessageAttachment>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            "SELECT m.id, elem->>'id', elem->>'filename', elem->>'content_type',
+                    a.path, a.thumbnail_path, a.scan_status IS NOT DISTINCT FROM 'infected'
+            FROM messages m
+            CROSS JOIN LATERAL jsonb_array_elements(m.attachments) elem
+            LEFT JOIN attachments a ON a.id = (elem->>'id')::BIGINT
+            WHERE m.id = ANY($1)
+            ORDER BY m.id, (elem->>'id')::BIGINT",
+            &[&message_ids],
+        )
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            let infected: bool = row.get(6);
+            MessageAttachment {
+                message_id: row.get(0),
+                id: row.get(1),
+                filename: row.get(2),
+                content_type: row.get(3),
+                path: row.get::<_, Option<String>>(4).filter(|_| !infected),
+                thumbnail_path: row.get::<_, Option<String>>(5).filter(|_| !infected),
+            }
+        })
+        .collect())
+}
+
+pub async fn search_messages(
+    filter: &MessageFilter,
+    db: &Client,
+) -> Result<Vec<MessageMatch>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            "SELECT m.id, m.channel_id, m.guild_id, m.author_id, u.username,
+                    COALESCE(NULLIF(m.content, ''), t.text), m.edited_at, m.deleted_at
+            FROM messages m
+            JOIN users u ON u.id = m.author_id
+            LEFT JOIN LATERAL (
+                SELECT string_agg(text, ' ' ORDER BY attachment_id) AS text,
+                       bool_or(text_tsv @@ websearch_to_tsquery('simple', $1)) AS matches
+                FROM message_transcripts
+                WHERE message_id = m.id
+            ) t ON TRUE
+            WHERE ($1::TEXT IS NULL
+                   OR m.content_tsv @@ websearch_to_tsquery('simple', $1)
+                   OR t.matches)
+              AND ($2::BIGINT IS NULL OR m.author_id = $2)
+              AND ($3::BIGINT IS NULL OR m.channel_id = $3)
+              AND ($4::BIGINT IS NULL OR m.id >= $4)
+              AND ($5::BIGINT IS NULL OR m.id < $5)
+            ORDER BY m.id DESC
+            LIMIT $6",
+            &[
+                &filter.text,
+                &filter.author_id.map(|id| id as i64),
+                &filter.channel_id.map(|id| id as i64),
+                &filter.min_id.map(|id| id as i64),
+                &filter.max_id.map(|id| id as i64),
+                &filter.limit,
+            ],
+        )
+        .await?;
+
+    Ok(rows.iter().map(MessageMatch::from_row).collect())
+}
+
+/// Messages closest to the embedding, among the ones embedded with the same model
+pub async fn semantic_search_messages(
+    embedding: &str,
+    model: &str,
+    filter: &MessageFilter,
+    db: &Client,
+) -> Result<Vec<MessageMatch>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            "SELECT m.id, m.channel_id, m.guild_id, m.author_id, u.username,
+                    m.content, m.edited_at, m.deleted_at
+            FROM message_embeddings e
+            JOIN messages m ON m.id = e.message_id
+            JOIN users u ON u.id = m.author_id
+            WHERE ($2::BIGINT IS NULL OR m.author_id = $2)
+              AND ($3::BIGINT IS NULL OR m.channel_id = $3)
+              AND ($4::BIGINT IS NULL OR m.id >= $4)
+              AND ($5::BIGINT IS NULL OR m.id < $5)
+              AND e.model = $7
+            ORDER BY e.embedding <=> $1::TEXT::VECTOR
+            LIMIT $6",
+            &[
+                &embedding,
+                &filter.author_id.map(|id| id as i64),
+                &filter.channel_id.map(|id| id as i64),
+                &filter.min_id.map(|id| id as i64),
+                &filter.max_id.map(|id| id as i64),
+                &filter.limit,
+                &model,
+            ],
+        )
+        .await?;
+
+    Ok(rows.iter().map(MessageMatch::from_row).collect())
+}
+
+/// Messages without an up to date embedding of the model: never embedded, embedded with another
+/// model, or edited since
+pub async fn get_messages_without_embedding(
+    model: &str,
+    limit: i64,
+    db: &Client,
+) -> Result<Vec<(i64, String)>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            "SELECT m.id, m.content FROM messages m
+            WHERE m.content IS NOT NULL AND length(trim(m.content)) > 0
+              AND m.deleted_at IS NULL
+              AND NOT EXISTS (
+                  SELECT 1 FROM message_embeddings e
+                  WHERE e.message_id = m.id
+                    AND e.model = $2
+                    AND (m.edited_at IS NULL OR m.edited_at <= e.created_at)
+              )
+            ORDER BY m.id DESC
+            LIMIT $1",
+            &[&limit, &model],
+        )
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect())
+}
+
+/// Saves embeddings given in the pgvector text format
+pub async fn save_embeddings(
+    embeddings: &[(i64, String)],
+    model: &str,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    for (message_id, embedding) in embeddings {
+        db.execute(
+            "INSERT INTO message_embeddings (message_id, model, embedding)
+            VALUES ($1, $2, $3::TEXT::VECTOR)
+            ON CONFLICT (message_id) DO UPDATE SET
+                model      = EXCLUDED.model,
+                embedding  = EXCLUDED.embedding,
+                created_at = NOW()",
+            &[message_id, &model, embedding],
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+pub struct ExportMessage {
+    pub id: i64,
+    pub author_id: i64,
+    pub username: String,
+    pub global_name: Option<String>,
+    pub avatar: Option<String>,
+    pub avatar_path: Option<String>,
+    pub content: Option<String>,
+    pub edited_at: Option<DateTime<Utc>>,
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub referenced_message_id: Option<i64>,
+    pub attachments: serde_json::Value,
+    pub embeds: serde_json::Value,
+    // local copies of the attachments, by attachment id
+    pub attachment_paths: Vec<(String, String)>,
+    // thumbnails of the attachments, by attachment id
+    pub attachment_thumbnails: Vec<(String, String)>,
+}
+
+pub async fn get_channel_name(
+    channel_id: u64,
+    db: &Client,
+) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+    let row = db
+        .query_opt(
+            "SELECT name FROM channels WHERE id = $1",
+            &[&(channel_id as i64)],
+        )
+        .await?;
+
+    Ok(row.and_then(|row| row.get(0)))
+}
+
+pub async fn get_channel_guild_id(
+    channel_id: u64,
+    db: &Client,
+) -> Result<Option<u64>, Box<dyn Error + Send + Sync>> {
+    let row = db
+        .query_opt(
+            "SELECT guild_id FROM channels WHERE id = $1",
+            &[&(channel_id as i64)],
+        )
+        .await?;
+
+    Ok(row
+        .and_then(|row| row.get::<_, Option<i64>>(0))
+        .map(|id| id as u64))
+}
+
+/// Bytes taken by a stored message, its indexes included, from the planner statistics.
+/// None until the table was analyzed.
+pub async fn get_average_message_size(
+    db: &Client,
+) -> Result<Option<u64>, Box<dyn Error + Send + Sync>> {
+    let row = db
+        .query_opt(
+            "SELECT (pg_total_relation_size(oid) / NULLIF(GREATEST(reltuples, 0), 0))::BIGINT
+             FROM pg_class WHERE relname = 'messages'",
+            &[],
+        )
+        .await?;
+
+    Ok(row
+        .and_then(|row| row.get::<_, Option<i64>>(0))
+        .map(|size| size as u64))
+}
+
+impl ExportMessage {
+    fn from_row(row: &tokio_postgres::Row) -> Self {
+        ExportMessage {
+            id: row.get(0),
+            author_id: row.get(1),
+            username: row.get(2),
+            global_name: row.get(3),
+            avatar: row.get(4),
+            avatar_path: row.get(5),
+            content: row.get(6),
+            edited_at: row.get(7),
+            deleted_at: row.get(8),
+            referenced_message_id: row.get(9),
+            attachments: row.get(10),
+            embeds: row.get(11),
+            attachment_paths: split_id_pairs(row.get(12)),
+            attachment_thumbnails: split_id_pairs(row.get(13)),
+        }
+    }
+}
+
+// `id|path` entries aggregated by the export query
+fn split_id_pairs(entries: Vec<String>) -> Vec<(String, String)> {
+    entries
+        .into_iter()
+        .filter_map(|entry| {
+            entry
+                .split_once('|')
+                .map(|(id, path)| (id.to_string(), path.to_string()))
+        })
+        .collect()
+}
+
+const EXPORT_MESSAGE_COLUMNS: &str =
+    "m.id, m.author_id, u.username, u.global_name, u.avatar, u.avatar_path,
+    m.content, m.edited_at, m.deleted_at, m.referenced_message_id,
+    m.attachments, m.embeds,
+    COALESCE(
+        (SELECT array_agg(a.id::TEXT || '|' || a.path) FROM attachments a
+         WHERE a.message_id = m.id AND a.path IS NOT NULL),
+        ARRAY[]::TEXT[]
+    ),
+    COALESCE(
+        (SELECT array_agg(a.id::TEXT || '|' || a.thumbnail_path) FROM attachments a
+         WHERE a.message_id = m.id AND a.thumbnail_path IS NOT NULL),
+        ARRAY[]::TEXT[]
+    )";
+
+pub async fn get_channel_messages_for_export(
+    channel_id: u64,
+    db: &Client,
+) -> Result<Vec<ExportMessage>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            &format!(
+                "SELECT {EXPORT_MESSAGE_COLUMNS}
+                FROM messages m
+                JOIN users u ON u.id = m.author_id
+                WHERE m.channel_id = $1
+                ORDER BY m.id"
+            ),
+            &[&(channel_id as i64)],
+        )
+        .await?;
+
+    Ok(rows.iter().map(ExportMessage::from_row).collect())
+}
+
+/// Parent channel (id and name) of a thread, None when the channel isn't a thread
+pub async fn get_thread_parent(
+    channel_id: u64,
+    db: &Client,
+) -> Result<Option<(i64, Option<String>)>, Box<dyn Error + Send + Sync>> {
+    let row = db
+        .query_opt(
+            "SELECT c.parent_id, p.name
+            FROM channels c
+            LEFT JOIN channels p ON p.id = c.parent_id
+            WHERE c.id = $1 AND c.type IN (10, 11, 12) AND c.parent_id IS NOT NULL",
+            &[&(channel_id as i64)],
+        )
+        .await?;
+
+    Ok(row.map(|row| (row.get(0), row.get(1))))
+}
+
+/// Message of the parent channel a thread was started from.
+/// Such threads share their id with the starter message.
+pub async fn get_thread_starter_for_export(
+    thread_id: u64,
+    db: &Client,
+) -> Result<Option<ExportMessage>, Box<dyn Error + Send + Sync>> {
+    let row = db
+        .query_opt(
+            &format!(
+                "SELECT {EXPORT_MESSAGE_COLUMNS}
+                FROM messages m
+                JOIN users u ON u.id = m.author_id
+                JOIN channels c ON c.id = m.id AND c.parent_id = m.channel_id
+                WHERE m.id = $1"
+            ),
+            &[&(thread_id as i64)],
+        )
+        .await?;
+
+    Ok(row.as_ref().map(ExportMessage::from_row))
+}
+
+/// Page of messages for the parquet export, ordered by id
+#[cfg(feature = "parquet")]
+pub async fn get_messages_page(
+    after_id: i64,
+    channel_id: Option<u64>,
+    limit: i64,
+    db: &Client,
+) -> Result<Vec<tokio_postgres::Row>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            "SELECT id, channel_id, guild_id, author_id, content, edited_at, deleted_at,
+                    message_type, flags, referenced_message_id, pinned, language, tags,
+                    attachments::TEXT, embeds::TEXT
+            FROM messages
+            WHERE id > $1 AND ($2::BIGINT IS NULL OR channel_id = $2)
+            ORDER BY id
+            LIMIT $3",
+            &[&after_id, &channel_id.map(|id| id as i64), &limit],
+        )
+        .await?;
+
+    Ok(rows)
+}
+
+pub struct IndexMessage {
+    pub id: i64,
+    pub channel_id: i64,
+    pub guild_id: Option<i64>,
+    pub author_id: i64,
+    pub username: String,
+    pub content: String,
+    pub language: Option<String>,
+    pub tags: Vec<String>,
+}
+
+/// Page of messages with content for the search index backfill, ordered by id
+pub async fn get_messages_for_index(
+    after_id: i64,
+    limit: i64,
+    db: &Client,
+) -> Result<Vec<IndexMessage>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            "SELECT m.id, m.channel_id, m.guild_id, m.author_id, u.username,
+                    m.content, m.language, m.tags
+            FROM messages m
+            JOIN users u ON u.id = m.author_id
+            WHERE m.id > $1 AND m.deleted_at IS NULL AND m.content <> ''
+            ORDER BY m.id
+            LIMIT $2",
+            &[&after_id, &limit],
+        )
+        .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| IndexMessage {
+            id: row.get(0),
+            channel_id: row.get(1),
+            guild_id: row.get(2),
+            author_id: row.get(3),
+            username: row.get(4),
+            content: row.get(5),
+            language: row.get(6),
+            tags: row.get(7),
+        })
+        .collect())
+}
+
+/// Page of users for the parquet export, ordered by id
+#[cfg(feature = "parquet")]
+pub async fn get_users_page(
+    after_id: i64,
+    limit: i64,
+    db: &Client,
+) -> Result<Vec<tokio_postgres::Row>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            "SELECT id, username, global_name, avatar, bot, banner, accent_color, flags,
+                    premium_type, public_flags, guilds
+            FROM users
+            WHERE id > $1
+            ORDER BY id
+            LIMIT $2",
+            &[&after_id, &limit],
+        )
+        .await?;
+
+    Ok(rows)
+}
+
+pub struct MediaMatch {
+    pub message_id: i64,
+    pub channel_id: i64,
+    pub author_id: i64,
+    pub attachment_id: String,
+    pub filename: String,
+    pub content_type: Option<String>,
+    pub size: Option<i64>,
+    pub path: Option<String>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub duration: Option<f64>,
+}
+
+/// Every filter set must match, patterns are SQL `LIKE` patterns
+pub struct MediaFilter {
+    pub name_pattern: Option<String>,
+    pub mime_pattern: Option<String>,
+    pub min_size: Option<i64>,
+    pub tag: Option<String>,
+    pub min_width: Option<i32>,
+    pub min_height: Option<i32>,
+    /// Seconds
+    pub min_duration: Option<f64>,
+    pub limit: i64,
+}
+
+pub async fn search_attachments(
+    filter: &MediaFilter,
+    db: &Client,
+) -> Result<Vec<MediaMatch>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            "SELECT m.id, m.channel_id, m.author_id,
+                    elem->>'id', elem->>'filename', elem->>'content_type',
+                    (elem->>'size')::BIGINT, a.path, a.width, a.height, a.duration
+            FROM messages m
+            CROSS JOIN LATERAL jsonb_array_elements(m.attachments) elem
+            LEFT JOIN attachments a ON a.id = (elem->>'id')::BIGINT
+            WHERE ($1::TEXT IS NULL OR elem->>'filename' ILIKE $1)
+              AND ($2::TEXT IS NULL OR elem->>'content_type' ILIKE $2)
+              AND ($3::BIGINT IS NULL OR (elem->>'size')::BIGINT >= $3)
+              AND ($4::TEXT IS NULL OR $4 = ANY(m.tags))
+              AND ($5::INT IS NULL OR a.width >= $5)
+              AND ($6::INT IS NULL OR a.height >= $6)
+              AND ($7::FLOAT8 IS NULL OR a.duration >= $7)
+            ORDER BY m.id DESC
+            LIMIT $8",
+            &[
+                &filter.name_pattern,
+                &filter.mime_pattern,
+                &filter.min_size,
+                &filter.tag,
+                &filter.min_width,
+                &filter.min_height,
+                &filter.min_duration,
+                &filter.limit,
+            ],
+        )
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| MediaMatch {
+            message_id: row.get(0),
+            channel_id: row.get(1),
+            author_id: row.get(2),
+            attachment_id: row.get(3),
+            filename: row.get(4),
+            content_type: row.get(5),
+            size: row.get(6),
+            path: row.get(7),
+            width: row.get(8),
+            height: row.get(9),
+            duration: row.get(10),
+        })
+        .collect())
+}
+
+// creation time of a message, derived from its snowflake, in the timezone given as $5
+const MESSAGE_TIME_SQL: &str =
+    "to_timestamp(((m.id >> 22) + 1420070400000) / 1000.0) AT TIME ZONE $5::TEXT";
+
+#[derive(Debug, Clone, Copy)]
+pub enum StatsGroup {
+    Day,
+    Month,
+    HourOfDay,
+    MessageType,
+    AttachmentType,
+    LinkDomain,
+    ToxicMonth,
+    SentimentMonth,
+    Guild,
+    Channel,
+    Author,
+}
+
+/// Restricts the statistics to the messages of a guild, channel or author
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StatsScope {
+    pub guild_id: Option<u64>,
+    pub channel_id: Option<u64>,
+    pub author_id: Option<u64>,
+}
+
+impl StatsGroup {
+    // groups bucketing the messages by their creation time
+    fn is_time(&self) -> bool {
+        matches!(
+            self,
+            StatsGroup::Day
+                | StatsGroup::Month
+                | StatsGroup::HourOfDay
+                | StatsGroup::ToxicMonth
+                | StatsGroup::SentimentMonth
+        )
+    }
+}
+
+impl StatsScope {
+    pub fn is_empty(&self) -> bool {
+        self.guild_id.is_none() && self.channel_id.is_none() && self.author_id.is_none()
+    }
+
+    fn params(&self) -> [Option<i64>; 3] {
+        [
+            self.guild_id.map(|id| id as i64),
+            self.channel_id.map(|id| id as i64),
+            self.author_id.map(|id| id as i64),
+        ]
+    }
+}
+
+// number of messages, sampled ones standing for 1 / sample_rate messages
+const MESSAGE_COUNT_SQL: &str = "SUM(1.0 / m.sample_rate)::BIGINT";
+
+// messages of the scope given as $1, $2 and $3
+const STATS_SCOPE_SQL: &str = "($1::BIGINT IS NULL OR m.guild_id = $1)
+    AND ($2::BIGINT IS NULL OR m.channel_id = $2)
+    AND ($3::BIGINT IS NULL OR m.author_id = $3)";
+
+pub async fn archive_totals(
+    scope: StatsScope,
+    db: &Client,
+) -> Result<Vec<(String, i64)>, Box<dyn Error + Send + Sync>> {
+    let [guild_id, channel_id, author_id] = scope.params();
+    let query = format!(
+        "SELECT
+            COALESCE({}, 0),
+            COALESCE(SUM(1.0 / m.sample_rate) FILTER (WHERE m.deleted_at IS NOT NULL), 0)::BIGINT,
+            COUNT(DISTINCT m.author_id),
+            COUNT(DISTINCT m.channel_id),
+            COALESCE(SUM(jsonb_array_length(m.attachments) / m.sample_rate), 0)::BIGINT,
+            COALESCE(SUM((
+                SELECT SUM((elem->>'size')::BIGINT) FROM jsonb_array_elements(m.attachments) elem
+            ) / m.sample_rate), 0)::BIGINT
+        FROM messages m
+        WHERE {}",
+        MESSAGE_COUNT_SQL, STATS_SCOPE_SQL
+    );
+    let row = db
+        .query_one(&query, &[&guild_id, &channel_id, &author_id])
+        .await?;
+
+    let mut totals = vec![
+        ("messages".to_string(), row.get(0)),
+        ("deleted messages".to_string(), row.get(1)),
+        ("authors".to_string(), row.get(2)),
+        ("active channels".to_string(), row.get(3)),
+        ("attachments".to_string(), row.get(4)),
+        ("attachment bytes".to_string(), row.get(5)),
+    ];
+
+    if scope.is_empty() {
+        let row = db
+            .query_one(
+                "SELECT (SELECT COUNT(*) FROM guilds), (SELECT COUNT(*) FROM channels)",
+                &[],
+            )
+            .await?;
+        totals.push(("guilds".to_string(), row.get(0)));
+        totals.push(("channels".to_string(), row.get(1)));
+    }
+
+    Ok(totals)
+}
+
+pub async fn count_messages_by(
+    group: StatsGroup,
+    scope: StatsScope,
+    limit: i64,
+    db: &Client,
+) -> Result<Vec<(String, i64)>, Box<dyn Error + Send + Sync>> {
+    let query = match group {
+        StatsGroup::Day => format!(
+            "SELECT to_char({}, 'YYYY-MM-DD') AS label, {} FROM messages m
+            WHERE {}
+            GROUP BY label ORDER BY label LIMIT $4",
+            MESSAGE_TIME_SQL, MESSAGE_COUNT_SQL, STATS_SCOPE_SQL
+        ),
+        StatsGroup::Month => format!(
+            "SELECT to_char({}, 'YYYY-MM') AS label, {} FROM messages m
+            WHERE {}
+            GROUP BY label ORDER BY label LIMIT $4",
+            MESSAGE_TIME_SQL, MESSAGE_COUNT_SQL, STATS_SCOPE_SQL
+        ),
+        StatsGroup::HourOfDay => format!(
+            "SELECT to_char({}, 'HH24') AS label, {} FROM messages m
+            WHERE {}
+            GROUP BY label ORDER BY label LIMIT $4",
+            MESSAGE_TIME_SQL, MESSAGE_COUNT_SQL, STATS_SCOPE_SQL
+        ),
+        StatsGroup::MessageType => format!(
+            "SELECT m.message_type::TEXT AS label, {} FROM messages m
+            WHERE {}
+            GROUP BY label ORDER BY 2 DESC LIMIT $4",
+            MESSAGE_COUNT_SQL, STATS_SCOPE_SQL
+        ),
+        StatsGroup::AttachmentType => format!(
+            "SELECT COALESCE(split_part(elem->>'content_type', '/', 1), 'unknown') AS label,
+                {}
+            FROM messages m CROSS JOIN LATERAL jsonb_array_elements(m.attachments) elem
+            WHERE {}
+            GROUP BY label ORDER BY 2 DESC LIMIT $4",
+            MESSAGE_COUNT_SQL, STATS_SCOPE_SQL
+        ),
+        StatsGroup::LinkDomain => format!(
+            "SELECT l.domain AS label, {}
+            FROM messages m JOIN message_links l ON l.message_id = m.id
+            WHERE {}
+            GROUP BY label ORDER BY 2 DESC LIMIT $4",
+            MESSAGE_COUNT_SQL, STATS_SCOPE_SQL
+        ),
+        StatsGroup::ToxicMonth => format!(
+            "SELECT to_char({}, 'YYYY-MM') AS label, {}
+            FROM messages m JOIN message_scores s ON s.message_id = m.id
+            WHERE s.toxicity >= {} AND {}
+            GROUP BY label ORDER BY label LIMIT $4",
+            MESSAGE_TIME_SQL,
+            MESSAGE_COUNT_SQL,
+            scoring::TOXIC_THRESHOLD,
+            STATS_SCOPE_SQL
+        ),
+        // average from -100 to 100, counts are integers. Sampled messages weigh as much as the
+        // messages they stand for
+        StatsGroup::SentimentMonth => format!(
+            "SELECT to_char({}, 'YYYY-MM') AS label,
+                ROUND(SUM(s.sentiment / m.sample_rate) / SUM(1.0 / m.sample_rate) * 100)::BIGINT
+            FROM messages m JOIN message_scores s ON s.message_id = m.id
+            WHERE s.sentiment IS NOT NULL AND {}
+            GROUP BY label ORDER BY label LIMIT $4",
+            MESSAGE_TIME_SQL, STATS_SCOPE_SQL
+        ),
+        StatsGroup::Guild => format!(
+            "SELECT COALESCE(g.name, m.guild_id::TEXT) AS label, {}
+            FROM messages m LEFT JOIN guilds g ON g.id = m.guild_id
+            WHERE m.guild_id IS NOT NULL AND {}
+            GROUP BY label ORDER BY 2 DESC LIMIT $4",
+            MESSAGE_COUNT_SQL, STATS_SCOPE_SQL
+        ),
+        StatsGroup::Channel => format!(
+            "SELECT m.channel_id::TEXT AS label, {} FROM messages m
+            WHERE {}
+            GROUP BY label ORDER BY 2 DESC LIMIT $4",
+            MESSAGE_COUNT_SQL, STATS_SCOPE_SQL
+        ),
+        StatsGroup::Author => format!(
+            "SELECT COALESCE(u.username, m.author_id::TEXT) AS label, {}
+            FROM messages m LEFT JOIN users u ON u.id = m.author_id
+            WHERE {}
+            GROUP BY label ORDER BY 2 DESC LIMIT $4",
+            MESSAGE_COUNT_SQL, STATS_SCOPE_SQL
+        ),
+    };
+
+    let [guild_id, channel_id, author_id] = scope.params();
+    let timezone = timezone::get().name();
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![&guild_id, &channel_id, &author_id, &limit];
+    if group.is_time() {
+        params.push(&timezone);
+    }
+    let rows = db.query(&query, &params).await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect())
+}
+
+pub async fn get_messages_for_language_backfill(
+    before_id: i64,
+    limit: i64,
+    db: &Client,
+) -> Result<Vec<(i64, String)>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            "SELECT id, content FROM messages
+            WHERE id < $1 AND language IS NULL AND content IS NOT NULL
+            ORDER BY id DESC
+            LIMIT $2",
+            &[&before_id, &limit],
+        )
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect())
+}
+
+pub async fn set_message_languages(
+    languages: &[(i64, String)],
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if languages.is_empty() {
+        return Ok(());
+    }
+
+    let ids: Vec<i64> = languages.iter().map(|(id, _)| *id).collect();
+    let codes: Vec<&str> = languages.iter().map(|(_, code)| code.as_str()).collect();
+
+    db.execute(
+        "UPDATE messages m SET language = l.language
+        FROM UNNEST($1::BIGINT[], $2::TEXT[]) AS l(id, language)
+        WHERE m.id = l.id",
+        &[&ids, &codes],
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn delete_message(msg_id: &u64, db: &Client) -> Result<(), Box<dyn Error>> {
+    let msg_id = *msg_id as i64;
+    db.execute(
+        "UPDATE messages SET deleted_at = NOW() WHERE id = $1 AND deleted_at IS NULL",
+        &[&msg_id],
+    )
+    .await?;
+    Ok(())
+}
+
+pub async fn bulk_delete_messages(msg_ids: &[u64], db: &Client) -> Result<(), Box<dyn Error>> {
+    if msg_ids.is_empty() {
+        return Ok(());
+    }
+
+    let mut sql_ids: Vec<i64> = msg_ids.iter().map(|&id| id as i64).collect();
+    sql_ids.sort_unstable();
+
+    db.execute(
+        "UPDATE messages SET deleted_at = NOW() WHERE id = ANY($1) AND deleted_at IS NULL",
+        &[&sql_ids],
+    )
+    .await?;
+
+    Ok(())
+}
+
+// identity changes between the `old` and `upserted` rows of the users, the snapshot of `old`
+// is taken before the upsert of the same statement
+const USER_HISTORY_SQL: &str = "INSERT INTO user_history (user_id, field, old_value, new_value)
+    SELECT u.id, c.field, c.old_value, c.new_value
+    FROM upserted u
+    JOIN old o ON o.id = u.id
+    CROSS JOIN LATERAL (VALUES
+        ('username', o.username, u.username),
+        ('global_name', o.global_name, u.global_name),
+        ('avatar', o.avatar, u.avatar)
+    ) AS c(field, old_value, new_value)
+    WHERE c.old_value IS DISTINCT FROM c.new_value";
+
+#[tracing::instrument(skip_all)]
+pub async fn upsert_user(
+    user: &User,
+    db: &Client,
+    guild_id: Option<u64>,
+) -> Result<(), Box<dyn Error>> {
+    let query = format!(
+        r#"
+        WITH old AS (SELECT id, username, global_name, avatar FROM users WHERE id = $1),
+        upserted AS (
+        INSERT INTO users (id, username, global_name, avatar, bot, banner, accent_color, flags, premium_type, public_flags, guilds)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, 
+                CASE WHEN $11::BIGINT IS NOT NULL THEN ARRAY[$11::BIGINT] ELSE ARRAY[]::BIGINT[] END)
+        ON CONFLICT (id) DO UPDATE SET
+            username = EXCLUDED.username,
+            global_name = EXCLUDED.global_name,
+            avatar = EXCLUDED.avatar,
+            bot = EXCLUDED.bot,
+            banner = EXCLUDED.banner,
+            accent_color = EXCLUDED.accent_color,
+            flags = EXCLUDED.flags,
+            premium_type = EXCLUDED.premium_type,
+            public_flags = EXCLUDED.public_flags,
+            guilds = CASE 
+                WHEN $11::BIGINT IS NOT NULL AND NOT ($11::BIGINT = ANY(users.guilds)) THEN 
+                    array_append(users.guilds, $11::BIGINT)
+                ELSE 
+                    users.guilds
+            END
+        RETURNING id, username, global_name, avatar
+        )
+        {}"#,
+        USER_HISTORY_SQL
+    );
+
+    let changes = db
+        .execute(
+            &query,
+            &[
+                &(user.id as i64),
+                &user.username,
+                &user.global_name,
+                &user.avatar,
+                &user.bot.unwrap_or(false),
+                &user.banner,
+                &user.accent_color.map(|v| v as i32),
+                &user.flags.map(|v| v as i32),
+                &user.premium_type.map(|v| v as i32),
+                &user.public_flags.map(|v| v as i32),
+                &guild_id.map(|id| id as i64),
+            ],
+        )
+        .await?;
+
+    if changes > 0 {
+        debug!("Recorded {} identity changes of user {}", changes, user.id);
+    }
+
+    Ok(())
+}
+
+/// Whether the current avatar and banner of the user were already downloaded
+pub async fn user_assets_stored(
+    user: &User,
+    db: &Client,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let avatar_path = user
+        .avatar
+        .as_ref()
+        .map(|hash| user_asset_path(user.id, "avatar", hash));
+    let banner_path = user
+        .banner
+        .as_ref()
+        .map(|hash| user_asset_path(user.id, "banner", hash));
+
+    let row = db
+        .query_opt(
+            "SELECT ($2::TEXT IS NULL OR avatar_path = $2) AND ($3::TEXT IS NULL OR banner_path = $3)
+            FROM users WHERE id = $1",
+            &[&(user.id as i64), &avatar_path, &banner_path],
+        )
+        .await?;
+
+    Ok(row.is_some_and(|row| row.get::<_, Option<bool>>(0).unwrap_or(false)))
+}
+
+pub async fn set_user_asset_paths(
+    user_id: u64,
+    avatar_path: Option<String>,
+    banner_path: Option<String>,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    db.execute(
+        "UPDATE users SET
+            avatar_path = COALESCE($2, avatar_path),
+            banner_path = COALESCE($3, banner_path)
+        WHERE id = $1",
+        &[&(user_id as i64), &avatar_path, &banner_path],
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn bulk_upsert_users(
+    users: &[User],
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if users.is_empty() {
+        return Ok(());
+    }
+
+    let mut user_data = Vec::new();
+
+    for user in users {
+        user_data.push((
+            user.id as i64,
+            user.username.clone(),
+            user.global_name.clone(),
+            user.avatar.clone(),
+            user.bot.unwrap_or(false),
+            user.banner.clone(),
+            user.accent_color.map(|v| v as i32),
+            user.flags.map(|v| v as i32),
+            user.premium_type.map(|v| v as i32),
+            user.public_flags.map(|v| v as i32),
+        ));
+    }
+
+    let mut placeholders = Vec::new();
+    let mut values: Vec<&(dyn ToSql + Sync)> = Vec::new();
+    let mut param_index = 1;
+
+    for data in &user_data {
+        placeholders.push(format!(
+            "(${}, ${}, ${}, ${}, ${}, ${}, ${}, ${}, ${}, ${})",
+            param_index,
+            param_index + 1,
+            param_index + 2,
+            param_index + 3,
+            param_index + 4,
+            param_index + 5,
+            param_index + 6,
+            param_index + 7,
+            param_index + 8,
+            param_index + 9
+        ));
+
+        values.extend_from_slice(&[
+            &data.0, &data.1, &data.2, &data.3, &data.4, &data.5, &data.6, &data.7, &data.8,
+            &data.9,
+        ]);
+
+        param_index += 10;
+    }
+
+    let ids: Vec<i64> = user_data.iter().map(|data| data.0).collect();
+    values.push(&ids);
+
+    let query = format!(
+        r#"WITH old AS (
+            SELECT id, username, global_name, avatar FROM users WHERE id = ANY(${}::BIGINT[])
+        ),
+        upserted AS (
+        INSERT INTO users (id, username, global_name, avatar, bot, banner, accent_color, flags, premium_type, public_flags)
+        VALUES {}
+        ON CONFLICT (id) DO UPDATE SET
+            username = EXCLUDED.username,
+            global_name = EXCLUDED.global_name,
+            avatar = EXCLUDED.avatar,
+            bot = EXCLUDED.bot,
+            banner = EXCLUDED.banner,
+            accent_color = EXCLUDED.accent_color,
+            flags = EXCLUDED.flags,
+            premium_type = EXCLUDED.premium_type,
+            public_flags = EXCLUDED.public_flags
+        RETURNING id, username, global_name, avatar
+        )
+        {}"#,
+        param_index,
+        placeholders.join(", "),
+        USER_HISTORY_SQL
+    );
+
+    let changes = db.execute(&query, &values).await?;
+
+    debug!(
+        "Bulk upserted {} users, {} identity changes",
+        users.len(),
+        changes
+    );
+
+    Ok(())
+}
+
+pub struct GuildAsset {
+    pub kind: &'static str,
+    pub hash: String,
+    /// Row of `guild_history` whose path is set once the asset is downloaded
+    pub history_id: i64,
+}
+
+/// Records the features gained and lost by the guild and vanity URL changes
+async fn record_guild_feature_changes(
+    guild_id: i64,
+    previous: &tokio_postgres::Row,
+    features: &[String],
+    vanity_url_code: &Option<String>,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut fields: Vec<&str> = Vec::new();
+    let mut values: Vec<Option<String>> = Vec::new();
+
+    // features are unknown for guilds first stored from a partial object
+    if let Some(previous_features) = previous.get::<_, Option<Vec<String>>>(3) {
+        for feature in features {
+            if !previous_features.contains(feature) {
+                fields.push("feature_added");
+                values.push(Some(feature.clone()));
+            }
+        }
+        for feature in previous_features {
+            if !features.contains(&feature) {
+                fields.push("feature_removed");
+                values.push(Some(feature));
+            }
+        }
+    }
+
+    let previous_vanity: Option<String> = previous.get(4);
+    if previous_vanity != *vanity_url_code {
+        fields.push("vanity_url_code");
+        values.push(vanity_url_code.clone());
+    }
+
+    if fields.is_empty() {
+        return Ok(());
+    }
+
+    db.execute(
+        "INSERT INTO guild_history (guild_id, field, value)
+        SELECT $1, * FROM UNNEST($2::TEXT[], $3::TEXT[])",
+        &[&guild_id, &fields, &values],
+    )
+    .await?;
+
+    debug!(
+        "Recorded {} feature changes of guild {}",
+        fields.len(),
+        guild_id
+    );
+
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn upsert_guild(
+    guild: &GatewayGuild,
+    db: &Client,
+) -> Result<Vec<GuildAsset>, Box<dyn Error + Send + Sync>> {
+    let guild_id = guild.id as i64;
+
+    let previous = db
+        .query_opt(
+            "SELECT icon, banner, splash, features, vanity_url_code FROM guilds WHERE id = $1",
+            &[&guild_id],
+        )
+        .await?;
+
+    let (icon, banner, splash, features, vanity_url_code) = if let Some(props) = &guild.properties {
+        let name = &props.name;
+        let icon = &props.icon;
+        let banner = &props.banner;
+        let splash = &props.splash;
+        let region = &props.region;
+        let owner_id = props.owner_id as i64;
+        let member_count = guild.member_count.map(|count| count as i32);
+        let features = props.features.clone();
+        let premium_tier = Some(props.premium_tier as i32);
+        let vanity_url_code = &props.vanity_url_code;
+
+        db.execute(
+            "INSERT INTO guilds (
+                id, name, icon, banner, splash, region, owner_id, member_count, features, premium_tier,
+                vanity_url_code
+            ) VALUES (
+                $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11
+            )
+            ON CONFLICT (id) DO UPDATE SET
+                name = EXCLUDED.name,
+                icon = EXCLUDED.icon,
+                banner = EXCLUDED.banner,
+                splash = EXCLUDED.splash,
+                region = EXCLUDED.region,
+                owner_id = EXCLUDED.owner_id,
+                member_count = EXCLUDED.member_count,
+                features = EXCLUDED.features,
+                premium_tier = EXCLUDED.premium_tier,
+                vanity_url_code = EXCLUDED.vanity_url_code",
+            &[
+                &guild_id,
+                &name,
+                &icon,
+                &banner,
+                &splash,
+                &region,
+                &owner_id,
+                &member_count,
+                &features,
+                &premium_tier,
+                &vanity_url_code,
+            ],
+        )
+        .await?;
+
+        (
+            icon.clone(),
+            banner.clone(),
+            splash.clone(),
+            Some(features),
+            vanity_url_code.clone(),
+        )
+    } else {
+        // Fallback to using the GatewayGuild fields
+        let name = &guild.name;
+        let icon = &guild.icon;
+        let banner = &guild.banner;
+        let splash = &guild.splash;
+        let region = &guild.region;
+        let owner_id = 0i64;
+        let member_count = guild.member_count.map(|count| count as i32);
+        let features: Option<Vec<String>> = guild.features.clone();
+        let premium_tier: Option<i32> = None;
+        let vanity_url_code = &guild.vanity_url_code;
+
+        db.execute(
+            "INSERT INTO guilds (
+                id, name, icon, banner, splash, region, owner_id, member_count, features, premium_tier,
+                vanity_url_code
+            ) VALUES (
+                $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11
+            )
+            ON CONFLICT (id) DO UPDATE SET
+                name = EXCLUDED.name,
+                icon = EXCLUDED.icon,
+                banner = EXCLUDED.banner,
+                splash = EXCLUDED.splash,
+                region = EXCLUDED.region,
+                member_count = EXCLUDED.member_count,
+                features = EXCLUDED.features,
+                premium_tier = EXCLUDED.premium_tier,
+                vanity_url_code = EXCLUDED.vanity_url_code",
+            &[
+                &guild_id,
+                &name,
+                &icon,
+                &banner,
+                &splash,
+                &region,
+                &owner_id,
+                &member_count,
+                &features,
+                &premium_tier,
+                &vanity_url_code,
+            ],
+        )
+        .await?;
+
+        (
+            icon.clone(),
+            banner.clone(),
+            splash.clone(),
+            features,
+            vanity_url_code.clone(),
+        )
+    };
+
+    // GUILD_UPDATE records the changes as they happen, READY the ones made while offline.
+    // Partial guilds come without features, their vanity code can't be trusted either
+    if let Some(features) = &features
+        && let Some(previous) = &previous
+    {
+        record_guild_feature_changes(guild_id, previous, features, &vanity_url_code, db).await?;
+    }
+
+    let mut changed_assets = Vec::new();
+
+    for (index, (kind, hash)) in [("icon", icon), ("banner", banner), ("splash", splash)]
+        .into_iter()
+        .enumerate()
+    {
+        let Some(hash) = hash else {
+            continue;
+        };
+
+        let previous_hash: Option<String> = previous.as_ref().and_then(|row| row.get(index));
+        let history_id: i64 = if previous_hash.as_deref() == Some(hash.as_str()) {
+            // the path is only set once the file is downloaded, a failed download is retried
+            if !Config::get().download_files {
+                continue;
+            }
+            let row = db
+                .query_opt(
+                    "SELECT id FROM guild_history
+                    WHERE guild_id = $1 AND field = $2 AND value = $3 AND path IS NULL
+                    ORDER BY id DESC
+                    LIMIT 1",
+                    &[&guild_id, &kind, &hash],
+                )
+                .await?;
+            match row {
+                Some(row) => row.get(0),
+                None => continue,
+            }
+        } else {
+            db.query_one(
+                "INSERT INTO guild_history (guild_id, field, value) VALUES ($1, $2, $3)
+                RETURNING id",
+                &[&guild_id, &kind, &hash],
+            )
+            .await?
+            .get(0)
+        };
+
+        changed_assets.push(GuildAsset {
+            kind,
+            hash,
+            history_id,
+        });
+    }
+
+    Ok(changed_assets)
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn bulk_upsert_roles(
+    roles: &[Role],
+    guild_id: u64,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if roles.is_empty() {
+        return Ok(());
+    }
+
+    let guild_id_i64 = guild_id as i64;
+    let mut role_data = Vec::new();
+
+    for role in roles {
+        role_data.push((
+            role.id as i64,
+            guild_id_i64,
+            role.name.clone(),
+            role.color as i32,
+            role.hoist,
+            role.position,
+            role.permissions.clone(),
+            role.flags.map(|f| f as i64),
+            role.icon.clone(),
+            role.unicode_emoji.clone(),
+            role.description.clone(),
+        ));
+    }
+
+    let mut placeholders = Vec::new();
+    let mut values: Vec<&(dyn ToSql + Sync)> = Vec::new();
+    let mut param_index = 1;
+
+    for data in &role_data {
+        placeholders.push(format!(
+            "(${}, ${}, ${}, ${}, ${}, ${}, ${}, ${}, ${}, ${}, ${})",
+            param_index,
+            param_index + 1,
+            param_index + 2,
+            param_index + 3,
+            param_index + 4,
+            param_index + 5,
+            param_index + 6,
+            param_index + 7,
+            param_index + 8,
+            param_index + 9,
+            param_index + 10
+        ));
+
+        values.extend_from_slice(&[
+            &data.0, &data.1, &data.2, &data.3, &data.4, &data.5, &data.6, &data.7, &data.8,
+            &data.9, &data.10,
+        ]);
+
+        param_index += 11;
+    }
+
+    let query = format!(
+        "INSERT INTO roles (
+            id, guild_id, name, color, hoist, position, permissions,
+            flags, icon, unicode_emoji, description
+        ) VALUES {}
+        ON CONFLICT (id, guild_id) DO UPDATE SET
+            name = EXCLUDED.name,
+            color = EXCLUDED.color,
+            hoist = EXCLUDED.hoist,
+            position = EXCLUDED.position,
+            permissions = EXCLUDED.permissions,
+            flags = EXCLUDED.flags,
+            icon = EXCLUDED.icon,
+            unicode_emoji = EXCLUDED.unicode_emoji,
+            description = EXCLUDED.description",
+        placeholders.join(", ")
+    );
+
+    db.execute(&query, &values).await?;
+    Ok(())
+}
+
+/// Records the icon of a role when it changed, including its removal once the role had one.
+/// Returns the id of the new history row, its path is set once the icon is downloaded.
+pub async fn record_role_icon(
+    role: &Role,
+    guild_id: u64,
+    db: &Client,
+) -> Result<Option<i64>, Box<dyn Error + Send + Sync>> {
+    let row = db
+        .query_opt(
+            "WITH last AS (
+                SELECT icon, unicode_emoji FROM role_icon_history
+                WHERE role_id = $1
+                ORDER BY recorded_at DESC
+                LIMIT 1
+            )
+            INSERT INTO role_icon_history (role_id, guild_id, icon, unicode_emoji)
+            SELECT $1, $2, $3, $4
+            WHERE NOT EXISTS (
+                SELECT 1 FROM last
+                WHERE last.icon IS NOT DISTINCT FROM $3
+                  AND last.unicode_emoji IS NOT DISTINCT FROM $4
+            )
+            AND ($3::TEXT IS NOT NULL OR $4::TEXT IS NOT NULL OR EXISTS (SELECT 1 FROM last))
+            RETURNING id",
+            &[
+                &(role.id as i64),
+                &(guild_id as i64),
+                &role.icon,
+                &role.unicode_emoji,
+            ],
+        )
+        .await?;
+
+    Ok(row.map(|row| row.get(0)))
+}
+
+pub async fn set_guild_asset_path(
+    history_id: i64,
+    path: &str,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    db.execute(
+        "UPDATE guild_history SET path = $2 WHERE id = $1",
+        &[&history_id, &path],
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn set_role_icon_path(
+    history_id: i64,
+    path: &str,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    db.execute(
+        "UPDATE role_icon_history SET path = $2 WHERE id = $1",
+        &[&history_id, &path],
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn bulk_upsert_channels(
+    channels: &[Channel],
+    guild_id: Option<u64>,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if channels.is_empty() {
+        return Ok(());
+    }
+
+    let mut channel_data = Vec::new();
+
+    for channel in channels {
+        let permission_overwrites = if let Some(overwrites) = &channel.permission_overwrites {
+            Some(serde_json::to_value(overwrites)?)
+        } else {
+            None
+        };
+
+        channel_data.push((
+            channel.id as i64,
+            guild_id.map(|id| id as i64),
+            channel.r#type as i32,
+            channel.name.clone(),
+            channel.topic.clone(),
+            channel.nsfw,
+            channel.position.map(|p| p as i32),
+            channel.parent_id.map(|id| id as i64),
+            channel.flags.map(|f| f as i64),
+            permission_overwrites,
+        ));
+    }
+
+    let mut placeholders = Vec::new();
+    let mut values: Vec<&(dyn ToSql + Sync)> = Vec::new();
+    let mut param_index = 1;
+
+    for data in &channel_data {
+        placeholders.push(format!(
+            "(${}, ${}, ${}, ${}, ${}, ${}, ${}, ${}, ${}, ${})",
+            param_index,
+            param_index + 1,
+            param_index + 2,
+            param_index + 3,
+            param_index + 4,
+            param_index + 5,
+            param_index + 6,
+            param_index + 7,
+            param_index + 8,
+            param_index + 9
+        ));
+
+        values.extend_from_slice(&[
+            &data.0, &data.1, &data.2, &data.3, &data.4, &data.5, &data.6, &data.7, &data.8,
+            &data.9,
+        ]);
+
+        param_index += 10;
+    }
+
+    let query = format!(
+        "INSERT INTO channels (
+            id, guild_id, type, name, topic, nsfw, position,
+            parent_id, flags, permission_overwrites
+        ) VALUES {}
+        ON CONFLICT (id) DO UPDATE SET
+            guild_id = EXCLUDED.guild_id,
+            type = EXCLUDED.type,
+            name = EXCLUDED.name,
+            topic = EXCLUDED.topic,
+            nsfw = EXCLUDED.nsfw,
+            position = EXCLUDED.position,
+            parent_id = EXCLUDED.parent_id,
+            flags = EXCLUDED.flags,
+            permission_overwrites = EXCLUDED.permission_overwrites",
+        placeholders.join(", ")
+    );
+
+    db.execute(&query, &values).await?;
+    Ok(())
+}
+
+pub async fn add_channel_recipients(
+    channel_id: u64,
+    user_ids: &[u64],
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let user_ids: Vec<i64> = user_ids.iter().map(|id| *id as i64).collect();
+    db.execute(
+        "INSERT INTO channel_recipients (channel_id, user_id)
+         SELECT $1, UNNEST($2::BIGINT[])
+         ON CONFLICT DO NOTHING",
+        &[&(channel_id as i64), &user_ids],
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn sync_guild_emojis(
+    emojis: &[Emoji],
+    guild_id: u64,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let guild_id_i64 = guild_id as i64;
+    let mut emoji_data = Vec::new();
+
+    for emoji in emojis {
+        let Some(emoji_id) = emoji.id else {
+            continue;
+        };
+
+        emoji_data.push((
+            emoji_id as i64,
+            guild_id_i64,
+            emoji.name.clone(),
+            emoji.animated.unwrap_or(false),
+            emoji.available,
+            emoji.user.as_ref().map(|user| user.id as i64),
+        ));
+    }
+
+    let emoji_ids: Vec<i64> = emoji_data.iter().map(|data| data.0).collect();
+    db.execute(
+        "UPDATE emojis SET deleted_at = NOW()
+        WHERE guild_id = $1 AND NOT (id = ANY($2)) AND deleted_at IS NULL",
+        &[&guild_id_i64, &emoji_ids],
+    )
+    .await?;
+
+    if emoji_data.is_empty() {
+        return Ok(());
+    }
+
+    let mut placeholders = Vec::new();
+    let mut values: Vec<&(dyn ToSql + Sync)> = Vec::new();
+    let mut param_index = 1;
+
+    for data in &emoji_data {
+        placeholders.push(format!(
+            "(${}, ${}, ${}, ${}, ${}, ${})",
+            param_index,
+            param_index + 1,
+            param_index + 2,
+            param_index + 3,
+            param_index + 4,
+            param_index + 5
+        ));
+
+        values.extend_from_slice(&[&data.0, &data.1, &data.2, &data.3, &data.4, &data.5]);
+
+        param_index += 6;
+    }
+
+    let query = format!(
+        "INSERT INTO emojis (id, guild_id, name, animated, available, creator_id)
+        VALUES {}
+        ON CONFLICT (id) DO UPDATE SET
+            name = EXCLUDED.name,
+            animated = EXCLUDED.animated,
+            available = EXCLUDED.available,
+            creator_id = COALESCE(EXCLUDED.creator_id, emojis.creator_id),
+            deleted_at = NULL",
+        placeholders.join(", ")
+    );
+
+    db.execute(&query, &values).await?;
+    Ok(())
+}
+
+pub async fn sync_guild_stickers(
+    stickers: &[Sticker],
+    guild_id: u64,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let guild_id_i64 = guild_id as i64;
+    let mut sticker_data = Vec::new();
+
+    for sticker in stickers {
+        sticker_data.push((
+            sticker.id as i64,
+            guild_id_i64,
+            sticker.name.clone(),
+            sticker.description.clone(),
+            sticker.tags.clone(),
+            sticker.format_type as i32,
+            sticker.available,
+            sticker.user.as_ref().map(|user| user.id as i64),
+        ));
+    }
+
+    let sticker_ids: Vec<i64> = sticker_data.iter().map(|data| data.0).collect();
+    db.execute(
+        "UPDATE stickers SET deleted_at = NOW()
+        WHERE guild_id = $1 AND NOT (id = ANY($2)) AND deleted_at IS NULL",
+        &[&guild_id_i64, &sticker_ids],
+    )
+    .await?;
+
+    if sticker_data.is_empty() {
+        return Ok(());
+    }
+
+    let mut placeholders = Vec::new();
+    let mut values: Vec<&(dyn ToSql + Sync)> = Vec::new();
+    let mut param_index = 1;
+
+    for data in &sticker_data {
+        placeholders.push(format!(
+            "(${}, ${}, ${}, ${}, ${}, ${}, ${}, ${})",
+            param_index,
+            param_index + 1,
+            param_index + 2,
+            param_index + 3,
+            param_index + 4,
+            param_index + 5,
+            param_index + 6,
+            param_index + 7
+        ));
+
+        values.extend_from_slice(&[
+            &data.0, &data.1, &data.2, &data.3, &data.4, &data.5, &data.6, &data.7,
+        ]);
+
+        param_index += 8;
+    }
+
+    let query = format!(
+        "INSERT INTO stickers (id, guild_id, name, description, tags, format_type, available, creator_id)
+        VALUES {}
+        ON CONFLICT (id) DO UPDATE SET
+            name = EXCLUDED.name,
+            description = EXCLUDED.description,
+            tags = EXCLUDED.tags,
+            format_type = EXCLUDED.format_type,
+            available = EXCLUDED.available,
+            creator_id = COALESCE(EXCLUDED.creator_id, stickers.creator_id),
+            deleted_at = NULL",
+        placeholders.join(", ")
+    );
+
+    db.execute(&query, &values).await?;
+    Ok(())
+}
+
+/// Newest stored message of the channel
+pub async fn get_latest_channel_message_id(
+    channel_id: u64,
+    db: &Client,
+) -> Result<Option<u64>, Box<dyn Error + Send + Sync>> {
+    let row = db
+        .query_one(
+            "SELECT MAX(id) FROM messages WHERE channel_id = $1",
+            &[&(channel_id as i64)],
+        )
+        .await?;
+
+    Ok(row.get::<_, Option<i64>>(0).map(|id| id as u64))
+}
+
+/// Newest stored message of the guild
+pub async fn get_latest_guild_message_id(
+    guild_id: u64,
+    db: &Client,
+) -> Result<Option<u64>, Box<dyn Error + Send + Sync>> {
+    let row = db
+        .query_one(
+            "SELECT MAX(id) FROM messages WHERE guild_id = $1",
+            &[&(guild_id as i64)],
+        )
+        .await?;
+
+    Ok(row.get::<_, Option<i64>>(0).map(|id| id as u64))
+}
+
+pub async fn get_latest_audit_log_id(
+    guild_id: u64,
+    db: &Client,
+) -> Result<Option<u64>, Box<dyn Error + Send + Sync>> {
+    let row = db
+        .query_one(
+            "SELECT MAX(id) FROM audit_logs WHERE guild_id = $1",
+            &[&(guild_id as i64)],
+        )
+        .await?;
+
+    Ok(row.get::<_, Option<i64>>(0).map(|id| id as u64))
+}
+
+/// Continuation token of the recent member search of the guild, None when the search starts
+/// over, and whether every member was listed once
+pub async fn get_member_search_progress(
+    guild_id: u64,
+    db: &Client,
+) -> Result<(Option<u64>, bool), Box<dyn Error + Send + Sync>> {
+    let row = db
+        .query_opt(
+            "SELECT continuation_token, completed_at IS NOT NULL FROM member_search_progress WHERE guild_id = $1",
+            &[&(guild_id as i64)],
+        )
+        .await?;
+
+    Ok(row.map_or((None, false), |row| {
+        (row.get::<_, Option<i64>>(0).map(|id| id as u64), row.get(1))
+    }))
+}
+
+/// Records a page of the member search of the guild. An empty page completes the guild.
+pub async fn update_member_search_progress(
+    guild_id: u64,
+    last_member_id: Option<u64>,
+    members: usize,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    db.execute(
+        "INSERT INTO member_search_progress (guild_id, continuation_token, members_seen, completed_at)
+         VALUES ($1, $2, $3, CASE WHEN $2 IS NULL THEN NOW() END)
+         ON CONFLICT (guild_id) DO UPDATE SET
+             continuation_token = COALESCE(EXCLUDED.continuation_token, member_search_progress.continuation_token),
+             members_seen = member_search_progress.members_seen + EXCLUDED.members_seen,
+             completed_at = COALESCE(member_search_progress.completed_at, EXCLUDED.completed_at),
+             updated_at = NOW()",
+        &[
+            &(guild_id as i64),
+            &last_member_id.map(|id| id as i64),
+            &(members as i64),
+        ],
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn bulk_insert_audit_logs(
+    entries: &[AuditLogEntry],
+    guild_id: u64,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    let guild_id_i64 = guild_id as i64;
+    let mut entry_data = Vec::new();
+
+    for entry in entries {
+        entry_data.push((
+            entry.id as i64,
+            guild_id_i64,
+            entry.user_id.map(|id| id as i64),
+            entry.target_id.map(|id| id as i64),
+            entry.action_type as i32,
+            entry.changes.clone(),
+            entry.options.clone(),
+            entry.reason.clone(),
+        ));
+    }
+
+    let mut placeholders = Vec::new();
+    let mut values: Vec<&(dyn ToSql + Sync)> = Vec::new();
+    let mut param_index = 1;
+
+    for data in &entry_data {
+        placeholders.push(format!(
+            "(${}, ${}, ${}, ${}, ${}, ${}, ${}, ${})",
+            param_index,
+            param_index + 1,
+            param_index + 2,
+            param_index + 3,
+            param_index + 4,
+            param_index + 5,
+            param_index + 6,
+            param_index + 7
+        ));
+
+        values.extend_from_slice(&[
+            &data.0, &data.1, &data.2, &data.3, &data.4, &data.5, &data.6, &data.7,
+        ]);
+
+        param_index += 8;
+    }
+
+    let query = format!(
+        "INSERT INTO audit_logs (
+            id, guild_id, user_id, target_id, action_type, changes, options, reason
+        ) VALUES {}
+        ON CONFLICT (id) DO NOTHING",
+        placeholders.join(", ")
+    );
+
+    db.execute(&query, &values).await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn upsert_scheduled_event(
+    event: &GuildScheduledEvent,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    db.execute(
+        "INSERT INTO scheduled_events (
+            id, guild_id, channel_id, creator_id, name, description, start_time, end_time,
+            privacy_level, status, entity_type, entity_id, location, user_count, image
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15)
+        ON CONFLICT (id) DO UPDATE SET
+            channel_id    = EXCLUDED.channel_id,
+            name          = EXCLUDED.name,
+            description   = EXCLUDED.description,
+            start_time    = EXCLUDED.start_time,
+            end_time      = EXCLUDED.end_time,
+            privacy_level = EXCLUDED.privacy_level,
+            status        = EXCLUDED.status,
+            entity_type   = EXCLUDED.entity_type,
+            entity_id     = EXCLUDED.entity_id,
+            location      = EXCLUDED.location,
+            user_count    = COALESCE(EXCLUDED.user_count, scheduled_events.user_count),
+            image         = EXCLUDED.image,
+            updated_at    = NOW()",
+        &[
+            &(event.id as i64),
+            &(event.guild_id as i64),
+            &event.channel_id.map(|id| id as i64),
+            &event.creator_id.map(|id| id as i64),
+            &event.name,
+            &event.description,
+            &event.scheduled_start_time,
+            &event.scheduled_end_time,
+            &(event.privacy_level as i16),
+            &(event.status as i16),
+            &(event.entity_type as i16),
+            &event.entity_id.map(|id| id as i64),
+            &event
+                .entity_metadata
+                .as_ref()
+                .and_then(|metadata| metadata.location.clone()),
+            &event.user_count.map(|count| count as i32),
+            &event.image,
+        ],
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn delete_scheduled_event(
+    event_id: u64,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    db.execute(
+        "UPDATE scheduled_events SET deleted_at = NOW() WHERE id = $1",
+        &[&(event_id as i64)],
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn upsert_stage_instance(
+    stage: &StageInstance,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    db.execute(
+        "INSERT INTO stage_instances (
+            id, guild_id, channel_id, topic, privacy_level, scheduled_event_id
+        ) VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (id) DO UPDATE SET
+            topic              = EXCLUDED.topic,
+            privacy_level      = EXCLUDED.privacy_level,
+            scheduled_event_id = EXCLUDED.scheduled_event_id",
+        &[
+            &(stage.id as i64),
+            &(stage.guild_id as i64),
+            &(stage.channel_id as i64),
+            &stage.topic,
+            &(stage.privacy_level as i16),
+            &stage.guild_scheduled_event_id.map(|id| id as i64),
+        ],
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn end_stage_instance(
+    stage_id: u64,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    db.execute(
+        "UPDATE stage_instances SET ended_at = NOW() WHERE id = $1",
+        &[&(stage_id as i64)],
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn insert_raw_event(
+    event_type: &str,
+    account_index: usize,
+    payload: &serde_json::Value,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    db.execute(
+        "INSERT INTO raw_events (event_type, account_index, payload) VALUES ($1, $2, $3)",
+        &[&event_type, &(account_index as i32), payload],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Removes every message and trace of a user, used to clean up synthetic data
+pub async fn delete_author_data(
+    author_id: u64,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let author_id = author_id as i64;
+
+    db.execute("DELETE FROM messages WHERE author_id = $1", &[&author_id])
+        .await?;
+    db.execute(
+        "DELETE FROM message_mentions WHERE mention_type = 'user' AND target_id = $1",
+        &[&author_id],
+    )
+    .await?;
+    db.execute("DELETE FROM user_history WHERE user_id = $1", &[&author_id])
+        .await?;
+    db.execute("DELETE FROM users WHERE id = $1", &[&author_id])
+        .await?;
+
+    Ok(())
+}
+
+pub async fn delete_guild_channels(
+    guild_id: u64,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let sql_guild_id: i64 = guild_id as i64;
+    db.execute("DELETE FROM channels WHERE guild_id = $1", &[&sql_guild_id])
+        .await?;
+
+    Ok(())
+}
+
+pub async fn delete_guild_roles(
+    guild_id: u64,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let sql_guild_id: i64 = guild_id as i64;
+    db.execute("DELETE FROM roles WHERE guild_id = $1", &[&sql_guild_id])
+        .await?;
+
+    Ok(())
+}
+
+pub async fn delete_channel(
+    channel_id: u64,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let sql_channel_id: i64 = channel_id as i64;
+    db.execute("DELETE FROM channels WHERE id = $1", &[&sql_channel_id])
+        .await?;
+
+    Ok(())
+}
+
+pub async fn delete_role(role_id: u64, db: &Client) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let sql_role_id: i64 = role_id as i64;
+    db.execute("DELETE FROM roles WHERE id = $1", &[&sql_role_id])
+        .await?;
+
+    Ok(())
+}
+
+/// Messages removed by `prune` and the retention rules
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PruneFilter {
+    /// Only messages with a smaller id, i.e. sent before a given time
+    pub before_id: Option<u64>,
+    pub guild_id: Option<u64>,
+    pub channel_id: Option<u64>,
+    pub only_bots: bool,
+}
+
+const PRUNE_FILTER_SQL: &str = "($1::BIGINT IS NULL OR m.id < $1)
+    AND ($2::BIGINT IS NULL OR m.guild_id = $2)
+    AND ($3::BIGINT IS NULL OR m.channel_id = $3)
+    AND (NOT $4 OR EXISTS (SELECT 1 FROM users u WHERE u.id = m.author_id AND u.bot))";
+
+pub async fn count_prunable_messages(
+    filter: &PruneFilter,
+    db: &Client,
+) -> Result<i64, Box<dyn Error + Send + Sync>> {
+    let query = format!("SELECT COUNT(*) FROM messages m WHERE {}", PRUNE_FILTER_SQL);
+    let row = db
+        .query_one(
+            &query,
+            &[
+                &filter.before_id.map(|id| id as i64),
+                &filter.guild_id.map(|id| id as i64),
+                &filter.channel_id.map(|id| id as i64),
+                &filter.only_bots,
+            ],
+        )
+        .await?;
+
+    Ok(row.get(0))
+}
+
+pub async fn get_prunable_message_ids(
+    filter: &PruneFilter,
+    limit: i64,
+    db: &Client,
+) -> Result<Vec<i64>, Box<dyn Error + Send + Sync>> {
+    let query = format!(
+        "SELECT m.id FROM messages m WHERE {} ORDER BY m.id LIMIT $5",
+        PRUNE_FILTER_SQL
+    );
+    let rows = db
+        .query(
+            &query,
+            &[
+                &filter.before_id.map(|id| id as i64),
+                &filter.guild_id.map(|id| id as i64),
+                &filter.channel_id.map(|id| id as i64),
+                &filter.only_bots,
+                &limit,
+            ],
+        )
+        .await?;
+
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+/// Permanently removes messages and what belongs to them, returns the paths of their downloaded attachments and thumbnails
+pub async fn purge_messages(
+    ids: &[i64],
+    db: &Client,
+) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    // the client stays locked by the caller, no other statement can run inside the transaction
+    db.batch_execute("BEGIN").await?;
+
+    match delete_messages(ids, db).await {
+        Ok(paths) => {
+            db.batch_execute("COMMIT").await?;
+            Ok(paths)
+        }
+        Err(e) => {
+            if let Err(rollback_error) = db.batch_execute("ROLLBACK").await {
+                error!("Failed to roll back the purge: {}", rollback_error);
+            }
+            Err(e)
+        }
+    }
+}
+
+// replies to purged messages keep their dangling reference, like replies to messages that
+// were never captured
+async fn delete_messages(
+    ids: &[i64],
+    db: &Client,
+) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    db.execute("DELETE FROM poll_votes WHERE message_id = ANY($1)", &[&ids])
+        .await?;
+    db.execute("DELETE FROM polls WHERE message_id = ANY($1)", &[&ids])
+        .await?;
+    db.execute(
+        "DELETE FROM message_transcripts WHERE message_id = ANY($1)",
+        &[&ids],
+    )
+    .await?;
+
+    let rows = db
+        .query(
+            "DELETE FROM attachments WHERE message_id = ANY($1) RETURNING path, thumbnail_path",
+            &[&ids],
+        )
+        .await?;
+
+    // revisions, snapshots, mentions and embeddings cascade
+    db.execute("DELETE FROM messages WHERE id = ANY($1)", &[&ids])
+        .await?;
+
+    Ok(rows
+        .iter()
+        .flat_map(|row| {
+            [
+                row.get::<_, Option<String>>(0),
+                row.get::<_, Option<String>>(1),
+            ]
+        })
+        .flatten()
+        .collect())
+}
+
+/// Ids among the given ones that are a stored message or attachment
+pub async fn get_known_media_ids(
+    ids: &[i64],
+    db: &Client,
+) -> Result<Vec<i64>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            "SELECT id FROM messages WHERE id = ANY($1)
+            UNION
+            SELECT id FROM attachments WHERE id = ANY($1)",
+            &[&ids],
+        )
+        .await?;
+
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+/// Paths among the given ones that are stored for an attachment or its thumbnail
+pub async fn get_stored_media_paths(
+    paths: &[String],
+    db: &Client,
+) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            "SELECT path FROM attachments WHERE path = ANY($1)
+            UNION
+            SELECT thumbnail_path FROM attachments WHERE thumbnail_path = ANY($1)",
+            &[&paths],
+        )
+        .await?;
+
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+/// Whether a message sent within an hour after the attachment id lists it,
+/// for files downloaded before the attachments table existed
+pub async fn is_attachment_of_stored_message(
+    attachment_id: u64,
+    db: &Client,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    // an hour, in snowflake units
+    const UPLOAD_WINDOW: i64 = (3600 * 1000) << 22;
+
+    let row = db
+        .query_one(
+            "SELECT EXISTS (
+                SELECT 1 FROM messages m CROSS JOIN LATERAL jsonb_array_elements(m.attachments) elem
+                WHERE m.id BETWEEN $1 AND $2 AND elem->>'id' = $3
+            )",
+            &[
+                &(attachment_id as i64),
+                &(attachment_id as i64).saturating_add(UPLOAD_WINDOW),
+                &attachment_id.to_string(),
+            ],
+        )
+        .await?;
+
+    Ok(row.get(0))
+}
+
+pub struct AccountStatus {
+    pub token_id: String,
+    pub label: Option<String>,
+    pub user_id: Option<u64>,
+    pub username: Option<String>,
+    pub status: &'static str,
+    pub email_verified: Option<bool>,
+    pub phone_verified: Option<bool>,
+    pub guild_count: Option<usize>,
+    pub error: Option<String>,
+}
+
+pub async fn save_account_status(
+    account: &AccountStatus,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    db.execute(
+        "INSERT INTO account_status (
+            token_id, label, user_id, username, status, email_verified, phone_verified,
+            guild_count, error, checked_at
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, NOW())
+        ON CONFLICT (token_id) DO UPDATE SET
+            label          = EXCLUDED.label,
+            user_id        = COALESCE(EXCLUDED.user_id, account_status.user_id),
+            username       = COALESCE(EXCLUDED.username, account_status.username),
+            status         = EXCLUDED.status,
+            email_verified = EXCLUDED.email_verified,
+            phone_verified = EXCLUDED.phone_verified,
+            guild_count    = EXCLUDED.guild_count,
+            error          = EXCLUDED.error,
+            checked_at     = NOW()",
+        &[
+            &account.token_id,
+            &account.label,
+            &account.user_id.map(|id| id as i64),
+            &account.username,
+            &account.status,
+            &account.email_verified,
+            &account.phone_verified,
+            &account.guild_count.map(|count| count as i32),
+            &account.error,
+        ],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Marks a token dead after the gateway rejected it, keeping what `tokens check` knew of it
+pub async fn mark_account_dead(
+    token_id: &str,
+    label: Option<&str>,
+    error: &str,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    db.execute(
+        "INSERT INTO account_status (token_id, label, status, error, checked_at)
+        VALUES ($1, $2, 'dead', $3, NOW())
+        ON CONFLICT (token_id) DO UPDATE SET
+            label      = EXCLUDED.label,
+            status     = 'dead',
+            error      = EXCLUDED.error,
+            checked_at = NOW()",
+        &[&token_id, &label, &error],
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_dead_token_ids(db: &Client) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            "SELECT token_id FROM account_status WHERE status = 'dead'",
+            &[],
+        )
+        .await?;
+
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+pub async fn save_image_hashes(
+    attachment_id: u64,
+    phash: u64,
+    dhash: u64,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    db.execute(
+        "INSERT INTO image_hashes (attachment_id, phash, dhash) VALUES ($1, $2, $3)
+        ON CONFLICT (attachment_id) DO UPDATE SET
+            phash       = EXCLUDED.phash,
+            dhash       = EXCLUDED.dhash,
+            computed_at = NOW()",
+        &[&(attachment_id as i64), &(phash as i64), &(dhash as i64)],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Downloaded images without hashes after the given attachment id, as (attachment id, path)
+pub async fn get_unhashed_images(
+    after_id: u64,
+    limit: i64,
+    db: &Client,
+) -> Result<Vec<(u64, String)>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            "SELECT a.id, a.path FROM attachments a
+            LEFT JOIN image_hashes h ON h.attachment_id = a.id
+            WHERE a.id > $1 AND h.attachment_id IS NULL AND a.path IS NOT NULL
+              AND a.content_type LIKE 'image/%'
+            ORDER BY a.id
+            LIMIT $2",
+            &[&(after_id as i64), &limit],
+        )
+        .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| (row.get::<_, i64>(0) as u64, row.get(1)))
+        .collect())
+}
+
+pub struct SimilarImage {
+    pub attachment_id: u64,
+    pub message_id: u64,
+    pub path: Option<String>,
+    pub distance: i32,
+}
+
+/// Images whose pHash differs from the given one by at most `max_distance` bits, closest first
+pub async fn find_similar_images(
+    phash: u64,
+    max_distance: i32,
+    limit: i64,
+    db: &Client,
+) -> Result<Vec<SimilarImage>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            "SELECT a.id, a.message_id, a.path, d.distance FROM image_hashes h
+            CROSS JOIN LATERAL (SELECT bit_count((h.phash # $1)::bit(64))::INT AS distance) d
+            JOIN attachments a ON a.id = h.attachment_id
+            WHERE d.distance <= $2
+            ORDER BY d.distance, a.id
+            LIMIT $3",
+            &[&(phash as i64), &max_distance, &limit],
+        )
+        .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| SimilarImage {
+            attachment_id: row.get::<_, i64>(0) as u64,
+            message_id: row.get::<_, i64>(1) as u64,
+            path: row.get(2),
+            distance: row.get(3),
+        })
+        .collect())
+}
+
+pub async fn set_attachment_metadata(
+    attachment_id: u64,
+    metadata: &MediaMetadata,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    db.execute(
+        "UPDATE attachments SET width = $2, height = $3, duration = $4, exif = $5 WHERE id = $1",
+        &[
+            &(attachment_id as i64),
+            &metadata.width,
+            &metadata.height,
+            &metadata.duration,
+            &metadata.exif,
+        ],
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn set_attachment_thumbnail(
+    attachment_id: u64,
+    thumbnail_path: &str,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    db.execute(
+        "UPDATE attachments SET thumbnail_path = $2 WHERE id = $1",
+        &[&(attachment_id as i64), &thumbnail_path],
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn set_attachment_scan(
+    attachment_id: u64,
+    status: &str,
+    detail: Option<&str>,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    db.execute(
+        "UPDATE attachments SET scan_status = $2, scan_detail = $3, scanned_at = NOW()
+        WHERE id = $1",
+        &[&(attachment_id as i64), &status, &detail],
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn set_nsfw_score(
+    attachment_id: u64,
+    score: f32,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    db.execute(
+        "UPDATE attachments SET nsfw_score = $2 WHERE id = $1",
+        &[&(attachment_id as i64), &score],
+    )
+    .await?;
 
-        db.execute(
-            "INSERT INTO guilds (
-                id, name, icon, region, owner_id, member_count, features, premium_tier
-            ) VALUES (
-                $1, $2, $3, $4, $5, $6, $7, $8
-            )
-            ON CONFLICT (id) DO UPDATE SET
-                name = EXCLUDED.name,
-                icon = EXCLUDED.icon,
-                region = EXCLUDED.region,
-                member_count = EXCLUDED.member_count,
-                features = EXCLUDED.features,
-                premium_tier = EXCLUDED.premium_tier",
-            &[
-                &guild_id,
-                &name,
-                &icon,
-                &region,
-                &owner_id,
-                &member_count,
-                &features,
-                &premium_tier,
-            ],
+    Ok(())
+}
+
+pub async fn get_unclassified_images(
+    after_id: u64,
+    limit: i64,
+    db: &Client,
+) -> Result<Vec<(u64, String)>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            "SELECT id, path FROM attachments
+            WHERE id > $1 AND nsfw_score IS NULL AND path IS NOT NULL
+              AND content_type LIKE 'image/%'
+              AND scan_status IS DISTINCT FROM 'infected'
+            ORDER BY id
+            LIMIT $2",
+            &[&(after_id as i64), &limit],
         )
         .await?;
-    }
 
-    Ok(())
+    Ok(rows
+        .iter()
+        .map(|row| (row.get::<_, i64>(0) as u64, row.get(1)))
+        .collect())
 }
 
-pub async fn bulk_upsert_roles(
-    roles: &[Role],
-    guild_id: u64,
+pub async fn save_transcript(
+    attachment_id: u64,
+    message_id: u64,
+    text: &str,
     db: &Client,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    if roles.is_empty() {
-        return Ok(());
-    }
+    db.execute(
+        "INSERT INTO message_transcripts (attachment_id, message_id, text)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (attachment_id) DO UPDATE SET
+            text = EXCLUDED.text,
+            transcribed_at = NOW()",
+        &[&(attachment_id as i64), &(message_id as i64), &text],
+    )
+    .await?;
 
-    let guild_id_i64 = guild_id as i64;
-    let mut role_data = Vec::new();
+    Ok(())
+}
 
-    for role in roles {
-        role_data.push((
-            role.id as i64,
-            guild_id_i64,
-            role.name.clone(),
-            role.color as i32,
-            role.hoist,
-            role.position,
-            role.permissions.clone(),
-            role.flags.map(|f| f as i64),
-            role.icon.clone(),
-            role.unicode_emoji.clone(),
-            role.description.clone(),
-        ));
-    }
+/// Downloaded voice messages, or every audio file with `all_audio`, without a transcript as
+/// (attachment id, message id, path)
+pub async fn get_untranscribed_audio(
+    after_id: u64,
+    all_audio: bool,
+    voice_message_flag: u64,
+    limit: i64,
+    db: &Client,
+) -> Result<Vec<(u64, u64, String)>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            "SELECT a.id, a.message_id, a.path FROM attachments a
+            JOIN messages m ON m.id = a.message_id
+            LEFT JOIN message_transcripts t ON t.attachment_id = a.id
+            WHERE a.id > $1 AND t.attachment_id IS NULL AND a.path IS NOT NULL
+              AND a.content_type LIKE 'audio/%'
+              AND ($2 OR m.flags & $3 <> 0)
+            ORDER BY a.id
+            LIMIT $4",
+            &[
+                &(after_id as i64),
+                &all_audio,
+                &(voice_message_flag as i64),
+                &limit,
+            ],
+        )
+        .await?;
 
-    let mut placeholders = Vec::new();
-    let mut values: Vec<&(dyn ToSql + Sync)> = Vec::new();
-    let mut param_index = 1;
+    Ok(rows
+        .iter()
+        .map(|row| {
+            (
+                row.get::<_, i64>(0) as u64,
+                row.get::<_, i64>(1) as u64,
+                row.get(2),
+            )
+        })
+        .collect())
+}
 
-    for data in &role_data {
-        placeholders.push(format!(
-            "(${}, ${}, ${}, ${}, ${}, ${}, ${}, ${}, ${}, ${}, ${})",
-            param_index,
-            param_index + 1,
-            param_index + 2,
-            param_index + 3,
-            param_index + 4,
-            param_index + 5,
-            param_index + 6,
-            param_index + 7,
-            param_index + 8,
-            param_index + 9,
-            param_index + 10
-        ));
+pub struct WebArchive {
+    pub url: String,
+    pub url_hash: String,
+    pub path: Option<String>,
+    pub status_code: Option<i32>,
+    pub content_type: Option<String>,
+    pub error: Option<String>,
+}
 
-        values.extend_from_slice(&[
-            &data.0, &data.1, &data.2, &data.3, &data.4, &data.5, &data.6, &data.7, &data.8,
-            &data.9, &data.10,
-        ]);
+/// Linked URLs without an archive, domains match themselves and their subdomains
+pub async fn get_unarchived_links(
+    domains: &[String],
+    exclude_domains: &[String],
+    limit: i64,
+    db: &Client,
+) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            "SELECT DISTINCT l.url FROM message_links l
+            LEFT JOIN web_archives w ON w.url = l.url
+            WHERE w.url IS NULL
+              AND (cardinality($1::TEXT[]) = 0 OR EXISTS (
+                  SELECT 1 FROM unnest($1::TEXT[]) d
+                  WHERE l.domain = d OR l.domain LIKE '%.' || d))
+              AND NOT EXISTS (
+                  SELECT 1 FROM unnest($2::TEXT[]) d
+                  WHERE l.domain = d OR l.domain LIKE '%.' || d)
+            LIMIT $3",
+            &[&domains, &exclude_domains, &limit],
+        )
+        .await?;
 
-        param_index += 11;
-    }
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
 
-    let query = format!(
-        "INSERT INTO roles (
-            id, guild_id, name, color, hoist, position, permissions,
-            flags, icon, unicode_emoji, description
-        ) VALUES {}
-        ON CONFLICT (id, guild_id) DO UPDATE SET
-            name = EXCLUDED.name,
-            color = EXCLUDED.color,
-            hoist = EXCLUDED.hoist,
-            position = EXCLUDED.position,
-            permissions = EXCLUDED.permissions,
-            flags = EXCLUDED.flags,
-            icon = EXCLUDED.icon,
-            unicode_emoji = EXCLUDED.unicode_emoji,
-            description = EXCLUDED.description",
-        placeholders.join(", ")
-    );
+pub async fn save_web_archive(
+    archive: &WebArchive,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    db.execute(
+        "INSERT INTO web_archives (url, url_hash, path, status_code, content_type, error)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (url) DO UPDATE SET
+            path         = EXCLUDED.path,
+            status_code  = EXCLUDED.status_code,
+            content_type = EXCLUDED.content_type,
+            error        = EXCLUDED.error,
+            archived_at  = NOW()",
+        &[
+            &archive.url,
+            &archive.url_hash,
+            &archive.path,
+            &archive.status_code,
+            &archive.content_type,
+            &archive.error,
+        ],
+    )
+    .await?;
 
-    db.execute(&query, &values).await?;
     Ok(())
 }
 
-pub async fn bulk_upsert_channels(
-    channels: &[Channel],
-    guild_id: Option<u64>,
-    db: &Client,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    if channels.is_empty() {
-        return Ok(());
-    }
+pub struct MessageScore {
+    pub message_id: i64,
+    pub toxicity: Option<f32>,
+    pub sentiment: Option<f32>,
+}
 
-    let mut channel_data = Vec::new();
+pub async fn get_unscored_messages(
+    limit: i64,
+    db: &Client,
+) -> Result<Vec<(i64, String)>, Box<dyn Error + Send + Sync>> {
+    let rows = db
+        .query(
+            "SELECT m.id, m.content FROM messages m
+            WHERE m.content IS NOT NULL AND length(trim(m.content)) > 0
+              AND m.deleted_at IS NULL
+              AND NOT EXISTS (SELECT 1 FROM message_scores s WHERE s.message_id = m.id)
+            ORDER BY m.id DESC
+            LIMIT $1",
+            &[&limit],
+        )
+        .await?;
 
-    for channel in channels {
-        let permission_overwrites = if let Some(overwrites) = &channel.permission_overwrites {
-            Some(serde_json::to_value(overwrites)?)
-        } else {
-            None
-        };
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect())
+}
 
-        channel_data.push((
-            channel.id as i64,
-            guild_id.map(|id| id as i64),
-            channel.r#type as i32,
-            channel.name.clone(),
-            channel.topic.clone(),
-            channel.nsfw,
-            channel.position.map(|p| p as i32),
-            channel.parent_id.map(|id| id as i64),
-            channel.flags.map(|f| f as i64),
-            permission_overwrites,
-        ));
+pub async fn save_message_scores(
+    scores: &[MessageScore],
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    for score in scores {
+        db.execute(
+            "INSERT INTO message_scores (message_id, toxicity, sentiment)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (message_id) DO UPDATE SET
+                toxicity  = EXCLUDED.toxicity,
+                sentiment = EXCLUDED.sentiment,
+                scored_at = NOW()",
+            &[&score.message_id, &score.toxicity, &score.sentiment],
+        )
+        .await?;
     }
 
-    let mut placeholders = Vec::new();
-    let mut values: Vec<&(dyn ToSql + Sync)> = Vec::new();
-    let mut param_index = 1;
+    Ok(())
+}
 
-    for data in &channel_data {
-        placeholders.push(format!(
-            "(${}, ${}, ${}, ${}, ${}, ${}, ${}, ${}, ${}, ${})",
-            param_index,
-            param_index + 1,
-            param_index + 2,
-            param_index + 3,
-            param_index + 4,
-            param_index + 5,
-            param_index + 6,
-            param_index + 7,
-            param_index + 8,
-            param_index + 9
-        ));
+#[tracing::instrument(skip_all)]
+pub async fn save_ban(
+    guild_id: u64,
+    user_id: u64,
+    username: &str,
+    own_account: bool,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    db.execute(
+        "INSERT INTO bans (guild_id, user_id, username, own_account) VALUES ($1, $2, $3, $4)",
+        &[
+            &(guild_id as i64),
+            &(user_id as i64),
+            &username,
+            &own_account,
+        ],
+    )
+    .await?;
 
-        values.extend_from_slice(&[
-            &data.0, &data.1, &data.2, &data.3, &data.4, &data.5, &data.6, &data.7, &data.8,
-            &data.9,
-        ]);
+    Ok(())
+}
 
-        param_index += 10;
-    }
+#[derive(Debug, Clone)]
+pub struct Relationship {
+    pub user_id: u64,
+    pub relationship_type: i16,
+    pub nickname: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+}
 
-    let query = format!(
-        "INSERT INTO channels (
-            id, guild_id, type, name, topic, nsfw, position,
-            parent_id, flags, permission_overwrites
-        ) VALUES {}
-        ON CONFLICT (id) DO UPDATE SET
-            guild_id = EXCLUDED.guild_id,
-            type = EXCLUDED.type,
-            name = EXCLUDED.name,
-            topic = EXCLUDED.topic,
-            nsfw = EXCLUDED.nsfw,
-            position = EXCLUDED.position,
-            parent_id = EXCLUDED.parent_id,
-            flags = EXCLUDED.flags,
-            permission_overwrites = EXCLUDED.permission_overwrites",
-        placeholders.join(", ")
-    );
+#[tracing::instrument(skip_all)]
+pub async fn upsert_relationship(
+    account_id: u64,
+    relationship: &Relationship,
+    db: &Client,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    db.execute(
+        "INSERT INTO relationships (account_id, user_id, relationship_type, nickname, since)
+         VALUES ($1, $2, $3, $4, $5)
+         ON CONFLICT (account_id, user_id) DO UPDATE SET
+             relationship_type = EXCLUDED.relationship_type,
+             nickname = EXCLUDED.nickname,
+             since = COALESCE(EXCLUDED.since, relationships.since),
+             updated_at = NOW(),
+             removed_at = NULL",
+        &[
+            &(account_id as i64),
+            &(relationship.user_id as i64),
+            &relationship.relationship_type,
+            &relationship.nickname,
+            &relationship.since,
+        ],
+    )
+    .await?;
 
-    db.execute(&query, &values).await?;
     Ok(())
 }
 
-pub async fn delete_guild_channels(
-    guild_id: u64,
+pub async fn remove_relationship(
+    account_id: u64,
+    user_id: u64,
     db: &Client,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let sql_guild_id: i64 = guild_id as i64;
-    db.execute("DELETE FROM channels WHERE guild_id = $1", &[&sql_guild_id])
-        .await?;
+    db.execute(
+        "UPDATE relationships SET removed_at = NOW()
+         WHERE account_id = $1 AND user_id = $2 AND removed_at IS NULL",
+        &[&(account_id as i64), &(user_id as i64)],
+    )
+    .await?;
 
     Ok(())
 }
 
-pub async fn delete_guild_roles(
-    guild_id: u64,
+/// Stores the relationships of the account listed in READY, the ones missing from it ended
+/// while the account was offline
+pub async fn sync_relationships(
+    account_id: u64,
+    relationships: &[Relationship],
     db: &Client,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let sql_guild_id: i64 = guild_id as i64;
-    db.execute("DELETE FROM roles WHERE guild_id = $1", &[&sql_guild_id])
-        .await?;
+    for relationship in relationships {
+        upsert_relationship(account_id, relationship, db).await?;
+    }
+
+    let user_ids: Vec<i64> = relationships
+        .iter()
+        .map(|relationship| relationship.user_id as i64)
+        .collect();
+    db.execute(
+        "UPDATE relationships SET removed_at = NOW()
+         WHERE account_id = $1 AND removed_at IS NULL AND NOT (user_id = ANY($2))",
+        &[&(account_id as i64), &user_ids],
+    )
+    .await?;
 
     Ok(())
 }
 
-pub async fn delete_channel(
+/// Creates or updates the call of the channel, the rung users and the participants are added
+/// to the ones already known
+#[tracing::instrument(skip_all)]
+pub async fn upsert_call(
     channel_id: u64,
+    message_id: u64,
+    region: Option<&str>,
+    ringing: &[u64],
+    participants: &[u64],
     db: &Client,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let sql_channel_id: i64 = channel_id as i64;
-    db.execute("DELETE FROM channels WHERE id = $1", &[&sql_channel_id])
-        .await?;
+    let ringing: Vec<i64> = ringing.iter().map(|id| *id as i64).collect();
+    let participants: Vec<i64> = participants.iter().map(|id| *id as i64).collect();
+    db.execute(
+        "INSERT INTO calls (channel_id, message_id, region, ringing, participants)
+         VALUES ($1, $2, $3, $4, $5)
+         ON CONFLICT (channel_id, message_id) DO UPDATE SET
+             region = COALESCE(EXCLUDED.region, calls.region),
+             ringing = ARRAY(SELECT DISTINCT UNNEST(calls.ringing || EXCLUDED.ringing)),
+             participants = ARRAY(SELECT DISTINCT UNNEST(calls.participants || EXCLUDED.participants))",
+        &[
+            &(channel_id as i64),
+            &(message_id as i64),
+            &region,
+            &ringing,
+            &participants,
+        ],
+    )
+    .await?;
 
     Ok(())
 }
 
-pub async fn delete_role(role_id: u64, db: &Client) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let sql_role_id: i64 = role_id as i64;
-    db.execute("DELETE FROM roles WHERE id = $1", &[&sql_role_id])
-        .await?;
+/// Ends the ongoing call of the channel
+pub async fn end_call(channel_id: u64, db: &Client) -> Result<(), Box<dyn Error + Send + Sync>> {
+    db.execute(
+        "UPDATE calls SET ended_at = NOW() WHERE channel_id = $1 AND ended_at IS NULL",
+        &[&(channel_id as i64)],
+    )
+    .await?;
 
     Ok(())
 }
+
+/// Member count of the guild, when it was first stored, and its messages sent since the
+/// message id, sampled messages weighted by their sample rate. None for unknown guilds.
+pub async fn get_guild_activity(
+    guild_id: u64,
+    since_id: u64,
+    db: &Client,
+) -> Result<Option<(Option<u64>, DateTime<Utc>, f64)>, Box<dyn Error + Send + Sync>> {
+    let row = db
+        .query_opt(
+            "SELECT member_count, first_seen,
+                 (SELECT COALESCE(SUM(1.0 / sample_rate), 0)::DOUBLE PRECISION
+                  FROM messages WHERE guild_id = $1 AND id >= $2 AND sample_rate > 0)
+             FROM guilds WHERE id = $1",
+            &[&(guild_id as i64), &(since_id as i64)],
+        )
+        .await?;
+
+    Ok(row.map(|row| {
+        (
+            row.get::<_, Option<i32>>(0).map(|count| count as u64),
+            row.get(1),
+            row.get::<_, f64>(2),
+        )
+    }))
+}
@@ -0,0 +1,120 @@
+use serde_json::{Value, json};
+use similar::{ChangeTag, TextDiff};
+
+/// Word level diff between two versions of a message, stored as a list of operations:
+/// `["=", n]` keeps the next n chars, `["-", text]` removes text and `["+", text]` inserts it.
+/// Applying the operations to the old content gives back the new one.
+pub fn word_diff(old: &str, new: &str) -> Value {
+    let diff = TextDiff::from_words(old, new);
+    let mut ops: Vec<(ChangeTag, String)> = Vec::new();
+
+    // merge consecutive changes of the same kind to keep the diff compact
+    for change in diff.iter_all_changes() {
+        match ops.last_mut() {
+            Some((tag, text)) if *tag == change.tag() => text.push_str(change.value()),
+            _ => ops.push((change.tag(), change.value().to_string())),
+        }
+    }
+
+    Value::Array(
+        ops.into_iter()
+            .map(|(tag, text)| match tag {
+                ChangeTag::Equal => json!(["=", text.chars().count()]),
+                ChangeTag::Delete => json!(["-", text]),
+                ChangeTag::Insert => json!(["+", text]),
+            })
+            .collect(),
+    )
+}
+
+/// Number of chars removed and added by the edit
+pub fn change_size(diff: &Value) -> (i32, i32) {
+    let mut removed = 0;
+    let mut added = 0;
+
+    for op in diff.as_array().into_iter().flatten() {
+        let len = op[1].as_str().map(|text| text.chars().count() as i32);
+        match (op[0].as_str(), len) {
+            (Some("-"), Some(len)) => removed += len,
+            (Some("+"), Some(len)) => added += len,
+            _ => {}
+        }
+    }
+
+    (removed, added)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // replays the operations on the old content
+    fn apply(old: &str, diff: &Value) -> String {
+        let old: Vec<char> = old.chars().collect();
+        let mut position = 0;
+        let mut new = String::new();
+
+        for op in diff.as_array().unwrap() {
+            match op[0].as_str().unwrap() {
+                "=" => {
+                    let count = op[1].as_u64().unwrap() as usize;
+                    new.extend(&old[position..position + count]);
+                    position += count;
+                }
+                "-" => {
+                    let text: Vec<char> = op[1].as_str().unwrap().chars().collect();
+                    assert_eq!(old[position..position + text.len()], text[..]);
+                    position += text.len();
+                }
+                "+" => new.push_str(op[1].as_str().unwrap()),
+                op => panic!("unknown operation {}", op),
+            }
+        }
+
+        assert_eq!(position, old.len());
+        new
+    }
+
+    #[test]
+    fn word_diff_applies_to_the_new_content() {
+        let edits = [
+            ("hello world", "hello there world"),
+            ("the quick brown fox", "the slow brown dog"),
+            ("", "new message"),
+            ("deleted message", ""),
+            ("same", "same"),
+            ("ça marche très bien 🎉", "ça marche 🎉 vraiment bien"),
+            ("line one\nline two", "line one\nline 2\nline three"),
+        ];
+
+        for (old, new) in edits {
+            assert_eq!(
+                apply(old, &word_diff(old, new)),
+                new,
+                "{:?} -> {:?}",
+                old,
+                new
+            );
+        }
+    }
+
+    #[test]
+    fn word_diff_merges_consecutive_changes() {
+        let diff = word_diff("a b c", "x y z");
+        let tags: Vec<&str> = diff
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|op| op[0].as_str().unwrap())
+            .collect();
+
+        assert!(tags.windows(2).all(|pair| pair[0] != pair[1]));
+    }
+
+    #[test]
+    fn change_size_counts_chars() {
+        let diff = word_diff("il était une fois", "il est une fois");
+        assert_eq!(change_size(&diff), (5, 3));
+        assert_eq!(change_size(&word_diff("same", "same")), (0, 0));
+    }
+}
@@ -0,0 +1,53 @@
+use crate::BoxedResult;
+use crate::database::{get_guilds_for_discovery, mark_guild_unlisted, save_guild_discovery};
+use discord_client_rest::rest::RestClient;
+use log::{error, info, warn};
+use std::time::Duration;
+use tokio_postgres::Client;
+
+// delay between two lookups, to stay under the REST rate limits
+const FETCH_DELAY: Duration = Duration::from_millis(1500);
+
+/// Fetches the discovery metadata of the stored discoverable guilds
+pub async fn enrich_guilds(token: String, limit: i64, db: &Client) -> BoxedResult<()> {
+    let guild_ids = get_guilds_for_discovery(limit, db).await?;
+    if guild_ids.is_empty() {
+        info!("No discoverable guilds to enrich");
+        return Ok(());
+    }
+
+    info!(
+        "Fetching discovery metadata of {} guilds...",
+        guild_ids.len()
+    );
+
+    let rest_client = RestClient::connect(token, Some(9), None)
+        .await
+        .map_err(|e| format!("Error connecting to Discord REST API: {}", e))?;
+
+    let mut enriched = 0;
+    for guild_id in &guild_ids {
+        match rest_client
+            .guild(Some(*guild_id))
+            .get_discoverable_guild()
+            .await
+        {
+            Ok(guild) => {
+                save_guild_discovery(&guild, db).await?;
+                enriched += 1;
+            }
+            Err(e) => {
+                warn!("Guild {} is not listed in discovery: {}", guild_id, e);
+                if let Err(e) = mark_guild_unlisted(*guild_id, db).await {
+                    error!("Failed to mark guild {} as unlisted: {}", guild_id, e);
+                }
+            }
+        }
+
+        tokio::time::sleep(FETCH_DELAY).await;
+    }
+
+    info!("Enriched {}/{} guilds", enriched, guild_ids.len());
+
+    Ok(())
+}
@@ -1,14 +1,37 @@
+use crate::alerts;
+use crate::config::{CollisionStrategy, Config};
+use crate::database::{
+    GuildAsset, lock_db, save_image_hashes, save_transcript, set_attachment_metadata,
+    set_attachment_scan, set_attachment_thumbnail, set_nsfw_score, upsert_attachment,
+};
+use crate::image_hash;
+use crate::media_metadata;
+use crate::nsfw;
+use crate::scanner::{self, ScanResult};
+use crate::status;
+use crate::thumbnails;
+use crate::timezone;
+use crate::transcription;
+use chrono::Utc;
+use discord_client_rest::rest::RestClient;
+use discord_client_structs::structs::emoji::Emoji;
 use discord_client_structs::structs::message::attachment::Attachment;
 use discord_client_structs::structs::message::embed::Embed;
-use log::{error, info, warn};
+use discord_client_structs::structs::sticker::Sticker;
+use discord_client_structs::structs::user::User;
+use log::{debug, error, info, warn};
 use mime_guess;
 use rquest::Client;
 use rquest_util::{Emulation, EmulationOS, EmulationOption};
+use std::collections::HashSet;
 use std::error::Error;
 use std::path::Path;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
 use tempfile::NamedTempFile;
 use tokio::sync::Mutex as AsyncMutex;
+use tokio_postgres::Client as DbClient;
 use tree_magic_mini;
 
 use sanitise_file_name::sanitise;
@@ -83,36 +106,259 @@ async fn detect_mime_type(attachment: &Attachment, url: &str) -> Result<String,
     }
 }
 
-pub async fn download_attachment(attachments: Vec<Attachment>) -> Result<(), Box<dyn Error>> {
+/// Root folder of every downloaded file
+pub fn download_root() -> &'static str {
+    &Config::get().download_dir
+}
+
+/// Placeholders of `download_path_template`
+pub const PATH_TEMPLATE_PLACEHOLDERS: [&str; 8] = [
+    "guild_id",
+    "channel_id",
+    "message_id",
+    "attachment_id",
+    "date",
+    "mime",
+    "filename",
+    "extension",
+];
+
+/// Where an attachment is saved, following `download_path_template`
+fn attachment_path(
+    attachment: &Attachment,
+    message_id: u64,
+    channel_id: u64,
+    guild_id: Option<u64>,
+    mime_type: &str,
+) -> String {
+    let filename = sanitize_filename(&attachment.filename);
+    let extension = Path::new(&filename)
+        .extension()
+        .map(|ext| ext.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    // stable whatever the display timezone
+    let date = timezone::snowflake_time(message_id)
+        .with_timezone(&Utc)
+        .format("%Y-%m-%d")
+        .to_string();
+    let guild = guild_id.map_or("dm".to_string(), |id| id.to_string());
+
+    let path = Config::get()
+        .download_path_template
+        .replace("{guild_id}", &guild)
+        .replace("{channel_id}", &channel_id.to_string())
+        .replace("{message_id}", &message_id.to_string())
+        .replace("{attachment_id}", &attachment.id.to_string())
+        .replace("{date}", &date)
+        .replace("{mime}", mime_type)
+        .replace("{filename}", &filename)
+        .replace("{extension}", &extension);
+
+    format!("{}/{}", download_root(), path)
+}
+
+#[tracing::instrument(skip_all, fields(message_id = message_id, attachments = attachments.len()))]
+pub async fn download_attachment(
+    attachments: Vec<Attachment>,
+    message_id: u64,
+    channel_id: u64,
+    guild_id: Option<u64>,
+    voice_message: bool,
+    db_client: Option<Arc<AsyncMutex<DbClient>>>,
+) -> Result<(), Box<dyn Error>> {
     for attachment in attachments {
         let url = &attachment.url;
-        let original_filename = attachment.filename.clone();
+
+        if let Some(reason) = filtered_out(&attachment) {
+            debug!("Skipping attachment {}: {}", attachment.id, reason);
+            continue;
+        }
 
         let mime_type = detect_mime_type(&attachment, url)
             .await
             .unwrap_or_else(|_| "application/octet-stream".to_string());
 
-        let safe_filename = sanitize_filename(&original_filename);
-        std::fs::create_dir_all(format!("downloads/{}", mime_type))?;
+        let final_filename =
+            attachment_path(&attachment, message_id, channel_id, guild_id, &mime_type);
+        if let Some(folder) = Path::new(&final_filename).parent() {
+            std::fs::create_dir_all(folder)?;
+        }
 
-        let final_filename = format!(
-            "downloads/{}/{}_{}",
-            mime_type, attachment.id, safe_filename
-        );
+        let mut final_filename =
+            match resolve_target(&final_filename, url, Some(attachment.size)).await {
+                Target::Existing(file_name) => {
+                    warn!("File already exists: {}", file_name);
+                    file_name
+                }
+                Target::Download(file_name) => {
+                    if let Err(e) = download_url(url, &file_name).await {
+                        error!("Failed to download {}: {}", file_name, e);
+                        continue;
+                    }
+                    file_name
+                }
+            };
 
-        if Path::new(&final_filename).exists() {
-            warn!("File already exists: {}", final_filename);
-            continue;
+        let scan = scanner::scan(&final_filename).await;
+        let infected = matches!(scan, Some(ScanResult::Infected(_)));
+        if let Some(ScanResult::Infected(signature)) = &scan {
+            warn!(
+                "Attachment {} is infected ({}), quarantining {}",
+                attachment.id, signature, final_filename
+            );
+            if let Some(quarantined) = scanner::quarantine(&final_filename) {
+                final_filename = quarantined;
+            }
         }
 
-        if let Err(e) = download_url(url, &final_filename).await {
-            error!("Failed to download {}: {}", final_filename, e);
+        if let Some(ref db) = db_client
+            && Path::new(&final_filename).exists()
+        {
+            // infected files are kept away from anything reading them
+            let hashes = if mime_type.starts_with("image/") && !infected {
+                image_hash::hash_image(attachment.id, final_filename.clone()).await
+            } else {
+                None
+            };
+            let metadata = if Config::get().extract_media_metadata && !infected {
+                Some(media_metadata::extract(&final_filename, &mime_type).await)
+            } else {
+                None
+            };
+            let nsfw_score = if nsfw::enabled() && mime_type.starts_with("image/") && !infected {
+                nsfw::classify_image(attachment.id, final_filename.clone()).await
+            } else {
+                None
+            };
+            let transcript =
+                if transcription::should_transcribe(&mime_type, voice_message) && !infected {
+                    transcription::transcribe(&final_filename)
+                        .await
+                        .inspect_err(|e| {
+                            warn!("Failed to transcribe attachment {}: {}", attachment.id, e)
+                        })
+                        .ok()
+                } else {
+                    None
+                };
+            let thumbnail = if Config::get().generate_thumbnails && !infected {
+                thumbnails::generate(&final_filename, &mime_type).await
+            } else {
+                None
+            };
+
+            let db = lock_db(db).await;
+            if let Err(e) =
+                upsert_attachment(&attachment, message_id, &mime_type, &final_filename, &db).await
+            {
+                error!("Failed to save attachment {}: {}", attachment.id, e);
+            } else if let Some(hashes) = hashes
+                && let Err(e) =
+                    save_image_hashes(attachment.id, hashes.phash, hashes.dhash, &db).await
+            {
+                error!(
+                    "Failed to save hashes of attachment {}: {}",
+                    attachment.id, e
+                );
+            }
+
+            if let Some(metadata) = metadata.filter(|metadata| !metadata.is_empty())
+                && let Err(e) = set_attachment_metadata(attachment.id, &metadata, &db).await
+            {
+                error!(
+                    "Failed to save metadata of attachment {}: {}",
+                    attachment.id, e
+                );
+            }
+
+            if let Some(scan) = &scan
+                && let Err(e) =
+                    set_attachment_scan(attachment.id, scan.status(), scan.detail(), &db).await
+            {
+                error!(
+                    "Failed to save scan result of attachment {}: {}",
+                    attachment.id, e
+                );
+            }
+
+            if let Some(score) = nsfw_score
+                && let Err(e) = set_nsfw_score(attachment.id, score, &db).await
+            {
+                error!(
+                    "Failed to save NSFW score of attachment {}: {}",
+                    attachment.id, e
+                );
+            }
+
+            if let Some(transcript) = transcript
+                && let Err(e) = save_transcript(attachment.id, message_id, &transcript, &db).await
+            {
+                error!(
+                    "Failed to save the transcript of attachment {}: {}",
+                    attachment.id, e
+                );
+            }
+
+            if let Some(thumbnail) = thumbnail
+                && let Err(e) = set_attachment_thumbnail(attachment.id, &thumbnail, &db).await
+            {
+                error!(
+                    "Failed to save thumbnail of attachment {}: {}",
+                    attachment.id, e
+                );
+            }
         }
     }
 
     Ok(())
 }
 
+/// Whether the MIME type matches a pattern such as `image/*` or `application/pdf`
+fn mime_matches(pattern: &str, mime_type: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(prefix) => prefix == "*" || mime_type.split('/').next() == Some(prefix),
+        None => pattern == "*" || pattern.eq_ignore_ascii_case(mime_type),
+    }
+}
+
+fn is_allowed_mime(mime_type: &str) -> bool {
+    let allowed = &Config::get().download_allowed_mime;
+    allowed.is_empty()
+        || allowed
+            .iter()
+            .any(|pattern| mime_matches(pattern, mime_type))
+}
+
+/// Why the attachment isn't downloaded, decided from its metadata without fetching it
+fn filtered_out(attachment: &Attachment) -> Option<String> {
+    if let Some(max_bytes) = Config::get().download_max_bytes
+        && attachment.size > max_bytes
+    {
+        return Some(format!(
+            "{} bytes is over download_max_bytes",
+            attachment.size
+        ));
+    }
+
+    let mime_type = match attachment.content_type.as_deref() {
+        Some(content_type) => content_type
+            .split(';')
+            .next()
+            .unwrap_or(content_type)
+            .trim()
+            .to_string(),
+        None => mime_guess::from_path(&attachment.filename)
+            .first_or_octet_stream()
+            .to_string(),
+    };
+    if !is_allowed_mime(&mime_type) {
+        return Some(format!("{} isn't in download_allowed_mime", mime_type));
+    }
+
+    None
+}
+
+#[tracing::instrument(skip_all, fields(message_id = message_id))]
 pub async fn download_embeds(embeds: Vec<Embed>, message_id: u64) -> Result<(), Box<dyn Error>> {
     let mut urls: Vec<(String, &str)> = Vec::new();
 
@@ -144,8 +390,11 @@ pub async fn download_embeds(embeds: Vec<Embed>, message_id: u64) -> Result<(),
 
     for (url, media_type) in urls {
         let extension = extract_extension_from_url(&url, media_type);
+        if !is_allowed_mime(&format!("{}/{}", media_type, extension)) {
+            continue;
+        }
 
-        let folder_path = format!("downloads/{}/{}", media_type, extension);
+        let folder_path = format!("{}/{}/{}", download_root(), media_type, extension);
         std::fs::create_dir_all(&folder_path)?;
 
         let file_name = format!(
@@ -155,8 +404,210 @@ pub async fn download_embeds(embeds: Vec<Embed>, message_id: u64) -> Result<(),
             sanitize_filename(url.split('/').last().unwrap_or("unknown"))
         );
 
+        let file_name = match resolve_target(&file_name, &url, None).await {
+            Target::Existing(file_name) => {
+                warn!("File already exists: {}", file_name);
+                continue;
+            }
+            Target::Download(file_name) => file_name,
+        };
+
+        if let Err(e) = download_url(&url, &file_name).await {
+            error!("Failed to download {}: {}", file_name, e);
+        }
+    }
+
+    Ok(())
+}
+
+enum Target {
+    Existing(String),
+    Download(String),
+}
+
+// decides where a file should be written according to the configured collision strategy
+async fn resolve_target(file_name: &str, url: &str, expected_size: Option<u64>) -> Target {
+    if !Path::new(file_name).exists() {
+        return Target::Download(file_name.to_string());
+    }
+
+    let strategy = Config::get().download_collision_strategy;
+    if strategy == CollisionStrategy::Skip {
+        return Target::Existing(file_name.to_string());
+    }
+
+    let expected_size = match expected_size {
+        Some(size) => Some(size),
+        None => remote_size(url).await,
+    };
+
+    // without a known size there is nothing to compare against
+    let Some(expected_size) = expected_size else {
+        return Target::Existing(file_name.to_string());
+    };
+
+    if file_size(file_name) == Some(expected_size) {
+        return Target::Existing(file_name.to_string());
+    }
+
+    match strategy {
+        CollisionStrategy::VersionSuffix => {
+            let path = Path::new(file_name);
+            let stem = path.with_extension("").to_string_lossy().into_owned();
+            let extension = path
+                .extension()
+                .map(|ext| format!(".{}", ext.to_string_lossy()))
+                .unwrap_or_default();
+
+            let mut version = 1;
+            loop {
+                let candidate = format!("{}_v{}{}", stem, version, extension);
+                if !Path::new(&candidate).exists() {
+                    return Target::Download(candidate);
+                }
+                if file_size(&candidate) == Some(expected_size) {
+                    return Target::Existing(candidate);
+                }
+                version += 1;
+            }
+        }
+        _ => {
+            warn!(
+                "File size mismatch for {}, expected {} bytes, downloading again",
+                file_name, expected_size
+            );
+            Target::Download(file_name.to_string())
+        }
+    }
+}
+
+fn file_size(file_name: &str) -> Option<u64> {
+    std::fs::metadata(file_name)
+        .ok()
+        .map(|metadata| metadata.len())
+}
+
+async fn remote_size(url: &str) -> Option<u64> {
+    let client = build_client().ok()?;
+    let response = client.head(url).send().await.ok()?;
+
+    if response.status().is_success() {
+        response.content_length()
+    } else {
+        None
+    }
+}
+
+// animated asset hashes are prefixed with "a_"
+fn cdn_extension(hash: &str) -> &'static str {
+    if hash.starts_with("a_") { "gif" } else { "png" }
+}
+
+pub fn guild_asset_path(guild_id: u64, kind: &str, hash: &str) -> String {
+    format!(
+        "{}/guilds/{}/{}_{}.{}",
+        download_root(),
+        guild_id,
+        kind,
+        hash,
+        cdn_extension(hash)
+    )
+}
+
+/// Downloads the icon, banner and splash of the guild, returns the history rows and paths of the
+/// assets that are on disk
+pub async fn download_guild_assets(
+    guild_id: u64,
+    assets: Vec<GuildAsset>,
+) -> Result<Vec<(i64, String)>, Box<dyn Error>> {
+    std::fs::create_dir_all(format!("{}/guilds/{}", download_root(), guild_id))?;
+    let mut stored = Vec::new();
+
+    for asset in assets {
+        let cdn_folder = match asset.kind {
+            "icon" => "icons",
+            "banner" => "banners",
+            "splash" => "splashes",
+            _ => continue,
+        };
+
+        let file_name = guild_asset_path(guild_id, asset.kind, &asset.hash);
+        if Path::new(&file_name).exists() {
+            stored.push((asset.history_id, file_name));
+            continue;
+        }
+
+        let url = format!(
+            "https://cdn.discordapp.com/{}/{}/{}.{}?size=4096",
+            cdn_folder,
+            guild_id,
+            asset.hash,
+            cdn_extension(&asset.hash)
+        );
+
+        match download_url(&url, &file_name).await {
+            Ok(_) => stored.push((asset.history_id, file_name)),
+            Err(e) => error!("Failed to download {}: {}", file_name, e),
+        }
+    }
+
+    Ok(stored)
+}
+
+pub async fn download_guild_expressions(
+    guild_id: u64,
+    emojis: Vec<Emoji>,
+    stickers: Vec<Sticker>,
+) -> Result<(), Box<dyn Error>> {
+    let mut urls: Vec<(String, String)> = Vec::new();
+
+    if !emojis.is_empty() {
+        let folder_path = format!("{}/guilds/{}/emojis", download_root(), guild_id);
+        std::fs::create_dir_all(&folder_path)?;
+
+        for emoji in emojis {
+            let Some(emoji_id) = emoji.id else {
+                continue;
+            };
+            let extension = if emoji.animated.unwrap_or(false) {
+                "gif"
+            } else {
+                "png"
+            };
+            urls.push((
+                format!(
+                    "https://cdn.discordapp.com/emojis/{}.{}",
+                    emoji_id, extension
+                ),
+                format!("{}/{}.{}", folder_path, emoji_id, extension),
+            ));
+        }
+    }
+
+    if !stickers.is_empty() {
+        let folder_path = format!("{}/guilds/{}/stickers", download_root(), guild_id);
+        std::fs::create_dir_all(&folder_path)?;
+
+        for sticker in stickers {
+            // 1 = PNG, 2 = APNG, 3 = LOTTIE, 4 = GIF
+            let extension = match sticker.format_type {
+                3 => "json",
+                4 => "gif",
+                _ => "png",
+            };
+            // lottie files aren't served by the media CDN
+            let host = if sticker.format_type == 3 {
+                "discord.com"
+            } else {
+                "media.discordapp.net"
+            };
+            let url = format!("https://{}/stickers/{}.{}", host, sticker.id, extension);
+            urls.push((url, format!("{}/{}.{}", folder_path, sticker.id, extension)));
+        }
+    }
+
+    for (url, file_name) in urls {
         if Path::new(&file_name).exists() {
-            warn!("File already exists: {}", file_name);
             continue;
         }
 
@@ -168,6 +619,117 @@ pub async fn download_embeds(embeds: Vec<Embed>, message_id: u64) -> Result<(),
     Ok(())
 }
 
+pub fn role_icon_path(guild_id: u64, role_id: u64, hash: &str) -> String {
+    format!(
+        "{}/roles/{}/{}_{}.{}",
+        download_root(),
+        guild_id,
+        role_id,
+        hash,
+        cdn_extension(hash)
+    )
+}
+
+pub async fn download_role_icon(
+    guild_id: u64,
+    role_id: u64,
+    hash: String,
+) -> Result<(), Box<dyn Error>> {
+    std::fs::create_dir_all(format!("{}/roles/{}", download_root(), guild_id))?;
+
+    let file_name = role_icon_path(guild_id, role_id, &hash);
+    if Path::new(&file_name).exists() {
+        return Ok(());
+    }
+
+    let url = format!(
+        "https://cdn.discordapp.com/role-icons/{}/{}.{}?size=512",
+        role_id,
+        hash,
+        cdn_extension(&hash)
+    );
+
+    download_url(&url, &file_name).await
+}
+
+/// Path of an avatar or banner of a user, `kind` being `avatar` or `banner`
+pub fn user_asset_path(user_id: u64, kind: &str, hash: &str) -> String {
+    format!(
+        "{}/avatars/{}/{}_{}.{}",
+        download_root(),
+        user_id,
+        kind,
+        hash,
+        cdn_extension(hash)
+    )
+}
+
+/// Marks the avatar and banner hashes as being downloaded, returns false when one of them
+/// already is, e.g. by the task of a previous message of the same author
+pub fn start_user_assets_download(hashes: &[String]) -> bool {
+    let mut downloads = USER_ASSET_DOWNLOADS.lock().unwrap();
+    if hashes.iter().any(|hash| downloads.contains(hash)) {
+        return false;
+    }
+
+    downloads.extend(hashes.iter().cloned());
+    true
+}
+
+pub fn finish_user_assets_download(hashes: &[String]) {
+    let mut downloads = USER_ASSET_DOWNLOADS.lock().unwrap();
+    for hash in hashes {
+        downloads.remove(hash);
+    }
+}
+
+pub async fn download_user_assets(
+    user: &User,
+) -> Result<(Option<String>, Option<String>), Box<dyn Error>> {
+    let mut paths = (None, None);
+
+    for (kind, hash) in [("avatar", &user.avatar), ("banner", &user.banner)] {
+        let Some(hash) = hash else {
+            continue;
+        };
+
+        std::fs::create_dir_all(format!("{}/avatars/{}", download_root(), user.id))?;
+
+        let extension = cdn_extension(hash);
+        let file_name = user_asset_path(user.id, kind, hash);
+
+        if !Path::new(&file_name).exists() {
+            wait_avatar_rate_limit().await;
+
+            let url = format!(
+                "https://cdn.discordapp.com/{}s/{}/{}.{}?size=1024",
+                kind, user.id, hash, extension
+            );
+            if let Err(e) = download_url(&url, &file_name).await {
+                error!("Failed to download {}: {}", file_name, e);
+            }
+        }
+
+        if Path::new(&file_name).exists() {
+            match kind {
+                "avatar" => paths.0 = Some(file_name),
+                _ => paths.1 = Some(file_name),
+            }
+        }
+    }
+
+    Ok(paths)
+}
+
+async fn wait_avatar_rate_limit() {
+    let mut last_download = LAST_AVATAR_DOWNLOAD.lock().await;
+    let elapsed = last_download.elapsed();
+    if elapsed < AVATAR_DOWNLOAD_INTERVAL {
+        tokio::time::sleep(AVATAR_DOWNLOAD_INTERVAL - elapsed).await;
+    }
+    *last_download = Instant::now();
+}
+
 fn extract_extension_from_url(url: &str, media_type: &str) -> String {
     let clean_url = url.split(['?', '#']).next().unwrap_or(url);
 
@@ -205,43 +767,133 @@ fn is_valid_extension_for_media_type(extension: &str, media_type: &str) -> bool
     }
 }
 
+// minimum delay between two avatar/banner downloads
+const AVATAR_DOWNLOAD_INTERVAL: Duration = Duration::from_millis(250);
+
+// downloads failed in a row, for the alerts
+static DOWNLOAD_FAILURES: AtomicU32 = AtomicU32::new(0);
+
 lazy_static::lazy_static! {
     static ref CACHE: Arc<AsyncMutex<Vec<String>>> = Arc::new(AsyncMutex::new(Vec::with_capacity(5)));
+    static ref LAST_AVATAR_DOWNLOAD: AsyncMutex<Instant> = AsyncMutex::new(Instant::now());
+    static ref REFRESH_CLIENT: AsyncMutex<Option<Arc<RestClient>>> = AsyncMutex::new(None);
+    // avatar and banner hashes being downloaded
+    static ref USER_ASSET_DOWNLOADS: std::sync::Mutex<HashSet<String>> =
+        std::sync::Mutex::new(HashSet::new());
 }
 
-pub async fn download_url(url: &str, file_name: &str) -> Result<(), Box<dyn Error>> {
-    let mut cache = CACHE.lock().await;
-    if cache.contains(&url.to_string()) {
-        return Ok(());
-    }
-    if cache.len() >= 5 {
-        cache.remove(0);
+/// Sets the REST client used to refresh expired attachment URLs
+pub async fn set_refresh_client(rest_client: Arc<RestClient>) {
+    *REFRESH_CLIENT.lock().await = Some(rest_client);
+}
+
+fn is_attachment_url(url: &str) -> bool {
+    url.starts_with("https://cdn.discordapp.com/attachments/")
+        || url.starts_with("https://media.discordapp.net/attachments/")
+}
+
+/// Signs the attachment URL again, None without a REST client or when Discord refuses
+async fn refresh_attachment_url(url: &str) -> Option<String> {
+    let rest_client = REFRESH_CLIENT.lock().await.clone()?;
+
+    match rest_client
+        .refresh_attachment_urls(vec![url.to_string()])
+        .await
+    {
+        Ok(urls) => urls.into_iter().next().map(|refreshed| refreshed.refreshed),
+        Err(e) => {
+            warn!("Failed to refresh attachment URL {}: {}", url, e);
+            None
+        }
     }
-    cache.push(url.to_string());
-    drop(cache);
+}
 
+fn build_client() -> Result<Client, rquest::Error> {
     let emu = EmulationOption::builder()
         .emulation(Emulation::Chrome136)
         .emulation_os(EmulationOS::Windows)
         .build();
 
-    let client = Client::builder()
+    Client::builder()
         .emulation(emu)
         .gzip(true)
         .deflate(true)
         .brotli(true)
         .zstd(true)
-        .build()?;
+        .build()
+}
 
-    let response = client.get(url).send().await?;
+#[tracing::instrument(skip_all, fields(url = url))]
+pub async fn download_url(url: &str, file_name: &str) -> Result<(), Box<dyn Error>> {
+    let mut cache = CACHE.lock().await;
+    if cache.contains(&url.to_string()) {
+        return Ok(());
+    }
+    if cache.len() >= 5 {
+        cache.remove(0);
+    }
+    cache.push(url.to_string());
+    drop(cache);
 
-    if response.status().is_success() {
-        let bytes = response.bytes().await?;
-        std::fs::write(file_name, bytes)?;
-        info!("Downloaded: {}", file_name);
-    } else {
-        error!("Failed to download {}: {}", file_name, response.status());
+    let _download = status::start_download();
+    // errors aren't Send, keep only the text across the alert
+    match fetch_url(url, file_name).await.map_err(|e| e.to_string()) {
+        Ok(()) => {
+            DOWNLOAD_FAILURES.store(0, Ordering::Relaxed);
+            Ok(())
+        }
+        Err(error) => {
+            download_failed(url, &error).await;
+            Err(error.into())
+        }
     }
+}
+
+// alerts once when `alert_download_failures` downloads failed in a row
+async fn download_failed(url: &str, error: &str) {
+    let threshold = Config::get().alert_download_failures;
+    let failures = DOWNLOAD_FAILURES.fetch_add(1, Ordering::Relaxed) + 1;
+    if threshold > 0 && failures == threshold {
+        alerts::send(&format!(
+            "{} downloads failed in a row, the last one {}: {}",
+            failures, url, error
+        ))
+        .await;
+    }
+}
+
+async fn fetch_url(url: &str, file_name: &str) -> Result<(), Box<dyn Error>> {
+    let client = build_client()?;
+    let mut response = client.get(url).send().await?;
+
+    // signed attachment URLs expire after a day
+    if matches!(response.status().as_u16(), 403 | 404)
+        && is_attachment_url(url)
+        && let Some(fresh_url) = refresh_attachment_url(url).await
+    {
+        debug!("Retrying {} with a refreshed URL", file_name);
+        response = client.get(&fresh_url).send().await?;
+    }
+
+    // embeds and assets have no known size before being fetched
+    if let Some(max_bytes) = Config::get().download_max_bytes
+        && let Some(size) = response.content_length()
+        && size > max_bytes
+    {
+        debug!(
+            "Skipping {}: {} bytes is over download_max_bytes",
+            file_name, size
+        );
+        return Ok(());
+    }
+
+    if !response.status().is_success() {
+        return Err(format!("HTTP {}", response.status()).into());
+    }
+
+    let bytes = response.bytes().await?;
+    std::fs::write(file_name, bytes)?;
+    info!("Downloaded: {}", file_name);
 
     Ok(())
 }
@@ -0,0 +1,141 @@
+use crate::BoxedResult;
+use crate::config::{Config, EmbeddingsConfig};
+use crate::database::{
+    MessageFilter, MessageMatch, get_messages_without_embedding, save_embeddings,
+    semantic_search_messages,
+};
+use crate::maintenance;
+use log::info;
+use serde::Deserialize;
+use tokio_postgres::Client;
+
+#[derive(Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingData {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+fn config() -> BoxedResult<&'static EmbeddingsConfig> {
+    Config::get()
+        .embeddings
+        .as_ref()
+        .ok_or_else(|| "Embeddings are not configured, add an [embeddings] section".into())
+}
+
+/// Creates the pgvector table, only run when embeddings are configured
+pub async fn setup(db: &Client) -> BoxedResult<()> {
+    let script = include_str!("../sql_scripts/embeddings.sql")
+        .replace("{dimensions}", &config()?.dimensions.to_string());
+
+    db.batch_execute(&script)
+        .await
+        .map_err(|e| format!("Error executing embeddings setup script: {}", e))?;
+
+    Ok(())
+}
+
+// pgvector text representation, e.g. `[0.1,0.2]`
+fn to_vector_literal(embedding: &[f32]) -> String {
+    let values: Vec<String> = embedding.iter().map(|value| value.to_string()).collect();
+    format!("[{}]", values.join(","))
+}
+
+/// Calls the OpenAI-compatible `/embeddings` endpoint
+async fn embed(texts: &[String]) -> BoxedResult<Vec<Vec<f32>>> {
+    let config = config()?;
+    let body = serde_json::json!({
+        "model": config.model,
+        "input": texts,
+    });
+
+    let client = rquest::Client::new();
+    let mut request = client
+        .post(&format!(
+            "{}/embeddings",
+            config.endpoint.trim_end_matches('/')
+        ))
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_string(&body)?);
+
+    if let Some(api_key) = &config.api_key {
+        request = request.header("Authorization", &format!("Bearer {}", api_key));
+    }
+
+    let response = request.send().await?;
+    let status = response.status();
+    let text = response.text().await?;
+    if !status.is_success() {
+        return Err(format!("Embedding endpoint returned {}: {}", status, text).into());
+    }
+
+    let mut response: EmbeddingResponse = serde_json::from_str(&text)?;
+    response.data.sort_by_key(|data| data.index);
+
+    Ok(response
+        .data
+        .into_iter()
+        .map(|data| data.embedding)
+        .collect())
+}
+
+/// Computes the embeddings of the stored messages that don't have an up to date one yet
+pub async fn backfill(limit: Option<usize>, db: &Client) -> BoxedResult<()> {
+    let config = config()?;
+    let mut total = 0;
+
+    loop {
+        let batch_size = match limit {
+            Some(limit) if limit <= total => break,
+            Some(limit) => config.batch_size.min(limit - total),
+            None => config.batch_size,
+        };
+
+        maintenance::wait_for_window("embedding backfill").await;
+
+        let messages = get_messages_without_embedding(&config.model, batch_size as i64, db).await?;
+        if messages.is_empty() {
+            break;
+        }
+
+        let texts: Vec<String> = messages
+            .iter()
+            .map(|(_, content)| content.clone())
+            .collect();
+        let embeddings = embed(&texts).await?;
+        if embeddings.len() != messages.len() {
+            return Err("Embedding endpoint returned a wrong amount of vectors".into());
+        }
+
+        let rows: Vec<(i64, String)> = messages
+            .iter()
+            .zip(&embeddings)
+            .map(|((id, _), embedding)| (*id, to_vector_literal(embedding)))
+            .collect();
+        save_embeddings(&rows, &config.model, db).await?;
+
+        total += rows.len();
+        info!("Embedded {} messages", total);
+    }
+
+    info!("Embedding backfill done, {} messages embedded", total);
+
+    Ok(())
+}
+
+pub async fn semantic_search(
+    text: &str,
+    filter: &MessageFilter,
+    db: &Client,
+) -> BoxedResult<Vec<MessageMatch>> {
+    let embedding = embed(&[text.to_string()])
+        .await?
+        .pop()
+        .ok_or("Embedding endpoint returned no vector")?;
+
+    semantic_search_messages(&to_vector_literal(&embedding), &config()?.model, filter, db).await
+}
@@ -0,0 +1,64 @@
+use super::parse_id;
+use crate::BoxedResult;
+use crate::database::{end_call, lock_db, upsert_call};
+use log::debug;
+use serde_json::Value;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio_postgres::Client;
+
+/// Stores the call of a CALL_CREATE or CALL_UPDATE, with the rung users and the ones in the call
+pub async fn process_call(call: &Value, db_client: &Option<Arc<Mutex<Client>>>) -> BoxedResult<()> {
+    let (Some(channel_id), Some(message_id)) =
+        (parse_id(&call["channel_id"]), parse_id(&call["message_id"]))
+    else {
+        return Err("Call without channel or message id".into());
+    };
+
+    let ringing: Vec<u64> = call["ringing"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(parse_id)
+        .collect();
+    // only CALL_CREATE lists the voice states
+    let participants: Vec<u64> = call["voice_states"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|voice_state| parse_id(&voice_state["user_id"]))
+        .collect();
+
+    if let Some(db_client) = db_client {
+        let db_client = lock_db(db_client).await;
+        upsert_call(
+            channel_id,
+            message_id,
+            call["region"].as_str(),
+            &ringing,
+            &participants,
+            &db_client,
+        )
+        .await?;
+        debug!("Call {} in channel {} saved", message_id, channel_id);
+    }
+
+    Ok(())
+}
+
+pub async fn process_call_delete(
+    call: &Value,
+    db_client: &Option<Arc<Mutex<Client>>>,
+) -> BoxedResult<()> {
+    let Some(channel_id) = parse_id(&call["channel_id"]) else {
+        return Err("Call without channel id".into());
+    };
+
+    if let Some(db_client) = db_client {
+        let db_client = lock_db(db_client).await;
+        end_call(channel_id, &db_client).await?;
+        debug!("Call in channel {} ended", channel_id);
+    }
+
+    Ok(())
+}
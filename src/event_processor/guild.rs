@@ -1,14 +1,25 @@
+use super::parse_id;
 use crate::BoxedResult;
+use crate::config::Config;
 use crate::database::*;
+use crate::downloader;
 use discord_client_gateway::events::structs::channel::{
     ChannelCreateEvent, ChannelDeleteEvent, ChannelUpdateEvent,
 };
+use discord_client_gateway::events::structs::guild::emoji::GuildEmojisUpdateEvent;
 use discord_client_gateway::events::structs::guild::role::{
     GuildRoleCreateEvent, GuildRoleDeleteEvent, GuildRoleUpdateEvent,
 };
+use discord_client_gateway::events::structs::guild::sticker::GuildStickersUpdateEvent;
+use discord_client_gateway::events::structs::scheduled_event::GuildScheduledEventDeleteEvent;
+use discord_client_gateway::events::structs::stage_instance::StageInstanceDeleteEvent;
 use discord_client_structs::structs::guild::GatewayGuild;
+use discord_client_structs::structs::guild::role::Role;
+use discord_client_structs::structs::scheduled_event::GuildScheduledEvent;
+use discord_client_structs::structs::stage_instance::StageInstance;
 use discord_client_structs::structs::user::{Member, User};
 use log::{debug, error};
+use serde_json::Value;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use tokio_postgres::Client;
@@ -18,6 +29,7 @@ pub async fn process_ready_guilds(
     ready_members: &Option<Vec<Vec<Member>>>,
     ready_users: &Option<Vec<User>>,
     db: &Client,
+    db_client: &Arc<Mutex<Client>>,
 ) -> BoxedResult<()> {
     if let Some(members_by_guild) = ready_members {
         for (guild_index, members) in members_by_guild.iter().enumerate() {
@@ -55,9 +67,12 @@ pub async fn process_ready_guilds(
     }
 
     for guild in guilds {
-        if let Err(e) = upsert_guild(guild, db).await {
-            error!("Failed to save guild {}: {}", guild.id, e);
-            continue;
+        match upsert_guild(guild, db).await {
+            Ok(changed_assets) => spawn_guild_downloads(guild, changed_assets, db_client),
+            Err(e) => {
+                error!("Failed to save guild {}: {}", guild.id, e);
+                continue;
+            }
         }
         debug!(
             "Saved guild: {} ({})",
@@ -78,6 +93,7 @@ pub async fn process_ready_guilds(
                     );
                 }
             }
+            archive_role_icons(roles, guild.id, db, db_client).await;
             debug!("Saved {} roles for guild {}", roles.len(), guild.id);
         }
 
@@ -97,6 +113,18 @@ pub async fn process_ready_guilds(
             debug!("Saved {} channels for guild {}", channels.len(), guild.id);
         }
 
+        if let Some(emojis) = &guild.emojis
+            && let Err(e) = sync_guild_emojis(emojis, guild.id, db).await
+        {
+            error!("Failed to save emojis for guild {}: {}", guild.id, e);
+        }
+
+        if let Some(stickers) = &guild.stickers
+            && let Err(e) = sync_guild_stickers(stickers, guild.id, db).await
+        {
+            error!("Failed to save stickers for guild {}: {}", guild.id, e);
+        }
+
         if let Some(threads) = &guild.threads {
             for thread in threads {
                 if let Err(e) = bulk_upsert_channels(&[thread.clone()], Some(guild.id), db).await {
@@ -115,12 +143,118 @@ pub async fn process_ready_guilds(
     Ok(())
 }
 
+/// Stores a guild joined or updated during the session and downloads its new icon, banner and
+/// splash, READY only covers the guilds the account was in when it connected
+pub async fn process_guild_upsert(
+    guild: &Value,
+    db_client: &Option<Arc<Mutex<Client>>>,
+) -> BoxedResult<()> {
+    let Some(db_client) = db_client else {
+        return Ok(());
+    };
+    let guild: GatewayGuild = serde_json::from_value(guild.clone())?;
+
+    let changed_assets = {
+        let db = lock_db(db_client).await;
+        upsert_guild(&guild, &db).await?
+    };
+    spawn_guild_downloads(&guild, changed_assets, db_client);
+    debug!("Saved guild {} from a guild event", guild.id);
+
+    Ok(())
+}
+
+// the history rows of the assets get their path once the files are on disk
+fn spawn_guild_downloads(
+    guild: &GatewayGuild,
+    changed_assets: Vec<GuildAsset>,
+    db_client: &Arc<Mutex<Client>>,
+) {
+    if !Config::get().download_files {
+        return;
+    }
+
+    let guild_id = guild.id;
+    let emojis = guild.emojis.clone().unwrap_or_default();
+    let stickers = guild.stickers.clone().unwrap_or_default();
+    let db_client = Arc::clone(db_client);
+
+    tokio::spawn(async move {
+        // errors aren't Send, keep only the text across the next await
+        let downloaded = downloader::download_guild_assets(guild_id, changed_assets)
+            .await
+            .map_err(|e| e.to_string());
+        match downloaded {
+            Ok(stored) if !stored.is_empty() => {
+                let db_client = lock_db(&db_client).await;
+                for (history_id, path) in stored {
+                    if let Err(e) = set_guild_asset_path(history_id, &path, &db_client).await {
+                        error!("Failed to save asset path for guild {}: {}", guild_id, e);
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(e) => error!("Failed to download assets for guild {}: {}", guild_id, e),
+        }
+
+        let downloaded = downloader::download_guild_expressions(guild_id, emojis, stickers)
+            .await
+            .map_err(|e| e.to_string());
+        if let Err(e) = downloaded {
+            error!(
+                "Failed to download emojis and stickers for guild {}: {}",
+                guild_id, e
+            );
+        }
+    });
+}
+
+async fn archive_role_icons(
+    roles: &[Role],
+    guild_id: u64,
+    db: &Client,
+    db_client: &Arc<Mutex<Client>>,
+) {
+    if !Config::get().download_role_icons {
+        return;
+    }
+
+    for role in roles {
+        let history_id = match record_role_icon(role, guild_id, db).await {
+            Ok(Some(history_id)) => history_id,
+            Ok(None) => continue,
+            Err(e) => {
+                error!("Failed to record icon for role {}: {}", role.id, e);
+                continue;
+            }
+        };
+        let Some(hash) = role.icon.clone() else {
+            continue;
+        };
+
+        let role_id = role.id;
+        let db_client = Arc::clone(db_client);
+        tokio::spawn(async move {
+            if let Err(e) = downloader::download_role_icon(guild_id, role_id, hash.clone()).await {
+                error!("Failed to download icon for role {}: {}", role_id, e);
+                return;
+            }
+
+            let path = downloader::role_icon_path(guild_id, role_id, &hash);
+            let db_client = lock_db(&db_client).await;
+            if let Err(e) = set_role_icon_path(history_id, &path, &db_client).await {
+                error!("Failed to save icon path for role {}: {}", role_id, e);
+            }
+        });
+    }
+}
+
 pub async fn process_channel_create(
     channel_create: &ChannelCreateEvent,
     db_client: &Option<Arc<Mutex<Client>>>,
 ) -> BoxedResult<()> {
     if let Some(db_client) = db_client {
-        let db_client = db_client.lock().await;
+        let db_client = lock_db(db_client).await;
         if let Err(e) =
             bulk_upsert_channels(&[channel_create.channel.clone()], None, &db_client).await
         {
@@ -141,7 +275,7 @@ pub async fn process_channel_update(
     db_client: &Option<Arc<Mutex<Client>>>,
 ) -> BoxedResult<()> {
     if let Some(db_client) = db_client {
-        let db_client = db_client.lock().await;
+        let db_client = lock_db(db_client).await;
         if let Err(e) =
             bulk_upsert_channels(&[channel_update.channel.clone()], None, &db_client).await
         {
@@ -162,7 +296,7 @@ pub async fn process_channel_delete(
     db_client: &Option<Arc<Mutex<Client>>>,
 ) -> BoxedResult<()> {
     if let Some(db_client) = db_client {
-        let db_client = db_client.lock().await;
+        let db_client = lock_db(db_client).await;
         if let Err(e) = delete_channel(channel_delete.channel.id, &db_client).await {
             error!(
                 "Failed to delete channel {}: {}",
@@ -179,8 +313,8 @@ pub async fn process_role_create(
     role_create: &GuildRoleCreateEvent,
     db_client: &Option<Arc<Mutex<Client>>>,
 ) -> BoxedResult<()> {
-    if let Some(db_client) = db_client {
-        let db_client = db_client.lock().await;
+    if let Some(shared_client) = db_client {
+        let db_client = lock_db(shared_client).await;
         if let Err(e) = bulk_upsert_roles(
             &[role_create.role.clone()],
             role_create.guild_id,
@@ -197,6 +331,13 @@ pub async fn process_role_create(
                 "Role {} created and saved in guild {}",
                 role_create.role.id, role_create.guild_id
             );
+            archive_role_icons(
+                std::slice::from_ref(&role_create.role),
+                role_create.guild_id,
+                &db_client,
+                shared_client,
+            )
+            .await;
         }
     }
 
@@ -207,8 +348,8 @@ pub async fn process_role_update(
     role_update: &GuildRoleUpdateEvent,
     db_client: &Option<Arc<Mutex<Client>>>,
 ) -> BoxedResult<()> {
-    if let Some(db_client) = db_client {
-        let db_client = db_client.lock().await;
+    if let Some(shared_client) = db_client {
+        let db_client = lock_db(shared_client).await;
         if let Err(e) = bulk_upsert_roles(
             &[role_update.role.clone()],
             role_update.guild_id,
@@ -225,6 +366,13 @@ pub async fn process_role_update(
                 "Role {} updated successfully in guild {}",
                 role_update.role.id, role_update.guild_id
             );
+            archive_role_icons(
+                std::slice::from_ref(&role_update.role),
+                role_update.guild_id,
+                &db_client,
+                shared_client,
+            )
+            .await;
         }
     }
 
@@ -236,7 +384,7 @@ pub async fn process_role_delete(
     db_client: &Option<Arc<Mutex<Client>>>,
 ) -> BoxedResult<()> {
     if let Some(db_client) = db_client {
-        let db_client = db_client.lock().await;
+        let db_client = lock_db(db_client).await;
         if let Err(e) = delete_role(role_delete.role_id, &db_client).await {
             error!(
                 "Failed to delete role {} in guild {}: {}",
@@ -252,3 +400,189 @@ pub async fn process_role_delete(
 
     Ok(())
 }
+
+pub async fn process_guild_ban(
+    guild_id: u64,
+    user: &User,
+    own_account: bool,
+    db_client: &Option<Arc<Mutex<Client>>>,
+) -> BoxedResult<()> {
+    if let Some(db_client) = db_client {
+        let db_client = lock_db(db_client).await;
+        save_ban(guild_id, user.id, &user.username, own_account, &db_client).await?;
+        debug!("Ban of user {} in guild {} saved", user.id, guild_id);
+    }
+
+    Ok(())
+}
+
+pub async fn process_emojis_update(
+    emojis_update: &GuildEmojisUpdateEvent,
+    db_client: &Option<Arc<Mutex<Client>>>,
+) -> BoxedResult<()> {
+    if let Some(db_client) = db_client {
+        let db_client = lock_db(db_client).await;
+        if let Err(e) =
+            sync_guild_emojis(&emojis_update.emojis, emojis_update.guild_id, &db_client).await
+        {
+            error!(
+                "Failed to update emojis in guild {}: {}",
+                emojis_update.guild_id, e
+            );
+        } else {
+            debug!(
+                "Saved {} emojis for guild {}",
+                emojis_update.emojis.len(),
+                emojis_update.guild_id
+            );
+        }
+    }
+
+    if Config::get().download_files {
+        let guild_id = emojis_update.guild_id;
+        let emojis = emojis_update.emojis.clone();
+        tokio::spawn(async move {
+            if let Err(e) = downloader::download_guild_expressions(guild_id, emojis, vec![]).await {
+                error!("Failed to download emojis for guild {}: {}", guild_id, e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+pub async fn process_stickers_update(
+    stickers_update: &GuildStickersUpdateEvent,
+    db_client: &Option<Arc<Mutex<Client>>>,
+) -> BoxedResult<()> {
+    if let Some(db_client) = db_client {
+        let db_client = lock_db(db_client).await;
+        if let Err(e) = sync_guild_stickers(
+            &stickers_update.stickers,
+            stickers_update.guild_id,
+            &db_client,
+        )
+        .await
+        {
+            error!(
+                "Failed to update stickers in guild {}: {}",
+                stickers_update.guild_id, e
+            );
+        } else {
+            debug!(
+                "Saved {} stickers for guild {}",
+                stickers_update.stickers.len(),
+                stickers_update.guild_id
+            );
+        }
+    }
+
+    if Config::get().download_files {
+        let guild_id = stickers_update.guild_id;
+        let stickers = stickers_update.stickers.clone();
+        tokio::spawn(async move {
+            if let Err(e) = downloader::download_guild_expressions(guild_id, vec![], stickers).await
+            {
+                error!("Failed to download stickers for guild {}: {}", guild_id, e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+pub async fn process_scheduled_event_upsert(
+    scheduled_event: &GuildScheduledEvent,
+    db_client: &Option<Arc<Mutex<Client>>>,
+) -> BoxedResult<()> {
+    if let Some(db_client) = db_client {
+        let db_client = lock_db(db_client).await;
+
+        if let Some(creator) = &scheduled_event.creator
+            && let Err(e) = upsert_user(creator, &db_client, Some(scheduled_event.guild_id)).await
+        {
+            error!(
+                "Failed to save scheduled event creator {}: {}",
+                creator.id, e
+            );
+        }
+
+        if let Err(e) = upsert_scheduled_event(scheduled_event, &db_client).await {
+            error!(
+                "Failed to save scheduled event {}: {}",
+                scheduled_event.id, e
+            );
+        } else {
+            debug!("Scheduled event {} saved", scheduled_event.id);
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn process_scheduled_event_delete(
+    event_delete: &GuildScheduledEventDeleteEvent,
+    db_client: &Option<Arc<Mutex<Client>>>,
+) -> BoxedResult<()> {
+    if let Some(db_client) = db_client {
+        let db_client = lock_db(db_client).await;
+        if let Err(e) = delete_scheduled_event(event_delete.scheduled_event.id, &db_client).await {
+            error!(
+                "Failed to delete scheduled event {}: {}",
+                event_delete.scheduled_event.id, e
+            );
+        } else {
+            debug!(
+                "Scheduled event {} deleted",
+                event_delete.scheduled_event.id
+            );
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn process_stage_instance_upsert(
+    stage_instance: &StageInstance,
+    db_client: &Option<Arc<Mutex<Client>>>,
+) -> BoxedResult<()> {
+    if let Some(db_client) = db_client {
+        let db_client = lock_db(db_client).await;
+        if let Err(e) = upsert_stage_instance(stage_instance, &db_client).await {
+            error!("Failed to save stage instance {}: {}", stage_instance.id, e);
+        } else {
+            debug!("Stage instance {} saved", stage_instance.id);
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn process_stage_instance_delete(
+    stage_delete: &StageInstanceDeleteEvent,
+    db_client: &Option<Arc<Mutex<Client>>>,
+) -> BoxedResult<()> {
+    if let Some(db_client) = db_client {
+        let db_client = lock_db(db_client).await;
+        if let Err(e) = end_stage_instance(stage_delete.stage_instance.id, &db_client).await {
+            error!(
+                "Failed to end stage instance {}: {}",
+                stage_delete.stage_instance.id, e
+            );
+        } else {
+            debug!("Stage instance {} ended", stage_delete.stage_instance.id);
+        }
+    }
+
+    Ok(())
+}
+
+/// Guild the account was removed from (kicked, banned or left) given a GUILD_DELETE payload,
+/// `None` when the guild is only unavailable because of an outage
+pub fn removed_guild_id(guild_delete: &Value) -> Option<u64> {
+    if guild_delete.get("unavailable").and_then(Value::as_bool) == Some(true) {
+        return None;
+    }
+
+    parse_id(guild_delete.get("id")?)
+}
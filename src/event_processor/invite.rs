@@ -0,0 +1,39 @@
+use crate::BoxedResult;
+use crate::database::{lock_db, mark_invite_deleted, upsert_invite_create};
+use discord_client_gateway::events::structs::invite::{InviteCreateEvent, InviteDeleteEvent};
+use log::{debug, error};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio_postgres::Client;
+
+pub async fn process_invite_create(
+    invite_create: &InviteCreateEvent,
+    db_client: &Option<Arc<Mutex<Client>>>,
+) -> BoxedResult<()> {
+    if let Some(db_client) = db_client {
+        let db_client = lock_db(db_client).await;
+        if let Err(e) = upsert_invite_create(invite_create, &db_client).await {
+            error!("Failed to save invite {}: {}", invite_create.code, e);
+        } else {
+            debug!("Invite {} created and saved", invite_create.code);
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn process_invite_delete(
+    invite_delete: &InviteDeleteEvent,
+    db_client: &Option<Arc<Mutex<Client>>>,
+) -> BoxedResult<()> {
+    if let Some(db_client) = db_client {
+        let db_client = lock_db(db_client).await;
+        if let Err(e) = mark_invite_deleted(&invite_delete.code, &db_client).await {
+            error!("Failed to delete invite {}: {}", invite_delete.code, e);
+        } else {
+            debug!("Invite {} deleted", invite_delete.code);
+        }
+    }
+
+    Ok(())
+}
@@ -1,16 +1,66 @@
+use crate::clickhouse;
 use crate::config::Config;
-use crate::database::{bulk_delete_messages, delete_message, upsert_message, upsert_user};
+use crate::database::{
+    add_poll_vote, bulk_delete_messages, delete_message, get_known_message_ids, lock_db,
+    record_invite_codes, remove_poll_vote, replace_message_links, replace_message_mentions,
+    set_user_asset_paths, sync_channel_pins, update_partial_message, upsert_message,
+    upsert_message_snapshots, upsert_poll, upsert_user, user_assets_stored,
+};
 use crate::downloader;
+use crate::invites;
+use crate::mirror;
+use crate::sampling;
+use crate::search_index;
+use crate::stream;
+use crate::transcription;
+use chrono::{DateTime, Utc};
+use discord_client_gateway::events::structs::channel::ChannelPinsUpdateEvent;
+use discord_client_gateway::events::structs::message::poll::{
+    MessagePollVoteAddEvent, MessagePollVoteRemoveEvent,
+};
 use discord_client_gateway::events::structs::message::{
     MessageCreateEvent, MessageDeleteBulkEvent, MessageDeleteEvent, MessageUpdateEvent,
 };
+use discord_client_rest::rest::RestClient;
 use discord_client_structs::structs::message::Message;
 use discord_client_structs::structs::user::User;
-use log::{error, info};
+use log::{debug, error, info};
 use std::error::Error;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use tokio_postgres::Client;
+use tracing::Instrument;
+
+// the author, mentioned users and the message are written together or not at all, so a
+// failure can't leave a message referencing users that were never stored
+#[tracing::instrument(skip_all, fields(message_id = msg.id))]
+async fn save_message(
+    msg: &Message,
+    user: &User,
+    guild_id: Option<u64>,
+    db: &Client,
+) -> Result<(), Box<dyn Error>> {
+    db.batch_execute("BEGIN").await?;
+
+    upsert_user(user, db, guild_id).await?;
+    for mention in msg.mentions.iter().flatten() {
+        upsert_user(mention, db, guild_id).await?;
+    }
+    upsert_message(msg, guild_id, db).await?;
+    upsert_message_snapshots(msg, db)
+        .await
+        .map_err(|e| e as Box<dyn Error>)?;
+    replace_message_mentions(msg, guild_id, db)
+        .await
+        .map_err(|e| e as Box<dyn Error>)?;
+    replace_message_links(msg, db)
+        .await
+        .map_err(|e| e as Box<dyn Error>)?;
+
+    db.batch_execute("COMMIT").await?;
+
+    Ok(())
+}
 
 pub async fn process_message_common(
     msg: &Message,
@@ -23,59 +73,159 @@ pub async fn process_message_common(
         return Ok(());
     }
 
+    if !sampling::is_sampled(msg.id, sampling::sample_rate(guild_id)) {
+        return Ok(());
+    }
+
     if log_content {
         if let Some(content) = &msg.content {
             info!("{}: {}", user.username, content);
         }
     }
 
+    let download_assets =
+        Config::get().download_avatars && (user.avatar.is_some() || user.banner.is_some());
+    let mut assets_stored = false;
+
     if let Some(db_client) = db_client {
-        let db_client = db_client.lock().await;
+        // the client stays locked, no other statement can run inside the transaction
+        let db_client = lock_db(db_client).await;
 
-        if let Err(e) = upsert_user(user, &db_client, guild_id).await {
-            error!("Failed to upsert user: {}", e);
+        // errors aren't Send, keep only the text across the rollback
+        let saved = save_message(msg, user, guild_id, &db_client)
+            .await
+            .map_err(|e| e.to_string());
+        if let Err(e) = saved {
+            if let Err(e) = db_client.batch_execute("ROLLBACK").await {
+                error!("Failed to roll back message {}: {}", msg.id, e);
+            }
+            // returned so the event is buffered and replayed when the database is down
+            return Err(format!("Failed to save message {}: {}", msg.id, e).into());
         }
 
-        if let Err(e) = upsert_message(msg, guild_id, &db_client).await {
-            error!("Failed to save message: {}", e);
+        if let Err(e) = upsert_poll(msg, guild_id, &db_client).await {
+            error!("Failed to save poll: {}", e);
         }
 
-        if let Some(mentions) = &msg.mentions {
-            for mention in mentions {
-                if let Err(e) = upsert_user(mention, &db_client, guild_id).await {
-                    error!("Failed to upsert mention user: {}", e);
-                }
+        if let Some(content) = &msg.content {
+            let codes = invites::extract_invite_codes(content);
+            if !codes.is_empty()
+                && let Err(e) = record_invite_codes(&codes, msg.id, guild_id, &db_client).await
+            {
+                error!("Failed to save invite codes: {}", e);
+            }
+        }
+
+        // most messages come from authors whose avatar is already stored
+        if download_assets {
+            match user_assets_stored(user, &db_client).await {
+                Ok(stored) => assets_stored = stored,
+                Err(e) => error!("Failed to check assets of user {}: {}", user.id, e),
             }
         }
     }
 
-    // spawn a task to download attachments
-    if Config::get().download_files {
-        if !msg.attachments.is_empty() {
-            let attachments = msg.attachments.clone();
+    // pushed once the message is saved, a replayed event would push it twice otherwise
+    clickhouse::push_message(msg, guild_id);
+    search_index::index_message(msg, guild_id);
 
-            tokio::spawn(async move {
-                if let Err(e) = downloader::download_attachment(attachments).await {
-                    error!("Failed to download attachments: {}", e);
+    let hashes: Vec<String> = [&user.avatar, &user.banner]
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect();
+    if download_assets && !assets_stored && downloader::start_user_assets_download(&hashes) {
+        let user = user.clone();
+        let db_client = db_client.clone();
+
+        tokio::spawn(async move {
+            // errors aren't Send, keep only the text across the next await
+            let downloaded = downloader::download_user_assets(&user)
+                .await
+                .map_err(|e| e.to_string());
+            match downloaded {
+                Ok((avatar_path, banner_path)) => {
+                    if let Some(db_client) = db_client {
+                        let db_client = lock_db(&db_client).await;
+                        if let Err(e) =
+                            set_user_asset_paths(user.id, avatar_path, banner_path, &db_client)
+                                .await
+                        {
+                            error!("Failed to save asset paths for user {}: {}", user.id, e);
+                        }
+                    }
                 }
-            });
-        }
+                Err(e) => error!("Failed to download assets for user {}: {}", user.id, e),
+            }
+            // released once the paths are stored, so the next messages find them
+            downloader::finish_user_assets_download(&hashes);
+        });
+    }
+
+    // spawn a task to download attachments
+    if Config::get().download_files {
+        // forwarded messages carry their attachments in the snapshots
+        let attachments: Vec<_> = msg
+            .attachments
+            .iter()
+            .chain(
+                msg.message_snapshots
+                    .iter()
+                    .flatten()
+                    .flat_map(|snapshot| snapshot.message.attachments.iter()),
+            )
+            .cloned()
+            .collect();
 
-        if !msg.embeds.is_empty() {
-            let embeds = msg.embeds.clone();
+        if !attachments.is_empty() {
             let message_id = msg.id;
+            let channel_id = msg.channel_id;
+            let voice_message = msg.flags as u64 & transcription::VOICE_MESSAGE_FLAG != 0;
+            let db_client = db_client.clone();
 
-            tokio::spawn(async move {
-                if let Err(e) = downloader::download_embeds(embeds, message_id).await {
-                    error!("Failed to download embeds: {}", e);
+            tokio::spawn(
+                async move {
+                    if let Err(e) = downloader::download_attachment(
+                        attachments,
+                        message_id,
+                        channel_id,
+                        guild_id,
+                        voice_message,
+                        db_client,
+                    )
+                    .await
+                    {
+                        error!("Failed to download attachments: {}", e);
+                    }
                 }
-            });
+                .in_current_span(),
+            );
         }
+
+        spawn_embed_download(msg);
     }
 
     Ok(())
 }
 
+fn spawn_embed_download(msg: &Message) {
+    if msg.embeds.is_empty() {
+        return;
+    }
+
+    let embeds = msg.embeds.clone();
+    let message_id = msg.id;
+
+    tokio::spawn(
+        async move {
+            if let Err(e) = downloader::download_embeds(embeds, message_id).await {
+                error!("Failed to download embeds: {}", e);
+            }
+        }
+        .in_current_span(),
+    );
+}
+
 pub async fn process_message_create(
     msg_create: &MessageCreateEvent,
     db_client: &Option<Arc<Mutex<Client>>>,
@@ -87,21 +237,63 @@ pub async fn process_message_create(
         db_client,
         true,
     )
-    .await
+    .await?;
+
+    mirror::mirror_message(&msg_create.message).await;
+    stream::publish_message("message_create", &msg_create.message, msg_create.guild_id);
+
+    Ok(())
 }
 
 pub async fn process_message_update(
     msg_update: &MessageUpdateEvent,
     db_client: &Option<Arc<Mutex<Client>>>,
 ) -> Result<(), Box<dyn Error>> {
-    process_message_common(
-        &msg_update.message,
-        &msg_update.message.author,
-        msg_update.guild_id,
-        db_client,
-        false,
-    )
-    .await
+    // partial updates (e.g. embeds resolving) have no content nor a real author, they
+    // would blank the stored message and its author
+    if msg_update.message.content.is_none() {
+        process_partial_message_update(&msg_update.message, db_client).await?;
+    } else {
+        process_message_common(
+            &msg_update.message,
+            &msg_update.message.author,
+            msg_update.guild_id,
+            db_client,
+            false,
+        )
+        .await?;
+    }
+
+    stream::publish_message("message_update", &msg_update.message, msg_update.guild_id);
+
+    Ok(())
+}
+
+async fn process_partial_message_update(
+    msg: &Message,
+    db_client: &Option<Arc<Mutex<Client>>>,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(db_client) = db_client {
+        let db_client = lock_db(db_client).await;
+
+        match update_partial_message(msg, &db_client).await {
+            Ok(0) => debug!("Partial update of unknown message {}", msg.id),
+            Ok(_) => debug!("Applied partial update of message {}", msg.id),
+            Err(e) => {
+                return Err(format!(
+                    "Failed to apply partial update of message {}: {}",
+                    msg.id, e
+                )
+                .into());
+            }
+        }
+    }
+
+    if Config::get().download_files {
+        spawn_embed_download(msg);
+    }
+
+    Ok(())
 }
 
 pub async fn process_message_delete(
@@ -109,14 +301,18 @@ pub async fn process_message_delete(
     db_client: &Option<Arc<Mutex<Client>>>,
 ) -> Result<(), Box<dyn Error>> {
     if let Some(db_client) = db_client {
-        let db_client = db_client.lock().await;
+        let db_client = lock_db(db_client).await;
         let msg_id = &msg_delete.id;
 
-        if let Err(e) = delete_message(msg_id, &db_client).await {
-            error!("Failed to delete message: {}", e);
-        }
+        delete_message(msg_id, &db_client)
+            .await
+            .map_err(|e| format!("Failed to delete message: {}", e))?;
     }
 
+    stream::publish_delete(&[msg_delete.id], msg_delete.channel_id, msg_delete.guild_id);
+    clickhouse::push_deletes(&[msg_delete.id], msg_delete.channel_id, msg_delete.guild_id);
+    search_index::remove_messages(&[msg_delete.id]);
+
     Ok(())
 }
 
@@ -125,13 +321,126 @@ pub async fn process_message_delete_bulk(
     db_client: &Option<Arc<Mutex<Client>>>,
 ) -> Result<(), Box<dyn Error>> {
     if let Some(db_client) = db_client {
-        let db_client = db_client.lock().await;
+        let db_client = lock_db(db_client).await;
 
         let ids = &msg_delete_bulk.ids;
-        if let Err(e) = bulk_delete_messages(ids, &db_client).await {
-            error!("Failed to bulk delete messages: {}", e);
+        bulk_delete_messages(ids, &db_client)
+            .await
+            .map_err(|e| format!("Failed to bulk delete messages: {}", e))?;
+    }
+
+    stream::publish_delete(
+        &msg_delete_bulk.ids,
+        msg_delete_bulk.channel_id,
+        msg_delete_bulk.guild_id,
+    );
+    clickhouse::push_deletes(
+        &msg_delete_bulk.ids,
+        msg_delete_bulk.channel_id,
+        msg_delete_bulk.guild_id,
+    );
+    search_index::remove_messages(&msg_delete_bulk.ids);
+
+    Ok(())
+}
+
+pub async fn process_poll_vote_add(
+    vote_add: &MessagePollVoteAddEvent,
+    db_client: &Option<Arc<Mutex<Client>>>,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(db_client) = db_client {
+        let db_client = lock_db(db_client).await;
+
+        add_poll_vote(
+            vote_add.message_id,
+            vote_add.answer_id,
+            vote_add.user_id,
+            &db_client,
+        )
+        .await
+        .map_err(|e| format!("Failed to save poll vote: {}", e))?;
+    }
+
+    Ok(())
+}
+
+pub async fn process_poll_vote_remove(
+    vote_remove: &MessagePollVoteRemoveEvent,
+    db_client: &Option<Arc<Mutex<Client>>>,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(db_client) = db_client {
+        let db_client = lock_db(db_client).await;
+
+        remove_poll_vote(
+            vote_remove.message_id,
+            vote_remove.answer_id,
+            vote_remove.user_id,
+            &db_client,
+        )
+        .await
+        .map_err(|e| format!("Failed to remove poll vote: {}", e))?;
+    }
+
+    Ok(())
+}
+
+/// Fetches the pinned messages of a channel and updates the pinned flag of its messages, the
+/// pinned messages missing from the archive are stored first
+pub async fn sync_pins(
+    rest_client: &RestClient,
+    channel_id: u64,
+    guild_id: Option<u64>,
+    pinned_at: Option<DateTime<Utc>>,
+    db_client: &Option<Arc<Mutex<Client>>>,
+) -> Result<(), Box<dyn Error>> {
+    let Some(db) = db_client else {
+        return Ok(());
+    };
+
+    let pins = rest_client
+        .message(channel_id)
+        .get_pinned_messages()
+        .await
+        .map_err(|e| format!("Error fetching pins of channel {}: {}", channel_id, e))?;
+
+    let pinned_ids: Vec<u64> = pins.iter().map(|pin| pin.id).collect();
+    let db = lock_db(db).await;
+
+    // pinned messages may predate the archive, only those are stored
+    let known = get_known_message_ids(&pinned_ids, &db)
+        .await
+        .map_err(|e| e as Box<dyn Error>)?;
+    for pin in pins.iter().filter(|pin| !known.contains(&pin.id)) {
+        // errors aren't Send, keep only the text across the rollback
+        let saved = save_message(pin, &pin.author, guild_id, &db)
+            .await
+            .map_err(|e| e.to_string());
+        if let Err(e) = saved {
+            if let Err(e) = db.batch_execute("ROLLBACK").await {
+                error!("Failed to roll back message {}: {}", pin.id, e);
+            }
+            return Err(format!("Failed to save pinned message {}: {}", pin.id, e).into());
         }
     }
 
+    sync_channel_pins(channel_id, &pinned_ids, pinned_at, &db)
+        .await
+        .map_err(|e| e as Box<dyn Error>)?;
+
     Ok(())
 }
+
+pub async fn process_channel_pins_update(
+    pins_update: &ChannelPinsUpdateEvent,
+    rest_client: &RestClient,
+    db_client: &Option<Arc<Mutex<Client>>>,
+) -> Result<(), Box<dyn Error>> {
+    sync_pins(
+        rest_client,
+        pins_update.channel_id,
+        pins_update.guild_id,
+        pins_update.last_pin_timestamp,
+        db_client,
+    )
+    .await
+}
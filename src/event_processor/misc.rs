@@ -1,7 +1,16 @@
 use crate::BoxedResult;
-use crate::database::bulk_upsert_users;
+use crate::config::Config;
+use crate::database::{
+    add_channel_recipients, bulk_upsert_channels, bulk_upsert_users, insert_raw_event, lock_db,
+};
+use discord_client_gateway::events::Event;
 use discord_client_gateway::events::structs::ready::ReadySupplementalEvent;
+use discord_client_structs::structs::channel::Channel;
 use discord_client_structs::structs::user::User;
+use log::debug;
+use serde_json::Value;
+use std::sync::Arc;
+use tokio::sync::Mutex;
 use tokio_postgres::Client;
 
 pub async fn process_ready_supplemental(
@@ -30,3 +39,83 @@ pub async fn process_ready_supplemental(
 
     bulk_upsert_users(users.as_slice(), client).await
 }
+
+/// DMs and group DMs
+pub fn is_private_channel(channel: &Channel) -> bool {
+    matches!(channel.r#type as i32, 1 | 3)
+}
+
+/// Stores the DMs and group DMs of the account with their recipients, so the messages sent in
+/// them can be attributed to a conversation
+pub async fn process_private_channels(
+    channels: &[Channel],
+    account_id: u64,
+    client: &Client,
+) -> BoxedResult<()> {
+    let channels: Vec<Channel> = channels
+        .iter()
+        .filter(|channel| is_private_channel(channel))
+        .cloned()
+        .collect();
+    if channels.is_empty() {
+        return Ok(());
+    }
+
+    let users: Vec<User> = channels
+        .iter()
+        .filter_map(|channel| channel.recipients.clone())
+        .flatten()
+        .collect();
+    bulk_upsert_users(users.as_slice(), client).await?;
+    bulk_upsert_channels(&channels, None, client).await?;
+
+    for channel in &channels {
+        let mut recipient_ids: Vec<u64> = channel
+            .recipients
+            .iter()
+            .flatten()
+            .map(|user| user.id)
+            .collect();
+        recipient_ids.push(account_id);
+        add_channel_recipients(channel.id, &recipient_ids, client).await?;
+    }
+    debug!(
+        "Saved {} private channels of account {}",
+        channels.len(),
+        account_id
+    );
+
+    Ok(())
+}
+
+/// Stores events that no processor handles, so they can be backfilled later
+pub async fn process_raw_event(
+    event: &Event,
+    account_index: usize,
+    db_client: &Option<Arc<Mutex<Client>>>,
+) -> BoxedResult<()> {
+    if !Config::get().store_raw_events {
+        return Ok(());
+    }
+
+    if let Some(db_client) = db_client {
+        let payload = serde_json::to_value(event)?;
+        let db_client = lock_db(db_client).await;
+        insert_raw_event(&event_type(&payload), account_index, &payload, &db_client).await?;
+    }
+
+    Ok(())
+}
+
+// name of the event from the tag of its serialized payload, e.g. `TypingStart`
+fn event_type(payload: &Value) -> String {
+    match payload {
+        Value::String(name) => name.clone(),
+        Value::Object(map) => match map.get("t").and_then(Value::as_str) {
+            Some(name) => name.to_string(),
+            None if map.len() == 1 => map.keys().next().cloned().unwrap_or_default(),
+            None => String::new(),
+        },
+        _ => String::new(),
+    }
+}
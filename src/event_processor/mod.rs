@@ -1,4 +1,14 @@
+pub mod call;
 pub mod guild;
+pub mod invite;
 pub mod message;
 pub mod misc;
+pub mod relationship;
 pub mod user;
+
+use serde_json::Value;
+
+// ids are strings in the payloads, numbers once deserialized
+fn parse_id(id: &Value) -> Option<u64> {
+    id.as_u64().or_else(|| id.as_str()?.parse().ok())
+}
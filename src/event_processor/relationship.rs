@@ -0,0 +1,96 @@
+use super::parse_id;
+use crate::BoxedResult;
+use crate::database::{
+    Relationship, bulk_upsert_users, lock_db, remove_relationship, sync_relationships,
+    upsert_relationship,
+};
+use chrono::{DateTime, Utc};
+use discord_client_structs::structs::user::User;
+use log::debug;
+use serde_json::Value;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio_postgres::Client;
+
+/// Stores the relationships listed in READY for the account, with their users when included
+pub async fn process_ready_relationships(
+    account_id: u64,
+    relationships: &Value,
+    db: &Client,
+) -> BoxedResult<()> {
+    let relationships = relationships.as_array().cloned().unwrap_or_default();
+
+    let users: Vec<User> = relationships.iter().filter_map(relationship_user).collect();
+    bulk_upsert_users(&users, db).await?;
+
+    let relationships: Vec<Relationship> = relationships
+        .iter()
+        .filter_map(parse_relationship)
+        .collect();
+    sync_relationships(account_id, &relationships, db).await?;
+    debug!(
+        "Saved {} relationships of account {}",
+        relationships.len(),
+        account_id
+    );
+
+    Ok(())
+}
+
+pub async fn process_relationship_add(
+    account_id: u64,
+    relationship: &Value,
+    db_client: &Option<Arc<Mutex<Client>>>,
+) -> BoxedResult<()> {
+    let Some(parsed) = parse_relationship(relationship) else {
+        return Err("Relationship without user id".into());
+    };
+
+    if let Some(db_client) = db_client {
+        let db_client = lock_db(db_client).await;
+        if let Some(user) = relationship_user(relationship) {
+            bulk_upsert_users(&[user], &db_client).await?;
+        }
+        upsert_relationship(account_id, &parsed, &db_client).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn process_relationship_remove(
+    account_id: u64,
+    relationship: &Value,
+    db_client: &Option<Arc<Mutex<Client>>>,
+) -> BoxedResult<()> {
+    let Some(user_id) = parse_id(&relationship["id"]) else {
+        return Err("Relationship without user id".into());
+    };
+
+    if let Some(db_client) = db_client {
+        let db_client = lock_db(db_client).await;
+        remove_relationship(account_id, user_id, &db_client).await?;
+    }
+
+    Ok(())
+}
+
+// the id of a relationship is the one of the other user
+fn parse_relationship(relationship: &Value) -> Option<Relationship> {
+    let user_id = parse_id(&relationship["id"])
+        .or_else(|| parse_id(&relationship["user_id"]))
+        .or_else(|| parse_id(&relationship["user"]["id"]))?;
+
+    Some(Relationship {
+        user_id,
+        relationship_type: relationship["type"].as_i64().unwrap_or_default() as i16,
+        nickname: relationship["nickname"].as_str().map(str::to_string),
+        since: relationship["since"]
+            .as_str()
+            .and_then(|since| DateTime::parse_from_rfc3339(since).ok())
+            .map(|since| since.with_timezone(&Utc)),
+    })
+}
+
+fn relationship_user(relationship: &Value) -> Option<User> {
+    serde_json::from_value(relationship["user"].clone()).ok()
+}
@@ -1,5 +1,5 @@
 use crate::BoxedResult;
-use crate::database::bulk_upsert_users;
+use crate::database::{bulk_upsert_users, lock_db, update_member_search_progress};
 use discord_client_gateway::events::structs::guild::GuildMemberUpdateEvent;
 use discord_client_gateway::events::structs::requested::GuildMembersChunkEvent;
 use log::error;
@@ -7,12 +7,15 @@ use std::sync::Arc;
 use tokio::sync::Mutex;
 use tokio_postgres::Client;
 
+/// Nonce of the recent member searches paging through a guild, see `member_search_interval`
+pub const MEMBER_SEARCH_NONCE: &str = "member_search";
+
 pub async fn process_guild_members_chunk(
     members_chunk: &GuildMembersChunkEvent,
     db_client: &Option<Arc<Mutex<Client>>>,
 ) -> BoxedResult<()> {
     if let Some(client) = db_client {
-        let client = client.lock().await;
+        let client = lock_db(client).await;
 
         let users = members_chunk
             .members
@@ -22,6 +25,17 @@ pub async fn process_guild_members_chunk(
             .collect::<Vec<_>>();
 
         bulk_upsert_users(users.as_slice(), &client).await?;
+
+        // the next page starts after the last member, an empty page ends the guild
+        if members_chunk.nonce.as_deref() == Some(MEMBER_SEARCH_NONCE) {
+            update_member_search_progress(
+                members_chunk.guild_id,
+                users.last().map(|user| user.id),
+                users.len(),
+                &client,
+            )
+            .await?;
+        }
     }
 
     Ok(())
@@ -32,7 +46,7 @@ pub async fn process_guild_member_update(
     db_client: &Option<Arc<Mutex<Client>>>,
 ) -> BoxedResult<()> {
     if let Some(client) = db_client {
-        let client = client.lock().await;
+        let client = lock_db(client).await;
         let user = &event.user;
         let guild_id = event.guild_id;
 
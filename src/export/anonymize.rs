@@ -0,0 +1,112 @@
+use hmac::{Hmac, Mac};
+use regex::{Captures, Regex};
+use sha2::Sha256;
+
+lazy_static::lazy_static! {
+    static ref MENTION_REGEX: Regex = Regex::new(r"<@!?(\d+)>").unwrap();
+    static ref LINK_REGEX: Regex = Regex::new(r"https?://\S+").unwrap();
+    static ref EMAIL_REGEX: Regex = Regex::new(r"[\w.+-]+@[\w-]+(\.[\w-]+)+").unwrap();
+    static ref PHONE_REGEX: Regex = Regex::new(r"\+?\d[\d ().-]{7,}\d").unwrap();
+}
+
+// phone numbers have 9 to 15 digits, fewer are usually dates or amounts
+fn is_phone_number(text: &str) -> bool {
+    let digits = text.chars().filter(char::is_ascii_digit).count();
+    (9..=15).contains(&digits)
+}
+
+/// Replaces users with pseudonyms and scrubs personal data from message content. Pseudonyms come
+/// from an HMAC-SHA256 of the user id keyed with a secret salt: the same salt gives the same
+/// pseudonyms in every export and in `tools/prepare_dataset.py`, and known user ids can't be
+/// hashed to find them without it.
+pub struct Anonymizer {
+    salt: Vec<u8>,
+}
+
+impl Anonymizer {
+    pub fn new(salt: &str) -> Self {
+        Self {
+            salt: salt.as_bytes().to_vec(),
+        }
+    }
+
+    fn digest(&self, id: i64) -> [u8; 32] {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.salt).expect("HMAC takes keys of any size");
+        mac.update(id.to_string().as_bytes());
+        mac.finalize().into_bytes().into()
+    }
+
+    /// First 8 bytes of the digest, shifted to stay a positive BIGINT
+    pub fn user_id(&self, id: i64) -> i64 {
+        let digest = self.digest(id);
+        (u64::from_be_bytes(digest[..8].try_into().unwrap()) >> 1) as i64
+    }
+
+    /// `user` followed by the first 12 hex digits of the digest
+    pub fn username(&self, id: i64) -> String {
+        let hex: String = self.digest(id)[..6]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        format!("user{}", hex)
+    }
+
+    /// Mentions become pseudonyms, links, emails and phone numbers are replaced by placeholders
+    pub fn content(&self, content: &str) -> String {
+        let content =
+            MENTION_REGEX.replace_all(content, |captures: &Captures| match captures[1].parse() {
+                Ok(id) => format!("@{}", self.username(id)),
+                Err(_) => "@user".to_string(),
+            });
+        let content = LINK_REGEX.replace_all(&content, "[link]");
+        let content = EMAIL_REGEX.replace_all(&content, "[email]");
+        let content = PHONE_REGEX.replace_all(&content, |captures: &Captures| {
+            if is_phone_number(&captures[0]) {
+                "[phone]".to_string()
+            } else {
+                captures[0].to_string()
+            }
+        });
+
+        content.into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pseudonyms_match_prepare_dataset() {
+        // hmac.new(b"pepper", b"80351110224678912", hashlib.sha256) in tools/prepare_dataset.py
+        let anonymizer = Anonymizer::new("pepper");
+        assert_eq!(anonymizer.username(80351110224678912), "userb5cf652585c4");
+        assert_eq!(anonymizer.user_id(80351110224678912), 6550400526438250472);
+    }
+
+    #[test]
+    fn pseudonyms_depend_on_the_salt() {
+        let anonymizer = Anonymizer::new("pepper");
+        assert_eq!(anonymizer.user_id(42), anonymizer.user_id(42));
+        assert_ne!(anonymizer.user_id(42), anonymizer.user_id(43));
+        assert_ne!(
+            anonymizer.username(42),
+            Anonymizer::new("salt").username(42)
+        );
+    }
+
+    #[test]
+    fn content_scrubs_personal_data() {
+        let anonymizer = Anonymizer::new("pepper");
+        assert_eq!(
+            anonymizer.content("hi <@!80351110224678912>, see https://example.com"),
+            "hi @userb5cf652585c4, see [link]"
+        );
+        assert_eq!(
+            anonymizer.content("mail me at jane.doe@example.com or +33 6 12 34 56 78"),
+            "mail me at [email] or [phone]"
+        );
+        assert_eq!(anonymizer.content("on 2024-01-15"), "on 2024-01-15");
+    }
+}
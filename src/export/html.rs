@@ -0,0 +1,327 @@
+use crate::BoxedResult;
+use crate::database::{
+    ExportMessage, get_channel_messages_for_export, get_channel_name, get_thread_parent,
+    get_thread_starter_for_export,
+};
+use crate::timezone;
+use log::info;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use tokio_postgres::Client;
+
+const STYLE: &str = r#"
+body { background: #313338; color: #dbdee1; font-family: "gg sans", "Helvetica Neue", Helvetica, Arial, sans-serif; margin: 0; }
+header { background: #2b2d31; padding: 16px 24px; border-bottom: 1px solid #1e1f22; }
+header h1 { margin: 0; font-size: 20px; }
+header p { margin: 4px 0 0; color: #949ba4; font-size: 13px; }
+.message { display: flex; padding: 8px 24px; }
+.message:hover { background: #2e3035; }
+.message.deleted { background: rgba(242, 63, 67, 0.08); }
+.avatar { width: 40px; height: 40px; border-radius: 50%; margin-right: 16px; flex-shrink: 0; }
+.body { min-width: 0; }
+.author { font-weight: 600; color: #f2f3f5; }
+.date, .edited, .deleted-tag { color: #949ba4; font-size: 12px; margin-left: 6px; }
+.deleted-tag { color: #f23f43; }
+.content { white-space: pre-wrap; word-wrap: break-word; margin-top: 2px; }
+.reply { color: #949ba4; font-size: 13px; margin-bottom: 4px; }
+.reply a { color: #949ba4; }
+.thread-starter { border-bottom: 1px solid #3f4147; padding-bottom: 8px; margin-bottom: 8px; }
+.thread-starter h2 { margin: 8px 24px 0; font-size: 13px; color: #949ba4; font-weight: 600; text-transform: uppercase; }
+.attachment { margin-top: 6px; }
+.attachment img, .attachment video { max-width: 480px; max-height: 360px; border-radius: 4px; }
+.embed { border-left: 4px solid #1e1f22; background: #2b2d31; border-radius: 4px; padding: 8px 12px; margin-top: 6px; max-width: 520px; }
+.embed-title { font-weight: 600; color: #00a8fc; }
+.embed img { max-width: 100%; border-radius: 4px; margin-top: 6px; }
+a { color: #00a8fc; }
+"#;
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// local files are linked relative to the exported file when possible
+fn link_path(path: &str, output_dir: &Path) -> String {
+    let path = PathBuf::from(path);
+    let absolute = std::fs::canonicalize(&path).unwrap_or(path);
+
+    let relative = std::fs::canonicalize(output_dir)
+        .ok()
+        .and_then(|dir| absolute.strip_prefix(dir).ok().map(Path::to_path_buf));
+
+    relative
+        .unwrap_or(absolute)
+        .to_string_lossy()
+        .replace('\\', "/")
+}
+
+fn avatar_url(message: &ExportMessage, output_dir: &Path) -> String {
+    if let Some(path) = &message.avatar_path
+        && Path::new(path).exists()
+    {
+        return link_path(path, output_dir);
+    }
+
+    match &message.avatar {
+        Some(hash) => format!(
+            "https://cdn.discordapp.com/avatars/{}/{}.png?size=64",
+            message.author_id, hash
+        ),
+        None => format!(
+            "https://cdn.discordapp.com/embed/avatars/{}.png",
+            (message.author_id >> 22) % 6
+        ),
+    }
+}
+
+fn display_name(message: &ExportMessage) -> &str {
+    message.global_name.as_deref().unwrap_or(&message.username)
+}
+
+fn render_attachments(message: &ExportMessage, output_dir: &Path, html: &mut String) {
+    let Some(attachments) = message.attachments.as_array() else {
+        return;
+    };
+
+    let local_paths: HashMap<&str, &str> = message
+        .attachment_paths
+        .iter()
+        .map(|(id, path)| (id.as_str(), path.as_str()))
+        .collect();
+    let thumbnails: HashMap<&str, &str> = message
+        .attachment_thumbnails
+        .iter()
+        .map(|(id, path)| (id.as_str(), path.as_str()))
+        .collect();
+
+    for attachment in attachments {
+        let id = match &attachment["id"] {
+            Value::String(id) => id.clone(),
+            other => other.to_string(),
+        };
+        let filename = attachment["filename"].as_str().unwrap_or("attachment");
+        let content_type = attachment["content_type"].as_str().unwrap_or_default();
+
+        let url = match local_paths.get(id.as_str()) {
+            Some(path) => link_path(path, output_dir),
+            None => attachment["url"].as_str().unwrap_or_default().to_string(),
+        };
+        let url = escape(&url);
+        // the full file is only loaded when opened
+        let preview = thumbnails
+            .get(id.as_str())
+            .filter(|path| Path::new(path).exists())
+            .map(|path| escape(&link_path(path, output_dir)));
+
+        let _ = write!(html, r#"<div class="attachment">"#);
+        if content_type.starts_with("image/") {
+            let _ = write!(
+                html,
+                r#"<a href="{url}"><img src="{}" alt="{}" loading="lazy"></a>"#,
+                preview.as_deref().unwrap_or(&url),
+                escape(filename)
+            );
+        } else if content_type.starts_with("video/") {
+            let poster = preview
+                .map(|preview| format!(r#" poster="{}""#, preview))
+                .unwrap_or_default();
+            let _ = write!(
+                html,
+                r#"<video src="{url}"{poster} controls preload="none"></video>"#
+            );
+        } else {
+            let _ = write!(html, r#"<a href="{url}">{}</a>"#, escape(filename));
+        }
+        html.push_str("</div>");
+    }
+}
+
+fn render_embeds(message: &ExportMessage, html: &mut String) {
+    let Some(embeds) = message.embeds.as_array() else {
+        return;
+    };
+
+    for embed in embeds {
+        html.push_str(r#"<div class="embed">"#);
+
+        if let Some(title) = embed["title"].as_str() {
+            match embed["url"].as_str() {
+                Some(url) => {
+                    let _ = write!(
+                        html,
+                        r#"<div class="embed-title"><a href="{}">{}</a></div>"#,
+                        escape(url),
+                        escape(title)
+                    );
+                }
+                None => {
+                    let _ = write!(html, r#"<div class="embed-title">{}</div>"#, escape(title));
+                }
+            }
+        }
+
+        if let Some(description) = embed["description"].as_str() {
+            let _ = write!(
+                html,
+                r#"<div class="content">{}</div>"#,
+                escape(description)
+            );
+        }
+
+        let image = embed["image"]["url"]
+            .as_str()
+            .or(embed["thumbnail"]["url"].as_str());
+        if let Some(image) = image {
+            let _ = write!(html, r#"<img src="{}" loading="lazy">"#, escape(image));
+        }
+
+        html.push_str("</div>");
+    }
+}
+
+fn render_message(
+    message: &ExportMessage,
+    by_id: &HashMap<i64, &ExportMessage>,
+    output_dir: &Path,
+    html: &mut String,
+) {
+    let _ = write!(
+        html,
+        r#"<div class="message{}" id="msg-{}">"#,
+        if message.deleted_at.is_some() {
+            " deleted"
+        } else {
+            ""
+        },
+        message.id
+    );
+    let _ = write!(
+        html,
+        r#"<img class="avatar" src="{}" loading="lazy"><div class="body">"#,
+        escape(&avatar_url(message, output_dir))
+    );
+
+    if let Some(reference_id) = message.referenced_message_id {
+        let _ = match by_id.get(&reference_id) {
+            Some(reference) => {
+                let snippet: String = reference
+                    .content
+                    .as_deref()
+                    .unwrap_or_default()
+                    .chars()
+                    .take(100)
+                    .collect();
+                write!(
+                    html,
+                    r##"<div class="reply">↪ <a href="#msg-{}">{}</a> {}</div>"##,
+                    reference_id,
+                    escape(display_name(reference)),
+                    escape(&snippet)
+                )
+            }
+            None => write!(
+                html,
+                r#"<div class="reply">↪ message {} not archived</div>"#,
+                reference_id
+            ),
+        };
+    }
+
+    let _ = write!(
+        html,
+        r#"<span class="author" title="{}">{}</span><span class="date">{}</span>"#,
+        escape(&message.username),
+        escape(display_name(message)),
+        timezone::format_snowflake(message.id as u64)
+    );
+    if message.edited_at.is_some() {
+        html.push_str(r#"<span class="edited">(edited)</span>"#);
+    }
+    if message.deleted_at.is_some() {
+        html.push_str(r#"<span class="deleted-tag">(deleted)</span>"#);
+    }
+
+    if let Some(content) = &message.content
+        && !content.is_empty()
+    {
+        let _ = write!(html, r#"<div class="content">{}</div>"#, escape(content));
+    }
+
+    render_attachments(message, output_dir, html);
+    render_embeds(message, html);
+
+    html.push_str("</div></div>\n");
+}
+
+pub async fn export_channel(channel_id: u64, output: &str, db: &Client) -> BoxedResult<()> {
+    let messages = get_channel_messages_for_export(channel_id, db).await?;
+    if messages.is_empty() {
+        return Err(format!("No stored messages in channel {}", channel_id).into());
+    }
+
+    let channel_name = get_channel_name(channel_id, db)
+        .await?
+        .unwrap_or_else(|| channel_id.to_string());
+
+    let output_dir = Path::new(output)
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+
+    // threads get the channel they belong to and the message they were started from as context
+    let parent = get_thread_parent(channel_id, db).await?;
+    let starter = match parent {
+        Some(_) => get_thread_starter_for_export(channel_id, db).await?,
+        None => None,
+    };
+    let title = match &parent {
+        Some((parent_id, parent_name)) => format!(
+            "#{} in #{}",
+            channel_name,
+            parent_name.clone().unwrap_or_else(|| parent_id.to_string())
+        ),
+        None => format!("#{}", channel_name),
+    };
+
+    let by_id: HashMap<i64, &ExportMessage> = messages
+        .iter()
+        .chain(starter.iter())
+        .map(|message| (message.id, message))
+        .collect();
+
+    let mut html = String::new();
+    let _ = writeln!(
+        html,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>{}</style>\n</head>\n<body>",
+        escape(&title),
+        STYLE
+    );
+    let _ = writeln!(
+        html,
+        "<header><h1>{}</h1><p>{} messages, exported {}</p></header>",
+        escape(&title),
+        messages.len(),
+        timezone::to_local(chrono::Utc::now()).format("%Y-%m-%d %H:%M:%S %Z")
+    );
+
+    if let Some(starter) = &starter {
+        html.push_str(r#"<section class="thread-starter"><h2>Thread started from</h2>"#);
+        render_message(starter, &by_id, output_dir, &mut html);
+        html.push_str("</section>\n");
+    }
+
+    for message in &messages {
+        render_message(message, &by_id, output_dir, &mut html);
+    }
+
+    html.push_str("</body>\n</html>\n");
+
+    std::fs::write(output, html)?;
+    info!("Exported {} messages to {}", messages.len(), output);
+
+    Ok(())
+}
@@ -0,0 +1,42 @@
+#[cfg(feature = "parquet")]
+mod anonymize;
+mod html;
+#[cfg(feature = "parquet")]
+mod parquet;
+
+use crate::BoxedResult;
+use clap::ValueEnum;
+use tokio_postgres::Client;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Html,
+    Parquet,
+}
+
+pub async fn export(
+    format: ExportFormat,
+    channel: Option<u64>,
+    output: Option<String>,
+    // salt of the pseudonyms, anonymizes the export when given
+    salt: Option<&str>,
+    db: &Client,
+) -> BoxedResult<()> {
+    match format {
+        ExportFormat::Html => {
+            if salt.is_some() {
+                return Err("The html export can't be anonymized".into());
+            }
+            let channel = channel.ok_or("The html export needs a --channel")?;
+            let output = output.unwrap_or_else(|| format!("export_{}.html", channel));
+            html::export_channel(channel, &output, db).await
+        }
+        #[cfg(feature = "parquet")]
+        ExportFormat::Parquet => {
+            let output = output.unwrap_or_else(|| "export".to_string());
+            parquet::export_tables(&output, channel, salt, db).await
+        }
+        #[cfg(not(feature = "parquet"))]
+        ExportFormat::Parquet => Err("slurpslurp was built without the parquet feature".into()),
+    }
+}
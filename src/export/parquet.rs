@@ -0,0 +1,326 @@
+use super::anonymize::Anonymizer;
+use crate::BoxedResult;
+use crate::database::{get_messages_page, get_users_page};
+use arrow::array::{
+    ArrayRef, BooleanBuilder, Int32Builder, Int64Builder, ListBuilder, StringBuilder,
+    TimestampMicrosecondBuilder,
+};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use arrow::record_batch::RecordBatch;
+use chrono::{DateTime, Utc};
+use log::info;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_postgres::Client;
+
+// rows fetched from postgres and written as one row group at a time
+const BATCH_SIZE: i64 = 50_000;
+
+// discord epoch, in milliseconds
+const DISCORD_EPOCH: i64 = 1_420_070_400_000;
+
+fn utc_timestamp() -> DataType {
+    DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into()))
+}
+
+fn messages_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("id", DataType::Int64, false),
+        Field::new("channel_id", DataType::Int64, false),
+        Field::new("guild_id", DataType::Int64, true),
+        Field::new("author_id", DataType::Int64, false),
+        Field::new("content", DataType::Utf8, true),
+        Field::new("created_at", utc_timestamp(), false),
+        Field::new("edited_at", utc_timestamp(), true),
+        Field::new("deleted_at", utc_timestamp(), true),
+        Field::new("message_type", DataType::Int32, false),
+        Field::new("flags", DataType::Int64, false),
+        Field::new("referenced_message_id", DataType::Int64, true),
+        Field::new("pinned", DataType::Boolean, false),
+        Field::new("language", DataType::Utf8, true),
+        Field::new(
+            "tags",
+            DataType::List(Arc::new(Field::new("item", DataType::Utf8, true))),
+            false,
+        ),
+        Field::new("attachments", DataType::Utf8, false),
+        Field::new("embeds", DataType::Utf8, false),
+    ]))
+}
+
+fn users_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("id", DataType::Int64, false),
+        Field::new("username", DataType::Utf8, false),
+        Field::new("global_name", DataType::Utf8, true),
+        Field::new("avatar", DataType::Utf8, true),
+        Field::new("bot", DataType::Boolean, false),
+        Field::new("banner", DataType::Utf8, true),
+        Field::new("accent_color", DataType::Int32, true),
+        Field::new("flags", DataType::Int32, true),
+        Field::new("premium_type", DataType::Int32, true),
+        Field::new("public_flags", DataType::Int32, true),
+    ]))
+}
+
+fn memberships_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("user_id", DataType::Int64, false),
+        Field::new("guild_id", DataType::Int64, false),
+    ]))
+}
+
+fn create_writer(dir: &Path, name: &str, schema: SchemaRef) -> BoxedResult<ArrowWriter<File>> {
+    let file = File::create(dir.join(format!("{}.parquet", name)))?;
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::ZSTD(ZstdLevel::default()))
+        .build();
+
+    Ok(ArrowWriter::try_new(file, schema, Some(properties))?)
+}
+
+fn snowflake_micros(id: i64) -> i64 {
+    ((id >> 22) + DISCORD_EPOCH) * 1000
+}
+
+fn timestamp_micros(time: Option<DateTime<Utc>>) -> Option<i64> {
+    time.map(|time| time.timestamp_micros())
+}
+
+fn messages_batch(
+    rows: &[tokio_postgres::Row],
+    anonymizer: Option<&Anonymizer>,
+) -> BoxedResult<RecordBatch> {
+    let mut id = Int64Builder::new();
+    let mut channel_id = Int64Builder::new();
+    let mut guild_id = Int64Builder::new();
+    let mut author_id = Int64Builder::new();
+    let mut content = StringBuilder::new();
+    let mut created_at = TimestampMicrosecondBuilder::new().with_timezone("UTC");
+    let mut edited_at = TimestampMicrosecondBuilder::new().with_timezone("UTC");
+    let mut deleted_at = TimestampMicrosecondBuilder::new().with_timezone("UTC");
+    let mut message_type = Int32Builder::new();
+    let mut flags = Int64Builder::new();
+    let mut referenced_message_id = Int64Builder::new();
+    let mut pinned = BooleanBuilder::new();
+    let mut language = StringBuilder::new();
+    let mut tags = ListBuilder::new(StringBuilder::new());
+    let mut attachments = StringBuilder::new();
+    let mut embeds = StringBuilder::new();
+
+    for row in rows {
+        let message_id: i64 = row.get(0);
+        id.append_value(message_id);
+        channel_id.append_value(row.get(1));
+        guild_id.append_option(row.get::<_, Option<i64>>(2));
+        let user_id: i64 = row.get(3);
+        let text = row.get::<_, Option<String>>(4);
+        match anonymizer {
+            // attachments and embeds are dropped, their urls and names identify people
+            Some(anonymizer) => {
+                author_id.append_value(anonymizer.user_id(user_id));
+                content.append_option(text.map(|text| anonymizer.content(&text)));
+                attachments.append_value("[]");
+                embeds.append_value("[]");
+            }
+            None => {
+                author_id.append_value(user_id);
+                content.append_option(text);
+                attachments.append_value(row.get::<_, String>(13));
+                embeds.append_value(row.get::<_, String>(14));
+            }
+        }
+        created_at.append_value(snowflake_micros(message_id));
+        edited_at.append_option(timestamp_micros(row.get(5)));
+        deleted_at.append_option(timestamp_micros(row.get(6)));
+        message_type.append_value(row.get(7));
+        flags.append_value(row.get(8));
+        referenced_message_id.append_option(row.get::<_, Option<i64>>(9));
+        pinned.append_value(row.get(10));
+        language.append_option(row.get::<_, Option<String>>(11));
+        for tag in row.get::<_, Vec<String>>(12) {
+            tags.values().append_value(tag);
+        }
+        tags.append(true);
+    }
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(id.finish()),
+        Arc::new(channel_id.finish()),
+        Arc::new(guild_id.finish()),
+        Arc::new(author_id.finish()),
+        Arc::new(content.finish()),
+        Arc::new(created_at.finish()),
+        Arc::new(edited_at.finish()),
+        Arc::new(deleted_at.finish()),
+        Arc::new(message_type.finish()),
+        Arc::new(flags.finish()),
+        Arc::new(referenced_message_id.finish()),
+        Arc::new(pinned.finish()),
+        Arc::new(language.finish()),
+        Arc::new(tags.finish()),
+        Arc::new(attachments.finish()),
+        Arc::new(embeds.finish()),
+    ];
+
+    Ok(RecordBatch::try_new(messages_schema(), columns)?)
+}
+
+/// Users and their guild memberships, both built from the same rows
+fn users_batches(
+    rows: &[tokio_postgres::Row],
+    anonymizer: Option<&Anonymizer>,
+) -> BoxedResult<(RecordBatch, RecordBatch)> {
+    let mut id = Int64Builder::new();
+    let mut username = StringBuilder::new();
+    let mut global_name = StringBuilder::new();
+    let mut avatar = StringBuilder::new();
+    let mut bot = BooleanBuilder::new();
+    let mut banner = StringBuilder::new();
+    let mut accent_color = Int32Builder::new();
+    let mut flags = Int32Builder::new();
+    let mut premium_type = Int32Builder::new();
+    let mut public_flags = Int32Builder::new();
+
+    let mut member_user_id = Int64Builder::new();
+    let mut member_guild_id = Int64Builder::new();
+
+    for row in rows {
+        let mut user_id: i64 = row.get(0);
+        match anonymizer {
+            Some(anonymizer) => {
+                username.append_value(anonymizer.username(user_id));
+                user_id = anonymizer.user_id(user_id);
+                global_name.append_null();
+                avatar.append_null();
+                banner.append_null();
+                accent_color.append_null();
+            }
+            None => {
+                username.append_value(row.get::<_, String>(1));
+                global_name.append_option(row.get::<_, Option<String>>(2));
+                avatar.append_option(row.get::<_, Option<String>>(3));
+                banner.append_option(row.get::<_, Option<String>>(5));
+                accent_color.append_option(row.get::<_, Option<i32>>(6));
+            }
+        }
+        id.append_value(user_id);
+        bot.append_value(row.get(4));
+        flags.append_option(row.get::<_, Option<i32>>(7));
+        premium_type.append_option(row.get::<_, Option<i32>>(8));
+        public_flags.append_option(row.get::<_, Option<i32>>(9));
+
+        for guild_id in row.get::<_, Vec<i64>>(10) {
+            member_user_id.append_value(user_id);
+            member_guild_id.append_value(guild_id);
+        }
+    }
+
+    let users: Vec<ArrayRef> = vec![
+        Arc::new(id.finish()),
+        Arc::new(username.finish()),
+        Arc::new(global_name.finish()),
+        Arc::new(avatar.finish()),
+        Arc::new(bot.finish()),
+        Arc::new(banner.finish()),
+        Arc::new(accent_color.finish()),
+        Arc::new(flags.finish()),
+        Arc::new(premium_type.finish()),
+        Arc::new(public_flags.finish()),
+    ];
+    let memberships: Vec<ArrayRef> = vec![
+        Arc::new(member_user_id.finish()),
+        Arc::new(member_guild_id.finish()),
+    ];
+
+    Ok((
+        RecordBatch::try_new(users_schema(), users)?,
+        RecordBatch::try_new(memberships_schema(), memberships)?,
+    ))
+}
+
+async fn export_messages(
+    dir: &Path,
+    channel: Option<u64>,
+    anonymizer: Option<&Anonymizer>,
+    db: &Client,
+) -> BoxedResult<usize> {
+    let mut writer = create_writer(dir, "messages", messages_schema())?;
+    let mut after_id = 0;
+    let mut exported = 0;
+
+    loop {
+        let rows = get_messages_page(after_id, channel, BATCH_SIZE, db).await?;
+        let Some(last) = rows.last() else {
+            break;
+        };
+        after_id = last.get(0);
+
+        writer.write(&messages_batch(&rows, anonymizer)?)?;
+        exported += rows.len();
+        info!("Exported {} messages", exported);
+    }
+
+    writer.close()?;
+    Ok(exported)
+}
+
+async fn export_users(
+    dir: &Path,
+    anonymizer: Option<&Anonymizer>,
+    db: &Client,
+) -> BoxedResult<(usize, usize)> {
+    let mut users_writer = create_writer(dir, "users", users_schema())?;
+    let mut memberships_writer = create_writer(dir, "memberships", memberships_schema())?;
+    let mut after_id = 0;
+    let mut users = 0;
+    let mut memberships = 0;
+
+    loop {
+        let rows = get_users_page(after_id, BATCH_SIZE, db).await?;
+        let Some(last) = rows.last() else {
+            break;
+        };
+        after_id = last.get(0);
+
+        let (users_batch, memberships_batch) = users_batches(&rows, anonymizer)?;
+        users_writer.write(&users_batch)?;
+        memberships_writer.write(&memberships_batch)?;
+        users += users_batch.num_rows();
+        memberships += memberships_batch.num_rows();
+    }
+
+    users_writer.close()?;
+    memberships_writer.close()?;
+    Ok((users, memberships))
+}
+
+/// Writes messages.parquet, users.parquet and memberships.parquet in the output directory
+pub async fn export_tables(
+    output: &str,
+    channel: Option<u64>,
+    salt: Option<&str>,
+    db: &Client,
+) -> BoxedResult<()> {
+    let dir = Path::new(output);
+    std::fs::create_dir_all(dir)?;
+
+    // shared by both tables, so pseudonymous ids still join
+    let anonymizer = salt.map(Anonymizer::new);
+    let messages = export_messages(dir, channel, anonymizer.as_ref(), db).await?;
+    let (users, memberships) = export_users(dir, anonymizer.as_ref(), db).await?;
+
+    info!(
+        "Exported {} messages, {} users and {} memberships to {}",
+        messages,
+        users,
+        memberships,
+        dir.display()
+    );
+
+    Ok(())
+}
@@ -1,65 +1,254 @@
 use crate::BoxedResult;
+use crate::accounts::{self, Account};
+use crate::alerts;
+use crate::audit_log::fetch_audit_logs;
+use crate::backoff::Backoff;
+use crate::config::Config;
+use crate::coordinator;
+use crate::database::{
+    get_member_search_progress, is_connection_lost, is_db_available, lock_db, wait_db_restored,
+};
+use crate::downloader;
+use crate::event_processor::call::*;
 use crate::event_processor::guild::*;
+use crate::event_processor::invite::*;
 use crate::event_processor::message::*;
 use crate::event_processor::misc::*;
+use crate::event_processor::relationship::*;
 use crate::event_processor::user::*;
+use crate::hygiene;
+use crate::maintenance;
+use crate::status::{self, ConnectionState};
+use crate::threads::discover_archived_threads;
 use discord_client_gateway::events::Event;
 use discord_client_gateway::gateway::GatewayClient;
+use discord_client_rest::rest::RestClient;
 use log::{debug, error, info, warn};
+use std::collections::{HashMap, VecDeque};
 use std::sync::atomic::AtomicUsize;
 use std::sync::{Arc, atomic};
 use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
 use tokio_postgres::Client;
+use tracing::{Instrument, Span};
 
-// delay for asking 1000 most recent guild joins (10 minutes)
-const REQUEST_DELAY: Duration = Duration::from_secs(600);
+lazy_static::lazy_static! {
+    static ref PENDING_EVENTS: Mutex<PendingEvents> = Mutex::new(PendingEvents::default());
+    // user id of each connected account, to detect two tokens of the same account
+    static ref CONNECTED_USERS: std::sync::Mutex<HashMap<u64, usize>> =
+        std::sync::Mutex::new(HashMap::new());
+}
+
+// message events received while the database is down, in the order they were received
+#[derive(Default)]
+struct PendingEvents {
+    events: VecDeque<(usize, Event)>,
+    // set while the replay task writes the buffered events, new events queue behind them
+    replaying: bool,
+}
+
+// first delay before reconnecting an account, doubled on each failed attempt
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
+// delay between the audit log fetches of two guilds
+const AUDIT_LOG_GUILD_DELAY: Duration = Duration::from_secs(2);
+// delay between the archived thread discoveries of two guilds
+const THREAD_DISCOVERY_GUILD_DELAY: Duration = Duration::from_secs(5);
+// delay between leaving two guilds
+const GUILD_LEAVE_DELAY: Duration = Duration::from_secs(10);
 
 pub async fn handle_account(
-    token: String,
+    account: Account,
     account_index: usize,
     db_client: Option<Arc<Mutex<Client>>>,
     build_number: u32,
 ) -> BoxedResult<()> {
+    let mut backoff = Backoff::new(
+        RECONNECT_BASE_DELAY,
+        Duration::from_secs(Config::get().reconnect_max_delay),
+    );
+
+    status::register_account(account_index, account.name(account_index));
+    let _claim = AccountClaim(account_index);
+
     loop {
-        info!("Connecting account {} ...", account_index);
+        info!("Connecting account {} ...", account.name(account_index));
+        status::set_account_state(account_index, ConnectionState::Connecting);
 
-        let mut gateway_client =
-            GatewayClient::connect(token.clone(), true, 53607934, build_number)
-                .await
-                .map_err(|e| format!("Gateway error for account {}: {}", account_index, e))?;
+        let connection =
+            GatewayClient::connect(account.token.clone(), true, 53607934, build_number).await;
+        let mut gateway_client = match connection {
+            Ok(gateway_client) => gateway_client,
+            Err(e) => {
+                let error = e.to_string();
+                if accounts::is_dead_token_error(&error) {
+                    accounts::quarantine(&account, account_index, &error, &db_client).await;
+                    return Err(
+                        format!("Gateway error for account {}: {}", account_index, error).into(),
+                    );
+                }
+                let delay = backoff.next_delay();
+                status::set_account_state(account_index, ConnectionState::Reconnecting);
+                error!(
+                    "Account {} : Gateway connection failed, retrying in {:?}: {}",
+                    account_index, delay, error
+                );
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+        };
 
         info!("Account {} connected successfully", account_index);
 
+        // used to fetch the pinned messages on pin updates and the audit logs
+        let rest_client =
+            match RestClient::connect(account.token.clone(), Some(9), Some(build_number)).await {
+                Ok(rest_client) => Arc::new(rest_client),
+                Err(e) => {
+                    let _ = gateway_client.close().await;
+                    let delay = backoff.next_delay();
+                    status::set_account_state(account_index, ConnectionState::Reconnecting);
+                    error!(
+                        "Account {} : REST connection failed, retrying in {:?}: {}",
+                        account_index, delay, e
+                    );
+                    tokio::time::sleep(delay).await;
+                    continue;
+                }
+            };
+
+        downloader::set_refresh_client(Arc::clone(&rest_client)).await;
+
+        let member_search_interval = Config::get().member_search_interval;
         let mut last_request = Instant::now();
         let ids: Arc<Mutex<Vec<u64>>> = Arc::new(Mutex::new(Vec::new()));
         let id_index: AtomicUsize = AtomicUsize::new(0);
+        // user of the token, known once READY is received
+        let mut user_id: Option<u64> = None;
+
+        let audit_log_task = spawn_audit_log_task(
+            account_index,
+            Arc::clone(&rest_client),
+            Arc::clone(&ids),
+            db_client.clone(),
+        );
+        let thread_discovery_task = spawn_thread_discovery_task(
+            account_index,
+            Arc::clone(&rest_client),
+            Arc::clone(&ids),
+            db_client.clone(),
+        );
+        let guild_hygiene_task = spawn_guild_hygiene_task(
+            account_index,
+            account.name(account_index),
+            Arc::clone(&rest_client),
+            Arc::clone(&ids),
+            db_client.clone(),
+        );
 
         loop {
             let event = gateway_client.next_event().await;
+            if event.is_ok() {
+                status::record_event(account_index);
+            }
             match event {
                 Ok(Event::Ready(ready)) => {
+                    backoff.reset();
+
+                    if !claim_account(ready.user.id, account_index) {
+                        warn!(
+                            "Account {} : User {} is already connected by another token, disabling this session",
+                            account_index, ready.user.id
+                        );
+                        if let Some(task) = audit_log_task {
+                            task.abort();
+                        }
+                        if let Some(task) = thread_discovery_task {
+                            task.abort();
+                        }
+                        if let Some(task) = guild_hygiene_task {
+                            task.abort();
+                        }
+                        let _ = gateway_client.close().await;
+                        return Ok(());
+                    }
+
+                    user_id = Some(ready.user.id);
                     let guilds = ready.guilds;
 
                     if let Some(ref db) = db_client {
-                        let client = db.lock().await;
-                        process_ready_guilds(&guilds, &ready.merged_members, &ready.users, &client)
-                            .await?;
+                        let client = lock_db(db).await;
+                        // a failed write is logged, returning would stop the account for good
+                        if let Err(e) = process_ready_guilds(
+                            &guilds,
+                            &ready.merged_members,
+                            &ready.users,
+                            &client,
+                            db,
+                        )
+                        .await
+                        {
+                            error!("Account {} : Error saving guilds: {}", account_index, e);
+                        }
+                        match serde_json::to_value(&ready.relationships) {
+                            Ok(relationships) => {
+                                if let Err(e) = process_ready_relationships(
+                                    ready.user.id,
+                                    &relationships,
+                                    &client,
+                                )
+                                .await
+                                {
+                                    error!(
+                                        "Account {} : Error saving relationships: {}",
+                                        account_index, e
+                                    );
+                                }
+                            }
+                            Err(e) => error!(
+                                "Account {} : Error saving relationships: {}",
+                                account_index, e
+                            ),
+                        }
+                        if let Err(e) = process_private_channels(
+                            &ready.private_channels,
+                            ready.user.id,
+                            &client,
+                        )
+                        .await
+                        {
+                            error!(
+                                "Account {} : Error saving private channels: {}",
+                                account_index, e
+                            );
+                        }
                     }
 
                     ids.lock().await.clear();
 
                     for guild in guilds {
                         let guild_id = guild.id;
-                        ids.lock().await.push(guild_id);
+                        if account.allows_guild(Some(guild_id)) {
+                            ids.lock().await.push(guild_id);
+                        }
                     }
 
+                    // guilds shared with other accounts are subscribed by a single one
+                    let owned =
+                        coordinator::register_account(account_index, &ids.lock().await).await;
+
                     let count = ids.lock().await.len();
+                    let subscribed = owned.len();
+                    status::set_account_guilds(account_index, count);
+                    status::set_account_state(account_index, ConnectionState::Connected);
                     gateway_client
-                        .bulk_guild_subscribe(ids.lock().await.clone())
+                        .bulk_guild_subscribe(owned)
                         .await
                         .map_err(|e| format!("Error subscribing to guilds: {}", e))?;
-                    debug!("Account {} : Subscribed to {} guilds", account_index, count);
+                    debug!(
+                        "Account {} : Subscribed to {}/{} guilds",
+                        account_index, subscribed, count
+                    );
 
                     if count > id_index.load(atomic::Ordering::Relaxed) {
                         id_index.store(0, atomic::Ordering::Relaxed);
@@ -67,50 +256,167 @@ pub async fn handle_account(
                 }
                 Ok(Event::ReadySupplemental(ready_supplemental)) => {
                     if let Some(ref db) = db_client {
-                        let client = db.lock().await;
-                        process_ready_supplemental(&ready_supplemental, &client).await?;
+                        let client = lock_db(db).await;
+                        if let Err(e) =
+                            process_ready_supplemental(&ready_supplemental, &client).await
+                        {
+                            error!(
+                                "Account {} : Error saving READY_SUPPLEMENTAL: {}",
+                                account_index, e
+                            );
+                        }
+                        if let Some(account_id) = user_id
+                            && let Err(e) = process_private_channels(
+                                &ready_supplemental.lazy_private_channels,
+                                account_id,
+                                &client,
+                            )
+                            .await
+                        {
+                            error!(
+                                "Account {} : Error saving private channels: {}",
+                                account_index, e
+                            );
+                        }
                     }
                 }
-                Ok(Event::MessageCreate(msg_create)) => {
-                    if let Err(e) = process_message_create(&msg_create, &db_client).await {
-                        warn!(
-                            "Account {} : Error processing message: {}",
+                Ok(event) if is_message_event(&event) => {
+                    let guild_id = message_event_guild_id(&event);
+                    // the channel may be processed by another account or outside the allowlist
+                    if account.allows_guild(guild_id)
+                        && coordinator::is_channel_owner(
+                            account_index,
+                            guild_id,
+                            message_event_channel_id(&event),
+                        )
+                        .await
+                    {
+                        if let Some(event) = queue_behind_pending(account_index, event).await {
+                            let span = message_event_span(&event);
+                            let failed = process_message_event(&event, account_index, &db_client)
+                                .instrument(span)
+                                .await;
+                            // the connection may have dropped since the last check of the
+                            // reconnect task, the event is kept for the replay instead of being lost
+                            if failed
+                                && let Some(db) = &db_client
+                                && is_connection_lost(db).await
+                            {
+                                buffer_event(account_index, event).await;
+                            }
+                        }
+                    }
+                }
+                Ok(Event::ChannelCreate(channel_create))
+                    if is_private_channel(&channel_create.channel) =>
+                {
+                    if let (Some(db), Some(account_id)) = (&db_client, user_id) {
+                        let client = lock_db(db).await;
+                        if let Err(e) = process_private_channels(
+                            &[channel_create.channel.clone()],
+                            account_id,
+                            &client,
+                        )
+                        .await
+                        {
+                            error!(
+                                "Account {} : Error saving private channel: {}",
+                                account_index, e
+                            );
+                        }
+                    }
+                }
+                Ok(Event::ChannelCreate(channel_create)) => {
+                    if let Err(e) = process_channel_create(&channel_create, &db_client).await {
+                        error!("Account {} : Error creating channel: {}", account_index, e);
+                    }
+                }
+                Ok(Event::ChannelUpdate(channel_update)) => {
+                    if let Err(e) = process_channel_update(&channel_update, &db_client).await {
+                        error!("Account {} : Error updating channel: {}", account_index, e);
+                    }
+                }
+                Ok(Event::ChannelDelete(channel_delete)) => {
+                    if let Err(e) = process_channel_delete(&channel_delete, &db_client).await {
+                        error!("Account {} : Error deleting channel: {}", account_index, e);
+                    }
+                }
+                Ok(Event::ChannelPinsUpdate(pins_update)) => {
+                    if let Err(e) =
+                        process_channel_pins_update(&pins_update, &rest_client, &db_client).await
+                    {
+                        error!("Account {} : Error updating pins: {}", account_index, e);
+                    }
+                }
+                Ok(Event::GuildScheduledEventCreate(event_create)) => {
+                    if let Err(e) =
+                        process_scheduled_event_upsert(&event_create.scheduled_event, &db_client)
+                            .await
+                    {
+                        error!(
+                            "Account {} : Error creating scheduled event: {}",
                             account_index, e
                         );
                     }
                 }
-                Ok(Event::MessageUpdate(msg_update)) => {
-                    if let Err(e) = process_message_update(&msg_update, &db_client).await {
-                        error!("Account {} : Error updating message: {}", account_index, e);
+                Ok(Event::GuildScheduledEventUpdate(event_update)) => {
+                    if let Err(e) =
+                        process_scheduled_event_upsert(&event_update.scheduled_event, &db_client)
+                            .await
+                    {
+                        error!(
+                            "Account {} : Error updating scheduled event: {}",
+                            account_index, e
+                        );
                     }
                 }
-                Ok(Event::MessageDelete(msg_delete)) => {
-                    if let Err(e) = process_message_delete(&msg_delete, &db_client).await {
-                        error!("Account {} : Error deleting message: {}", account_index, e);
+                Ok(Event::GuildScheduledEventDelete(event_delete)) => {
+                    if let Err(e) = process_scheduled_event_delete(&event_delete, &db_client).await
+                    {
+                        error!(
+                            "Account {} : Error deleting scheduled event: {}",
+                            account_index, e
+                        );
                     }
                 }
-                Ok(Event::MessageDeleteBulk(msg_delete_bulk)) => {
-                    if let Err(e) = process_message_delete_bulk(&msg_delete_bulk, &db_client).await
+                Ok(Event::StageInstanceCreate(stage_create)) => {
+                    if let Err(e) =
+                        process_stage_instance_upsert(&stage_create.stage_instance, &db_client)
+                            .await
                     {
                         error!(
-                            "Account {} : Error deleting bulk messages: {}",
+                            "Account {} : Error creating stage instance: {}",
                             account_index, e
                         );
                     }
                 }
-                Ok(Event::ChannelCreate(channel_create)) => {
-                    if let Err(e) = process_channel_create(&channel_create, &db_client).await {
-                        error!("Account {} : Error creating channel: {}", account_index, e);
+                Ok(Event::StageInstanceUpdate(stage_update)) => {
+                    if let Err(e) =
+                        process_stage_instance_upsert(&stage_update.stage_instance, &db_client)
+                            .await
+                    {
+                        error!(
+                            "Account {} : Error updating stage instance: {}",
+                            account_index, e
+                        );
                     }
                 }
-                Ok(Event::ChannelUpdate(channel_update)) => {
-                    if let Err(e) = process_channel_update(&channel_update, &db_client).await {
-                        error!("Account {} : Error updating channel: {}", account_index, e);
+                Ok(Event::StageInstanceDelete(stage_delete)) => {
+                    if let Err(e) = process_stage_instance_delete(&stage_delete, &db_client).await {
+                        error!(
+                            "Account {} : Error deleting stage instance: {}",
+                            account_index, e
+                        );
                     }
                 }
-                Ok(Event::ChannelDelete(channel_delete)) => {
-                    if let Err(e) = process_channel_delete(&channel_delete, &db_client).await {
-                        error!("Account {} : Error deleting channel: {}", account_index, e);
+                Ok(Event::InviteCreate(invite_create)) => {
+                    if let Err(e) = process_invite_create(&invite_create, &db_client).await {
+                        error!("Account {} : Error creating invite: {}", account_index, e);
+                    }
+                }
+                Ok(Event::InviteDelete(invite_delete)) => {
+                    if let Err(e) = process_invite_delete(&invite_delete, &db_client).await {
+                        error!("Account {} : Error deleting invite: {}", account_index, e);
                     }
                 }
                 Ok(Event::GuildRoleCreate(role_create)) => {
@@ -144,61 +450,613 @@ pub async fn handle_account(
                         );
                     }
                 }
+                Ok(Event::GuildEmojisUpdate(emojis_update)) => {
+                    if let Err(e) = process_emojis_update(&emojis_update, &db_client).await {
+                        error!("Account {} : Error updating emojis: {}", account_index, e);
+                    }
+                }
+                Ok(Event::GuildStickersUpdate(stickers_update)) => {
+                    if let Err(e) = process_stickers_update(&stickers_update, &db_client).await {
+                        error!("Account {} : Error updating stickers: {}", account_index, e);
+                    }
+                }
                 Ok(Event::GuildBanAdd(guild_ban_add)) => {
-                    warn!(
-                        "Guild {} banned user {}",
-                        guild_ban_add.guild_id, guild_ban_add.user.id
-                    );
-                    // create debug file with banned user
-                    let file_name = format!("banned_user_{}.txt", guild_ban_add.guild_id);
-                    if let Err(e) = std::fs::write(
-                        file_name,
-                        format!(
-                            "Guild ID: {}\nUser ID: {}\nUsername: {}",
-                            guild_ban_add.guild_id,
-                            guild_ban_add.user.id,
-                            guild_ban_add.user.username,
-                        ),
-                    ) {
-                        error!("Failed to write banned user file: {}", e);
+                    let guild_id = guild_ban_add.guild_id;
+                    let user = &guild_ban_add.user;
+                    let own_account = user_id == Some(user.id);
+                    info!("Guild {} banned user {}", guild_id, user.id);
+
+                    if let Err(e) = process_guild_ban(guild_id, user, own_account, &db_client).await
+                    {
+                        error!("Account {} : Error saving ban: {}", account_index, e);
+                    }
+                }
+                Ok(Event::GuildCreate(guild_create)) => match serde_json::to_value(&guild_create) {
+                    Ok(guild) => {
+                        if let Err(e) = process_guild_upsert(&guild, &db_client).await {
+                            error!("Account {} : Error saving guild: {}", account_index, e);
+                        }
+                    }
+                    Err(e) => error!("Account {} : Error saving guild: {}", account_index, e),
+                },
+                Ok(Event::GuildUpdate(guild_update)) => match serde_json::to_value(&guild_update) {
+                    Ok(guild) => {
+                        if let Err(e) = process_guild_upsert(&guild, &db_client).await {
+                            error!("Account {} : Error updating guild: {}", account_index, e);
+                        }
+                    }
+                    Err(e) => error!("Account {} : Error updating guild: {}", account_index, e),
+                },
+                // the removed account only receives a GUILD_DELETE, not the ban
+                Ok(Event::GuildDelete(guild_delete)) => {
+                    let removed = serde_json::to_value(&guild_delete)
+                        .ok()
+                        .and_then(|guild| removed_guild_id(&guild));
+                    let mut guild_ids = ids.lock().await;
+                    // guilds left by the hygiene task are already gone
+                    if let Some(guild_id) = removed
+                        && guild_ids.contains(&guild_id)
+                    {
+                        guild_ids.retain(|id| *id != guild_id);
+                        status::set_account_guilds(account_index, guild_ids.len());
+                        drop(guild_ids);
+
+                        coordinator::leave_guild(account_index, guild_id).await;
+                        warn!(
+                            "Account {} was removed from guild {}",
+                            account_index, guild_id
+                        );
+                        alerts::send(&format!(
+                            "Account {} was removed from guild {} (kicked or banned)",
+                            account.name(account_index),
+                            guild_id
+                        ))
+                        .await;
                     }
                 }
 
+                Ok(Event::CallCreate(call_create)) => match serde_json::to_value(&call_create) {
+                    Ok(call) => {
+                        if let Err(e) = process_call(&call, &db_client).await {
+                            error!("Account {} : Error saving call: {}", account_index, e);
+                        }
+                    }
+                    Err(e) => error!("Account {} : Error saving call: {}", account_index, e),
+                },
+                Ok(Event::CallUpdate(call_update)) => match serde_json::to_value(&call_update) {
+                    Ok(call) => {
+                        if let Err(e) = process_call(&call, &db_client).await {
+                            error!("Account {} : Error updating call: {}", account_index, e);
+                        }
+                    }
+                    Err(e) => error!("Account {} : Error updating call: {}", account_index, e),
+                },
+                Ok(Event::CallDelete(call_delete)) => match serde_json::to_value(&call_delete) {
+                    Ok(call) => {
+                        if let Err(e) = process_call_delete(&call, &db_client).await {
+                            error!("Account {} : Error ending call: {}", account_index, e);
+                        }
+                    }
+                    Err(e) => error!("Account {} : Error ending call: {}", account_index, e),
+                },
+                Ok(Event::RelationshipAdd(relationship_add)) => {
+                    if let Some(account_id) = user_id {
+                        match serde_json::to_value(&relationship_add) {
+                            Ok(relationship) => {
+                                if let Err(e) =
+                                    process_relationship_add(account_id, &relationship, &db_client)
+                                        .await
+                                {
+                                    error!(
+                                        "Account {} : Error saving relationship: {}",
+                                        account_index, e
+                                    );
+                                }
+                            }
+                            Err(e) => error!(
+                                "Account {} : Error saving relationship: {}",
+                                account_index, e
+                            ),
+                        }
+                    }
+                }
+                Ok(Event::RelationshipRemove(relationship_remove)) => {
+                    if let Some(account_id) = user_id {
+                        match serde_json::to_value(&relationship_remove) {
+                            Ok(relationship) => {
+                                if let Err(e) = process_relationship_remove(
+                                    account_id,
+                                    &relationship,
+                                    &db_client,
+                                )
+                                .await
+                                {
+                                    error!(
+                                        "Account {} : Error removing relationship: {}",
+                                        account_index, e
+                                    );
+                                }
+                            }
+                            Err(e) => error!(
+                                "Account {} : Error removing relationship: {}",
+                                account_index, e
+                            ),
+                        }
+                    }
+                }
+                Err(e) if accounts::is_dead_token_error(&e.to_string()) => {
+                    accounts::quarantine(&account, account_index, &e.to_string(), &db_client).await;
+                    if let Some(task) = audit_log_task {
+                        task.abort();
+                    }
+                    if let Some(task) = thread_discovery_task {
+                        task.abort();
+                    }
+                    if let Some(task) = guild_hygiene_task {
+                        task.abort();
+                    }
+                    let _ = gateway_client.close().await;
+                    return Err(format!("Token of account {} is dead", account_index).into());
+                }
+                // any other gateway error reconnects from scratch with backoff
                 Err(e) => {
                     error!("Event error account {}: {}", account_index, e);
-                    // if client error (Connect) break the loop to reconnect
-                    if e.to_string().contains("client error (Connect)") {
-                        info!("Reconnecting account {} in 5 seconds...", account_index);
-                        tokio::time::sleep(Duration::from_secs(5)).await;
-                        break;
+                    break;
+                }
+                Ok(event) => {
+                    if let Err(e) = process_raw_event(&event, account_index, &db_client).await {
+                        error!("Account {} : Error saving raw event: {}", account_index, e);
                     }
                 }
-                _ => (),
             }
 
-            if db_client.is_some() {
-                if Instant::now().duration_since(last_request) >= REQUEST_DELAY {
-                    let index = id_index.load(atomic::Ordering::Relaxed);
-                    if let Some(guild_id) = ids.lock().await.get(index) {
-                        if let Err(e) = gateway_client
-                            .search_recent_members(*guild_id, "", None, None)
-                            .await
-                        {
-                            error!(
-                                "Account {} : Error requesting guild members: {}",
-                                account_index, e
-                            );
+            // guilds of a disconnected account
+            let handed_over = coordinator::take_pending_subscriptions(account_index).await;
+            if !handed_over.is_empty() {
+                let count = handed_over.len();
+                match gateway_client.bulk_guild_subscribe(handed_over).await {
+                    Ok(_) => info!(
+                        "Account {} : Subscribed to {} guilds of a disconnected account",
+                        account_index, count
+                    ),
+                    Err(e) => error!(
+                        "Account {} : Error subscribing to handed over guilds: {}",
+                        account_index, e
+                    ),
+                }
+            }
+
+            if let Some(db) = &db_client
+                && member_search_interval > 0
+                && last_request.elapsed() >= Duration::from_secs(member_search_interval)
+            {
+                let index = id_index.load(atomic::Ordering::Relaxed);
+                let guild_id = ids.lock().await.get(index).copied();
+                if let Some(guild_id) = guild_id {
+                    let progress = get_member_search_progress(guild_id, &*lock_db(db).await).await;
+                    match progress {
+                        Ok((continuation_token, completed)) => {
+                            // guilds listed once only get their recent joins
+                            let (continuation_token, nonce) = match completed {
+                                true => (None, None),
+                                false => {
+                                    (continuation_token, Some(MEMBER_SEARCH_NONCE.to_string()))
+                                }
+                            };
+                            if let Err(e) = gateway_client
+                                .search_recent_members(guild_id, "", continuation_token, nonce)
+                                .await
+                            {
+                                error!(
+                                    "Account {} : Error requesting guild members: {}",
+                                    account_index, e
+                                );
+                            }
                         }
+                        Err(e) => error!(
+                            "Account {} : Error reading member search progress of guild {}: {}",
+                            account_index, guild_id, e
+                        ),
                     }
+                }
 
-                    if index + 1 >= ids.lock().await.len() {
-                        id_index.store(0, atomic::Ordering::Relaxed);
-                    } else {
-                        id_index.fetch_add(1, atomic::Ordering::Relaxed);
+                if index + 1 >= ids.lock().await.len() {
+                    id_index.store(0, atomic::Ordering::Relaxed);
+                } else {
+                    id_index.fetch_add(1, atomic::Ordering::Relaxed);
+                }
+                last_request = Instant::now();
+            }
+        }
+
+        if let Some(task) = audit_log_task {
+            task.abort();
+        }
+        if let Some(task) = thread_discovery_task {
+            task.abort();
+        }
+        if let Some(task) = guild_hygiene_task {
+            task.abort();
+        }
+        let _ = gateway_client.close().await;
+        coordinator::unregister_account(account_index).await;
+
+        let delay = backoff.next_delay();
+        status::set_account_state(account_index, ConnectionState::Reconnecting);
+        info!("Reconnecting account {} in {:?}...", account_index, delay);
+        tokio::time::sleep(delay).await;
+    }
+}
+
+fn spawn_audit_log_task(
+    account_index: usize,
+    rest_client: Arc<RestClient>,
+    guild_ids: Arc<Mutex<Vec<u64>>>,
+    db_client: Option<Arc<Mutex<Client>>>,
+) -> Option<JoinHandle<()>> {
+    let interval = Config::get().audit_log_interval;
+    let db_client = db_client?;
+    if interval == 0 {
+        return None;
+    }
+
+    Some(tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(interval * 60)).await;
+
+            let guild_ids = guild_ids.lock().await.clone();
+            for guild_id in guild_ids {
+                // most guilds don't give the audit log permission, so errors are expected
+                if let Err(e) = fetch_audit_logs(&rest_client, guild_id, &db_client).await {
+                    debug!("Account {} : {}", account_index, e);
+                }
+
+                tokio::time::sleep(AUDIT_LOG_GUILD_DELAY).await;
+            }
+        }
+    }))
+}
+
+fn spawn_guild_hygiene_task(
+    account_index: usize,
+    account_name: String,
+    rest_client: Arc<RestClient>,
+    guild_ids: Arc<Mutex<Vec<u64>>>,
+    db_client: Option<Arc<Mutex<Client>>>,
+) -> Option<JoinHandle<()>> {
+    let rules = Config::get().guild_hygiene.clone()?;
+
+    Some(tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(rules.interval * 3600)).await;
+
+            let ids = guild_ids.lock().await.clone();
+            for guild_id in ids {
+                let reason = match hygiene::leave_reason(guild_id, &rules, &db_client).await {
+                    Ok(Some(reason)) => reason,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        error!(
+                            "Account {} : Error checking guild {}: {}",
+                            account_index, guild_id, e
+                        );
+                        continue;
+                    }
+                };
+
+                if rules.dry_run {
+                    info!(
+                        "Account {} : Would leave guild {} ({})",
+                        account_index, guild_id, reason
+                    );
+                    continue;
+                }
+
+                // removed first so the GUILD_DELETE of the leave isn't taken for a kick
+                guild_ids.lock().await.retain(|id| *id != guild_id);
+                if let Err(e) = hygiene::leave_guild(&rest_client, guild_id).await {
+                    error!("Account {} : {}", account_index, e);
+                    guild_ids.lock().await.push(guild_id);
+                    continue;
+                }
+                coordinator::leave_guild(account_index, guild_id).await;
+                status::set_account_guilds(account_index, guild_ids.lock().await.len());
+                alerts::send(&format!(
+                    "Account {} left guild {} ({})",
+                    account_name, guild_id, reason
+                ))
+                .await;
+
+                tokio::time::sleep(GUILD_LEAVE_DELAY).await;
+            }
+        }
+    }))
+}
+
+fn spawn_thread_discovery_task(
+    account_index: usize,
+    rest_client: Arc<RestClient>,
+    guild_ids: Arc<Mutex<Vec<u64>>>,
+    db_client: Option<Arc<Mutex<Client>>>,
+) -> Option<JoinHandle<()>> {
+    let interval = Config::get().thread_discovery_interval;
+    let db_client = db_client?;
+    if interval == 0 {
+        return None;
+    }
+
+    Some(tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(interval * 60)).await;
+
+            let guild_ids = guild_ids.lock().await.clone();
+            for guild_id in guild_ids {
+                // the guild may be processed by another account
+                if !coordinator::is_owner(account_index, Some(guild_id)).await {
+                    continue;
+                }
+
+                maintenance::wait_for_window("archived thread discovery").await;
+                if let Err(e) = discover_archived_threads(&rest_client, guild_id, &db_client).await
+                {
+                    error!(
+                        "Account {} : Error discovering archived threads: {}",
+                        account_index, e
+                    );
+                }
+
+                tokio::time::sleep(THREAD_DISCOVERY_GUILD_DELAY).await;
+            }
+        }
+    }))
+}
+
+/// Returns false when another account index is already connected as this user
+fn claim_account(user_id: u64, account_index: usize) -> bool {
+    let mut connected = CONNECTED_USERS.lock().unwrap();
+    match connected.get(&user_id) {
+        Some(index) => *index == account_index,
+        None => {
+            connected.insert(user_id, account_index);
+            true
+        }
+    }
+}
+
+// releases the user claimed by an account once its handler exits, whichever way it exits, so
+// another token of the same user can take over
+struct AccountClaim(usize);
+
+impl Drop for AccountClaim {
+    fn drop(&mut self) {
+        // panicking again while unwinding would abort
+        let mut connected = CONNECTED_USERS.lock().unwrap_or_else(|e| e.into_inner());
+        connected.retain(|_, account_index| *account_index != self.0);
+    }
+}
+
+fn message_event_guild_id(event: &Event) -> Option<u64> {
+    match event {
+        Event::MessageCreate(msg_create) => msg_create.guild_id,
+        Event::MessageUpdate(msg_update) => msg_update.guild_id,
+        Event::MessageDelete(msg_delete) => msg_delete.guild_id,
+        Event::MessageDeleteBulk(msg_delete_bulk) => msg_delete_bulk.guild_id,
+        Event::MessagePollVoteAdd(vote_add) => vote_add.guild_id,
+        Event::MessagePollVoteRemove(vote_remove) => vote_remove.guild_id,
+        _ => None,
+    }
+}
+
+// poll votes only carry the message id
+fn message_event_channel_id(event: &Event) -> Option<u64> {
+    match event {
+        Event::MessageCreate(msg_create) => Some(msg_create.message.channel_id),
+        Event::MessageUpdate(msg_update) => Some(msg_update.message.channel_id),
+        Event::MessageDelete(msg_delete) => Some(msg_delete.channel_id),
+        Event::MessageDeleteBulk(msg_delete_bulk) => Some(msg_delete_bulk.channel_id),
+        _ => None,
+    }
+}
+
+// fields of the logs written while processing a message event
+fn message_event_span(event: &Event) -> Span {
+    let event_type = match event {
+        Event::MessageCreate(_) => "message_create",
+        Event::MessageUpdate(_) => "message_update",
+        Event::MessageDelete(_) => "message_delete",
+        Event::MessageDeleteBulk(_) => "message_delete_bulk",
+        Event::MessagePollVoteAdd(_) => "message_poll_vote_add",
+        Event::MessagePollVoteRemove(_) => "message_poll_vote_remove",
+        _ => "unknown",
+    };
+
+    tracing::info_span!(
+        "event",
+        event_type,
+        guild_id = message_event_guild_id(event),
+        channel_id = message_event_channel_id(event)
+    )
+}
+
+fn is_message_event(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::MessageCreate(_)
+            | Event::MessageUpdate(_)
+            | Event::MessageDelete(_)
+            | Event::MessageDeleteBulk(_)
+            | Event::MessagePollVoteAdd(_)
+            | Event::MessagePollVoteRemove(_)
+    )
+}
+
+// returns whether the event failed to be written
+async fn process_message_event(
+    event: &Event,
+    account_index: usize,
+    db_client: &Option<Arc<Mutex<Client>>>,
+) -> bool {
+    match event {
+        Event::MessageCreate(msg_create) => {
+            match process_message_create(msg_create, db_client).await {
+                Err(e) => {
+                    warn!(
+                        "Account {} : Error processing message: {}",
+                        account_index, e
+                    );
+                    true
+                }
+                Ok(_) => false,
+            }
+        }
+        Event::MessageUpdate(msg_update) => {
+            match process_message_update(msg_update, db_client).await {
+                Err(e) => {
+                    error!("Account {} : Error updating message: {}", account_index, e);
+                    true
+                }
+                Ok(_) => false,
+            }
+        }
+        Event::MessageDelete(msg_delete) => {
+            match process_message_delete(msg_delete, db_client).await {
+                Err(e) => {
+                    error!("Account {} : Error deleting message: {}", account_index, e);
+                    true
+                }
+                Ok(_) => false,
+            }
+        }
+        Event::MessageDeleteBulk(msg_delete_bulk) => {
+            match process_message_delete_bulk(msg_delete_bulk, db_client).await {
+                Err(e) => {
+                    error!(
+                        "Account {} : Error deleting bulk messages: {}",
+                        account_index, e
+                    );
+                    true
+                }
+                Ok(_) => false,
+            }
+        }
+        Event::MessagePollVoteAdd(vote_add) => {
+            match process_poll_vote_add(vote_add, db_client).await {
+                Err(e) => {
+                    error!("Account {} : Error saving poll vote: {}", account_index, e);
+                    true
+                }
+                Ok(_) => false,
+            }
+        }
+        Event::MessagePollVoteRemove(vote_remove) => {
+            match process_poll_vote_remove(vote_remove, db_client).await {
+                Err(e) => {
+                    error!(
+                        "Account {} : Error removing poll vote: {}",
+                        account_index, e
+                    );
+                    true
+                }
+                Ok(_) => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+// keeps message events received while the database is down, to replay them once it's back.
+// Other events aren't buffered, guilds, channels and members are synced again on the next READY
+async fn buffer_event(account_index: usize, event: Event) {
+    let mut pending = PENDING_EVENTS.lock().await;
+    push_pending(&mut pending, account_index, event);
+}
+
+/// Buffers the event while the database is down or older events are being replayed, so the
+/// events of a message are written in the order they were received. Returns the event when it
+/// can be processed right away.
+async fn queue_behind_pending(account_index: usize, event: Event) -> Option<Event> {
+    let mut pending = PENDING_EVENTS.lock().await;
+    if is_db_available() && !pending.replaying && pending.events.is_empty() {
+        return Some(event);
+    }
+
+    push_pending(&mut pending, account_index, event);
+    None
+}
+
+fn push_pending(pending: &mut PendingEvents, account_index: usize, event: Event) {
+    let limit = Config::get().db_buffer_limit;
+    if pending.events.len() >= limit {
+        if limit == 0 {
+            return;
+        }
+        pending.events.pop_front();
+    }
+
+    pending.events.push_back((account_index, event));
+    status::set_buffered_events(pending.events.len());
+    if pending.events.len() == limit {
+        warn!(
+            "Database still down, buffer full ({} events), dropping the oldest events",
+            limit
+        );
+    }
+}
+
+// writes the buffered events one at a time, the events received meanwhile wait behind them
+async fn replay_buffered_events(db: &Arc<Mutex<Client>>) {
+    let db_client = Some(Arc::clone(db));
+    let mut replayed = 0;
+
+    loop {
+        let (account_index, event) = {
+            let mut pending = PENDING_EVENTS.lock().await;
+            match pending.events.pop_front() {
+                Some(entry) => {
+                    if !pending.replaying {
+                        info!(
+                            "Replaying {} events buffered during the database outage",
+                            pending.events.len() + 1
+                        );
+                    }
+                    pending.replaying = true;
+                    status::set_buffered_events(pending.events.len());
+                    entry
+                }
+                None => {
+                    pending.replaying = false;
+                    if replayed > 0 {
+                        info!("Replayed {} buffered events", replayed);
                     }
-                    last_request = Instant::now();
+                    return;
                 }
             }
+        };
+
+        let span = message_event_span(&event);
+        let failed = process_message_event(&event, account_index, &db_client)
+            .instrument(span)
+            .await;
+
+        if failed && is_connection_lost(db).await {
+            // down again, the event goes back in front until the next restore
+            let mut pending = PENDING_EVENTS.lock().await;
+            pending.events.push_front((account_index, event));
+            pending.replaying = false;
+            status::set_buffered_events(pending.events.len());
+            return;
         }
+        replayed += 1;
     }
 }
+
+/// Replays the buffered message events as soon as the database is back, the only place they
+/// are replayed from so they keep their order
+pub fn spawn_replay_task(db: Arc<Mutex<Client>>) {
+    tokio::spawn(async move {
+        loop {
+            wait_db_restored().await;
+            replay_buffered_events(&db).await;
+        }
+    });
+}
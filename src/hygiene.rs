@@ -0,0 +1,145 @@
+use crate::BoxedResult;
+use crate::config::GuildHygieneConfig;
+use crate::database::{get_guild_activity, is_db_available, lock_db};
+use crate::timezone::time_to_snowflake;
+use chrono::{DateTime, Utc};
+use discord_client_rest::rest::RestClient;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio_postgres::Client;
+
+/// Why the guild should be left under the rules, None when it's kept
+pub async fn leave_reason(
+    guild_id: u64,
+    rules: &GuildHygieneConfig,
+    db_client: &Option<Arc<Mutex<Client>>>,
+) -> BoxedResult<Option<String>> {
+    if rules.kept_guilds.contains(&guild_id) {
+        return Ok(None);
+    }
+    if rules.blocked_guilds.contains(&guild_id) {
+        return Ok(Some("blocked".to_string()));
+    }
+
+    // the stats of a database that is down would make every guild look dead
+    let Some(db_client) = db_client else {
+        return Ok(None);
+    };
+    if !is_db_available() {
+        return Ok(None);
+    }
+
+    let window = chrono::Duration::days(rules.activity_days as i64);
+    let since = Utc::now() - window;
+    let activity = {
+        let db = lock_db(db_client).await;
+        get_guild_activity(guild_id, time_to_snowflake(since), &db).await?
+    };
+    let Some((member_count, first_seen, messages)) = activity else {
+        return Ok(None);
+    };
+
+    Ok(activity_reason(
+        rules,
+        member_count,
+        first_seen,
+        messages,
+        since,
+    ))
+}
+
+fn activity_reason(
+    rules: &GuildHygieneConfig,
+    member_count: Option<u64>,
+    first_seen: DateTime<Utc>,
+    messages: f64,
+    since: DateTime<Utc>,
+) -> Option<String> {
+    if let (Some(min), Some(count)) = (rules.min_member_count, member_count)
+        && count < min
+    {
+        return Some(format!("{} members, below {}", count, min));
+    }
+
+    // the activity is only known once the guild was watched for the whole window
+    if let Some(min) = rules.min_messages_per_day
+        && first_seen <= since
+    {
+        let per_day = messages / rules.activity_days as f64;
+        if per_day < min {
+            return Some(format!("{:.1} messages per day, below {}", per_day, min));
+        }
+    }
+
+    None
+}
+
+pub async fn leave_guild(rest_client: &RestClient, guild_id: u64) -> BoxedResult<()> {
+    rest_client
+        .user(None)
+        .leave_guild(guild_id)
+        .await
+        .map_err(|e| format!("Error leaving guild {}: {}", guild_id, e))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn rules() -> GuildHygieneConfig {
+        toml::from_str(
+            r#"
+            blocked_guilds = [1]
+            kept_guilds = [2]
+            min_member_count = 50
+            min_messages_per_day = 2.0
+            activity_days = 10
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn listed_guilds_skip_the_activity_rules() {
+        let rules = rules();
+        assert_eq!(
+            leave_reason(1, &rules, &None).await.unwrap().as_deref(),
+            Some("blocked")
+        );
+        assert_eq!(leave_reason(2, &rules, &None).await.unwrap(), None);
+        // without a database nothing is known about the others
+        assert_eq!(leave_reason(3, &rules, &None).await.unwrap(), None);
+    }
+
+    #[test]
+    fn small_guilds_are_left() {
+        let since = Utc::now() - Duration::days(10);
+        assert_eq!(
+            activity_reason(&rules(), Some(12), since, 100.0, since).as_deref(),
+            Some("12 members, below 50")
+        );
+        assert_eq!(activity_reason(&rules(), None, since, 100.0, since), None);
+    }
+
+    #[test]
+    fn quiet_guilds_are_left_once_watched_for_the_whole_window() {
+        let since = Utc::now() - Duration::days(10);
+        assert_eq!(
+            activity_reason(&rules(), Some(500), since, 5.0, since).as_deref(),
+            Some("0.5 messages per day, below 2")
+        );
+        assert_eq!(
+            activity_reason(&rules(), Some(500), since, 30.0, since),
+            None
+        );
+        // joined a day ago, too early to judge
+        let joined = Utc::now() - Duration::days(1);
+        assert_eq!(
+            activity_reason(&rules(), Some(500), joined, 0.0, since),
+            None
+        );
+    }
+}
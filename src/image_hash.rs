@@ -0,0 +1,189 @@
+use crate::BoxedResult;
+use crate::database::{find_similar_images, get_unhashed_images, save_image_hashes};
+use crate::maintenance;
+use crate::timezone;
+use image::imageops::FilterType;
+use log::{info, warn};
+use std::f64::consts::PI;
+use std::path::Path;
+use tokio_postgres::Client;
+
+const BACKFILL_BATCH_SIZE: i64 = 500;
+// side of the image the DCT of the pHash is computed on
+const PHASH_SIZE: usize = 32;
+// lowest frequencies kept in the pHash
+const PHASH_FREQUENCIES: usize = 8;
+
+pub struct ImageHashes {
+    pub phash: u64,
+    pub dhash: u64,
+}
+
+/// pHash (low frequencies of the DCT) and dHash (horizontal gradient) of the image
+pub fn hash_file(path: &Path) -> Result<ImageHashes, image::ImageError> {
+    let image = image::open(path)?;
+
+    let small = image
+        .resize_exact(PHASH_SIZE as u32, PHASH_SIZE as u32, FilterType::Triangle)
+        .to_luma8();
+    let pixels: Vec<f64> = small.pixels().map(|pixel| pixel.0[0] as f64).collect();
+
+    let gradient = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut dhash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let left = gradient.get_pixel(x, y).0[0];
+            let right = gradient.get_pixel(x + 1, y).0[0];
+            dhash = (dhash << 1) | (left < right) as u64;
+        }
+    }
+
+    Ok(ImageHashes {
+        phash: phash(&pixels),
+        dhash,
+    })
+}
+
+fn phash(pixels: &[f64]) -> u64 {
+    let mut cosines = [[0f64; PHASH_SIZE]; PHASH_FREQUENCIES];
+    for (frequency, row) in cosines.iter_mut().enumerate() {
+        for (position, cosine) in row.iter_mut().enumerate() {
+            *cosine =
+                ((2 * position + 1) as f64 * frequency as f64 * PI / (2 * PHASH_SIZE) as f64).cos();
+        }
+    }
+
+    // 2D DCT-II, only for the lowest frequencies
+    let mut coefficients = Vec::with_capacity(PHASH_FREQUENCIES * PHASH_FREQUENCIES);
+    for vertical in &cosines {
+        for horizontal in &cosines {
+            let sum: f64 = pixels
+                .chunks(PHASH_SIZE)
+                .zip(vertical)
+                .map(|(row, cos_y)| {
+                    row.iter()
+                        .zip(horizontal)
+                        .map(|(pixel, cos_x)| pixel * cos_x)
+                        .sum::<f64>()
+                        * cos_y
+                })
+                .sum();
+            coefficients.push(sum);
+        }
+    }
+
+    // the DC coefficient is the average brightness, it would dominate the median
+    let mut sorted: Vec<f64> = coefficients[1..].to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let median = sorted[sorted.len() / 2];
+
+    coefficients.iter().fold(0u64, |hash, coefficient| {
+        (hash << 1) | (*coefficient > median) as u64
+    })
+}
+
+pub fn format_hash(hash: u64) -> String {
+    format!("{:016x}", hash)
+}
+
+fn parse_hash(value: &str) -> Option<u64> {
+    if value.len() != 16 {
+        return None;
+    }
+    u64::from_str_radix(value, 16).ok()
+}
+
+/// Hashes a downloaded image off the async runtime, errors are logged
+pub async fn hash_image(attachment_id: u64, path: String) -> Option<ImageHashes> {
+    match tokio::task::spawn_blocking(move || hash_file(Path::new(&path))).await {
+        Ok(Ok(hashes)) => Some(hashes),
+        Ok(Err(e)) => {
+            warn!("Failed to hash attachment {}: {}", attachment_id, e);
+            None
+        }
+        Err(e) => {
+            warn!("Hashing of attachment {} panicked: {}", attachment_id, e);
+            None
+        }
+    }
+}
+
+/// Hashes the downloaded images that were saved before hashing existed
+pub async fn backfill(limit: Option<usize>, db: &Client) -> BoxedResult<()> {
+    let mut after_id = 0;
+    let mut hashed = 0;
+    let mut failed = 0;
+
+    loop {
+        maintenance::wait_for_window("image hashing").await;
+
+        let images = get_unhashed_images(after_id, BACKFILL_BATCH_SIZE, db).await?;
+        let Some((last_id, _)) = images.last() else {
+            break;
+        };
+        after_id = *last_id;
+
+        for (attachment_id, path) in images {
+            let file = path.clone();
+            match tokio::task::spawn_blocking(move || hash_file(Path::new(&file))).await? {
+                Ok(hashes) => {
+                    save_image_hashes(attachment_id, hashes.phash, hashes.dhash, db).await?;
+                    hashed += 1;
+                }
+                Err(e) => {
+                    warn!("Failed to hash {}: {}", path, e);
+                    failed += 1;
+                }
+            }
+
+            if limit.is_some_and(|limit| hashed >= limit) {
+                info!("Hashed {} images", hashed);
+                return Ok(());
+            }
+        }
+
+        info!("Hashed {} images", hashed);
+    }
+
+    info!("Image hashing done, {} images could not be read", failed);
+
+    Ok(())
+}
+
+/// Prints the stored images close to a file or a pHash
+pub async fn find_similar(
+    target: String,
+    max_distance: i32,
+    limit: i64,
+    db: &Client,
+) -> BoxedResult<()> {
+    let phash = match parse_hash(&target) {
+        Some(hash) => hash,
+        None => {
+            let hashes = hash_file(Path::new(&target))
+                .map_err(|e| format!("{} is neither a hash nor a readable image: {}", target, e))?;
+            println!(
+                "pHash {} dHash {}",
+                format_hash(hashes.phash),
+                format_hash(hashes.dhash)
+            );
+            hashes.phash
+        }
+    };
+
+    let matches = find_similar_images(phash, max_distance, limit, db).await?;
+    for image in &matches {
+        println!(
+            "[distance {}] {} attachment {} of message {}\n    {}",
+            image.distance,
+            timezone::format_snowflake(image.attachment_id),
+            image.attachment_id,
+            image.message_id,
+            image.path.as_deref().unwrap_or("(not downloaded)")
+        );
+    }
+
+    println!("{} result(s)", matches.len());
+
+    Ok(())
+}
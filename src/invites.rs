@@ -0,0 +1,66 @@
+use crate::BoxedResult;
+use crate::database::{get_unresolved_invites, mark_invite_invalid, save_resolved_invite};
+use discord_client_rest::rest::RestClient;
+use log::{error, info, warn};
+use regex::Regex;
+use std::time::Duration;
+use tokio_postgres::Client;
+
+// delay between two invite lookups
+const RESOLVE_DELAY: Duration = Duration::from_millis(1500);
+
+lazy_static::lazy_static! {
+    static ref INVITE_REGEX: Regex = Regex::new(
+        r"(?i)(?:https?://)?(?:www\.)?(?:discord(?:app)?\.com/invite|discord\.gg)/([a-z0-9-]{2,32})"
+    )
+    .unwrap();
+}
+
+pub fn extract_invite_codes(content: &str) -> Vec<String> {
+    let mut codes: Vec<String> = Vec::new();
+
+    for capture in INVITE_REGEX.captures_iter(content) {
+        let code = capture[1].to_string();
+        if !codes.contains(&code) {
+            codes.push(code);
+        }
+    }
+
+    codes
+}
+
+pub async fn resolve_invites(token: String, limit: i64, db: &Client) -> BoxedResult<()> {
+    let codes = get_unresolved_invites(limit, db).await?;
+    if codes.is_empty() {
+        info!("No invites left to resolve");
+        return Ok(());
+    }
+
+    info!("Resolving {} invites...", codes.len());
+
+    let rest_client = RestClient::connect(token, Some(9), None)
+        .await
+        .map_err(|e| format!("Error connecting to Discord REST API: {}", e))?;
+
+    let mut resolved = 0;
+    for code in &codes {
+        match rest_client.invite(code).get_invite(true).await {
+            Ok(invite) => {
+                save_resolved_invite(&invite, db).await?;
+                resolved += 1;
+            }
+            Err(e) => {
+                warn!("Invite {} could not be resolved: {}", code, e);
+                if let Err(e) = mark_invite_invalid(code, db).await {
+                    error!("Failed to mark invite {} as invalid: {}", code, e);
+                }
+            }
+        }
+
+        tokio::time::sleep(RESOLVE_DELAY).await;
+    }
+
+    info!("Resolved {}/{} invites", resolved, codes.len());
+
+    Ok(())
+}
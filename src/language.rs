@@ -0,0 +1,53 @@
+use crate::BoxedResult;
+use crate::database::{get_messages_for_language_backfill, set_message_languages};
+use crate::maintenance;
+use log::info;
+use tokio_postgres::Client;
+use whatlang::detect;
+
+// shorter messages are mostly emotes and slang, too short to detect reliably
+const MIN_CHARS: usize = 12;
+const BACKFILL_BATCH_SIZE: i64 = 1000;
+
+/// ISO 639-3 code of the message language, e.g. "eng", when it can be told reliably
+pub fn detect_language(content: &str) -> Option<&'static str> {
+    if content.chars().filter(|c| c.is_alphabetic()).count() < MIN_CHARS {
+        return None;
+    }
+
+    detect(content)
+        .filter(|info| info.is_reliable())
+        .map(|info| info.lang().code())
+}
+
+/// Detects the language of stored messages that don't have one yet
+pub async fn backfill(db: &Client) -> BoxedResult<()> {
+    let mut before_id = i64::MAX;
+    let mut detected = 0;
+
+    loop {
+        maintenance::wait_for_window("language detection").await;
+
+        let messages =
+            get_messages_for_language_backfill(before_id, BACKFILL_BATCH_SIZE, db).await?;
+        let Some((last_id, _)) = messages.last() else {
+            break;
+        };
+        before_id = *last_id;
+
+        let languages: Vec<(i64, String)> = messages
+            .iter()
+            .filter_map(|(id, content)| {
+                detect_language(content).map(|language| (*id, language.to_string()))
+            })
+            .collect();
+
+        set_message_languages(&languages, db).await?;
+        detected += languages.len();
+        info!("Detected the language of {} messages", detected);
+    }
+
+    info!("Language backfill done");
+
+    Ok(())
+}
@@ -0,0 +1,71 @@
+use crate::BoxedResult;
+use crate::status::StatusLayer;
+use crate::telemetry;
+use clap::ValueEnum;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+// used when neither --log-filter nor RUST_LOG are set
+const DEFAULT_FILTER: &str = "slurpslurp=debug";
+
+// set while the dashboard owns the terminal
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Pretty,
+    /// One JSON object per line, with the fields of the account and event being processed
+    Json,
+}
+
+/// Installs the global logger. `filter` takes `RUST_LOG` directives, like
+/// `slurpslurp=info,slurpslurp::downloader=debug`.
+pub fn init(format: LogFormat, filter: Option<&str>) -> BoxedResult<()> {
+    let filter = match filter {
+        Some(filter) => EnvFilter::try_new(filter)?,
+        None => {
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER))
+        }
+    };
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(StatusLayer::new())
+        .with(telemetry::layer());
+
+    match format {
+        LogFormat::Pretty => registry
+            .with(tracing_subscriber::fmt::layer().with_writer(writer))
+            .try_init()?,
+        LogFormat::Json => registry
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .flatten_event(true)
+                    .with_current_span(false)
+                    .with_span_list(true)
+                    .with_writer(writer),
+            )
+            .try_init()?,
+    }
+
+    Ok(())
+}
+
+/// Stops printing the logs, they would draw over the dashboard
+#[cfg_attr(not(feature = "dashboard"), allow(dead_code))]
+pub fn silence() {
+    QUIET.store(true, Ordering::Relaxed);
+}
+
+fn writer() -> Box<dyn Write> {
+    if QUIET.load(Ordering::Relaxed) {
+        Box::new(io::sink())
+    } else {
+        Box::new(io::stderr())
+    }
+}
@@ -1,14 +1,66 @@
+mod accounts;
+mod alerts;
+mod api;
+mod audit_log;
+mod backoff;
+mod bench;
 mod cli;
+mod clickhouse;
 mod config;
+mod coordinator;
+#[cfg(feature = "dashboard")]
+mod dashboard;
 mod database;
+mod diff;
+mod discovery;
 mod downloader;
+mod embeddings;
 mod event_processor;
+mod export;
 mod handler;
+mod hygiene;
+mod image_hash;
+mod invites;
+mod language;
+mod links;
+mod logging;
+mod maintenance;
+mod media;
+mod media_metadata;
+mod member_list;
+mod migrations;
+mod mirror;
+mod nsfw;
+mod previews;
+mod progress;
+mod prune;
+mod query;
+mod ratelimit;
+mod references;
+mod sampling;
+mod scanner;
+mod scoring;
 mod scraper;
-
-use crate::cli::{Cli, Mode};
+mod search_index;
+mod server;
+mod stats;
+mod status;
+mod stream;
+mod systemd;
+mod tagging;
+mod telemetry;
+mod threads;
+mod thumbnails;
+mod timezone;
+mod transcription;
+mod web_archive;
+
+use crate::accounts::Account;
+use crate::cli::{Cli, Mode, TokensAction};
 use crate::config::Config;
-use crate::database::connect_db;
+use crate::database::{
+    MessageFilter, PruneFilter, StatsScope, connect_db, get_dead_token_ids, lock_db,
+};
 use crate::handler::handle_account;
 use crate::scraper::*;
 use clap::Parser;
@@ -19,29 +71,53 @@ use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::Mutex;
 use tokio_postgres::Client;
+use tracing::Instrument;
 
 type BoxedError = Box<dyn Error + Send + Sync>;
 type BoxedResult<T> = Result<T, BoxedError>;
 
 #[tokio::main]
 async fn main() -> BoxedResult<()> {
-    pretty_env_logger::formatted_builder()
-        .filter(None, log::LevelFilter::Off)
-        .filter_module("slurpslurp", log::LevelFilter::Debug)
-        .init();
-
     let cli = Cli::parse();
+    logging::init(cli.log_format, cli.log_filter.as_deref())?;
     if cli.help {
         todo!("Implement clap-help functionality");
     }
 
-    let mode = cli.mode.unwrap_or(Mode::Sniff);
+    let mode = cli.mode.unwrap_or(Mode::Sniff {
+        dashboard: false,
+        health_bind: None,
+    });
 
-    if let Err(e) = Config::init() {
+    if let Err(e) = Config::init(cli.config.as_deref()) {
         error!("Error initializing config: {}", e);
         std::process::exit(1);
     }
 
+    let timezone = cli
+        .timezone
+        .as_deref()
+        .or(Config::get().timezone.as_deref());
+    if let Err(e) = timezone::init(timezone) {
+        error!("Error initializing timezone: {}", e);
+        std::process::exit(1);
+    }
+
+    if let Err(e) = tagging::init() {
+        error!("Error initializing tag rules: {}", e);
+        std::process::exit(1);
+    }
+
+    if let Err(e) = maintenance::init() {
+        error!("Error initializing maintenance windows: {}", e);
+        std::process::exit(1);
+    }
+
+    if let Err(e) = prune::check_rules() {
+        error!("Error initializing retention rules: {}", e);
+        std::process::exit(1);
+    }
+
     let db_client = if Config::get().use_db {
         Some(Arc::new(Mutex::new(connect_db().await.map_err(|e| {
             format!("Error connecting to database: {}", e)
@@ -50,82 +126,350 @@ async fn main() -> BoxedResult<()> {
         None
     };
 
-    let setup_script = include_str!("../sql_scripts/setup.sql");
     if let Some(ref db) = db_client {
-        let client = db.lock().await;
-        client
-            .batch_execute(setup_script)
+        let mut client = lock_db(db).await;
+        migrations::run(&mut client)
             .await
-            .map_err(|e| format!("Error executing setup script: {}", e))?;
+            .map_err(|e| format!("Error migrating the database: {}", e))?;
+
+        debug!("Database migrations applied successfully");
 
-        debug!("Database setup script executed successfully");
+        if Config::get().embeddings.is_some() {
+            embeddings::setup(&client).await?;
+            debug!("Embeddings setup script executed successfully");
+        }
+    }
+
+    if let Some(ref db) = db_client {
+        database::spawn_reconnect_task(Arc::clone(db));
     }
 
     match mode {
-        Mode::Sniff => start_sniff(db_client).await?,
+        Mode::Sniff {
+            dashboard,
+            health_bind,
+        } => {
+            if let Some(bind) = health_bind {
+                server::start_health(&bind).await?;
+            }
+            start_sniff(db_client, dashboard).await?
+        }
+        Mode::Serve { bind, no_sniff, ui } => {
+            let server = server::start(&bind, ui, db_client.clone()).await?;
+            if no_sniff {
+                systemd::notify("READY=1");
+                server.await?;
+            } else {
+                start_sniff(db_client, false).await?;
+            }
+        }
         Mode::Scrape {
-            target_type,
-            id,
-            tokens,
+            target,
+            dry_run,
+            progress,
+        } => {
+            let (target_type, id, tokens, mut options) = target.into_parts();
+            options.progress = progress;
+            start_scrape(target_type, id, tokens, options, dry_run, db_client).await?;
+        }
+        Mode::Tokens { action } => match action {
+            TokensAction::Check => {
+                let client = match db_client {
+                    Some(ref db) => Some(lock_db(db).await),
+                    None => None,
+                };
+                accounts::check(client.as_deref()).await?;
+            }
+        },
+        Mode::FindMedia {
+            name,
+            mime,
+            min_size,
+            tag,
+            min_width,
+            min_height,
+            min_duration,
+            limit,
         } => {
-            start_scrape(target_type, id, tokens, db_client).await?;
+            let db = db_client.ok_or("find-media requires use_db to be enabled")?;
+            let client = lock_db(&db).await;
+            media::find_media(
+                name,
+                mime,
+                min_size,
+                tag,
+                min_width,
+                min_height,
+                min_duration,
+                limit,
+                &client,
+            )
+            .await?;
+        }
+        Mode::FindSimilar {
+            target,
+            max_distance,
+            limit,
+        } => {
+            let db = db_client.ok_or("find-similar requires use_db to be enabled")?;
+            let client = lock_db(&db).await;
+            image_hash::find_similar(target, max_distance, limit, &client).await?;
+        }
+        Mode::HashImages { limit } => {
+            let db = db_client.ok_or("hash-images requires use_db to be enabled")?;
+            let client = lock_db(&db).await;
+            image_hash::backfill(limit, &client).await?;
+        }
+        Mode::ClassifyImages { limit } => {
+            let db = db_client.ok_or("classify-images requires use_db to be enabled")?;
+            let client = lock_db(&db).await;
+            nsfw::backfill(limit, &client).await?;
+        }
+        Mode::Transcribe { limit } => {
+            let db = db_client.ok_or("transcribe requires use_db to be enabled")?;
+            let client = lock_db(&db).await;
+            transcription::backfill(limit, &client).await?;
+        }
+        Mode::ArchiveLinks { limit } => {
+            let db = db_client.ok_or("archive-links requires use_db to be enabled")?;
+            let count = web_archive::archive_links(limit, &db).await?;
+            info!("Link archiving done, {} pages saved", count);
+        }
+        Mode::ResolveInvites { token, limit } => {
+            let db = db_client.ok_or("resolve-invites requires use_db to be enabled")?;
+            let client = lock_db(&db).await;
+            invites::resolve_invites(token, limit, &client).await?;
+        }
+        Mode::RepairReferences { token, limit } => {
+            let db = db_client.ok_or("repair-references requires use_db to be enabled")?;
+            references::repair(token, limit, db).await?;
+        }
+        Mode::ScrapeMembers {
+            guild_id,
+            token,
+            channel,
+        } => {
+            let db = db_client.ok_or("scrape-members requires use_db to be enabled")?;
+            member_list::scrape_members(token, guild_id, channel, db).await?;
+        }
+        Mode::EnrichDiscovery { token, limit } => {
+            let db = db_client.ok_or("enrich-discovery requires use_db to be enabled")?;
+            let client = lock_db(&db).await;
+            discovery::enrich_guilds(token, limit, &client).await?;
+        }
+        Mode::PreviewGuilds { token, limit } => {
+            let db = db_client.ok_or("preview-guilds requires use_db to be enabled")?;
+            let client = lock_db(&db).await;
+            previews::fetch_previews(token, limit, &client).await?;
+        }
+        Mode::Bench {
+            rate,
+            duration,
+            download_url,
+        } => {
+            bench::run_bench(rate, duration, download_url, db_client).await?;
+        }
+        Mode::Query {
+            text,
+            author,
+            channel,
+            after,
+            before,
+            limit,
+            semantic,
+            json,
+        } => {
+            let db = db_client.ok_or("query requires use_db to be enabled")?;
+            let client = lock_db(&db).await;
+            let (min_id, max_id) = query::snowflake_range(after, before);
+            let filter = MessageFilter {
+                text,
+                author_id: author,
+                channel_id: channel,
+                min_id,
+                max_id,
+                limit,
+            };
+            query::query_messages(filter, semantic, json, &client).await?;
+        }
+        Mode::Embed { limit } => {
+            let db = db_client.ok_or("embed requires use_db to be enabled")?;
+            let client = lock_db(&db).await;
+            embeddings::backfill(limit, &client).await?;
+        }
+        Mode::ScoreMessages { limit } => {
+            let db = db_client.ok_or("score-messages requires use_db to be enabled")?;
+            let client = lock_db(&db).await;
+            scoring::backfill(limit, &client).await?;
+        }
+        Mode::Index { after } => {
+            let db = db_client.ok_or("index requires use_db to be enabled")?;
+            let client = lock_db(&db).await;
+            search_index::backfill(after, &client).await?;
+        }
+        Mode::DetectLanguages => {
+            let db = db_client.ok_or("detect-languages requires use_db to be enabled")?;
+            let client = lock_db(&db).await;
+            language::backfill(&client).await?;
+        }
+        Mode::Export {
+            format,
+            channel,
+            output,
+            anonymize: _,
+            salt,
+        } => {
+            let db = db_client.ok_or("export requires use_db to be enabled")?;
+            let client = lock_db(&db).await;
+            export::export(format, channel, output, salt.as_deref(), &client).await?;
+        }
+        Mode::Stats {
+            guild,
+            channel,
+            user,
+            publishable,
+            min_count,
+            json,
+        } => {
+            let db = db_client.ok_or("stats requires use_db to be enabled")?;
+            let client = lock_db(&db).await;
+            let scope = StatsScope {
+                guild_id: guild,
+                channel_id: channel,
+                author_id: user,
+            };
+            stats::print_stats(scope, publishable, min_count, json, &client).await?;
+        }
+        Mode::Prune {
+            older_than,
+            guild,
+            channel,
+            only_bots,
+            orphaned_files,
+            dry_run,
+        } => {
+            let db = db_client.ok_or("prune requires use_db to be enabled")?;
+            let filter = PruneFilter {
+                before_id: older_than.map(prune::age_to_snowflake),
+                guild_id: guild,
+                channel_id: channel,
+                only_bots,
+            };
+            prune::prune(filter, orphaned_files, dry_run, &db).await?;
         }
     }
 
+    telemetry::shutdown();
+
     Ok(())
 }
 
-async fn start_sniff(db_client: Option<Arc<Mutex<Client>>>) -> BoxedResult<()> {
+async fn start_sniff(db_client: Option<Arc<Mutex<Client>>>, dashboard: bool) -> BoxedResult<()> {
+    #[cfg(not(feature = "dashboard"))]
+    if dashboard {
+        return Err("slurpslurp was built without the dashboard feature".into());
+    }
+
     info!("Starting sniff mode...");
 
-    if !std::path::Path::new("downloads").exists() {
-        std::fs::create_dir("downloads")?;
-        debug!("Created downloads directory");
+    let download_dir = downloader::download_root();
+    if !std::path::Path::new(download_dir).exists() {
+        std::fs::create_dir_all(download_dir)?;
+        debug!("Created {} directory", download_dir);
+    }
+
+    stream::init()
+        .await
+        .map_err(|e| format!("Error connecting to the event stream: {}", e))?;
+
+    clickhouse::init()
+        .await
+        .map_err(|e| format!("Error connecting to ClickHouse: {}", e))?;
+
+    search_index::init()
+        .await
+        .map_err(|e| format!("Error connecting to the search engine: {}", e))?;
+
+    alerts::spawn_alert_task();
+    if let Some(ref db) = db_client {
+        handler::spawn_replay_task(Arc::clone(db));
     }
+    prune::spawn_retention_task(db_client.clone());
+    web_archive::spawn_archive_task(db_client.clone());
 
-    let tokens_content = std::fs::read_to_string("tokens.txt")
-        .map_err(|e| format!("Error reading tokens.txt: {}", e))?;
+    let dead_token_ids = match db_client {
+        Some(ref db) => get_dead_token_ids(&*lock_db(db).await).await?,
+        None => Vec::new(),
+    };
 
-    let tokens: Vec<String> = tokens_content
-        .lines()
-        .map(|line| line.trim().to_string())
-        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    let accounts: Vec<Account> = accounts::load()?
+        .into_iter()
+        .filter(|account| account.can_sniff())
+        .filter(|account| {
+            let token_id = accounts::token_id(&account.token);
+            if !dead_token_ids.contains(&token_id) {
+                return true;
+            }
+            warn!(
+                "Skipping account {}, its token is dead, run `tokens check` once it's fixed",
+                account.label.as_deref().unwrap_or(&token_id)
+            );
+            false
+        })
         .collect();
 
-    if tokens.is_empty() {
-        error!("No tokens found in tokens.txt");
+    if accounts.is_empty() {
+        error!("No enabled sniff account found in tokens.toml");
         return Err("No valid tokens".into());
     }
 
-    info!("Starting {} accounts", tokens.len());
+    info!("Starting {} accounts", accounts.len());
 
     let mut handles = Vec::new();
 
-    let rest_client = RestClient::connect(tokens.get(0).unwrap().clone(), Some(9), None)
+    let rest_client = RestClient::connect(accounts[0].token.clone(), Some(9), None)
         .await
         .map_err(|e| format!("Error connecting to Discord REST API: {}", e))?;
 
     let build_number = rest_client.build_number;
     debug!("Retrieved latest client build number: {}", build_number);
 
-    for (index, token) in tokens.into_iter().enumerate() {
+    for (index, account) in accounts.into_iter().enumerate() {
         let db_client_clone = if let Some(ref db) = db_client {
             Some(Arc::clone(db))
         } else {
             None
         };
 
-        let handle = tokio::spawn(async move {
-            if let Err(e) = handle_account(token, index, db_client_clone, build_number).await {
-                error!("Error with account {}: {}", index, e);
+        let name = account.name(index);
+        // every log of the account carries its index and name
+        let span = tracing::info_span!("account", index, name = %name);
+        let handle = tokio::spawn(
+            async move {
+                if let Err(e) = handle_account(account, index, db_client_clone, build_number).await
+                {
+                    error!("Error with account {}: {}", name, e);
+                }
+                status::mark_stopped(index);
+                coordinator::unregister_account(index).await;
             }
-        });
+            .instrument(span),
+        );
 
         handles.push(handle);
 
         tokio::time::sleep(Duration::from_millis(600)).await;
     }
 
+    systemd::notify("READY=1");
+    systemd::spawn_watchdog_task(db_client);
+
+    #[cfg(feature = "dashboard")]
+    if dashboard {
+        // the accounts keep running in the background until the dashboard is closed
+        return dashboard::run().await;
+    }
+
     for handle in handles {
         if let Err(e) = handle.await {
             error!("Error in task: {}", e);
@@ -139,27 +483,49 @@ async fn start_scrape(
     target_type: ScrapeType,
     id: u64,
     tokens: Vec<String>,
+    options: ScrapeOptions,
+    dry_run: bool,
     db_client: Option<Arc<Mutex<Client>>>,
 ) -> BoxedResult<()> {
+    // without tokens on the command line, the scrape accounts of tokens.toml are used
+    let tokens = if tokens.is_empty() {
+        let guild_id = (target_type == ScrapeType::Guild).then_some(id);
+        accounts::load()?
+            .into_iter()
+            .filter(|account| account.can_scrape() && account.allows_guild(guild_id))
+            .map(|account| account.token)
+            .collect()
+    } else {
+        tokens
+    };
+
     if tokens.is_empty() {
         error!("No tokens provided for scraping");
         return Err("No valid tokens".into());
     }
 
     info!("Starting scrape mode...");
-    if target_type == ScrapeType::Guild && tokens.len() < 3 {
+    if target_type == ScrapeType::Guild
+        && options.strategy == GuildStrategy::Search
+        && !options.is_filtered()
+        && tokens.len() < 3
+    {
         warn!(
-            "Guild scraping is way slower than channel scraping with a low amount of tokens. I'd recommend to run multiple channel scrapers instead."
+            "Guild scraping is way slower than channel scraping with a low amount of tokens. I'd recommend `--strategy channels` or running multiple channel scrapers instead."
         );
     }
 
-    let scraper = Scraper::new(tokens, id, target_type, db_client).await;
+    let scraper = Scraper::new(tokens, id, target_type, options, db_client).await;
 
     if scraper.bots.is_empty() {
         error!("No valid bots connected for scraping");
         return Err("No valid bots".into());
     }
 
+    if dry_run {
+        return scraper.estimate().await;
+    }
+
     info!("Starting scraping with {} bots", scraper.bots.len());
 
     if let Err(e) = scraper.start().await {
@@ -0,0 +1,94 @@
+use crate::config::Config;
+use crate::timezone;
+use chrono::{DateTime, Datelike, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+use log::info;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Window {
+    start: NaiveTime,
+    end: NaiveTime,
+    days: Vec<Weekday>,
+}
+
+static WINDOWS: OnceLock<Vec<Window>> = OnceLock::new();
+
+fn parse_time(time: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .map_err(|_| format!("Invalid maintenance window time '{}', expected HH:MM", time))
+}
+
+pub fn init() -> Result<(), String> {
+    let mut windows = Vec::new();
+
+    for window in &Config::get().maintenance_windows {
+        let start = parse_time(&window.start)?;
+        let end = parse_time(&window.end)?;
+        if start == end {
+            return Err(format!(
+                "Maintenance window starts and ends at {}",
+                window.start
+            ));
+        }
+
+        let days = window
+            .days
+            .iter()
+            .map(|day| {
+                day.parse::<Weekday>()
+                    .map_err(|_| format!("Invalid maintenance window day '{}'", day))
+            })
+            .collect::<Result<_, _>>()?;
+
+        windows.push(Window { start, end, days });
+    }
+
+    WINDOWS
+        .set(windows)
+        .map_err(|_| "Maintenance windows already initialized".to_string())
+}
+
+impl Window {
+    fn starts_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    fn contains(&self, now: DateTime<Tz>) -> bool {
+        let time = now.time();
+        let today = now.weekday();
+
+        if self.start < self.end {
+            self.starts_on(today) && time >= self.start && time < self.end
+        } else {
+            // spans midnight, the end belongs to the window started the day before
+            (self.starts_on(today) && time >= self.start)
+                || (self.starts_on(today.pred()) && time < self.end)
+        }
+    }
+}
+
+/// Whether heavy jobs may run now, always true without configured windows
+pub fn is_open() -> bool {
+    let Some(windows) = WINDOWS.get().filter(|windows| !windows.is_empty()) else {
+        return true;
+    };
+
+    let now = timezone::to_local(Utc::now());
+    windows.iter().any(|window| window.contains(now))
+}
+
+/// Waits until a maintenance window is open
+pub async fn wait_for_window(job: &str) {
+    if is_open() {
+        return;
+    }
+
+    info!("Waiting for a maintenance window to run {}", job);
+    while !is_open() {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+    info!("Maintenance window open, running {}", job);
+}
@@ -0,0 +1,98 @@
+use crate::BoxedResult;
+use crate::database::{MediaFilter, MediaMatch, search_attachments};
+use crate::downloader;
+use crate::timezone;
+use tokio_postgres::Client;
+
+fn to_like_pattern(pattern: &str, wrap: bool) -> String {
+    let escaped = pattern
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    let like = escaped.replace('*', "%").replace('?', "_");
+
+    if wrap && !pattern.contains(['*', '?']) {
+        format!("%{}%", like)
+    } else {
+        like
+    }
+}
+
+pub async fn find_media(
+    name: Option<String>,
+    mime: Option<String>,
+    min_size: Option<u64>,
+    tag: Option<String>,
+    min_width: Option<u32>,
+    min_height: Option<u32>,
+    min_duration: Option<f64>,
+    limit: i64,
+    db: &Client,
+) -> BoxedResult<()> {
+    let filter = MediaFilter {
+        name_pattern: name.as_deref().map(|name| to_like_pattern(name, true)),
+        mime_pattern: mime.as_deref().map(|mime| to_like_pattern(mime, false)),
+        min_size: min_size.map(|size| size as i64),
+        tag,
+        min_width: min_width.map(|width| width as i32),
+        min_height: min_height.map(|height| height as i32),
+        min_duration,
+        limit,
+    };
+
+    let matches = search_attachments(&filter, db).await?;
+
+    if matches.is_empty() {
+        println!("No matching media found");
+        return Ok(());
+    }
+
+    for media in &matches {
+        let path = media
+            .path
+            .clone()
+            .or_else(|| guess_local_path(media))
+            .unwrap_or_else(|| "(not downloaded)".to_string());
+
+        let mut properties = String::new();
+        if let (Some(width), Some(height)) = (media.width, media.height) {
+            properties.push_str(&format!("\t{}x{}", width, height));
+        }
+        if let Some(duration) = media.duration {
+            properties.push_str(&format!("\t{:.1}s", duration));
+        }
+
+        println!(
+            "{}\t{}\t{}\t{}\t{}{}\tmessage {} in channel {} by {}",
+            timezone::format_snowflake(media.message_id as u64),
+            path,
+            media.filename,
+            media.size.unwrap_or_default(),
+            media.content_type.as_deref().unwrap_or("unknown"),
+            properties,
+            media.message_id,
+            media.channel_id,
+            media.author_id
+        );
+    }
+
+    println!("{} result(s)", matches.len());
+
+    Ok(())
+}
+
+// files downloaded before the attachments table existed follow the `downloads/<mime>/<id>_<name>` layout
+fn guess_local_path(media: &MediaMatch) -> Option<String> {
+    let folder = format!(
+        "{}/{}",
+        downloader::download_root(),
+        media.content_type.as_deref()?.split(';').next()?.trim()
+    );
+    let prefix = format!("{}_", media.attachment_id);
+
+    std::fs::read_dir(&folder)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .find(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+        .map(|entry| entry.path().to_string_lossy().into_owned())
+}
@@ -0,0 +1,112 @@
+use log::{debug, warn};
+use serde_json::{Map, Value};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use tokio::process::Command;
+
+/// Properties read from a downloaded file
+#[derive(Debug, Default)]
+pub struct MediaMetadata {
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    /// Seconds, for videos and audio
+    pub duration: Option<f64>,
+    /// EXIF fields of images, by tag name
+    pub exif: Option<Value>,
+}
+
+impl MediaMetadata {
+    pub fn is_empty(&self) -> bool {
+        self.width.is_none()
+            && self.height.is_none()
+            && self.duration.is_none()
+            && self.exif.is_none()
+    }
+}
+
+/// Reads the size and EXIF of images, and the duration and size of videos and audio
+/// with `ffprobe`. Unreadable files give empty metadata.
+pub async fn extract(path: &str, mime_type: &str) -> MediaMetadata {
+    if mime_type.starts_with("image/") {
+        let file = path.to_string();
+        return tokio::task::spawn_blocking(move || read_image(Path::new(&file)))
+            .await
+            .unwrap_or_default();
+    }
+
+    if mime_type.starts_with("video/") || mime_type.starts_with("audio/") {
+        return probe(path).await.unwrap_or_default();
+    }
+
+    MediaMetadata::default()
+}
+
+fn read_image(path: &Path) -> MediaMetadata {
+    let mut metadata = MediaMetadata::default();
+
+    match image::image_dimensions(path) {
+        Ok((width, height)) => {
+            metadata.width = Some(width as i32);
+            metadata.height = Some(height as i32);
+        }
+        Err(e) => debug!("Failed to read the size of {}: {}", path.display(), e),
+    }
+
+    // most images posted on Discord went through a tool stripping EXIF
+    let Ok(file) = File::open(path) else {
+        return metadata;
+    };
+    if let Ok(exif) = exif::Reader::new().read_from_container(&mut BufReader::new(file)) {
+        let fields: Map<String, Value> = exif
+            .fields()
+            .filter(|field| field.ifd_num == exif::In::PRIMARY)
+            .map(|field| {
+                (
+                    field.tag.to_string(),
+                    Value::String(field.display_value().with_unit(&exif).to_string()),
+                )
+            })
+            .collect();
+        if !fields.is_empty() {
+            metadata.exif = Some(Value::Object(fields));
+        }
+    }
+
+    metadata
+}
+
+async fn probe(path: &str) -> Option<MediaMetadata> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-print_format", "json", "-show_format"])
+        .args(["-show_streams", path])
+        .output()
+        .await
+        .inspect_err(|e| warn!("Failed to run ffprobe, is it installed? {}", e))
+        .ok()?;
+    if !output.status.success() {
+        debug!("ffprobe could not read {}", path);
+        return None;
+    }
+
+    let probe: Value = serde_json::from_slice(&output.stdout).ok()?;
+    let video = probe["streams"].as_array().and_then(|streams| {
+        streams
+            .iter()
+            .find(|stream| stream["codec_type"] == "video")
+    });
+
+    Some(MediaMetadata {
+        width: video
+            .and_then(|stream| stream["width"].as_i64())
+            .map(|width| width as i32),
+        height: video
+            .and_then(|stream| stream["height"].as_i64())
+            .map(|height| height as i32),
+        // ffprobe prints numbers as strings
+        duration: probe["format"]["duration"]
+            .as_str()
+            .and_then(|duration| duration.parse().ok()),
+        exif: None,
+    })
+}
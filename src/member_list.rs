@@ -0,0 +1,164 @@
+use crate::BoxedResult;
+use crate::database::{get_guild_message_channel_ids, lock_db, upsert_user};
+use discord_client_gateway::events::Event;
+use discord_client_gateway::gateway::GatewayClient;
+use discord_client_rest::rest::RestClient;
+use discord_client_structs::structs::user::User;
+use log::{debug, info, warn};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+use tokio_postgres::Client;
+
+// members per range of the sidebar, like the client
+const RANGE_SIZE: u32 = 100;
+// delay between two range requests, like scrolling the sidebar
+const REQUEST_DELAY: Duration = Duration::from_secs(1);
+// wait for the list update answering a request
+const UPDATE_TIMEOUT: Duration = Duration::from_secs(10);
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Stores the members of the guild listed in the member sidebar of the channel, the first text
+/// channel of the guild by default. The sidebar only lists the members who can see the channel.
+/// Returns the amount of stored members.
+pub async fn scrape_members(
+    token: String,
+    guild_id: u64,
+    channel_id: Option<u64>,
+    db_client: Arc<Mutex<Client>>,
+) -> BoxedResult<usize> {
+    let channel_id = match channel_id {
+        Some(channel_id) => channel_id,
+        None => {
+            let db = lock_db(&db_client).await;
+            *get_guild_message_channel_ids(guild_id, &db)
+                .await?
+                .first()
+                .ok_or("No known channel in the guild, pass one with --channel")?
+        }
+    };
+
+    let rest_client = RestClient::connect(token.clone(), Some(9), None)
+        .await
+        .map_err(|e| format!("Error connecting to Discord REST API: {}", e))?;
+    let mut gateway_client =
+        GatewayClient::connect(token, true, 53607934, rest_client.build_number)
+            .await
+            .map_err(|e| format!("Error connecting to the gateway: {}", e))?;
+
+    timeout(READY_TIMEOUT, async {
+        loop {
+            if let Ok(Event::Ready(_)) = gateway_client.next_event().await {
+                return;
+            }
+        }
+    })
+    .await
+    .map_err(|_| "Timed out waiting for READY")?;
+
+    let mut members: HashMap<u64, User> = HashMap::new();
+    let mut start = 0;
+
+    loop {
+        // the first range stays subscribed, the client always asks for it
+        let mut ranges = vec![
+            (0, RANGE_SIZE - 1),
+            (start, start + RANGE_SIZE - 1),
+            (start + RANGE_SIZE, start + 2 * RANGE_SIZE - 1),
+        ];
+        ranges.dedup();
+        gateway_client
+            .update_guild_subscriptions(guild_id, channel_id, ranges)
+            .await
+            .map_err(|e| format!("Error requesting the member list: {}", e))?;
+
+        let Some(update) = next_list_update(&mut gateway_client, guild_id).await else {
+            warn!("No member list update for guild {}, stopping", guild_id);
+            break;
+        };
+
+        let before = members.len();
+        for user in list_users(&update) {
+            members.insert(user.id, user);
+        }
+        let member_count = update["member_count"].as_u64().unwrap_or_default();
+        debug!(
+            "Member list of guild {}: {}/{} members",
+            guild_id,
+            members.len(),
+            member_count
+        );
+
+        start += 2 * RANGE_SIZE;
+        // offline members aren't listed in large guilds, the list ends early
+        if members.len() == before || start as u64 >= member_count {
+            break;
+        }
+
+        tokio::time::sleep(REQUEST_DELAY).await;
+    }
+
+    let _ = gateway_client.close().await;
+
+    let db = lock_db(&db_client).await;
+    for user in members.values() {
+        upsert_user(user, &db, Some(guild_id))
+            .await
+            .map_err(|e| format!("Failed to save member {}: {}", user.id, e))?;
+    }
+
+    info!(
+        "Stored {} members of guild {} from the member list",
+        members.len(),
+        guild_id
+    );
+
+    Ok(members.len())
+}
+
+// payload of the next member list update of the guild
+async fn next_list_update(gateway_client: &mut GatewayClient, guild_id: u64) -> Option<Value> {
+    timeout(UPDATE_TIMEOUT, async {
+        loop {
+            match gateway_client.next_event().await {
+                Ok(Event::GuildMemberListUpdate(update)) => {
+                    let update = serde_json::to_value(&update).ok()?;
+                    if update["guild_id"].as_u64() == Some(guild_id)
+                        || update["guild_id"].as_str() == Some(&guild_id.to_string())
+                    {
+                        return Some(update);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Gateway error while reading the member list: {}", e);
+                    return None;
+                }
+            }
+        }
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+// users of the members in the SYNC, INSERT and UPDATE operations, groups are skipped
+fn list_users(update: &Value) -> Vec<User> {
+    let Some(ops) = update["ops"].as_array() else {
+        return Vec::new();
+    };
+
+    ops.iter()
+        .flat_map(|op| {
+            let mut items = op["items"].as_array().cloned().unwrap_or_default();
+            if !op["item"].is_null() {
+                items.push(op["item"].clone());
+            }
+            items
+        })
+        .filter_map(|item| serde_json::from_value(item["member"]["user"].clone()).ok())
+        .collect()
+}
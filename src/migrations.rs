@@ -0,0 +1,174 @@
+use crate::BoxedResult;
+use log::{info, warn};
+use tokio_postgres::Client;
+
+// (version, name, script), a migration is never edited once released, schema changes
+// go in a new file of `sql_scripts/migrations`
+const MIGRATIONS: &[(i32, &str, &str)] = &[
+    (
+        1,
+        "initial",
+        include_str!("../sql_scripts/migrations/0001_initial.sql"),
+    ),
+    (
+        2,
+        "message_mentions",
+        include_str!("../sql_scripts/migrations/0002_message_mentions.sql"),
+    ),
+    (
+        3,
+        "deferred_references",
+        include_str!("../sql_scripts/migrations/0003_deferred_references.sql"),
+    ),
+    (
+        4,
+        "account_status",
+        include_str!("../sql_scripts/migrations/0004_account_status.sql"),
+    ),
+    (
+        5,
+        "image_hashes",
+        include_str!("../sql_scripts/migrations/0005_image_hashes.sql"),
+    ),
+    (
+        6,
+        "media_metadata",
+        include_str!("../sql_scripts/migrations/0006_media_metadata.sql"),
+    ),
+    (
+        7,
+        "thumbnails",
+        include_str!("../sql_scripts/migrations/0007_thumbnails.sql"),
+    ),
+    (
+        8,
+        "scan_results",
+        include_str!("../sql_scripts/migrations/0008_scan_results.sql"),
+    ),
+    (
+        9,
+        "nsfw_score",
+        include_str!("../sql_scripts/migrations/0009_nsfw_score.sql"),
+    ),
+    (
+        10,
+        "message_transcripts",
+        include_str!("../sql_scripts/migrations/0010_message_transcripts.sql"),
+    ),
+    (
+        11,
+        "message_links",
+        include_str!("../sql_scripts/migrations/0011_message_links.sql"),
+    ),
+    (
+        12,
+        "web_archives",
+        include_str!("../sql_scripts/migrations/0012_web_archives.sql"),
+    ),
+    (
+        13,
+        "message_scores",
+        include_str!("../sql_scripts/migrations/0013_message_scores.sql"),
+    ),
+    (
+        14,
+        "bans",
+        include_str!("../sql_scripts/migrations/0014_bans.sql"),
+    ),
+    (
+        15,
+        "member_search",
+        include_str!("../sql_scripts/migrations/0015_member_search.sql"),
+    ),
+    (
+        16,
+        "relationships",
+        include_str!("../sql_scripts/migrations/0016_relationships.sql"),
+    ),
+    (
+        17,
+        "channel_recipients",
+        include_str!("../sql_scripts/migrations/0017_channel_recipients.sql"),
+    ),
+    (
+        18,
+        "calls",
+        include_str!("../sql_scripts/migrations/0018_calls.sql"),
+    ),
+    (
+        19,
+        "guild_first_seen",
+        include_str!("../sql_scripts/migrations/0019_guild_first_seen.sql"),
+    ),
+    (
+        20,
+        "guild_previews",
+        include_str!("../sql_scripts/migrations/0020_guild_previews.sql"),
+    ),
+];
+
+// held while migrating, so instances started together don't apply the same migration twice
+const LOCK_KEY: i64 = 0x736c757270;
+
+/// Applies the migrations missing from `schema_migrations`, each one in its own transaction
+pub async fn run(db: &mut Client) -> BoxedResult<()> {
+    db.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_migrations
+        (
+            version    INTEGER PRIMARY KEY,
+            name       TEXT        NOT NULL,
+            applied_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+        )",
+    )
+    .await?;
+
+    db.execute("SELECT pg_advisory_lock($1)", &[&LOCK_KEY])
+        .await?;
+    let result = apply_pending(db).await;
+    db.execute("SELECT pg_advisory_unlock($1)", &[&LOCK_KEY])
+        .await?;
+
+    result
+}
+
+async fn apply_pending(db: &mut Client) -> BoxedResult<()> {
+    let applied: Vec<i32> = db
+        .query("SELECT version FROM schema_migrations", &[])
+        .await?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+
+    let latest = MIGRATIONS.last().map(|(version, _, _)| *version);
+    if let Some(newest) = applied.iter().max()
+        && Some(*newest) > latest
+    {
+        warn!(
+            "The database schema is at version {}, newer than this build, consider updating",
+            newest
+        );
+    }
+
+    for (version, name, script) in MIGRATIONS {
+        if applied.contains(version) {
+            continue;
+        }
+
+        let transaction = db.transaction().await?;
+        transaction
+            .batch_execute(script)
+            .await
+            .map_err(|e| format!("Error applying migration {} ({}): {}", version, name, e))?;
+        transaction
+            .execute(
+                "INSERT INTO schema_migrations (version, name) VALUES ($1, $2)",
+                &[version, name],
+            )
+            .await?;
+        transaction.commit().await?;
+
+        info!("Applied database migration {} ({})", version, name);
+    }
+
+    Ok(())
+}
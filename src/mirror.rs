@@ -0,0 +1,166 @@
+use crate::BoxedResult;
+use crate::config::Config;
+use discord_client_structs::structs::message::Message;
+use log::{debug, error, warn};
+use regex::Regex;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+
+// limits of the webhook execute endpoint
+const MAX_CONTENT_CHARS: usize = 2000;
+const MAX_USERNAME_CHARS: usize = 80;
+const MAX_EMBEDS: usize = 10;
+// webhooks allow around 5 requests every 2 seconds
+const SEND_DELAY: Duration = Duration::from_millis(400);
+const MAX_ATTEMPTS: u32 = 3;
+
+lazy_static::lazy_static! {
+    // one queue per webhook, so messages are mirrored in order
+    static ref QUEUES: Mutex<HashMap<String, UnboundedSender<Value>>> = Mutex::new(HashMap::new());
+    // webhook names can't contain these
+    static ref FORBIDDEN_NAME_REGEX: Regex = Regex::new(r"(?i)discord|clyde").unwrap();
+}
+
+fn webhook_url(channel_id: u64) -> Option<&'static str> {
+    Config::get()
+        .mirrors
+        .iter()
+        .find(|mirror| mirror.channel_id == channel_id)
+        .map(|mirror| mirror.webhook_url.as_str())
+}
+
+fn webhook_username(message: &Message) -> String {
+    let name = message
+        .author
+        .global_name
+        .as_deref()
+        .unwrap_or(&message.author.username);
+
+    let sanitized: String = FORBIDDEN_NAME_REGEX
+        .replace_all(name, "")
+        .trim()
+        .chars()
+        .take(MAX_USERNAME_CHARS)
+        .collect();
+    if sanitized.is_empty() {
+        message.author.id.to_string()
+    } else {
+        sanitized
+    }
+}
+
+fn avatar_url(message: &Message) -> String {
+    match &message.author.avatar {
+        Some(hash) => format!(
+            "https://cdn.discordapp.com/avatars/{}/{}.png",
+            message.author.id, hash
+        ),
+        None => format!(
+            "https://cdn.discordapp.com/embed/avatars/{}.png",
+            (message.author.id >> 22) % 6
+        ),
+    }
+}
+
+fn build_payload(message: &Message) -> Option<Value> {
+    // attachments are linked, re-uploading them would double the bandwidth
+    let mut content = message.content.clone().unwrap_or_default();
+    for attachment in &message.attachments {
+        if !content.is_empty() {
+            content.push('\n');
+        }
+        content.push_str(&attachment.url);
+    }
+    let content: String = content.chars().take(MAX_CONTENT_CHARS).collect();
+
+    let embeds: Vec<_> = message.embeds.iter().take(MAX_EMBEDS).collect();
+    if content.is_empty() && embeds.is_empty() {
+        return None;
+    }
+
+    Some(json!({
+        "content": content,
+        "username": webhook_username(message),
+        "avatar_url": avatar_url(message),
+        "embeds": embeds,
+        // never ping anyone in the mirror
+        "allowed_mentions": { "parse": [] },
+    }))
+}
+
+/// Queues the message for the webhook mirroring its channel, if any
+pub async fn mirror_message(message: &Message) {
+    let Some(url) = webhook_url(message.channel_id) else {
+        return;
+    };
+    let Some(payload) = build_payload(message) else {
+        return;
+    };
+
+    let mut queues = QUEUES.lock().await;
+    let sender = queues.entry(url.to_string()).or_insert_with(|| {
+        let (sender, receiver) = unbounded_channel();
+        tokio::spawn(run_webhook_queue(url.to_string(), receiver));
+        sender
+    });
+
+    if sender.send(payload).is_err() {
+        error!("Mirror queue of channel {} is closed", message.channel_id);
+    }
+}
+
+async fn run_webhook_queue(url: String, mut receiver: UnboundedReceiver<Value>) {
+    let client = rquest::Client::new();
+
+    while let Some(payload) = receiver.recv().await {
+        for attempt in 1..=MAX_ATTEMPTS {
+            match execute_webhook(&client, &url, &payload).await {
+                Ok(None) => break,
+                Ok(Some(retry_after)) => {
+                    debug!("Mirror webhook rate limited, retrying in {:?}", retry_after);
+                    tokio::time::sleep(retry_after).await;
+                }
+                Err(e) => {
+                    warn!("Failed to mirror message (attempt {}): {}", attempt, e);
+                    tokio::time::sleep(SEND_DELAY).await;
+                }
+            }
+        }
+
+        tokio::time::sleep(SEND_DELAY).await;
+    }
+}
+
+/// Returns the delay to wait before retrying when rate limited
+pub async fn execute_webhook(
+    client: &rquest::Client,
+    url: &str,
+    payload: &Value,
+) -> BoxedResult<Option<Duration>> {
+    let response = client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_string(payload)?)
+        .send()
+        .await?;
+
+    let status = response.status();
+    let text = response.text().await?;
+
+    if status.as_u16() == 429 {
+        let retry_after = serde_json::from_str::<Value>(&text)
+            .ok()
+            .and_then(|body| body["retry_after"].as_f64())
+            .unwrap_or(1.0);
+        return Ok(Some(Duration::from_secs_f64(retry_after)));
+    }
+
+    if !status.is_success() {
+        return Err(format!("Webhook returned {}: {}", status, text).into());
+    }
+
+    Ok(None)
+}
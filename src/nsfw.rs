@@ -0,0 +1,152 @@
+use crate::BoxedResult;
+use crate::config::Config;
+use crate::database::{get_unclassified_images, set_nsfw_score};
+use crate::maintenance;
+use log::{info, warn};
+use std::path::Path;
+use tokio_postgres::Client;
+
+const BACKFILL_BATCH_SIZE: i64 = 500;
+
+/// Whether downloaded images are classified
+pub fn enabled() -> bool {
+    Config::get().nsfw.is_some()
+}
+
+/// Score of a downloaded image, from 0.0 (safe) to 1.0 (explicit), errors are logged
+pub async fn classify_image(attachment_id: u64, path: String) -> Option<f32> {
+    match tokio::task::spawn_blocking(move || classify_file(Path::new(&path))).await {
+        Ok(Ok(score)) => Some(score),
+        Ok(Err(e)) => {
+            warn!("Failed to classify attachment {}: {}", attachment_id, e);
+            None
+        }
+        Err(e) => {
+            warn!(
+                "Classification of attachment {} panicked: {}",
+                attachment_id, e
+            );
+            None
+        }
+    }
+}
+
+#[cfg(feature = "nsfw")]
+fn classify_file(path: &Path) -> BoxedResult<f32> {
+    use crate::config::TensorLayout;
+    use image::imageops::FilterType;
+    use ort::session::Session;
+    use ort::value::Tensor;
+    use std::sync::OnceLock;
+
+    // loaded on the first image, the model is shared by every download
+    static SESSION: OnceLock<Result<Session, String>> = OnceLock::new();
+
+    let config = Config::get()
+        .nsfw
+        .as_ref()
+        .ok_or("nsfw is not configured")?;
+    let session = SESSION
+        .get_or_init(|| {
+            Session::builder()
+                .and_then(|builder| builder.commit_from_file(&config.model))
+                .map_err(|e| format!("Failed to load the model {}: {}", config.model, e))
+        })
+        .as_ref()
+        .map_err(|e| e.clone())?;
+
+    let size = config.input_size as usize;
+    let image = image::open(path)?
+        .resize_exact(config.input_size, config.input_size, FilterType::Triangle)
+        .to_rgb8();
+
+    let mut input = vec![0f32; 3 * size * size];
+    for (x, y, pixel) in image.enumerate_pixels() {
+        let (x, y) = (x as usize, y as usize);
+        for (channel, value) in pixel.0.iter().enumerate() {
+            let index = match config.layout {
+                TensorLayout::Nhwc => (y * size + x) * 3 + channel,
+                TensorLayout::Nchw => channel * size * size + y * size + x,
+            };
+            input[index] = (*value as f32 / 255.0 - config.mean[channel]) / config.std[channel];
+        }
+    }
+    let shape = match config.layout {
+        TensorLayout::Nhwc => [1, size, size, 3],
+        TensorLayout::Nchw => [1, 3, size, size],
+    };
+
+    let outputs = session.run(ort::inputs![Tensor::from_array((shape, input))?]?)?;
+    let (_, outputs) = outputs[0].try_extract_raw_tensor::<f32>()?;
+    let probabilities = if config.softmax {
+        softmax(outputs)
+    } else {
+        outputs.to_vec()
+    };
+
+    let score: f32 = config
+        .explicit_outputs
+        .iter()
+        .filter_map(|index| probabilities.get(*index))
+        .sum();
+
+    Ok(score.clamp(0.0, 1.0))
+}
+
+#[cfg(feature = "nsfw")]
+fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exponentials: Vec<f32> = logits.iter().map(|logit| (logit - max).exp()).collect();
+    let sum: f32 = exponentials.iter().sum();
+    exponentials.iter().map(|value| value / sum).collect()
+}
+
+#[cfg(not(feature = "nsfw"))]
+fn classify_file(_path: &Path) -> BoxedResult<f32> {
+    Err("slurpslurp was built without the nsfw feature".into())
+}
+
+/// Classifies the downloaded images that were saved before classification was enabled
+pub async fn backfill(limit: Option<usize>, db: &Client) -> BoxedResult<()> {
+    if !enabled() {
+        return Err("classify-images needs the nsfw section of the config".into());
+    }
+
+    let mut after_id = 0;
+    let mut classified = 0;
+    let mut failed = 0;
+
+    loop {
+        maintenance::wait_for_window("image classification").await;
+
+        let images = get_unclassified_images(after_id, BACKFILL_BATCH_SIZE, db).await?;
+        let Some((last_id, _)) = images.last() else {
+            break;
+        };
+        after_id = *last_id;
+
+        for (attachment_id, path) in images {
+            match classify_image(attachment_id, path).await {
+                Some(score) => {
+                    set_nsfw_score(attachment_id, score, db).await?;
+                    classified += 1;
+                }
+                None => failed += 1,
+            }
+
+            if limit.is_some_and(|limit| classified >= limit) {
+                info!("Classified {} images", classified);
+                return Ok(());
+            }
+        }
+
+        info!("Classified {} images", classified);
+    }
+
+    info!(
+        "Image classification done, {} images could not be classified",
+        failed
+    );
+
+    Ok(())
+}
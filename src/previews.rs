@@ -0,0 +1,74 @@
+use crate::BoxedResult;
+use crate::database::{get_guilds_for_preview, save_guild_preview};
+use discord_client_rest::rest::RestClient;
+use log::{debug, info};
+use serde_json::Value;
+use std::time::Duration;
+use tokio_postgres::Client;
+
+// delay between two guilds, to stay under the REST rate limits
+const FETCH_DELAY: Duration = Duration::from_millis(1500);
+const WIDGET_URL: &str = "https://discord.com/api/v9/guilds";
+
+/// Stores the preview and the widget of the guilds seen through invites that no account joined
+pub async fn fetch_previews(token: String, limit: i64, db: &Client) -> BoxedResult<()> {
+    let guild_ids = get_guilds_for_preview(limit, db).await?;
+    if guild_ids.is_empty() {
+        info!("No unjoined guilds to preview");
+        return Ok(());
+    }
+
+    info!("Fetching the preview of {} guilds...", guild_ids.len());
+
+    let rest_client = RestClient::connect(token, Some(9), None)
+        .await
+        .map_err(|e| format!("Error connecting to Discord REST API: {}", e))?;
+    let http_client = rquest::Client::new();
+
+    let mut available = 0;
+    for guild_id in &guild_ids {
+        // only discoverable guilds and the ones with a widget are public
+        let preview = match rest_client.guild(Some(*guild_id)).get_preview().await {
+            Ok(preview) => Some(serde_json::to_value(&preview)?),
+            Err(e) => {
+                debug!("Guild {} has no public preview: {}", guild_id, e);
+                None
+            }
+        };
+        let widget = match fetch_widget(&http_client, *guild_id).await {
+            Ok(widget) => widget,
+            Err(e) => {
+                debug!("Error fetching the widget of guild {}: {}", guild_id, e);
+                None
+            }
+        };
+
+        if preview.is_some() || widget.is_some() {
+            available += 1;
+        }
+        save_guild_preview(*guild_id, preview.as_ref(), widget.as_ref(), db).await?;
+
+        tokio::time::sleep(FETCH_DELAY).await;
+    }
+
+    info!(
+        "Previewed {}/{} guilds, the others aren't public",
+        available,
+        guild_ids.len()
+    );
+
+    Ok(())
+}
+
+// the widget is public and needs no token, None when the guild disabled it
+async fn fetch_widget(client: &rquest::Client, guild_id: u64) -> BoxedResult<Option<Value>> {
+    let response = client
+        .get(&format!("{}/{}/widget.json", WIDGET_URL, guild_id))
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Ok(None);
+    }
+
+    Ok(Some(serde_json::from_str(&response.text().await?)?))
+}
@@ -0,0 +1,132 @@
+use clap::ValueEnum;
+use log::info;
+use progress_bar::*;
+use serde_json::json;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+// between two JSON lines, or two log lines when the total isn't known
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ProgressFormat {
+    /// Progress bar with the rate and the time left
+    #[default]
+    Bar,
+    /// One JSON object per line on stdout, every few seconds
+    Json,
+}
+
+/// Messages scraped out of the expected total, shared by the scrape tasks
+pub struct Progress {
+    format: ProgressFormat,
+    total: OnceLock<u64>,
+    done: AtomicU64,
+    started: Instant,
+    last_report: Mutex<Instant>,
+}
+
+impl Progress {
+    pub fn new(format: ProgressFormat) -> Self {
+        let now = Instant::now();
+        Self {
+            format,
+            total: OnceLock::new(),
+            done: AtomicU64::new(0),
+            started: now,
+            last_report: Mutex::new(now),
+        }
+    }
+
+    /// Sets the amount of messages expected, only the first call counts
+    pub fn set_total(&self, total: u64) {
+        if self.total.set(total).is_err() {
+            return;
+        }
+
+        if self.format == ProgressFormat::Bar {
+            init_progress_bar(total as usize);
+            set_progress_bar_action("Scraping", Color::Blue, Style::Bold);
+        }
+    }
+
+    pub fn add(&self, count: u64) {
+        let done = self.done.fetch_add(count, Ordering::Relaxed) + count;
+        if self.format == ProgressFormat::Bar && self.total.get().is_some() {
+            set_progress_bar_progress(done as usize);
+        }
+
+        {
+            let mut last_report = self.last_report.lock().unwrap();
+            if last_report.elapsed() < REPORT_INTERVAL {
+                return;
+            }
+            *last_report = Instant::now();
+        }
+        self.report(done);
+    }
+
+    pub fn finish(&self) {
+        let done = self.done.load(Ordering::Relaxed);
+        match self.format {
+            ProgressFormat::Json => println!(
+                "{}",
+                json!({
+                    "done": done,
+                    "total": self.total.get(),
+                    "elapsed_seconds": self.started.elapsed().as_secs(),
+                    "finished": true,
+                })
+            ),
+            ProgressFormat::Bar if self.total.get().is_some() => print_progress_bar_info(
+                "Finished",
+                &format!("{} messages scraped", done),
+                Color::Green,
+                Style::Bold,
+            ),
+            ProgressFormat::Bar => info!("Finished, {} messages scraped", done),
+        }
+    }
+
+    fn report(&self, done: u64) {
+        let elapsed = self.started.elapsed();
+        let rate = done as f64 / elapsed.as_secs_f64().max(1.0);
+        // more than the total is scraped when the count was an estimate
+        let eta = self
+            .total
+            .get()
+            .filter(|_| rate > 0.0)
+            .map(|total| Duration::from_secs_f64(total.saturating_sub(done) as f64 / rate));
+
+        match self.format {
+            ProgressFormat::Json => println!(
+                "{}",
+                json!({
+                    "done": done,
+                    "total": self.total.get(),
+                    "rate": rate,
+                    "eta_seconds": eta.map(|eta| eta.as_secs()),
+                    "elapsed_seconds": elapsed.as_secs(),
+                })
+            ),
+            ProgressFormat::Bar => match eta {
+                Some(eta) => set_progress_bar_action(
+                    &format!("{:.0}/s, {} left", rate, format_duration(eta)),
+                    Color::Blue,
+                    Style::Bold,
+                ),
+                None => info!("Scraped {} messages, {:.0}/s", done, rate),
+            },
+        }
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        0..60 => format!("{}s", seconds),
+        60..3600 => format!("{}m {}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
@@ -0,0 +1,302 @@
+use crate::BoxedResult;
+use crate::cli::parse_age;
+use crate::config::{Config, RetentionRule};
+use crate::database::{
+    PruneFilter, count_prunable_messages, get_known_media_ids, get_prunable_message_ids,
+    get_stored_media_paths, is_attachment_of_stored_message, lock_db, purge_messages,
+};
+use crate::downloader;
+use crate::maintenance;
+use crate::search_index;
+use crate::timezone;
+use chrono::{TimeDelta, Utc};
+use log::{debug, error, info, warn};
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio_postgres::Client;
+
+// messages removed at once, the database is unlocked between batches
+const BATCH_SIZE: i64 = 5000;
+const FILE_BATCH_SIZE: usize = 1000;
+// folders of `downloads` that don't hold message media
+const NON_MEDIA_FOLDERS: [&str; 4] = ["avatars", "guilds", "roles", "web"];
+
+/// Smallest id of the messages younger than the given age
+pub fn age_to_snowflake(age: TimeDelta) -> u64 {
+    timezone::time_to_snowflake(Utc::now() - age)
+}
+
+fn rule_filter(rule: &RetentionRule) -> Result<PruneFilter, String> {
+    Ok(PruneFilter {
+        before_id: Some(age_to_snowflake(parse_age(&rule.older_than)?)),
+        guild_id: rule.guild_id,
+        channel_id: rule.channel_id,
+        only_bots: rule.only_bots,
+    })
+}
+
+/// Fails on retention rules with an invalid age
+pub fn check_rules() -> Result<(), String> {
+    for rule in &Config::get().retention_rules {
+        rule_filter(rule)?;
+    }
+
+    Ok(())
+}
+
+fn remove_file(path: &Path) {
+    if let Err(e) = std::fs::remove_file(path)
+        && e.kind() != ErrorKind::NotFound
+    {
+        warn!("Failed to remove {}: {}", path.display(), e);
+    }
+}
+
+/// Permanently removes the matching messages, returns how many were (or would be) removed
+pub async fn prune_messages(
+    filter: &PruneFilter,
+    dry_run: bool,
+    db: &Arc<Mutex<Client>>,
+) -> BoxedResult<u64> {
+    if dry_run {
+        let count = count_prunable_messages(filter, &*lock_db(db).await).await?;
+        return Ok(count as u64);
+    }
+
+    let mut total = 0;
+
+    loop {
+        maintenance::wait_for_window("pruning").await;
+
+        let (ids, paths) = {
+            let db = lock_db(db).await;
+            let ids = get_prunable_message_ids(filter, BATCH_SIZE, &db).await?;
+            if ids.is_empty() {
+                break;
+            }
+            let paths = purge_messages(&ids, &db).await?;
+            (ids, paths)
+        };
+
+        for path in paths {
+            remove_file(Path::new(&path));
+        }
+
+        let ids: Vec<u64> = ids.into_iter().map(|id| id as u64).collect();
+        search_index::remove_messages(&ids);
+
+        total += ids.len() as u64;
+        info!("Pruned {} messages", total);
+    }
+
+    Ok(total)
+}
+
+// every file of `downloads` except the avatars and guild assets
+fn media_files(root: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let Ok(entries) = std::fs::read_dir(root) else {
+        return files;
+    };
+
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        if entry.path().is_dir() && !NON_MEDIA_FOLDERS.contains(&&*name.to_string_lossy()) {
+            collect_media_files(&entry.path(), &mut files);
+        }
+    }
+
+    files
+}
+
+fn collect_media_files(folder: &Path, files: &mut Vec<PathBuf>) {
+    let entries = std::fs::read_dir(folder).into_iter().flatten();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_media_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+// embeds aren't recorded with their path, they are always saved as
+// `<image|video>/<extension>/<message id>_<name>`. Attachments downloaded before their path was
+// stored follow the same layout with the attachment id.
+fn embed_layout_id(root: &Path, path: &Path) -> Option<u64> {
+    let relative = path.strip_prefix(root).ok()?;
+    let parts: Vec<_> = relative.iter().map(|part| part.to_string_lossy()).collect();
+    let [media_type, _, name] = parts.as_slice() else {
+        return None;
+    };
+    if *media_type != "image" && *media_type != "video" {
+        return None;
+    }
+
+    name.split_once('_')?.0.parse().ok()
+}
+
+/// Removes the downloaded media of messages and attachments that aren't stored anymore. Files
+/// are matched against the paths stored for the attachments and their thumbnails, files that
+/// are neither recorded nor an embed are left alone.
+pub async fn remove_orphaned_files(dry_run: bool, db: &Arc<Mutex<Client>>) -> BoxedResult<u64> {
+    let root = Path::new(downloader::download_root());
+    let files = media_files(root);
+    let mut removed = 0;
+
+    for batch in files.chunks(FILE_BATCH_SIZE) {
+        maintenance::wait_for_window("orphaned file cleanup").await;
+
+        let mut orphans = Vec::new();
+        {
+            let db = lock_db(db).await;
+            let paths: Vec<String> = batch
+                .iter()
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect();
+            let stored: HashSet<String> = get_stored_media_paths(&paths, &db)
+                .await?
+                .into_iter()
+                .collect();
+
+            let unrecorded: Vec<(&PathBuf, u64)> = batch
+                .iter()
+                .zip(&paths)
+                .filter(|(_, path)| !stored.contains(*path))
+                .filter_map(|(file, _)| Some((file, embed_layout_id(root, file)?)))
+                .collect();
+            let ids: Vec<i64> = unrecorded.iter().map(|(_, id)| *id as i64).collect();
+            let known: HashSet<i64> = get_known_media_ids(&ids, &db).await?.into_iter().collect();
+
+            for (path, id) in unrecorded {
+                if !known.contains(&(id as i64))
+                    && !is_attachment_of_stored_message(id, &db).await?
+                {
+                    orphans.push(path);
+                }
+            }
+        }
+
+        for path in orphans {
+            if dry_run {
+                debug!("Orphaned file: {}", path.display());
+            } else {
+                remove_file(path);
+            }
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Prune command, without any filter only orphaned files can be removed
+pub async fn prune(
+    filter: PruneFilter,
+    orphaned_files: bool,
+    dry_run: bool,
+    db: &Arc<Mutex<Client>>,
+) -> BoxedResult<()> {
+    let has_filter = filter.before_id.is_some()
+        || filter.guild_id.is_some()
+        || filter.channel_id.is_some()
+        || filter.only_bots;
+    if !has_filter && !orphaned_files {
+        return Err(
+            "prune needs --older-than, --guild, --channel, --only-bots or --orphaned-files".into(),
+        );
+    }
+
+    let verb = if dry_run { "Would remove" } else { "Removed" };
+
+    if has_filter {
+        let count = prune_messages(&filter, dry_run, db).await?;
+        info!("{} {} messages", verb, count);
+    }
+
+    if orphaned_files {
+        let count = remove_orphaned_files(dry_run, db).await?;
+        info!("{} {} orphaned files", verb, count);
+    }
+
+    Ok(())
+}
+
+/// Applies the retention rules periodically in sniff mode
+pub fn spawn_retention_task(db_client: Option<Arc<Mutex<Client>>>) {
+    let config = Config::get();
+    let Some(db_client) = db_client else {
+        return;
+    };
+    if config.retention_interval == 0 {
+        return;
+    }
+
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(config.retention_interval * 3600)).await;
+
+            for rule in &config.retention_rules {
+                let result = match rule_filter(rule) {
+                    Ok(filter) => prune_messages(&filter, false, &db_client).await,
+                    Err(e) => Err(e.into()),
+                };
+                match result {
+                    Ok(count) => info!(
+                        "Retention rule for messages older than {} removed {} messages",
+                        rule.older_than, count
+                    ),
+                    Err(e) => error!("Error applying retention rule: {}", e),
+                }
+            }
+
+            if config.retention_remove_orphaned_files {
+                match remove_orphaned_files(false, &db_client).await {
+                    Ok(count) => info!("Removed {} orphaned files", count),
+                    Err(e) => error!("Error removing orphaned files: {}", e),
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout_id(path: &str) -> Option<u64> {
+        embed_layout_id(Path::new("downloads"), &Path::new("downloads").join(path))
+    }
+
+    #[test]
+    fn embeds_give_their_message_id() {
+        assert_eq!(layout_id("image/png/1234_cat.png"), Some(1234));
+        assert_eq!(layout_id("video/mp4/5678_clip_final.mp4"), Some(5678));
+    }
+
+    #[test]
+    fn other_layouts_are_left_alone() {
+        for path in [
+            "avatars/1234/abc.png",
+            "image/1234_cat.png",
+            "image/png/sub/1234_cat.png",
+            "audio/ogg/1234_voice.ogg",
+            "image/png/cat.png",
+            "image/png/cat_1234.png",
+        ] {
+            assert_eq!(layout_id(path), None, "{}", path);
+        }
+        assert_eq!(
+            embed_layout_id(
+                Path::new("downloads"),
+                Path::new("elsewhere/image/png/1_a.png")
+            ),
+            None
+        );
+    }
+}
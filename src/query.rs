@@ -0,0 +1,60 @@
+use crate::BoxedResult;
+use crate::database::{MessageFilter, search_messages};
+use crate::embeddings;
+use crate::timezone;
+use chrono::{Days, NaiveDate};
+use tokio_postgres::Client;
+
+/// Message id bounds matching the days, both included
+pub fn snowflake_range(
+    after: Option<NaiveDate>,
+    before: Option<NaiveDate>,
+) -> (Option<u64>, Option<u64>) {
+    let min_id = after.map(timezone::date_to_snowflake);
+    let max_id = before
+        .and_then(|date| date.checked_add_days(Days::new(1)))
+        .map(timezone::date_to_snowflake);
+
+    (min_id, max_id)
+}
+
+pub async fn query_messages(
+    filter: MessageFilter,
+    semantic: bool,
+    json: bool,
+    db: &Client,
+) -> BoxedResult<()> {
+    let messages = if semantic {
+        let text = filter
+            .text
+            .as_deref()
+            .ok_or("A text is required for a semantic search")?;
+        embeddings::semantic_search(text, &filter, db).await?
+    } else {
+        search_messages(&filter, db).await?
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&messages)?);
+        return Ok(());
+    }
+
+    for message in &messages {
+        println!(
+            "{}\tchannel {}\t{}{}: {}",
+            timezone::format_snowflake(message.id as u64),
+            message.channel_id,
+            message.username,
+            if message.deleted_at.is_some() {
+                " (deleted)"
+            } else {
+                ""
+            },
+            message.content.as_deref().unwrap_or_default()
+        );
+    }
+
+    println!("{} result(s)", messages.len());
+
+    Ok(())
+}
@@ -0,0 +1,231 @@
+use lazy_static::lazy_static;
+use log::{info, warn};
+use regex::Regex;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// cooldown after a 429 without retry_after, doubled on each one in a row
+const BASE_COOLDOWN: Duration = Duration::from_secs(1);
+const MAX_COOLDOWN: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    // "retry_after": 1.5 in the body of a 429, when the error carries it
+    static ref RETRY_AFTER_REGEX: Regex =
+        Regex::new(r#"(?i)retry[_-]after"?\s*[:=]\s*"?([0-9]+(?:\.[0-9]+)?)"#).unwrap();
+    // "429 Too Many Requests", a 429 status or a rate limit message, a bare 429 can be part of
+    // a snowflake in the error
+    static ref RATE_LIMIT_REGEX: Regex =
+        Regex::new(r#"(?i)too many requests|rate[ _-]?limit|\bstatus(?:[ _]?code)?\W{0,3}429\b"#)
+            .unwrap();
+}
+
+/// Routes with their own budget, per token
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Route {
+    ChannelMessages,
+    Search,
+    ArchivedThreads,
+}
+
+impl Route {
+    /// Spacing keeping a token under the limit of the route
+    pub fn interval(&self) -> Duration {
+        match self {
+            Route::ChannelMessages => Duration::from_millis(250),
+            Route::Search => Duration::from_millis(1500),
+            Route::ArchivedThreads => Duration::from_millis(500),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TokenStats {
+    pub requests: u64,
+    pub rate_limited: u64,
+    pub errors: u64,
+    pub waited: Duration,
+}
+
+#[derive(Default)]
+struct Bucket {
+    next_request: HashMap<Route, Instant>,
+    cooldown_until: Option<Instant>,
+    // 429s in a row
+    strikes: u32,
+    stats: TokenStats,
+}
+
+impl Bucket {
+    // a token that is already ready is ready at `now`, so the ready ones tie
+    fn ready_at(&self, route: Route, now: Instant) -> Instant {
+        let next = self
+            .next_request
+            .get(&route)
+            .map_or(now, |next| (*next).max(now));
+        self.cooldown_until
+            .map_or(next, |cooldown| next.max(cooldown))
+    }
+}
+
+/// Schedules the REST requests of the scrape tokens. RestClient doesn't expose the rate limit
+/// headers, so each token spaces its requests per route and backs off on 429s, for the
+/// `retry_after` of the error when it carries one.
+pub struct RateLimiter {
+    buckets: Mutex<Vec<Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(tokens: usize) -> Self {
+        Self {
+            buckets: Mutex::new((0..tokens).map(|_| Bucket::default()).collect()),
+        }
+    }
+
+    /// Token with the most budget left for the route: the first one ready, then the least used
+    pub fn pick(&self, route: Route) -> usize {
+        let buckets = self.buckets.lock().unwrap();
+        let now = Instant::now();
+        buckets
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, bucket)| (bucket.ready_at(route, now), bucket.stats.requests))
+            .map(|(token, _)| token)
+            .unwrap_or(0)
+    }
+
+    /// Waits until the token can send a request on the route, and books it
+    pub async fn acquire(&self, token: usize, route: Route) {
+        let wait = {
+            let mut buckets = self.buckets.lock().unwrap();
+            let bucket = &mut buckets[token];
+            let ready_at = bucket.ready_at(route, Instant::now());
+            bucket
+                .next_request
+                .insert(route, ready_at + route.interval());
+            bucket.stats.requests += 1;
+
+            let wait = ready_at.saturating_duration_since(Instant::now());
+            bucket.stats.waited += wait;
+            wait
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    pub fn record_success(&self, token: usize) {
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets[token].strikes = 0;
+    }
+
+    /// Puts the token on cooldown if the error is a rate limit, returns whether it was one
+    pub fn record_error(&self, token: usize, error: &str) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = &mut buckets[token];
+
+        if !is_rate_limit(error) {
+            bucket.stats.errors += 1;
+            return false;
+        }
+
+        let cooldown = retry_after(error).unwrap_or_else(|| {
+            BASE_COOLDOWN
+                .saturating_mul(2u32.saturating_pow(bucket.strikes))
+                .min(MAX_COOLDOWN)
+        });
+        bucket.strikes += 1;
+        bucket.stats.rate_limited += 1;
+        bucket.cooldown_until = Some(Instant::now() + cooldown);
+        warn!(
+            "Token {} rate limited, cooling down for {:?}",
+            token, cooldown
+        );
+
+        true
+    }
+
+    pub fn stats(&self) -> Vec<TokenStats> {
+        let buckets = self.buckets.lock().unwrap();
+        buckets.iter().map(|bucket| bucket.stats.clone()).collect()
+    }
+
+    pub fn log_stats(&self) {
+        for (token, stats) in self.stats().iter().enumerate() {
+            info!(
+                "Token {}: {} requests, {} rate limited, {} errors, waited {:.1}s",
+                token,
+                stats.requests,
+                stats.rate_limited,
+                stats.errors,
+                stats.waited.as_secs_f64()
+            );
+        }
+    }
+}
+
+fn is_rate_limit(error: &str) -> bool {
+    RATE_LIMIT_REGEX.is_match(error)
+}
+
+fn retry_after(error: &str) -> Option<Duration> {
+    let seconds: f64 = RETRY_AFTER_REGEX.captures(error)?[1].parse().ok()?;
+    Some(Duration::from_secs_f64(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_rate_limit_detects_429s() {
+        for error in [
+            "HTTP error: 429 Too Many Requests",
+            "status: 429",
+            "StatusCode(429)",
+            "You are being rate limited.",
+            r#"{"message": "You are being rate limited.", "retry_after": 1.5, "global": false}"#,
+        ] {
+            assert!(is_rate_limit(error), "{:?}", error);
+        }
+    }
+
+    #[test]
+    fn is_rate_limit_ignores_other_errors() {
+        for error in [
+            "404 Not Found",
+            "Unknown Message 1242942913849429429",
+            "Missing Access in channel 429429429429429429",
+            "status: 4290",
+        ] {
+            assert!(!is_rate_limit(error), "{:?}", error);
+        }
+    }
+
+    #[tokio::test]
+    async fn pick_prefers_the_least_used_ready_token() {
+        let limiter = RateLimiter::new(3);
+        limiter.acquire(0, Route::Search).await;
+        limiter.acquire(1, Route::ChannelMessages).await;
+        limiter.acquire(1, Route::ChannelMessages).await;
+        limiter.acquire(2, Route::ChannelMessages).await;
+
+        // token 0 waits for its search budget, 2 is ready and used less than 1
+        assert_eq!(limiter.pick(Route::Search), 2);
+        assert_eq!(limiter.pick(Route::ArchivedThreads), 0);
+        // once the channel budget of 2 is back, it ties with 0 that never used it
+        tokio::time::sleep(Route::ChannelMessages.interval()).await;
+        assert_eq!(limiter.pick(Route::ChannelMessages), 0);
+    }
+
+    #[test]
+    fn retry_after_reads_seconds() {
+        assert_eq!(
+            retry_after(r#"{"retry_after": 1.5, "global": false}"#),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(retry_after("Retry-After: 3"), Some(Duration::from_secs(3)));
+        assert_eq!(retry_after("429 Too Many Requests"), None);
+    }
+}
@@ -0,0 +1,123 @@
+use crate::BoxedResult;
+use crate::config::Config;
+use crate::database::{get_missing_reply_parents, lock_db, mark_message_unavailable};
+use crate::event_processor::message::process_message_common;
+use crate::sampling;
+use discord_client_rest::rest::RestClient;
+use discord_client_structs::structs::message::query::MessageQueryBuilder;
+use discord_client_structs::structs::message::{Message, MessageType};
+use log::{info, warn};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio_postgres::Client;
+
+// delay between two message fetches
+pub const FETCH_DELAY: Duration = Duration::from_millis(1500);
+
+/// Fetches a single message, the REST API has no endpoint for it with user tokens
+async fn fetch_message(rest_client: &RestClient, channel_id: u64, id: u64) -> Option<Message> {
+    let query = match MessageQueryBuilder::default().around(id).limit(1).build() {
+        Ok(query) => query,
+        Err(e) => {
+            warn!("Error building the query of message {}: {}", id, e);
+            return None;
+        }
+    };
+
+    match rest_client
+        .message(channel_id)
+        .get_channel_messages(None, query)
+        .await
+    {
+        Ok(messages) => messages.into_iter().find(|message| message.id == id),
+        Err(e) => {
+            warn!("Message {} could not be fetched: {}", id, e);
+            None
+        }
+    }
+}
+
+/// Id of the message a reply answers, also known when Discord left the message itself out
+pub fn reply_parent_id(message: &Message) -> Option<u64> {
+    match &message.referenced_message {
+        Some(parent) => Some(parent.id),
+        None if matches!(message.r#type, MessageType::Reply) => message
+            .message_reference
+            .as_ref()
+            .and_then(|reference| reference.message_id),
+        None => None,
+    }
+}
+
+/// Stores the parent of a reply, returns it when stored. The copy of the parent embedded in the
+/// reply is stored as is, the parent is only fetched when Discord left it out. Parents that can't
+/// be stored are recorded in `unavailable_messages` so they aren't fetched again.
+pub async fn store_parent(
+    rest_client: &RestClient,
+    id: u64,
+    channel_id: u64,
+    embedded: Option<Message>,
+    guild_id: Option<u64>,
+    db: &Arc<Mutex<Client>>,
+) -> BoxedResult<Option<Message>> {
+    // the parent would be skipped again when stored
+    let reason = if !sampling::is_sampled(id, sampling::sample_rate(guild_id)) {
+        "sampled_out"
+    } else {
+        let parent = match embedded {
+            Some(parent) => Some(parent),
+            None => fetch_message(rest_client, channel_id, id).await,
+        };
+        match parent {
+            Some(parent)
+                if Config::get().skip_bot_messages && parent.author.bot.unwrap_or(false) =>
+            {
+                "bot"
+            }
+            Some(parent) => {
+                process_message_common(&parent, &parent.author, guild_id, &Some(db.clone()), false)
+                    .await
+                    .map_err(|e| format!("Failed to save message {}: {}", id, e))?;
+                return Ok(Some(parent));
+            }
+            None => "not_found",
+        }
+    };
+
+    mark_message_unavailable(id, channel_id, reason, &*lock_db(db).await).await?;
+
+    Ok(None)
+}
+
+/// Fetches the parents of stored replies that aren't stored, e.g. captured out of order
+/// or sent before the archive started
+pub async fn repair(token: String, limit: i64, db: Arc<Mutex<Client>>) -> BoxedResult<()> {
+    let parents = get_missing_reply_parents(limit, &*lock_db(&db).await).await?;
+    if parents.is_empty() {
+        info!("No missing reply parents");
+        return Ok(());
+    }
+
+    info!("Fetching {} missing reply parents...", parents.len());
+
+    let rest_client = RestClient::connect(token, Some(9), None)
+        .await
+        .map_err(|e| format!("Error connecting to Discord REST API: {}", e))?;
+
+    let mut fetched = 0;
+    for (id, channel_id, guild_id) in parents {
+        if store_parent(&rest_client, id, channel_id, None, guild_id, &db)
+            .await?
+            .is_some()
+        {
+            fetched += 1;
+        }
+
+        tokio::time::sleep(FETCH_DELAY).await;
+    }
+
+    info!("Fetched {} missing reply parents", fetched);
+
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use crate::config::Config;
+
+/// Fraction of the messages stored for the guild, 1.0 when it isn't sampled
+pub fn sample_rate(guild_id: Option<u64>) -> f64 {
+    let Some(guild_id) = guild_id else {
+        return 1.0;
+    };
+
+    Config::get()
+        .sampling_rules
+        .iter()
+        .find(|rule| rule.guild_id == guild_id)
+        .map(|rule| rule.rate.clamp(0.0, 1.0))
+        .unwrap_or(1.0)
+}
+
+/// Whether the message is kept, derived from its id so creates, edits and
+/// deletes of the same message always get the same answer
+pub fn is_sampled(message_id: u64, rate: f64) -> bool {
+    if rate >= 1.0 {
+        return true;
+    }
+
+    // splitmix64 finalizer, snowflakes are far from uniformly distributed
+    let mut x = message_id.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^= x >> 31;
+
+    (x as f64 / u64::MAX as f64) < rate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_rate_keeps_everything() {
+        assert!((0..1000).all(|id| is_sampled(id, 1.0)));
+        assert!((0..1000).all(|id| is_sampled(id, 1.5)));
+    }
+
+    #[test]
+    fn zero_rate_keeps_nothing() {
+        assert!(!(0..1000).any(|id| is_sampled(id, 0.0)));
+    }
+
+    #[test]
+    fn same_message_same_answer() {
+        let id = 1_242_942_913_849_429_429;
+        assert_eq!(is_sampled(id, 0.3), is_sampled(id, 0.3));
+    }
+
+    #[test]
+    fn rate_is_respected_on_snowflakes() {
+        // consecutive snowflakes of one worker, the worst case for a plain modulo
+        let kept = (0..10_000u64)
+            .map(|i| (1_200_000_000_000_000_000 + (i << 22)))
+            .filter(|id| is_sampled(*id, 0.25))
+            .count();
+        assert!((2_200..2_800).contains(&kept), "{}", kept);
+    }
+}
@@ -0,0 +1,138 @@
+use crate::config::Config;
+use log::{error, warn};
+use std::error::Error;
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::process::Command;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Outcome of the scan of a downloaded file, stored in `attachments.scan_status`
+pub enum ScanResult {
+    Clean,
+    Infected(String),
+    /// The scanner couldn't be reached or couldn't read the file
+    Failed(String),
+}
+
+impl ScanResult {
+    pub fn status(&self) -> &'static str {
+        match self {
+            ScanResult::Clean => "clean",
+            ScanResult::Infected(_) => "infected",
+            ScanResult::Failed(_) => "error",
+        }
+    }
+
+    /// Signature found, or the scan error
+    pub fn detail(&self) -> Option<&str> {
+        match self {
+            ScanResult::Clean => None,
+            ScanResult::Infected(detail) | ScanResult::Failed(detail) => Some(detail),
+        }
+    }
+}
+
+/// Scans the file with clamd or the configured command, `None` when scanning is disabled
+pub async fn scan(path: &str) -> Option<ScanResult> {
+    let scan = Config::get().scan.as_ref()?;
+
+    let result = if let Some(address) = &scan.clamd_address {
+        scan_clamd(address, path).await
+    } else if let Some(command) = &scan.command {
+        scan_command(command, path).await
+    } else {
+        return None;
+    };
+
+    Some(result.unwrap_or_else(|e| {
+        warn!("Failed to scan {}: {}", path, e);
+        ScanResult::Failed(e.to_string())
+    }))
+}
+
+async fn scan_clamd(address: &str, path: &str) -> Result<ScanResult, Box<dyn Error>> {
+    // a path is a unix socket, anything else a host:port
+    let response = if address.starts_with('/') {
+        instream(UnixStream::connect(address).await?, path).await?
+    } else {
+        instream(TcpStream::connect(address).await?, path).await?
+    };
+
+    // "stream: OK" or "stream: <signature> FOUND"
+    let verdict = response.trim_end_matches(['\0', '\n']);
+    let verdict = verdict.strip_prefix("stream: ").unwrap_or(verdict);
+    if verdict == "OK" {
+        Ok(ScanResult::Clean)
+    } else if let Some(signature) = verdict.strip_suffix(" FOUND") {
+        Ok(ScanResult::Infected(signature.to_string()))
+    } else {
+        Err(format!("clamd answered: {}", verdict).into())
+    }
+}
+
+// INSTREAM sends the file as chunks prefixed by their big endian length, ended by an empty one
+async fn instream<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    path: &str,
+) -> Result<String, Box<dyn Error>> {
+    stream.write_all(b"zINSTREAM\0").await?;
+
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        stream.write_all(&(read as u32).to_be_bytes()).await?;
+        stream.write_all(&buffer[..read]).await?;
+    }
+    stream.write_all(&0u32.to_be_bytes()).await?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+
+    Ok(response)
+}
+
+// exit codes of clamscan: 0 clean, 1 infected, anything else an error
+async fn scan_command(command: &[String], path: &str) -> Result<ScanResult, Box<dyn Error>> {
+    let (program, args) = command.split_first().ok_or("scan.command is empty")?;
+    let output = Command::new(program)
+        .args(args.iter().map(|arg| arg.replace("{path}", path)))
+        .output()
+        .await?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    match output.status.code() {
+        Some(0) => Ok(ScanResult::Clean),
+        Some(1) => Ok(ScanResult::Infected(stdout)),
+        _ => Err(format!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into()),
+    }
+}
+
+/// Moves an infected file to `scan.quarantine_dir`, returning its new path
+pub fn quarantine(path: &str) -> Option<String> {
+    let quarantine_dir = &Config::get().scan.as_ref()?.quarantine_dir;
+    let file_name = Path::new(path).file_name()?;
+    let target = Path::new(quarantine_dir).join(file_name);
+
+    let moved = std::fs::create_dir_all(quarantine_dir)
+        .and_then(|_| std::fs::rename(path, &target))
+        // rename doesn't work across filesystems
+        .or_else(|_| std::fs::copy(path, &target).and_then(|_| std::fs::remove_file(path)));
+    match moved {
+        Ok(()) => Some(target.to_string_lossy().into_owned()),
+        Err(e) => {
+            error!("Failed to quarantine {}: {}", path, e);
+            None
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use crate::BoxedResult;
+use crate::config::{Config, ScoringConfig};
+use crate::database::{MessageScore, get_unscored_messages, save_message_scores};
+use crate::maintenance;
+use log::info;
+use serde::Deserialize;
+use tokio_postgres::Client;
+
+/// Toxicity from which a message counts as toxic in the statistics
+pub const TOXIC_THRESHOLD: f32 = 0.5;
+
+#[derive(Deserialize)]
+struct LabelScore {
+    label: String,
+    score: f32,
+}
+
+fn config() -> BoxedResult<&'static ScoringConfig> {
+    Config::get()
+        .scoring
+        .as_ref()
+        .ok_or_else(|| "Message scoring is not configured, add a [scoring] section".into())
+}
+
+/// Calls a Hugging Face text classification endpoint, returning the score of every label
+/// for each text
+async fn classify(
+    config: &ScoringConfig,
+    endpoint: &str,
+    texts: &[String],
+) -> BoxedResult<Vec<Vec<LabelScore>>> {
+    let body = serde_json::json!({ "inputs": texts });
+
+    let client = rquest::Client::new();
+    let mut request = client
+        .post(endpoint)
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_string(&body)?);
+
+    if let Some(api_key) = &config.api_key {
+        request = request.header("Authorization", &format!("Bearer {}", api_key));
+    }
+
+    let response = request.send().await?;
+    let status = response.status();
+    let text = response.text().await?;
+    if !status.is_success() {
+        return Err(format!("Classification endpoint returned {}: {}", status, text).into());
+    }
+
+    let scores: Vec<Vec<LabelScore>> = serde_json::from_str(&text)?;
+    if scores.len() != texts.len() {
+        return Err("Classification endpoint returned a wrong amount of results".into());
+    }
+
+    Ok(scores)
+}
+
+fn label_score(scores: &[LabelScore], label: &str) -> f32 {
+    scores
+        .iter()
+        .filter(|score| score.label.eq_ignore_ascii_case(label))
+        .map(|score| score.score)
+        .sum()
+}
+
+/// Scores the toxicity and sentiment of the stored messages that weren't scored yet
+pub async fn backfill(limit: Option<usize>, db: &Client) -> BoxedResult<()> {
+    let config = config()?;
+    let mut total = 0;
+
+    loop {
+        let batch_size = match limit {
+            Some(limit) if limit <= total => break,
+            Some(limit) => config.batch_size.min(limit - total),
+            None => config.batch_size,
+        };
+
+        maintenance::wait_for_window("message scoring").await;
+
+        let messages = get_unscored_messages(batch_size as i64, db).await?;
+        if messages.is_empty() {
+            break;
+        }
+        let texts: Vec<String> = messages
+            .iter()
+            .map(|(_, content)| content.clone())
+            .collect();
+
+        let toxicity = match &config.toxicity_endpoint {
+            Some(endpoint) => Some(classify(config, endpoint, &texts).await?),
+            None => None,
+        };
+        let sentiment = match &config.sentiment_endpoint {
+            Some(endpoint) => Some(classify(config, endpoint, &texts).await?),
+            None => None,
+        };
+
+        let scores: Vec<MessageScore> = messages
+            .iter()
+            .enumerate()
+            .map(|(index, (message_id, _))| MessageScore {
+                message_id: *message_id,
+                toxicity: toxicity.as_ref().map(|toxicity| {
+                    config
+                        .toxic_labels
+                        .iter()
+                        .map(|label| label_score(&toxicity[index], label))
+                        .sum::<f32>()
+                        .min(1.0)
+                }),
+                // from -1.0 (negative) to 1.0 (positive)
+                sentiment: sentiment.as_ref().map(|sentiment| {
+                    label_score(&sentiment[index], &config.positive_label)
+                        - label_score(&sentiment[index], &config.negative_label)
+                }),
+            })
+            .collect();
+        save_message_scores(&scores, db).await?;
+
+        total += scores.len();
+        info!("Scored {} messages", total);
+    }
+
+    info!("Message scoring done, {} messages scored", total);
+
+    Ok(())
+}
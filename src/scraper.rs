@@ -1,49 +1,140 @@
 use crate::BoxedResult;
+use crate::audit_log::fetch_audit_logs;
 use crate::config::Config;
-use crate::event_processor::message::process_message_common;
+use crate::database::{
+    bulk_upsert_channels, get_average_message_size, get_channel_guild_id, get_guild_channel_ids,
+    get_guild_message_channel_ids, get_guild_thread_parent_ids, get_known_message_ids,
+    get_latest_channel_message_id, get_latest_guild_message_id, lock_db,
+};
+use crate::downloader;
+use crate::event_processor::message::{process_message_common, sync_pins};
+use crate::progress::{Progress, ProgressFormat, format_duration};
+use crate::ratelimit::{RateLimiter, Route};
+use crate::references;
 use clap::ValueEnum;
 use discord_client_rest::rest::RestClient;
+use discord_client_structs::structs::channel::Channel;
 use discord_client_structs::structs::message::Message;
 use discord_client_structs::structs::message::query::{
-    MessageQuery, MessageQueryBuilder, MessageSearchQueryBuilder, MessageSearchResult,
+    MessageQuery, MessageQueryBuilder, MessageSearchQuery, MessageSearchQueryBuilder,
 };
-use log::{error, info};
-use progress_bar::*;
+use futures::future::join_all;
+use log::{debug, error, info, warn};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use tokio_postgres::Client;
 
+// parents of parents fetched at most, reply chains are rarely longer
+const MAX_PARENT_DEPTH: usize = 10;
+const KNOWN_IDS_BATCH_SIZE: usize = 10000;
+// max threads per page allowed by discord
+const THREAD_PAGE_LIMIT: u32 = 100;
+const CHANNEL_PAGE_SIZE: u64 = 100;
+const SEARCH_PAGE_SIZE: u64 = 25;
+// stored message with its indexes, when the database has no statistics yet
+const DEFAULT_MESSAGE_SIZE: u64 = 2048;
+
 pub struct Scraper {
     pub bots: Vec<RestClient>,
+    limiter: RateLimiter,
+    progress: Progress,
     id: u64,
     scrape_type: ScrapeType,
+    options: ScrapeOptions,
     db_client: Option<Arc<Mutex<Client>>>,
 }
 
-#[derive(ValueEnum, Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum ScrapeType {
     Channel,
     Guild,
 }
 
+/// How guild scrapes go through the messages
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GuildStrategy {
+    /// Page through the search results of the guild, one token at a time
+    #[default]
+    Search,
+    /// Read the history of each channel, one channel per token at the same time
+    Channels,
+}
+
+/// Part of the channel or search results to scrape, as message ids, everything when empty
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScrapeWindow {
+    /// The page of messages around this one
+    pub around: Option<u64>,
+    pub before: Option<u64>,
+    pub after: Option<u64>,
+}
+
+impl ScrapeWindow {
+    pub fn is_empty(&self) -> bool {
+        self.around.is_none() && self.before.is_none() && self.after.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ScrapeOptions {
+    pub strategy: GuildStrategy,
+    /// Start after the newest stored message
+    pub since_db: bool,
+    pub window: ScrapeWindow,
+    /// Only the messages of this user, found with the guild search
+    pub author_id: Option<u64>,
+    /// Only the messages matching this guild search query
+    pub content: Option<String>,
+    /// Only the guild search results in this channel
+    pub channel_id: Option<u64>,
+    /// Only the guild search results mentioning this user
+    pub mentions: Option<u64>,
+    pub progress: ProgressFormat,
+}
+
+impl ScrapeOptions {
+    /// Whether only some of the messages are wanted, the pins, audit log and archived threads
+    /// of the target are left alone then
+    pub fn is_filtered(&self) -> bool {
+        !self.window.is_empty()
+            || self.author_id.is_some()
+            || self.content.is_some()
+            || self.channel_id.is_some()
+            || self.mentions.is_some()
+    }
+}
+
 impl Scraper {
     pub async fn new(
         tokens: Vec<String>,
         id: u64,
         scrape_type: ScrapeType,
+        options: ScrapeOptions,
         db_client: Option<Arc<Mutex<Client>>>,
     ) -> Scraper {
         let mut bots = Vec::new();
-        for token in tokens {
+        for token in &tokens {
             match RestClient::connect(token.clone(), Some(9), None).await {
                 Ok(client) => bots.push(client),
                 Err(e) => eprintln!("Failed to connect with token: {}. Error: {}", token, e),
             }
         }
+
+        // downloads of attachments whose URL expired meanwhile
+        if Config::get().download_files
+            && let Some(token) = tokens.first()
+            && let Ok(client) = RestClient::connect(token.clone(), Some(9), None).await
+        {
+            downloader::set_refresh_client(Arc::new(client)).await;
+        }
         Scraper {
+            limiter: RateLimiter::new(bots.len()),
+            progress: Progress::new(options.progress),
             bots,
             id,
             scrape_type,
+            options,
             db_client,
         }
     }
@@ -52,30 +143,452 @@ impl Scraper {
         if self.bots.is_empty() {
             return Err("No valid bots connected for scraping".into());
         }
+        if self.options.since_db && self.db_client.is_none() {
+            return Err("--since-db needs a database".into());
+        }
 
-        let mut bot_index = 0;
-        let mut scrape_state = ScrapeState::new();
+        if !self.options.is_filtered() {
+            self.scrape_pins().await;
+            self.scrape_audit_logs().await;
+        }
 
-        loop {
-            if bot_index >= self.bots.len() {
-                bot_index = 0;
+        // search results carry their total, channel histories don't
+        if (self.scrape_type == ScrapeType::Channel && self.options.window.around.is_none())
+            || self.options.strategy == GuildStrategy::Channels
+        {
+            match self.count_messages().await {
+                Ok((total, _)) => self.progress.set_total(total),
+                Err(e) => debug!("Can't count the messages to scrape: {}", e),
             }
+        }
+
+        let mut scraped_channels = HashSet::new();
+        let mut reply_parents = if self.scrape_type == ScrapeType::Guild
+            && self.options.strategy == GuildStrategy::Channels
+        {
+            let channel_ids = self.guild_channel_ids().await?;
+            scraped_channels.extend(channel_ids.iter().copied());
+            info!(
+                "Scraping {} channels of guild {} with {} bots",
+                channel_ids.len(),
+                self.id,
+                self.bots.len()
+            );
+            self.scrape_channels(channel_ids).await?
+        } else {
+            self.scrape_sequentially().await?
+        };
+
+        // search and channel histories leave out the messages of archived threads
+        let mut thread_ids = Vec::new();
+        if !self.options.is_filtered() {
+            thread_ids = self.archived_thread_ids().await?;
+            thread_ids.retain(|id| !scraped_channels.contains(id));
+        }
+        if !thread_ids.is_empty() {
+            info!("Scraping {} archived threads", thread_ids.len());
+            reply_parents.extend(self.scrape_channels(thread_ids).await?);
+        }
+
+        self.progress.finish();
+        self.limiter.log_stats();
+
+        self.fetch_missing_parents(reply_parents).await?;
+
+        Ok(())
+    }
+
+    /// Reports how many messages the scrape would go through, and the requests, time and
+    /// storage it would take, from the first search page. Nothing is stored.
+    pub async fn estimate(&self) -> BoxedResult<()> {
+        if self.options.window.around.is_some() {
+            info!(
+                "Dry run: a single request for up to {} messages",
+                CHANNEL_PAGE_SIZE
+            );
+            return Ok(());
+        }
+
+        let (total, sample) = self.count_messages().await?;
+
+        let (route, page_size) = if self.scrape_type == ScrapeType::Channel
+            || self.options.strategy == GuildStrategy::Channels
+        {
+            (Route::ChannelMessages, CHANNEL_PAGE_SIZE)
+        } else {
+            (Route::Search, SEARCH_PAGE_SIZE)
+        };
+        let requests = total.div_ceil(page_size).max(1);
+        let time = route
+            .interval()
+            .mul_f64(requests as f64 / self.bots.len() as f64);
+
+        let message_size = match &self.db_client {
+            Some(db) => get_average_message_size(&*lock_db(db).await)
+                .await?
+                .unwrap_or(DEFAULT_MESSAGE_SIZE),
+            None => DEFAULT_MESSAGE_SIZE,
+        };
+        // the attachments of the first page stand for the others
+        let attachment_size = if Config::get().download_files && !sample.is_empty() {
+            let sample_size: u64 = sample
+                .iter()
+                .flat_map(|message| &message.attachments)
+                .map(|attachment| attachment.size as u64)
+                .sum();
+            sample_size * total / sample.len() as u64
+        } else {
+            0
+        };
+
+        info!("Dry run: {} messages to scrape", total);
+        info!(
+            "About {} requests, {} with {} bots",
+            requests,
+            format_duration(time),
+            self.bots.len()
+        );
+        info!(
+            "About {} in the database and {} of attachments",
+            format_bytes(message_size * total),
+            format_bytes(attachment_size)
+        );
+        info!("Archived threads and missing reply parents aren't counted");
+
+        Ok(())
+    }
+
+    /// Counts the messages of the target with the first page of its search results, channels
+    /// through the guild search of their guild. Returns the count and the page.
+    async fn count_messages(&self) -> BoxedResult<(u64, Vec<Message>)> {
+        // channel histories have no count, the guild search of the channel gives it
+        let (guild_id, channel_id) = match self.scrape_type {
+            ScrapeType::Guild => (self.id, self.options.channel_id),
+            ScrapeType::Channel => match self.channel_guild_id().await? {
+                Some(guild_id) => (guild_id, Some(self.id)),
+                None => {
+                    return Err(format!(
+                        "Channel {} isn't in a guild, its messages can't be counted",
+                        self.id
+                    )
+                    .into());
+                }
+            },
+        };
+
+        let state = self.target_state().await?;
+        let query = self.build_search_query(&state, channel_id)?;
+        let search_result = self.bots[0]
+            .guild(Some(guild_id))
+            .search_guild_messages(query)
+            .await
+            .map_err(|e| format!("Error searching guild {}: {}", guild_id, e))?;
+
+        let total = search_result.total_results as u64;
+        let page = search_result.messages.into_iter().flatten().collect();
+
+        Ok((total, page))
+    }
 
+    async fn channel_guild_id(&self) -> BoxedResult<Option<u64>> {
+        if let Some(db) = &self.db_client
+            && let Some(guild_id) = get_channel_guild_id(self.id, &*lock_db(db).await).await?
+        {
+            return Ok(Some(guild_id));
+        }
+
+        let channel = self.bots[0]
+            .channel(self.id)
+            .get_channel()
+            .await
+            .map_err(|e| format!("Error fetching channel {}: {}", self.id, e))?;
+
+        Ok(channel.guild_id)
+    }
+
+    /// State of a scrape of the target itself, within the window
+    async fn target_state(&self) -> BoxedResult<ScrapeState> {
+        let channel_id = (self.scrape_type == ScrapeType::Channel).then_some(self.id);
+        let mut state = self.initial_state(channel_id).await?;
+        state.around = self.options.window.around;
+        state.until = self.options.window.before;
+        if let Some(before) = self.options.window.before {
+            state.last_id = before;
+        }
+        if let Some(after) = self.options.window.after {
+            state.after = Some(after);
+        } else if let Some(before) = self.options.window.before {
+            state.last_message_id = Some(before);
+        }
+
+        Ok(state)
+    }
+
+    /// Scrapes the target page by page, each page with the token with the most budget left.
+    /// Returns the reply parents to fetch.
+    async fn scrape_sequentially(&self) -> BoxedResult<ReplyParents> {
+        let route = match self.scrape_type {
+            ScrapeType::Channel => Route::ChannelMessages,
+            ScrapeType::Guild => Route::Search,
+        };
+        let mut scrape_state = self.target_state().await?;
+
+        loop {
+            let bot_index = self.limiter.pick(route);
             let bot = &self.bots[bot_index];
 
             let should_continue = match self.scrape_type {
                 ScrapeType::Channel => {
-                    self.scrape_channel(bot, bot_index, &mut scrape_state)
+                    self.scrape_channel(bot, bot_index, self.id, &mut scrape_state)
                         .await?
                 }
-                ScrapeType::Guild => self.scrape_guild(bot, &mut scrape_state).await?,
+                ScrapeType::Guild => self.scrape_guild(bot, bot_index, &mut scrape_state).await?,
             };
 
             if !should_continue {
                 break;
             }
+        }
+
+        Ok(scrape_state.reply_parents)
+    }
+
+    /// Scrapes the history of the channels, each token working through its own channel at the
+    /// same time. Returns the reply parents to fetch.
+    async fn scrape_channels(&self, channel_ids: Vec<u64>) -> BoxedResult<ReplyParents> {
+        let queue = std::sync::Mutex::new(VecDeque::from(channel_ids));
+        let workers = (0..self.bots.len()).map(|bot_index| self.channel_worker(bot_index, &queue));
+
+        let mut reply_parents = HashMap::new();
+        for parents in join_all(workers).await {
+            reply_parents.extend(parents?);
+        }
+
+        Ok(reply_parents)
+    }
+
+    async fn channel_worker(
+        &self,
+        bot_index: usize,
+        queue: &std::sync::Mutex<VecDeque<u64>>,
+    ) -> BoxedResult<ReplyParents> {
+        let bot = &self.bots[bot_index];
+        let mut reply_parents = HashMap::new();
+
+        loop {
+            let Some(channel_id) = queue.lock().unwrap().pop_front() else {
+                break;
+            };
+
+            let mut state = self.initial_state(Some(channel_id)).await?;
+            while self
+                .scrape_channel(bot, bot_index, channel_id, &mut state)
+                .await?
+            {}
+            reply_parents.extend(state.reply_parents);
+        }
+
+        Ok(reply_parents)
+    }
+
+    /// Channels of the guild with a message history, from the database, or fetched and stored
+    /// when the guild isn't known yet
+    async fn guild_channel_ids(&self) -> BoxedResult<Vec<u64>> {
+        if let Some(db) = &self.db_client {
+            let ids = get_guild_message_channel_ids(self.id, &*lock_db(db).await).await?;
+            if !ids.is_empty() {
+                return Ok(ids);
+            }
+        }
+
+        Ok(self
+            .fetch_guild_channels()
+            .await?
+            .iter()
+            .filter(|channel| matches!(channel.r#type as i32, 0 | 2 | 5 | 13))
+            .map(|channel| channel.id)
+            .collect())
+    }
+
+    async fn fetch_guild_channels(&self) -> BoxedResult<Vec<Channel>> {
+        let channels = self.bots[0]
+            .guild(Some(self.id))
+            .get_channels()
+            .await
+            .map_err(|e| format!("Error fetching channels of guild {}: {}", self.id, e))?;
+        if let Some(db) = &self.db_client {
+            bulk_upsert_channels(&channels, Some(self.id), &*lock_db(db).await).await?;
+        }
+
+        Ok(channels)
+    }
+
+    /// Public and private archived threads of the scraped channel, or of the channels of the
+    /// scraped guild, stored along the way
+    async fn archived_thread_ids(&self) -> BoxedResult<Vec<u64>> {
+        let parent_ids = match self.scrape_type {
+            ScrapeType::Channel => vec![self.id],
+            ScrapeType::Guild => {
+                let mut ids = Vec::new();
+                if let Some(db) = &self.db_client {
+                    ids = get_guild_thread_parent_ids(self.id, &*lock_db(db).await).await?;
+                }
+                if ids.is_empty() {
+                    ids = self
+                        .fetch_guild_channels()
+                        .await?
+                        .iter()
+                        .filter(|channel| matches!(channel.r#type as i32, 0 | 5 | 15 | 16))
+                        .map(|channel| channel.id)
+                        .collect();
+                }
+                ids
+            }
+        };
+
+        let guild_id = (self.scrape_type == ScrapeType::Guild).then_some(self.id);
+        let mut thread_ids = Vec::new();
+        for parent_id in parent_ids {
+            for private in [false, true] {
+                // private ones need the manage threads permission, most tokens can't list them
+                let threads = match self.fetch_archived_threads(parent_id, private).await {
+                    Ok(threads) => threads,
+                    Err(e) => {
+                        debug!("{}", e);
+                        continue;
+                    }
+                };
+
+                if let Some(db) = &self.db_client {
+                    bulk_upsert_channels(&threads, guild_id, &*lock_db(db).await).await?;
+                }
+                thread_ids.extend(threads.iter().map(|thread| thread.id));
+            }
+        }
+
+        Ok(thread_ids)
+    }
+
+    async fn fetch_archived_threads(
+        &self,
+        parent_id: u64,
+        private: bool,
+    ) -> BoxedResult<Vec<Channel>> {
+        let mut threads = Vec::new();
+        let mut before = None;
+
+        loop {
+            let bot_index = self.limiter.pick(Route::ArchivedThreads);
+            self.limiter
+                .acquire(bot_index, Route::ArchivedThreads)
+                .await;
+
+            let channel_rest = self.bots[bot_index].channel(parent_id);
+            let result = if private {
+                channel_rest
+                    .get_archived_private_threads(before, Some(THREAD_PAGE_LIMIT))
+                    .await
+            } else {
+                channel_rest
+                    .get_archived_public_threads(before, Some(THREAD_PAGE_LIMIT))
+                    .await
+            };
+            let page = match result {
+                Ok(page) => {
+                    self.limiter.record_success(bot_index);
+                    page
+                }
+                Err(e) => {
+                    let error = e.to_string();
+                    if self.limiter.record_error(bot_index, &error) {
+                        continue;
+                    }
+                    return Err(format!(
+                        "Error fetching archived threads of channel {}: {}",
+                        parent_id, error
+                    )
+                    .into());
+                }
+            };
+
+            before = page
+                .threads
+                .iter()
+                .filter_map(|thread| thread.thread_metadata.as_ref()?.archive_timestamp)
+                .min();
+            threads.extend(page.threads);
+
+            if !page.has_more || before.is_none() {
+                break;
+            }
+        }
+
+        Ok(threads)
+    }
+
+    /// Fetches the parents of scraped replies that weren't scraped, e.g. outside of the
+    /// search results, and their own parents, so reply chains aren't cut
+    async fn fetch_missing_parents(&self, mut parents: ReplyParents) -> BoxedResult<()> {
+        let Some(db) = &self.db_client else {
+            return Ok(());
+        };
+
+        let guild_id = (self.scrape_type == ScrapeType::Guild).then_some(self.id);
+        let mut fetched = 0;
+
+        for _ in 0..MAX_PARENT_DEPTH {
+            // most parents were scraped after their replies
+            let ids: Vec<u64> = parents.keys().copied().collect();
+            for chunk in ids.chunks(KNOWN_IDS_BATCH_SIZE) {
+                for id in get_known_message_ids(chunk, &*lock_db(db).await).await? {
+                    parents.remove(&id);
+                }
+            }
+            if parents.is_empty() {
+                break;
+            }
+
+            info!("Fetching {} missing reply parents...", parents.len());
+
+            let mut next_parents = HashMap::new();
+            for (index, (id, parent)) in parents.into_iter().enumerate() {
+                let bot = &self.bots[index % self.bots.len()];
+                let fetch = parent.message.is_none();
+                match references::store_parent(
+                    bot,
+                    id,
+                    parent.channel_id,
+                    parent.message,
+                    guild_id,
+                    db,
+                )
+                .await
+                {
+                    Ok(Some(parent)) => {
+                        fetched += 1;
+                        if let Some(grandparent_id) = references::reply_parent_id(&parent) {
+                            next_parents.insert(
+                                grandparent_id,
+                                ReplyParent {
+                                    channel_id: parent.channel_id,
+                                    message: parent.referenced_message.map(|message| *message),
+                                },
+                            );
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => error!("Error fetching reply parent {}: {}", id, e),
+                }
+
+                if fetch {
+                    tokio::time::sleep(references::FETCH_DELAY).await;
+                }
+            }
+            parents = next_parents;
+        }
 
-            bot_index += 1;
+        if fetched > 0 {
+            info!("Fetched {} missing reply parents", fetched);
         }
 
         Ok(())
@@ -85,16 +598,33 @@ impl Scraper {
         &self,
         bot: &RestClient,
         bot_index: usize,
+        channel_id: u64,
         state: &mut ScrapeState,
     ) -> BoxedResult<bool> {
-        let message_rest = bot.message(self.id);
-        let query = self.build_channel_query(state.last_message_id)?;
-
-        let messages = match message_rest.get_channel_messages(None, query).await {
-            Ok(messages) => messages,
+        let message_rest = bot.message(channel_id);
+        let query = self.build_channel_query(state)?;
+
+        self.limiter
+            .acquire(bot_index, Route::ChannelMessages)
+            .await;
+        let mut messages = match message_rest.get_channel_messages(None, query).await {
+            Ok(messages) => {
+                self.limiter.record_success(bot_index);
+                messages
+            }
             Err(e) => {
-                error!("Error fetching messages: {}", e);
-                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                let error = e.to_string();
+                if self.limiter.record_error(bot_index, &error) {
+                    return Ok(true); // Retry once the cooldown is over
+                }
+                if is_missing_access(&error) {
+                    warn!(
+                        "Bot {} can't read channel {}: {}",
+                        bot_index, channel_id, error
+                    );
+                    return Ok(false);
+                }
+                error!("Error fetching messages: {}", error);
                 return Ok(true); // Continue with the next bot
             }
         };
@@ -102,45 +632,78 @@ impl Scraper {
         if messages.is_empty() {
             info!(
                 "Bot {}: No more messages to scrape in channel {}",
-                bot_index, self.id
+                bot_index, channel_id
             );
             return Ok(false); // Scraping done for this channel
         }
 
-        self.process_messages(&messages, true).await?;
+        // going forward, the page can cross the end of the window
+        let page_size = messages.len();
+        if let Some(until) = state.until {
+            messages.retain(|m| m.id < until);
+        }
+        let window_end = messages.len() < page_size;
 
-        state.last_message_id = Some(
-            messages
-                .iter()
-                .min_by_key(|m| m.id)
-                .map(|m| m.id)
-                .unwrap_or_default(),
-        );
+        // the content of whole guilds would flood the logs
+        let log_content = self.scrape_type == ScrapeType::Channel;
+        self.process_messages(&messages, log_content).await?;
+        state.record_reply_parents(&messages);
+        self.progress.add(messages.len() as u64);
+
+        if state.around.is_some() || window_end {
+            info!(
+                "Bot {}: Scraped the requested messages of channel {}",
+                bot_index, channel_id
+            );
+            return Ok(false);
+        }
+
+        // pages come newest first either way
+        if state.after.is_some() {
+            state.after = messages.iter().map(|m| m.id).max();
+        } else {
+            state.last_message_id = Some(
+                messages
+                    .iter()
+                    .min_by_key(|m| m.id)
+                    .map(|m| m.id)
+                    .unwrap_or_default(),
+            );
+        }
 
         Ok(true)
     }
 
-    async fn scrape_guild(&self, bot: &RestClient, state: &mut ScrapeState) -> BoxedResult<bool> {
+    async fn scrape_guild(
+        &self,
+        bot: &RestClient,
+        bot_index: usize,
+        state: &mut ScrapeState,
+    ) -> BoxedResult<bool> {
         let guild_rest = bot.guild(Some(self.id));
-        let query = MessageSearchQueryBuilder::default()
-            .max_id(state.last_id)
-            .include_nsfw(true)
-            .build()?;
+        let query = self.build_search_query(state, self.options.channel_id)?;
 
-        let search_result = guild_rest.search_guild_messages(query).await?;
+        self.limiter.acquire(bot_index, Route::Search).await;
+        let search_result = match guild_rest.search_guild_messages(query).await {
+            Ok(search_result) => {
+                self.limiter.record_success(bot_index);
+                search_result
+            }
+            Err(e) => {
+                let error = e.to_string();
+                if self.limiter.record_error(bot_index, &error) {
+                    return Ok(true); // Retry with the next bot
+                }
+                return Err(error.into());
+            }
+        };
 
-        self.initialize_progress_bar_if_needed(&search_result, &mut state.progress_bar_initialized);
+        self.progress.set_total(search_result.total_results as u64);
 
         let mut messages: Vec<Message> = search_result.messages.into_iter().flatten().collect();
         let count = messages.len();
 
         if count == 0 {
-            print_progress_bar_info(
-                "Finished",
-                "No more messages to scrape in guild",
-                Color::Green,
-                Style::Bold,
-            );
             return Ok(false); // Scraping done for this guild
         }
 
@@ -157,67 +720,202 @@ impl Scraper {
                 .collect();
         }
 
-        state.progress += count;
-        set_progress_bar_progress(state.progress);
+        self.progress.add(count as u64);
 
         self.process_messages(&messages, false).await?;
+        state.record_reply_parents(&messages);
 
         Ok(true)
     }
 
-    fn build_channel_query(&self, last_message_id: Option<u64>) -> BoxedResult<MessageQuery> {
+    async fn scrape_pins(&self) {
+        if self.db_client.is_none() {
+            return;
+        }
+
+        let (channel_ids, guild_id) = match self.scrape_type {
+            ScrapeType::Channel => (vec![self.id], None),
+            ScrapeType::Guild => {
+                let db = lock_db(self.db_client.as_ref().unwrap()).await;
+                match get_guild_channel_ids(self.id, &db).await {
+                    Ok(ids) => (ids, Some(self.id)),
+                    Err(e) => {
+                        error!("Error fetching channels of guild {}: {}", self.id, e);
+                        return;
+                    }
+                }
+            }
+        };
+
+        for (index, channel_id) in channel_ids.into_iter().enumerate() {
+            let bot = &self.bots[index % self.bots.len()];
+            if let Err(e) = sync_pins(bot, channel_id, guild_id, None, &self.db_client).await {
+                error!("Error syncing pins of channel {}: {}", channel_id, e);
+            }
+        }
+
+        info!("Pinned messages synced");
+    }
+
+    async fn scrape_audit_logs(&self) {
+        if self.scrape_type != ScrapeType::Guild {
+            return;
+        }
+        let Some(db_client) = &self.db_client else {
+            return;
+        };
+
+        // the audit log is only readable with the view audit log permission
+        for bot in &self.bots {
+            match fetch_audit_logs(bot, self.id, db_client).await {
+                Ok(_) => return,
+                Err(e) => debug!("Bot can't read the audit log: {}", e),
+            }
+        }
+
+        info!("No bot has access to the audit log of guild {}", self.id);
+    }
+
+    /// State of a new scrape of the channel, or of the guild search without one. With
+    /// `--since-db`, it starts after the newest stored message.
+    async fn initial_state(&self, channel_id: Option<u64>) -> BoxedResult<ScrapeState> {
+        let mut state = ScrapeState::new();
+        if !self.options.since_db {
+            return Ok(state);
+        }
+        let Some(db) = &self.db_client else {
+            return Ok(state);
+        };
+
+        let db = lock_db(db).await;
+        state.after = match channel_id {
+            Some(channel_id) => get_latest_channel_message_id(channel_id, &db).await?,
+            None => get_latest_guild_message_id(self.id, &db).await?,
+        };
+
+        Ok(state)
+    }
+
+    fn build_search_query(
+        &self,
+        state: &ScrapeState,
+        channel_id: Option<u64>,
+    ) -> BoxedResult<MessageSearchQuery> {
+        let mut builder = MessageSearchQueryBuilder::default();
+        builder.max_id(state.last_id).include_nsfw(true);
+        if let Some(after) = state.after {
+            builder.min_id(after);
+        }
+        if let Some(author_id) = self.options.author_id {
+            builder.author_id(author_id);
+        }
+        if let Some(content) = &self.options.content {
+            builder.content(content.clone());
+        }
+        if let Some(channel_id) = channel_id {
+            builder.channel_id(channel_id);
+        }
+        if let Some(mentions) = self.options.mentions {
+            builder.mentions(mentions);
+        }
+
+        Ok(builder.build()?)
+    }
+
+    fn build_channel_query(&self, state: &ScrapeState) -> BoxedResult<MessageQuery> {
         let mut builder = MessageQueryBuilder::default();
         builder.limit(100);
 
-        if let Some(last_id) = last_message_id {
+        if let Some(around) = state.around {
+            builder.around(around);
+        } else if let Some(after) = state.after {
+            builder.after(after);
+        } else if let Some(last_id) = state.last_message_id {
             builder.before(last_id);
         }
 
         Ok(builder.build()?)
     }
 
-    async fn process_messages(&self, messages: &[Message], is_channel: bool) -> BoxedResult<()> {
+    async fn process_messages(&self, messages: &[Message], log_content: bool) -> BoxedResult<()> {
         for message in messages {
-            process_message_common(
+            if let Err(e) = process_message_common(
                 message,
                 &message.author,
                 Some(self.id),
                 &self.db_client,
-                is_channel,
+                log_content,
             )
             .await
-            .unwrap();
+            {
+                error!("{}", e);
+            }
         }
         Ok(())
     }
-
-    fn initialize_progress_bar_if_needed(
-        &self,
-        search_result: &MessageSearchResult,
-        progress_bar_initialized: &mut bool,
-    ) {
-        if !*progress_bar_initialized {
-            init_progress_bar(search_result.total_results as usize);
-            set_progress_bar_action("Scraping", Color::Blue, Style::Bold);
-            *progress_bar_initialized = true;
-        }
-    }
 }
 
 struct ScrapeState {
     last_message_id: Option<u64>,
-    progress_bar_initialized: bool,
-    progress: usize,
     last_id: u64,
+    // newest stored message with --since-db, or --after, the scrape goes forward from it
+    after: Option<u64>,
+    // --before while going forward
+    until: Option<u64>,
+    around: Option<u64>,
+    // parents of the scraped replies, by id
+    reply_parents: ReplyParents,
 }
 
 impl ScrapeState {
     fn new() -> Self {
         Self {
             last_message_id: None,
-            progress_bar_initialized: false,
-            progress: 0,
             last_id: (chrono::Utc::now().timestamp_millis() << 22) as u64,
+            after: None,
+            until: None,
+            around: None,
+            reply_parents: HashMap::new(),
         }
     }
+
+    fn record_reply_parents(&mut self, messages: &[Message]) {
+        for message in messages {
+            if let Some(parent_id) = references::reply_parent_id(message) {
+                self.reply_parents.insert(
+                    parent_id,
+                    ReplyParent {
+                        channel_id: message.channel_id,
+                        message: message.referenced_message.as_deref().cloned(),
+                    },
+                );
+            }
+        }
+    }
+}
+
+// parent of a scraped reply, with the copy of it embedded in the reply when Discord includes it
+struct ReplyParent {
+    channel_id: u64,
+    message: Option<Message>,
+}
+
+type ReplyParents = HashMap<u64, ReplyParent>;
+
+// the token isn't in the channel or lacks the permission to read its history
+fn is_missing_access(error: &str) -> bool {
+    let error = error.to_lowercase();
+    error.contains("403") || error.contains("404") || error.contains("missing access")
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", size, UNITS[unit])
 }
@@ -0,0 +1,325 @@
+use crate::BoxedResult;
+use crate::config::{Config, SearchBackend, SearchIndexConfig};
+use crate::database::{IndexMessage, get_messages_for_index};
+use crate::language;
+use crate::maintenance;
+use crate::tagging;
+use crate::timezone;
+use discord_client_structs::structs::message::Message;
+use log::{debug, error, info, warn};
+use serde_json::{Value, json};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+use tokio_postgres::Client;
+
+static SENDER: OnceLock<UnboundedSender<Operation>> = OnceLock::new();
+
+// failed batches are retried, up to this many batches
+const MAX_PENDING_BATCHES: usize = 10;
+
+enum Operation {
+    Upsert(Value),
+    Delete(u64),
+}
+
+// ids are strings, javascript clients would round them as numbers
+fn document(message: &IndexMessage) -> Value {
+    json!({
+        "id": message.id.to_string(),
+        "channel_id": message.channel_id.to_string(),
+        "guild_id": message.guild_id.map(|id| id.to_string()),
+        "author_id": message.author_id.to_string(),
+        "author": message.username,
+        "content": message.content,
+        "created_at": timezone::snowflake_time(message.id as u64).timestamp(),
+        "language": message.language,
+        "tags": message.tags,
+    })
+}
+
+fn config() -> BoxedResult<&'static SearchIndexConfig> {
+    Config::get()
+        .search_index
+        .as_ref()
+        .ok_or_else(|| "search_index is not configured".into())
+}
+
+fn with_auth(
+    request: rquest::RequestBuilder,
+    config: &SearchIndexConfig,
+) -> rquest::RequestBuilder {
+    let Some(api_key) = &config.api_key else {
+        return request;
+    };
+
+    match config.backend {
+        SearchBackend::Meilisearch => {
+            request.header("Authorization", &format!("Bearer {}", api_key))
+        }
+        SearchBackend::Elasticsearch => {
+            request.header("Authorization", &format!("ApiKey {}", api_key))
+        }
+    }
+}
+
+async fn send(request: rquest::RequestBuilder, config: &SearchIndexConfig) -> BoxedResult<String> {
+    let response = with_auth(request, config).send().await?;
+    let status = response.status();
+    let text = response.text().await?;
+
+    if !status.is_success() {
+        return Err(format!("Search engine returned {}: {}", status, text).into());
+    }
+
+    Ok(text)
+}
+
+/// Creates the index with the attributes used to filter and sort, if missing
+async fn setup(client: &rquest::Client, config: &SearchIndexConfig) -> BoxedResult<()> {
+    let url = config.url.trim_end_matches('/');
+
+    match config.backend {
+        SearchBackend::Meilisearch => {
+            let settings = json!({
+                "searchableAttributes": ["content", "author"],
+                "filterableAttributes": ["guild_id", "channel_id", "author_id", "language", "tags", "created_at"],
+                "sortableAttributes": ["created_at"],
+            });
+            let request = client
+                .patch(&format!("{}/indexes/{}/settings", url, config.index))
+                .header("Content-Type", "application/json")
+                .body(settings.to_string());
+            send(request, config).await?;
+        }
+        SearchBackend::Elasticsearch => {
+            let mappings = json!({
+                "mappings": {
+                    "properties": {
+                        "id": { "type": "keyword" },
+                        "channel_id": { "type": "keyword" },
+                        "guild_id": { "type": "keyword" },
+                        "author_id": { "type": "keyword" },
+                        "author": { "type": "keyword" },
+                        "content": { "type": "text" },
+                        "created_at": { "type": "date", "format": "epoch_second" },
+                        "language": { "type": "keyword" },
+                        "tags": { "type": "keyword" },
+                    }
+                }
+            });
+            let request = client
+                .put(&format!("{}/{}", url, config.index))
+                .header("Content-Type", "application/json")
+                .body(mappings.to_string());
+            if let Err(e) = send(request, config).await
+                && !e.to_string().contains("resource_already_exists_exception")
+            {
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn push(
+    client: &rquest::Client,
+    config: &SearchIndexConfig,
+    documents: &[Value],
+    deleted_ids: &[u64],
+) -> BoxedResult<()> {
+    let url = config.url.trim_end_matches('/');
+
+    match config.backend {
+        SearchBackend::Meilisearch => {
+            if !documents.is_empty() {
+                let request = client
+                    .post(&format!(
+                        "{}/indexes/{}/documents?primaryKey=id",
+                        url, config.index
+                    ))
+                    .header("Content-Type", "application/json")
+                    .body(serde_json::to_string(documents)?);
+                send(request, config).await?;
+            }
+
+            if !deleted_ids.is_empty() {
+                let ids: Vec<String> = deleted_ids.iter().map(|id| id.to_string()).collect();
+                let request = client
+                    .post(&format!(
+                        "{}/indexes/{}/documents/delete-batch",
+                        url, config.index
+                    ))
+                    .header("Content-Type", "application/json")
+                    .body(serde_json::to_string(&ids)?);
+                send(request, config).await?;
+            }
+        }
+        SearchBackend::Elasticsearch => {
+            let mut body = String::new();
+            for document in documents {
+                let action = json!({ "index": { "_index": config.index, "_id": document["id"] } });
+                body.push_str(&format!("{}\n{}\n", action, document));
+            }
+            for id in deleted_ids {
+                let action = json!({ "delete": { "_index": config.index, "_id": id.to_string() } });
+                body.push_str(&format!("{}\n", action));
+            }
+            if body.is_empty() {
+                return Ok(());
+            }
+
+            let request = client
+                .post(&format!("{}/_bulk", url))
+                .header("Content-Type", "application/x-ndjson")
+                .body(body);
+            let response: Value = serde_json::from_str(&send(request, config).await?)?;
+            // the bulk API succeeds even when some of the operations failed
+            if response["errors"].as_bool().unwrap_or(false) {
+                return Err("Some documents were rejected by Elasticsearch".into());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates the index and starts pushing new messages, only run when a search index is configured
+pub async fn init() -> BoxedResult<()> {
+    let Some(config) = &Config::get().search_index else {
+        return Ok(());
+    };
+
+    let client = rquest::Client::new();
+    setup(&client, config)
+        .await
+        .map_err(|e| format!("Error setting up the search index: {}", e))?;
+
+    let (sender, receiver) = unbounded_channel();
+    tokio::spawn(run_indexer(client, config, receiver));
+    let _ = SENDER.set(sender);
+
+    info!(
+        "Indexing messages into {:?} at {}",
+        config.backend, config.url
+    );
+
+    Ok(())
+}
+
+async fn run_indexer(
+    client: rquest::Client,
+    config: &'static SearchIndexConfig,
+    mut receiver: UnboundedReceiver<Operation>,
+) {
+    let mut documents = Vec::new();
+    let mut deleted_ids = Vec::new();
+    let mut interval = tokio::time::interval(Duration::from_secs(config.flush_interval));
+
+    loop {
+        let flush_now = tokio::select! {
+            operation = receiver.recv() => {
+                match operation {
+                    Some(Operation::Upsert(document)) => documents.push(document),
+                    Some(Operation::Delete(id)) => deleted_ids.push(id),
+                    None => break,
+                }
+                documents.len() + deleted_ids.len() >= config.batch_size
+            }
+            _ = interval.tick() => true,
+        };
+
+        if !flush_now || (documents.is_empty() && deleted_ids.is_empty()) {
+            continue;
+        }
+
+        match push(&client, config, &documents, &deleted_ids).await {
+            Ok(()) => {
+                debug!(
+                    "Indexed {} messages and removed {}",
+                    documents.len(),
+                    deleted_ids.len()
+                );
+                documents.clear();
+                deleted_ids.clear();
+            }
+            Err(e) => {
+                error!("Failed to update the search index: {}", e);
+
+                let max_documents = config.batch_size * MAX_PENDING_BATCHES;
+                if documents.len() > max_documents {
+                    let dropped = documents.len() - max_documents;
+                    documents.drain(..dropped);
+                    warn!("Dropped {} documents waiting for the search index", dropped);
+                }
+            }
+        }
+    }
+}
+
+fn send_operation(operation: Operation) {
+    if let Some(sender) = SENDER.get()
+        && sender.send(operation).is_err()
+    {
+        error!("Search indexer is closed");
+    }
+}
+
+pub fn index_message(msg: &Message, guild_id: Option<u64>) {
+    if SENDER.get().is_none() {
+        return;
+    }
+    // updates without content would blank the indexed message
+    let Some(content) = msg.content.clone().filter(|content| !content.is_empty()) else {
+        return;
+    };
+
+    let message = IndexMessage {
+        id: msg.id as i64,
+        channel_id: msg.channel_id as i64,
+        guild_id: guild_id.map(|id| id as i64),
+        author_id: msg.author.id as i64,
+        username: msg.author.username.clone(),
+        language: language::detect_language(&content).map(str::to_string),
+        tags: tagging::tags_for(msg, guild_id),
+        content,
+    };
+    send_operation(Operation::Upsert(document(&message)));
+}
+
+pub fn remove_messages(message_ids: &[u64]) {
+    for id in message_ids {
+        send_operation(Operation::Delete(*id));
+    }
+}
+
+/// Pushes the stored messages to the search index, starting after the given message id
+pub async fn backfill(after_id: u64, db: &Client) -> BoxedResult<()> {
+    let config = config()?;
+    let client = rquest::Client::new();
+    setup(&client, config).await?;
+
+    let mut after_id = after_id as i64;
+    let mut total = 0;
+
+    loop {
+        maintenance::wait_for_window("search index backfill").await;
+
+        let messages = get_messages_for_index(after_id, config.batch_size as i64, db).await?;
+        let Some(last) = messages.last() else {
+            break;
+        };
+        after_id = last.id;
+
+        let documents: Vec<Value> = messages.iter().map(document).collect();
+        push(&client, config, &documents, &[]).await?;
+
+        total += documents.len();
+        info!("Indexed {} messages, up to {}", total, after_id);
+    }
+
+    info!("Search index backfill done, {} messages indexed", total);
+
+    Ok(())
+}
@@ -0,0 +1,278 @@
+use crate::BoxedResult;
+use crate::api;
+use crate::config::Config;
+use crate::database::is_db_available;
+use crate::downloader;
+use crate::status::{self, AccountActivity};
+use crate::web_archive;
+use axum::extract::Query;
+use axum::extract::Request;
+use axum::extract::ws::{Message as WsMessage, WebSocket, WebSocketUpgrade};
+use axum::http::{HeaderValue, StatusCode, header};
+use axum::middleware::{self, Next};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, OnceLock};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{Mutex, broadcast};
+use tokio::task::JoinHandle;
+use tokio_postgres::Client;
+use tower_http::services::ServeDir;
+
+// single page browsing the archive through the API
+const UI_PAGE: &str = include_str!("ui.html");
+
+// events a slow client can fall behind before missing some
+const FIREHOSE_CAPACITY: usize = 4096;
+
+static FIREHOSE: OnceLock<broadcast::Sender<Arc<FirehoseEvent>>> = OnceLock::new();
+
+struct FirehoseEvent {
+    guild_id: Option<u64>,
+    channel_id: u64,
+    payload: String,
+}
+
+/// Events a connection subscribed to. Empty lists match everything.
+#[derive(Debug, Default, Deserialize)]
+struct FirehoseFilter {
+    #[serde(default)]
+    guild_ids: Vec<u64>,
+    #[serde(default)]
+    channel_ids: Vec<u64>,
+}
+
+impl FirehoseFilter {
+    fn matches(&self, event: &FirehoseEvent) -> bool {
+        (self.guild_ids.is_empty()
+            || event
+                .guild_id
+                .is_some_and(|guild_id| self.guild_ids.contains(&guild_id)))
+            && (self.channel_ids.is_empty() || self.channel_ids.contains(&event.channel_id))
+    }
+}
+
+// `?guild_id=1,2&channel_id=3`
+#[derive(Debug, Default, Deserialize)]
+struct FirehoseQuery {
+    guild_id: Option<String>,
+    channel_id: Option<String>,
+}
+
+fn parse_ids(ids: Option<&str>) -> Vec<u64> {
+    ids.unwrap_or_default()
+        .split(',')
+        .filter_map(|id| id.trim().parse().ok())
+        .collect()
+}
+
+/// Sends the event to the connected firehose clients, if the server is running
+pub fn broadcast(guild_id: Option<u64>, channel_id: u64, payload: &str) {
+    if let Some(sender) = FIREHOSE.get()
+        && sender.receiver_count() > 0
+    {
+        let _ = sender.send(Arc::new(FirehoseEvent {
+            guild_id,
+            channel_id,
+            payload: payload.to_string(),
+        }));
+    }
+}
+
+async fn firehose(ws: WebSocketUpgrade, Query(query): Query<FirehoseQuery>) -> Response {
+    let filter = FirehoseFilter {
+        guild_ids: parse_ids(query.guild_id.as_deref()),
+        channel_ids: parse_ids(query.channel_id.as_deref()),
+    };
+
+    ws.on_upgrade(move |socket| handle_firehose(socket, filter))
+}
+
+async fn handle_firehose(mut socket: WebSocket, mut filter: FirehoseFilter) {
+    let Some(sender) = FIREHOSE.get() else {
+        return;
+    };
+    let mut receiver = sender.subscribe();
+    debug!("Firehose client connected with {:?}", filter);
+
+    loop {
+        tokio::select! {
+            event = receiver.recv() => match event {
+                Ok(event) => {
+                    if filter.matches(&event)
+                        && socket
+                            .send(WsMessage::Text(event.payload.clone().into()))
+                            .await
+                            .is_err()
+                    {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Firehose client is too slow, skipped {} events", skipped);
+                }
+                Err(RecvError::Closed) => break,
+            },
+            message = socket.recv() => match message {
+                // clients change their subscription by sending a new filter
+                Some(Ok(WsMessage::Text(text))) => match serde_json::from_str(&text) {
+                    Ok(new_filter) => {
+                        filter = new_filter;
+                        debug!("Firehose client changed its filter to {:?}", filter);
+                    }
+                    Err(e) => debug!("Invalid firehose filter: {}", e),
+                },
+                Some(Ok(_)) => {}
+                Some(Err(_)) | None => break,
+            },
+        }
+    }
+
+    debug!("Firehose client disconnected");
+}
+
+#[derive(Serialize)]
+struct HealthReport {
+    healthy: bool,
+    database: &'static str,
+    accounts: Vec<AccountHealth>,
+}
+
+#[derive(Serialize)]
+struct AccountHealth {
+    index: usize,
+    #[serde(flatten)]
+    activity: AccountActivity,
+}
+
+// 503 when unhealthy, so load balancers and supervisors don't need to parse the body
+async fn healthz() -> (StatusCode, Json<HealthReport>) {
+    let database = if !Config::get().use_db {
+        "disabled"
+    } else if is_db_available() {
+        "up"
+    } else {
+        "down"
+    };
+    let report = HealthReport {
+        healthy: status::is_healthy(),
+        database,
+        accounts: status::accounts()
+            .into_iter()
+            .map(|(index, activity)| AccountHealth { index, activity })
+            .collect(),
+    };
+
+    let code = if report.healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, Json(report))
+}
+
+// downloaded files are uploads of anyone on Discord, served from the origin of the UI and the
+// API. They're sandboxed so an html or svg file can't run script reading the archive, and only
+// media is shown inline.
+async fn protect_files(request: Request, next: Next) -> Response {
+    if is_web_snapshot(request.uri().path()) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let mut response = next.run(request).await;
+    let inline = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| {
+            ["image/", "video/", "audio/"]
+                .iter()
+                .any(|prefix| content_type.starts_with(prefix))
+                && !content_type.starts_with("image/svg")
+        });
+
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_SECURITY_POLICY,
+        HeaderValue::from_static("sandbox"),
+    );
+    headers.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    if !inline {
+        headers.insert(
+            header::CONTENT_DISPOSITION,
+            HeaderValue::from_static("attachment"),
+        );
+    }
+
+    response
+}
+
+// the archived web pages are whole sites, they aren't served at all
+fn is_web_snapshot(path: &str) -> bool {
+    let path = urlencoding::decode(path).unwrap_or_default();
+    path.split('/')
+        .find(|segment| !segment.is_empty() && *segment != ".")
+        .is_some_and(|segment| segment == web_archive::WEB_FOLDER)
+}
+
+/// Serves `/healthz` alone, for sniff mode
+pub async fn start_health(bind: &str) -> BoxedResult<()> {
+    let app = Router::new().route("/healthz", get(healthz));
+    let listener = tokio::net::TcpListener::bind(bind).await?;
+    info!("Serving the health check on http://{}/healthz", bind);
+
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            error!("Health check server error: {}", e);
+        }
+    });
+
+    Ok(())
+}
+
+/// Serves the firehose, and the HTTP API when the database is enabled, in the background.
+/// With `ui`, also serves the web UI and the downloaded files.
+pub async fn start(
+    bind: &str,
+    ui: bool,
+    db_client: Option<Arc<Mutex<Client>>>,
+) -> BoxedResult<JoinHandle<()>> {
+    if ui && db_client.is_none() {
+        return Err("serve --ui requires use_db to be enabled".into());
+    }
+
+    let (sender, _) = broadcast::channel(FIREHOSE_CAPACITY);
+    let _ = FIREHOSE.set(sender);
+
+    let mut app = Router::new()
+        .route("/firehose", get(firehose))
+        .route("/healthz", get(healthz));
+    if let Some(db_client) = db_client {
+        app = app.merge(api::router(db_client));
+        info!("Serving the API on http://{}", bind);
+    }
+    if ui {
+        let files = Router::new()
+            .fallback_service(ServeDir::new(downloader::download_root()))
+            .layer(middleware::from_fn(protect_files));
+        app = app
+            .route("/", get(|| async { Html(UI_PAGE) }))
+            .nest("/files", files);
+        info!("Serving the web UI on http://{}/", bind);
+    }
+
+    let listener = tokio::net::TcpListener::bind(bind).await?;
+    info!("Serving the firehose on ws://{}/firehose", bind);
+
+    Ok(tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            error!("Server error: {}", e);
+        }
+    }))
+}
@@ -0,0 +1,213 @@
+use crate::BoxedResult;
+use crate::config::Config;
+use crate::database::{StatsGroup, StatsScope, archive_totals, count_messages_by};
+use crate::timezone;
+use serde_json::{Map, Value, json};
+use tokio_postgres::Client;
+
+const TOP_LIMIT: i64 = 20;
+// enough rows to cover every month/hour/type bucket
+const BUCKET_LIMIT: i64 = 1000;
+// about 10 years of days
+const DAY_LIMIT: i64 = 3660;
+
+pub async fn print_stats(
+    scope: StatsScope,
+    publishable: bool,
+    min_count: i64,
+    json: bool,
+    db: &Client,
+) -> BoxedResult<()> {
+    let mut sections = vec![("totals", "Totals", archive_totals(scope, db).await?)];
+
+    let mut groups = vec![(
+        "messages_per_month",
+        "Messages per month",
+        StatsGroup::Month,
+        BUCKET_LIMIT,
+    )];
+    // a single guild, channel or user is small enough for daily counts
+    if !scope.is_empty() {
+        groups.push((
+            "messages_per_day",
+            "Messages per day",
+            StatsGroup::Day,
+            DAY_LIMIT,
+        ));
+    }
+    groups.extend([
+        (
+            "messages_per_hour",
+            "Messages per hour of day",
+            StatsGroup::HourOfDay,
+            BUCKET_LIMIT,
+        ),
+        (
+            "messages_per_type",
+            "Messages per type",
+            StatsGroup::MessageType,
+            BUCKET_LIMIT,
+        ),
+        (
+            "attachments_per_type",
+            "Attachments per type",
+            StatsGroup::AttachmentType,
+            BUCKET_LIMIT,
+        ),
+        (
+            "top_domains",
+            "Top linked domains",
+            StatsGroup::LinkDomain,
+            TOP_LIMIT,
+        ),
+    ]);
+    if Config::get().scoring.is_some() {
+        groups.push((
+            "toxic_messages_per_month",
+            "Toxic messages per month",
+            StatsGroup::ToxicMonth,
+            BUCKET_LIMIT,
+        ));
+        // averages aren't counts, suppressing the small ones would make no sense
+        if !publishable {
+            groups.push((
+                "sentiment_per_month",
+                "Average sentiment per month, from -100 to 100",
+                StatsGroup::SentimentMonth,
+                BUCKET_LIMIT,
+            ));
+        }
+    }
+
+    // per guild, channel and user counts name the communities they come from
+    if !publishable {
+        if scope.guild_id.is_none() && scope.channel_id.is_none() {
+            groups.push(("top_guilds", "Top guilds", StatsGroup::Guild, TOP_LIMIT));
+        }
+        if scope.channel_id.is_none() {
+            groups.push((
+                "top_channels",
+                "Top channels",
+                StatsGroup::Channel,
+                TOP_LIMIT,
+            ));
+        }
+        if scope.author_id.is_none() {
+            groups.push(("top_users", "Top users", StatsGroup::Author, TOP_LIMIT));
+        }
+    }
+
+    for (key, title, group, limit) in groups {
+        let rows = count_messages_by(group, scope, limit, db).await?;
+        sections.push((key, title, rows));
+    }
+
+    if json {
+        println!("{}", sections_json(&sections, publishable, min_count));
+        return Ok(());
+    }
+
+    for (_, title, rows) in &sections {
+        print_section(title, rows, publishable, min_count);
+    }
+
+    println!();
+    println!(
+        "Days, months and hours are in the {} timezone.",
+        timezone::get()
+    );
+    if publishable {
+        println!(
+            "Counts below {} are suppressed, no identifiers are included.",
+            min_count
+        );
+    }
+
+    Ok(())
+}
+
+// sections are keyed by stable snake_case names, suppressed counts are null
+fn sections_json(
+    sections: &[(&str, &str, Vec<(String, i64)>)],
+    publishable: bool,
+    min_count: i64,
+) -> Value {
+    let mut object = Map::new();
+
+    for (key, _, rows) in sections {
+        let rows: Vec<Value> = rows
+            .iter()
+            .map(|(label, count)| {
+                let count = (!publishable || *count >= min_count).then_some(*count);
+                json!({ "label": label, "count": count })
+            })
+            .collect();
+        object.insert(key.to_string(), Value::Array(rows));
+    }
+    object.insert("timezone".to_string(), json!(timezone::get().name()));
+
+    if publishable {
+        object.insert("min_count".to_string(), json!(min_count));
+    }
+
+    Value::Object(object)
+}
+
+fn print_section(title: &str, rows: &[(String, i64)], publishable: bool, min_count: i64) {
+    println!();
+    println!("{}", title);
+
+    let label_width = rows
+        .iter()
+        .map(|(label, _)| label.chars().count())
+        .max()
+        .unwrap_or_default();
+
+    for (label, count) in rows {
+        let count = if publishable && *count < min_count {
+            format!("<{}", min_count)
+        } else {
+            count.to_string()
+        };
+        println!("  {:<width$}  {:>12}", label, count, width = label_width);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sections() -> Vec<(&'static str, &'static str, Vec<(String, i64)>)> {
+        vec![
+            (
+                "messages_per_month",
+                "Messages per month",
+                vec![("2024-04".to_string(), 3), ("2024-05".to_string(), 42)],
+            ),
+            ("top_domains", "Top domains", Vec::new()),
+        ]
+    }
+
+    #[test]
+    fn sections_are_keyed_by_name() {
+        let json = sections_json(&sections(), false, 10);
+        assert_eq!(
+            json["messages_per_month"],
+            json!([
+                { "label": "2024-04", "count": 3 },
+                { "label": "2024-05", "count": 42 },
+            ])
+        );
+        assert_eq!(json["top_domains"], json!([]));
+        assert_eq!(json["timezone"], json!("UTC"));
+        assert!(json.get("min_count").is_none());
+    }
+
+    #[test]
+    fn publishable_stats_suppress_small_counts() {
+        let json = sections_json(&sections(), true, 10);
+        assert_eq!(json["messages_per_month"][0]["count"], Value::Null);
+        assert_eq!(json["messages_per_month"][1]["count"], json!(42));
+        assert_eq!(json["min_count"], json!(10));
+    }
+}
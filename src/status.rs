@@ -0,0 +1,236 @@
+use crate::config::Config;
+use crate::database::is_db_available;
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use tracing::field::{Field, Visit};
+use tracing::{Level, Subscriber};
+use tracing_subscriber::Layer;
+use tracing_subscriber::layer::Context;
+
+// warnings and errors kept for the dashboard
+const MAX_RECENT_ERRORS: usize = 200;
+
+lazy_static! {
+    static ref STARTED_AT: Instant = Instant::now();
+    static ref ACCOUNTS: Mutex<BTreeMap<usize, AccountActivity>> = Mutex::new(BTreeMap::new());
+    static ref RECENT_ERRORS: Mutex<VecDeque<LogEntry>> = Mutex::new(VecDeque::new());
+}
+
+static BUFFERED_EVENTS: AtomicUsize = AtomicUsize::new(0);
+static DOWNLOADS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Reconnecting,
+    Dead,
+    Stopped,
+}
+
+impl ConnectionState {
+    #[cfg_attr(not(feature = "dashboard"), allow(dead_code))]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::Connected => "connected",
+            ConnectionState::Reconnecting => "reconnecting",
+            ConnectionState::Dead => "dead",
+            ConnectionState::Stopped => "stopped",
+        }
+    }
+}
+
+/// What a sniffing account is doing, for the dashboard and the health check
+#[derive(Debug, Clone, Serialize)]
+pub struct AccountActivity {
+    pub name: String,
+    pub state: ConnectionState,
+    pub guilds: usize,
+    pub events: u64,
+    pub last_event: Option<DateTime<Utc>>,
+}
+
+#[cfg_attr(not(feature = "dashboard"), allow(dead_code))]
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub time: DateTime<Utc>,
+    pub level: Level,
+    pub message: String,
+}
+
+pub fn uptime() -> std::time::Duration {
+    STARTED_AT.elapsed()
+}
+
+pub fn register_account(index: usize, name: String) {
+    let mut accounts = ACCOUNTS.lock().unwrap();
+    accounts.entry(index).or_insert(AccountActivity {
+        name,
+        state: ConnectionState::Connecting,
+        guilds: 0,
+        events: 0,
+        last_event: None,
+    });
+}
+
+pub fn set_account_state(index: usize, state: ConnectionState) {
+    if let Some(account) = ACCOUNTS.lock().unwrap().get_mut(&index) {
+        account.state = state;
+    }
+}
+
+/// Marks an account whose handler returned as stopped, unless its token died
+pub fn mark_stopped(index: usize) {
+    if let Some(account) = ACCOUNTS.lock().unwrap().get_mut(&index)
+        && account.state != ConnectionState::Dead
+    {
+        account.state = ConnectionState::Stopped;
+    }
+}
+
+pub fn set_account_guilds(index: usize, guilds: usize) {
+    if let Some(account) = ACCOUNTS.lock().unwrap().get_mut(&index) {
+        account.guilds = guilds;
+    }
+}
+
+pub fn record_event(index: usize) {
+    if let Some(account) = ACCOUNTS.lock().unwrap().get_mut(&index) {
+        account.events += 1;
+        account.last_event = Some(Utc::now());
+    }
+}
+
+pub fn accounts() -> Vec<(usize, AccountActivity)> {
+    ACCOUNTS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(index, account)| (*index, account.clone()))
+        .collect()
+}
+
+/// Whether some account recorded an event within `window`, always true while the process is
+/// younger than `window` so the accounts have time to connect
+pub fn received_event_within(window: std::time::Duration) -> bool {
+    if uptime() < window {
+        return true;
+    }
+    let since = Utc::now() - window;
+    ACCOUNTS
+        .lock()
+        .unwrap()
+        .values()
+        .any(|account| account.last_event.is_some_and(|time| time > since))
+}
+
+/// Whether the database is reachable, when used, and at least one account is connected.
+/// Without sniffing accounts only the database counts.
+pub fn is_healthy() -> bool {
+    let database_up = !Config::get().use_db || is_db_available();
+    let accounts = ACCOUNTS.lock().unwrap();
+    let connected = accounts.is_empty()
+        || accounts
+            .values()
+            .any(|account| account.state == ConnectionState::Connected);
+
+    database_up && connected
+}
+
+/// Message events waiting for the database to come back
+pub fn set_buffered_events(count: usize) {
+    BUFFERED_EVENTS.store(count, Ordering::Relaxed);
+}
+
+#[cfg_attr(not(feature = "dashboard"), allow(dead_code))]
+pub fn buffered_events() -> usize {
+    BUFFERED_EVENTS.load(Ordering::Relaxed)
+}
+
+/// Counts a download as in progress until the returned guard is dropped
+pub fn start_download() -> DownloadGuard {
+    DOWNLOADS.fetch_add(1, Ordering::Relaxed);
+    DownloadGuard
+}
+
+#[cfg_attr(not(feature = "dashboard"), allow(dead_code))]
+pub fn downloads_in_progress() -> usize {
+    DOWNLOADS.load(Ordering::Relaxed)
+}
+
+pub struct DownloadGuard;
+
+impl Drop for DownloadGuard {
+    fn drop(&mut self) {
+        DOWNLOADS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Newest first
+#[cfg_attr(not(feature = "dashboard"), allow(dead_code))]
+pub fn recent_errors() -> Vec<LogEntry> {
+    RECENT_ERRORS
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .cloned()
+        .collect()
+}
+
+/// Layer keeping the warnings and errors for the dashboard
+pub struct StatusLayer;
+
+impl StatusLayer {
+    pub fn new() -> Self {
+        lazy_static::initialize(&STARTED_AT);
+        StatusLayer
+    }
+}
+
+impl<S: Subscriber> Layer<S> for StatusLayer {
+    fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+        let level = *event.metadata().level();
+        if level > Level::WARN {
+            return;
+        }
+
+        let mut message = MessageVisitor::default();
+        event.record(&mut message);
+
+        let mut errors = RECENT_ERRORS.lock().unwrap();
+        if errors.len() >= MAX_RECENT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(LogEntry {
+            time: Utc::now(),
+            level,
+            message: message.0,
+        });
+    }
+}
+
+#[derive(Default)]
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.0 = value.to_string();
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{:?}", value);
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use crate::BoxedResult;
+use crate::config::{Config, StreamBackend};
+use crate::server;
+use discord_client_structs::structs::message::Message;
+use log::{error, info};
+use serde_json::{Value, json};
+use std::sync::OnceLock;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+
+static SENDER: OnceLock<UnboundedSender<StreamEvent>> = OnceLock::new();
+
+// nothing reads the events without a backend compiled in, and only kafka uses keys
+#[cfg_attr(not(feature = "kafka"), allow(dead_code))]
+struct StreamEvent {
+    topic: String,
+    // events of a channel share a key, so kafka keeps them in order
+    key: String,
+    payload: Vec<u8>,
+}
+
+enum Sink {
+    #[cfg(feature = "kafka")]
+    Kafka(rdkafka::producer::FutureProducer),
+    #[cfg(feature = "nats")]
+    Nats(async_nats::Client),
+}
+
+#[cfg(feature = "kafka")]
+async fn connect_kafka(url: &str) -> BoxedResult<Sink> {
+    let producer = rdkafka::ClientConfig::new()
+        .set("bootstrap.servers", url)
+        .set("message.timeout.ms", "10000")
+        .create()?;
+
+    Ok(Sink::Kafka(producer))
+}
+
+#[cfg(not(feature = "kafka"))]
+async fn connect_kafka(_url: &str) -> BoxedResult<Sink> {
+    Err("slurpslurp was built without the kafka feature".into())
+}
+
+#[cfg(feature = "nats")]
+async fn connect_nats(url: &str) -> BoxedResult<Sink> {
+    Ok(Sink::Nats(async_nats::connect(url).await?))
+}
+
+#[cfg(not(feature = "nats"))]
+async fn connect_nats(_url: &str) -> BoxedResult<Sink> {
+    Err("slurpslurp was built without the nats feature".into())
+}
+
+#[cfg_attr(
+    not(any(feature = "kafka", feature = "nats")),
+    allow(unused_variables, unreachable_code)
+)]
+impl Sink {
+    async fn publish(&self, event: StreamEvent) -> BoxedResult<()> {
+        match *self {
+            #[cfg(feature = "kafka")]
+            Sink::Kafka(ref producer) => {
+                let record = rdkafka::producer::FutureRecord::to(&event.topic)
+                    .key(&event.key)
+                    .payload(&event.payload);
+                producer
+                    .send(record, std::time::Duration::from_secs(10))
+                    .await
+                    .map_err(|(e, _)| e)?;
+            }
+            #[cfg(feature = "nats")]
+            Sink::Nats(ref client) => {
+                client.publish(event.topic, event.payload.into()).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Connects to the configured broker, events are published by a background task
+pub async fn init() -> BoxedResult<()> {
+    let Some(config) = &Config::get().stream else {
+        return Ok(());
+    };
+
+    let sink = match config.backend {
+        StreamBackend::Kafka => connect_kafka(&config.url).await?,
+        StreamBackend::Nats => connect_nats(&config.url).await?,
+    };
+
+    let (sender, receiver) = unbounded_channel();
+    tokio::spawn(run_sink(sink, receiver));
+    let _ = SENDER.set(sender);
+
+    info!("Streaming events to {:?} at {}", config.backend, config.url);
+
+    Ok(())
+}
+
+async fn run_sink(sink: Sink, mut receiver: UnboundedReceiver<StreamEvent>) {
+    while let Some(event) = receiver.recv().await {
+        let topic = event.topic.clone();
+        if let Err(e) = sink.publish(event).await {
+            error!("Failed to publish event to {}: {}", topic, e);
+        }
+    }
+}
+
+fn publish(event_type: &str, guild_id: Option<u64>, channel_id: u64, payload: Value) {
+    let payload = payload.to_string();
+    server::broadcast(guild_id, channel_id, &payload);
+
+    let (Some(sender), Some(config)) = (SENDER.get(), &Config::get().stream) else {
+        return;
+    };
+
+    let event = StreamEvent {
+        topic: format!("{}.{}", config.topic_prefix, event_type),
+        key: channel_id.to_string(),
+        payload: payload.into_bytes(),
+    };
+
+    if sender.send(event).is_err() {
+        error!("Event stream is closed");
+    }
+}
+
+pub fn publish_message(event_type: &str, msg: &Message, guild_id: Option<u64>) {
+    publish(
+        event_type,
+        guild_id,
+        msg.channel_id,
+        json!({
+            "type": event_type,
+            "guild_id": guild_id,
+            "channel_id": msg.channel_id,
+            "message": msg,
+        }),
+    );
+}
+
+pub fn publish_delete(message_ids: &[u64], channel_id: u64, guild_id: Option<u64>) {
+    publish(
+        "message_delete",
+        guild_id,
+        channel_id,
+        json!({
+            "type": "message_delete",
+            "guild_id": guild_id,
+            "channel_id": channel_id,
+            "message_ids": message_ids,
+        }),
+    );
+}
@@ -0,0 +1,81 @@
+use crate::status;
+use log::{debug, warn};
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+#[cfg(target_os = "linux")]
+use std::os::unix::net::SocketAddr;
+use std::os::unix::net::UnixDatagram;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio_postgres::Client;
+
+/// Sends a `sd_notify` message like `READY=1` when started by systemd with `Type=notify`,
+/// does nothing otherwise
+pub fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let path = path.to_string_lossy().to_string();
+
+    let result = UnixDatagram::unbound().and_then(|socket| send(&socket, state, &path));
+
+    if let Err(e) = result {
+        warn!("Failed to notify systemd of {}: {}", state, e);
+    }
+}
+
+// a leading @ is an abstract socket, which only exists on linux
+#[cfg(target_os = "linux")]
+fn send(socket: &UnixDatagram, state: &str, path: &str) -> std::io::Result<usize> {
+    match path.strip_prefix('@') {
+        Some(name) => socket.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?),
+        None => socket.send_to(state.as_bytes(), path),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send(socket: &UnixDatagram, state: &str, path: &str) -> std::io::Result<usize> {
+    socket.send_to(state.as_bytes(), path)
+}
+
+/// Pings the systemd watchdog at half the `WatchdogSec` of the unit while the process is alive:
+/// some account recorded an event within `WatchdogSec` and the database, when used, can be
+/// locked within half of it. A sniffer whose accounts all stopped receiving events or whose
+/// database lock is stuck stops pinging and gets restarted.
+pub fn spawn_watchdog_task(db: Option<Arc<Mutex<Client>>>) {
+    let Some(watchdog_sec) = std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+        .map(Duration::from_micros)
+    else {
+        return;
+    };
+    let interval = watchdog_sec / 2;
+    debug!("Pinging the systemd watchdog every {:?}", interval);
+
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+
+            if !status::received_event_within(watchdog_sec) {
+                warn!(
+                    "No account received an event in {:?}, skipping the watchdog ping",
+                    watchdog_sec
+                );
+                continue;
+            }
+            if let Some(ref db) = db
+                && tokio::time::timeout(interval, db.lock()).await.is_err()
+            {
+                warn!(
+                    "The database lock could not be taken in {:?}, skipping the watchdog ping",
+                    interval
+                );
+                continue;
+            }
+
+            notify("WATCHDOG=1");
+        }
+    });
+}
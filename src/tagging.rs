@@ -0,0 +1,141 @@
+use crate::config::{Config, TagRule};
+use discord_client_structs::structs::message::Message;
+use regex::Regex;
+use std::sync::OnceLock;
+
+struct CompiledRule {
+    rule: &'static TagRule,
+    pattern: Option<Regex>,
+}
+
+static RULES: OnceLock<Vec<CompiledRule>> = OnceLock::new();
+
+pub fn init() -> Result<(), String> {
+    let rules = Config::get()
+        .tag_rules
+        .iter()
+        .map(CompiledRule::new)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    RULES
+        .set(rules)
+        .map_err(|_| "Tag rules already initialized".to_string())
+}
+
+impl CompiledRule {
+    fn new(rule: &'static TagRule) -> Result<Self, String> {
+        let pattern = match &rule.pattern {
+            Some(pattern) => Some(
+                Regex::new(pattern)
+                    .map_err(|e| format!("Invalid pattern for tag '{}': {}", rule.tag, e))?,
+            ),
+            None => None,
+        };
+
+        Ok(CompiledRule { rule, pattern })
+    }
+
+    fn matches(&self, msg: &Message, guild_id: Option<u64>) -> bool {
+        self.matches_fields(
+            guild_id,
+            msg.channel_id,
+            msg.author.id,
+            msg.content.as_deref(),
+        )
+    }
+
+    fn matches_fields(
+        &self,
+        guild_id: Option<u64>,
+        channel_id: u64,
+        author_id: u64,
+        content: Option<&str>,
+    ) -> bool {
+        let rule = self.rule;
+
+        if !rule.guild_ids.is_empty() && !guild_id.is_some_and(|id| rule.guild_ids.contains(&id)) {
+            return false;
+        }
+
+        if !rule.channel_ids.is_empty() && !rule.channel_ids.contains(&channel_id) {
+            return false;
+        }
+
+        if !rule.author_ids.is_empty() && !rule.author_ids.contains(&author_id) {
+            return false;
+        }
+
+        match &self.pattern {
+            Some(pattern) => content.is_some_and(|content| pattern.is_match(content)),
+            None => true,
+        }
+    }
+}
+
+/// Tags of every rule matching the message, without duplicates
+pub fn tags_for(msg: &Message, guild_id: Option<u64>) -> Vec<String> {
+    let Some(rules) = RULES.get() else {
+        return Vec::new();
+    };
+
+    let mut tags: Vec<String> = Vec::new();
+    for compiled in rules {
+        if compiled.matches(msg, guild_id) && !tags.contains(&compiled.rule.tag) {
+            tags.push(compiled.rule.tag.clone());
+        }
+    }
+
+    tags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(rule: &str) -> Result<CompiledRule, String> {
+        let rule: TagRule = toml::from_str(rule).unwrap();
+        CompiledRule::new(Box::leak(Box::new(rule)))
+    }
+
+    #[test]
+    fn empty_filters_match_everything() {
+        let rule = compile(r#"tag = "all""#).unwrap();
+        assert!(rule.matches_fields(None, 1, 2, None));
+        assert!(rule.matches_fields(Some(3), 1, 2, Some("hi")));
+    }
+
+    #[test]
+    fn every_set_filter_must_match() {
+        let rule = compile(
+            r#"
+            tag = "scam"
+            guild_ids = [10]
+            channel_ids = [20, 21]
+            author_ids = [30]
+            pattern = "(?i)free nitro"
+            "#,
+        )
+        .unwrap();
+
+        assert!(rule.matches_fields(Some(10), 21, 30, Some("FREE NITRO here")));
+        assert!(!rule.matches_fields(None, 21, 30, Some("free nitro")));
+        assert!(!rule.matches_fields(Some(11), 21, 30, Some("free nitro")));
+        assert!(!rule.matches_fields(Some(10), 22, 30, Some("free nitro")));
+        assert!(!rule.matches_fields(Some(10), 21, 31, Some("free nitro")));
+        assert!(!rule.matches_fields(Some(10), 21, 30, Some("paid nitro")));
+        assert!(!rule.matches_fields(Some(10), 21, 30, None));
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        let error = compile(
+            r#"
+            tag = "broken"
+            pattern = "(unclosed"
+            "#,
+        )
+        .err()
+        .unwrap();
+        assert!(error.contains("'broken'"), "{}", error);
+    }
+}
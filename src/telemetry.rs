@@ -0,0 +1,66 @@
+use tracing::Subscriber;
+use tracing_subscriber::Layer;
+use tracing_subscriber::registry::LookupSpan;
+
+#[cfg(feature = "otel")]
+use opentelemetry::trace::TracerProvider;
+#[cfg(feature = "otel")]
+use opentelemetry_sdk::Resource;
+#[cfg(feature = "otel")]
+use opentelemetry_sdk::trace::SdkTracerProvider;
+#[cfg(feature = "otel")]
+use std::sync::OnceLock;
+
+#[cfg(feature = "otel")]
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+/// Exports the spans over OTLP/HTTP when `OTEL_EXPORTER_OTLP_ENDPOINT` is set, needs the otel
+/// cargo feature. The other `OTEL_` variables of the exporter apply as well.
+#[cfg(feature = "otel")]
+pub fn layer<S>() -> Option<Box<dyn Layer<S> + Send + Sync>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT")?;
+
+    let exporter = match opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()
+    {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            // the logger isn't installed yet
+            eprintln!("Failed to create the OTLP exporter: {}", e);
+            return None;
+        }
+    };
+
+    let service_name =
+        std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "slurpslurp".to_string());
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(service_name).build())
+        .build();
+    let tracer = provider.tracer("slurpslurp");
+    let _ = PROVIDER.set(provider);
+
+    Some(Box::new(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn layer<S>() -> Option<Box<dyn Layer<S> + Send + Sync>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    None
+}
+
+/// Sends the spans still waiting in the batch
+pub fn shutdown() {
+    #[cfg(feature = "otel")]
+    if let Some(provider) = PROVIDER.get()
+        && let Err(e) = provider.shutdown()
+    {
+        log::warn!("Failed to flush the traces: {}", e);
+    }
+}
@@ -0,0 +1,158 @@
+use crate::BoxedResult;
+use crate::database::{
+    bulk_upsert_channels, get_guild_thread_parent_ids, get_known_channel_ids, lock_db,
+};
+use crate::event_processor::message::process_message_common;
+use discord_client_rest::rest::RestClient;
+use discord_client_structs::structs::channel::Channel;
+use discord_client_structs::structs::message::query::MessageQueryBuilder;
+use log::{debug, error, info};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio_postgres::Client;
+
+// max threads per page allowed by discord
+const THREAD_PAGE_LIMIT: u32 = 100;
+const MESSAGE_PAGE_LIMIT: u8 = 100;
+// delay between two requests, discovery runs next to the gateway connection of the account
+const REQUEST_DELAY: Duration = Duration::from_secs(1);
+
+/// Stores and backfills the archived public threads of the guild that aren't known yet.
+/// Returns the amount of discovered threads.
+pub async fn discover_archived_threads(
+    rest_client: &RestClient,
+    guild_id: u64,
+    db_client: &Arc<Mutex<Client>>,
+) -> BoxedResult<usize> {
+    let parent_ids = {
+        let db = lock_db(db_client).await;
+        get_guild_thread_parent_ids(guild_id, &db).await?
+    };
+
+    let mut discovered = 0;
+    for parent_id in parent_ids {
+        // channels the account can't read are expected
+        match discover_channel_threads(rest_client, guild_id, parent_id, db_client).await {
+            Ok(count) => discovered += count,
+            Err(e) => debug!("{}", e),
+        }
+    }
+
+    if discovered > 0 {
+        info!(
+            "Discovered {} archived threads in guild {}",
+            discovered, guild_id
+        );
+    } else {
+        debug!("No new archived threads in guild {}", guild_id);
+    }
+
+    Ok(discovered)
+}
+
+async fn discover_channel_threads(
+    rest_client: &RestClient,
+    guild_id: u64,
+    parent_id: u64,
+    db_client: &Arc<Mutex<Client>>,
+) -> BoxedResult<usize> {
+    let channel_rest = rest_client.channel(parent_id);
+    let mut before = None;
+    let mut discovered = 0;
+
+    loop {
+        let page = channel_rest
+            .get_archived_public_threads(before, Some(THREAD_PAGE_LIMIT))
+            .await
+            .map_err(|e| {
+                format!(
+                    "Error fetching archived threads of channel {}: {}",
+                    parent_id, e
+                )
+            })?;
+        tokio::time::sleep(REQUEST_DELAY).await;
+
+        let ids: Vec<u64> = page.threads.iter().map(|thread| thread.id).collect();
+        let known = {
+            let db = lock_db(db_client).await;
+            get_known_channel_ids(&ids, &db).await?
+        };
+        let new_threads: Vec<&Channel> = page
+            .threads
+            .iter()
+            .filter(|thread| !known.contains(&thread.id))
+            .collect();
+
+        for thread in &new_threads {
+            {
+                let db = lock_db(db_client).await;
+                bulk_upsert_channels(std::slice::from_ref(*thread), Some(guild_id), &db).await?;
+            }
+            backfill_thread(rest_client, thread.id, guild_id, db_client).await?;
+            discovered += 1;
+        }
+
+        // threads come most recently archived first, the older ones were seen by previous runs
+        if new_threads.len() < page.threads.len() || !page.has_more {
+            break;
+        }
+
+        before = page
+            .threads
+            .iter()
+            .filter_map(|thread| thread.thread_metadata.as_ref()?.archive_timestamp)
+            .min();
+        if before.is_none() {
+            break;
+        }
+    }
+
+    Ok(discovered)
+}
+
+async fn backfill_thread(
+    rest_client: &RestClient,
+    thread_id: u64,
+    guild_id: u64,
+    db_client: &Arc<Mutex<Client>>,
+) -> BoxedResult<()> {
+    let message_rest = rest_client.message(thread_id);
+    let db_client = Some(Arc::clone(db_client));
+    let mut before = None;
+    let mut count = 0;
+
+    loop {
+        let mut builder = MessageQueryBuilder::default();
+        builder.limit(MESSAGE_PAGE_LIMIT);
+        if let Some(before) = before {
+            builder.before(before);
+        }
+
+        let messages = message_rest
+            .get_channel_messages(None, builder.build()?)
+            .await
+            .map_err(|e| format!("Error fetching messages of thread {}: {}", thread_id, e))?;
+        tokio::time::sleep(REQUEST_DELAY).await;
+
+        for message in &messages {
+            if let Err(e) =
+                process_message_common(message, &message.author, Some(guild_id), &db_client, false)
+                    .await
+            {
+                error!("Failed to save message of thread {}: {}", thread_id, e);
+            }
+        }
+
+        count += messages.len();
+        before = messages.iter().map(|message| message.id).min();
+
+        if before.is_none() || messages.len() < MESSAGE_PAGE_LIMIT as usize {
+            break;
+        }
+    }
+
+    debug!("Backfilled {} messages of thread {}", count, thread_id);
+
+    Ok(())
+}
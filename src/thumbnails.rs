@@ -0,0 +1,106 @@
+use crate::config::Config;
+use crate::downloader;
+use image::ImageFormat;
+use log::{debug, warn};
+use std::path::Path;
+use tokio::process::Command;
+
+// folder of `download_dir` mirroring the downloaded media
+const THUMBNAILS_FOLDER: &str = "thumbs";
+
+/// Where the thumbnail of a downloaded file goes, `thumbs/` followed by the path of the file
+/// inside `download_dir`, as a JPEG
+pub fn thumbnail_path(path: &str) -> String {
+    let root = downloader::download_root();
+    let relative = Path::new(path)
+        .strip_prefix(root)
+        .ok()
+        .or_else(|| Path::new(path).file_name().map(Path::new))
+        .unwrap_or(Path::new(path));
+
+    format!(
+        "{}/{}/{}.jpg",
+        root,
+        THUMBNAILS_FOLDER,
+        relative.to_string_lossy()
+    )
+}
+
+/// Creates the thumbnail of a downloaded image or video, `None` for other types or when the
+/// file can't be read. Videos need ffmpeg.
+pub async fn generate(path: &str, mime_type: &str) -> Option<String> {
+    let is_image = mime_type.starts_with("image/");
+    if !is_image && !mime_type.starts_with("video/") {
+        return None;
+    }
+
+    let thumbnail = thumbnail_path(path);
+    if Path::new(&thumbnail).exists() {
+        return Some(thumbnail);
+    }
+    if let Some(folder) = Path::new(&thumbnail).parent()
+        && let Err(e) = std::fs::create_dir_all(folder)
+    {
+        warn!("Failed to create {}: {}", folder.display(), e);
+        return None;
+    }
+
+    let size = Config::get().thumbnail_size;
+    let created = if is_image {
+        let (source, target) = (path.to_string(), thumbnail.clone());
+        tokio::task::spawn_blocking(move || resize_image(&source, &target, size))
+            .await
+            .unwrap_or(false)
+    } else {
+        extract_frame(path, &thumbnail, size).await
+    };
+
+    created.then_some(thumbnail)
+}
+
+fn resize_image(path: &str, thumbnail: &str, size: u32) -> bool {
+    let image = match image::open(path) {
+        Ok(image) => image,
+        Err(e) => {
+            debug!("Failed to read {} for its thumbnail: {}", path, e);
+            return false;
+        }
+    };
+
+    // JPEG has no alpha channel
+    let result = image
+        .thumbnail(size, size)
+        .to_rgb8()
+        .save_with_format(thumbnail, ImageFormat::Jpeg);
+    if let Err(e) = result {
+        warn!("Failed to save thumbnail {}: {}", thumbnail, e);
+        return false;
+    }
+
+    true
+}
+
+async fn extract_frame(path: &str, thumbnail: &str, size: u32) -> bool {
+    // the thumbnail filter picks a representative frame among the first ones
+    let filter = format!(
+        "thumbnail,scale={size}:{size}:force_original_aspect_ratio=decrease",
+        size = size
+    );
+    let output = Command::new("ffmpeg")
+        .args(["-v", "error", "-y", "-i", path, "-vf", &filter])
+        .args(["-frames:v", "1", thumbnail])
+        .output()
+        .await;
+
+    match output {
+        Ok(output) if output.status.success() => true,
+        Ok(_) => {
+            debug!("ffmpeg could not extract a frame of {}", path);
+            false
+        }
+        Err(e) => {
+            warn!("Failed to run ffmpeg, is it installed? {}", e);
+            false
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use chrono::{DateTime, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use std::sync::OnceLock;
+
+// milliseconds between the unix epoch and the discord epoch (2015-01-01)
+const DISCORD_EPOCH: i64 = 1_420_070_400_000;
+
+static TIMEZONE: OnceLock<Tz> = OnceLock::new();
+
+pub fn init(name: Option<&str>) -> Result<(), String> {
+    let timezone = match name {
+        Some(name) => name
+            .parse::<Tz>()
+            .map_err(|_| format!("Unknown timezone: {}", name))?,
+        None => Tz::UTC,
+    };
+
+    TIMEZONE
+        .set(timezone)
+        .map_err(|_| "Timezone already initialized".to_string())
+}
+
+pub fn get() -> Tz {
+    *TIMEZONE.get().unwrap_or(&Tz::UTC)
+}
+
+pub fn to_local(time: DateTime<Utc>) -> DateTime<Tz> {
+    time.with_timezone(&get())
+}
+
+pub fn snowflake_time(id: u64) -> DateTime<Tz> {
+    let millis = (id >> 22) as i64 + DISCORD_EPOCH;
+    get()
+        .timestamp_millis_opt(millis)
+        .single()
+        .unwrap_or_else(|| to_local(DateTime::UNIX_EPOCH))
+}
+
+/// Smallest snowflake of the given day, midnight being in the display timezone
+pub fn date_to_snowflake(date: NaiveDate) -> u64 {
+    let millis = get()
+        .from_local_datetime(&date.and_time(NaiveTime::MIN))
+        .earliest()
+        .map(|time| time.timestamp_millis())
+        .unwrap_or_default();
+
+    ((millis - DISCORD_EPOCH).max(0) as u64) << 22
+}
+
+/// Smallest snowflake of the given time
+pub fn time_to_snowflake(time: DateTime<Utc>) -> u64 {
+    ((time.timestamp_millis() - DISCORD_EPOCH).max(0) as u64) << 22
+}
+
+pub fn format_snowflake(id: u64) -> String {
+    snowflake_time(id)
+        .format("%Y-%m-%d %H:%M:%S %Z")
+        .to_string()
+}
@@ -0,0 +1,200 @@
+use crate::BoxedResult;
+use crate::config::{Config, TranscriptionConfig};
+use crate::database::{get_untranscribed_audio, save_transcript};
+use crate::maintenance;
+use log::{info, warn};
+use serde::Deserialize;
+use std::path::Path;
+use tokio::process::Command;
+use tokio_postgres::Client;
+
+/// `IS_VOICE_MESSAGE` message flag
+pub const VOICE_MESSAGE_FLAG: u64 = 1 << 13;
+
+const BACKFILL_BATCH_SIZE: i64 = 100;
+const BOUNDARY: &str = "slurpslurp-transcription";
+
+#[derive(Deserialize)]
+struct TranscriptionResponse {
+    text: String,
+}
+
+fn config() -> Option<&'static TranscriptionConfig> {
+    Config::get().transcription.as_ref()
+}
+
+/// Whether the downloaded attachment has to be transcribed, voice messages always are and
+/// other audio files only with `all_audio`
+pub fn should_transcribe(mime_type: &str, voice_message: bool) -> bool {
+    config().is_some_and(|config| {
+        mime_type.starts_with("audio/") && (voice_message || config.all_audio)
+    })
+}
+
+/// Text spoken in an audio file, with the configured command or endpoint
+pub async fn transcribe(path: &str) -> BoxedResult<String> {
+    let config = config().ok_or("transcription is not configured")?;
+
+    let text = match (&config.command, &config.endpoint) {
+        (Some(command), _) => transcribe_command(command, path).await?,
+        (None, Some(endpoint)) => transcribe_endpoint(config, endpoint, path).await?,
+        (None, None) => return Err("transcription needs an endpoint or a command".into()),
+    };
+
+    Ok(text.trim().to_string())
+}
+
+async fn transcribe_command(command: &[String], path: &str) -> BoxedResult<String> {
+    let (program, args) = command
+        .split_first()
+        .ok_or("transcription.command is empty")?;
+    let output = Command::new(program)
+        .args(args.iter().map(|arg| arg.replace("{path}", path)))
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// OpenAI-compatible `/audio/transcriptions`, the file is sent as multipart form data
+async fn transcribe_endpoint(
+    config: &TranscriptionConfig,
+    endpoint: &str,
+    path: &str,
+) -> BoxedResult<String> {
+    let file = tokio::fs::read(path).await?;
+    let file_name = Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().replace('"', ""))
+        .unwrap_or_else(|| "audio".to_string());
+
+    let mut fields = vec![("model", config.model.as_str())];
+    if let Some(language) = &config.language {
+        fields.push(("language", language.as_str()));
+    }
+
+    let mut body = Vec::new();
+    for (name, value) in fields {
+        body.extend_from_slice(
+            format!(
+                "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n"
+            )
+            .as_bytes(),
+        );
+    }
+    body.extend_from_slice(
+        format!(
+            "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{file_name}\"\r\nContent-Type: application/octet-stream\r\n\r\n"
+        )
+        .as_bytes(),
+    );
+    body.extend_from_slice(&file);
+    body.extend_from_slice(format!("\r\n--{BOUNDARY}--\r\n").as_bytes());
+
+    let client = rquest::Client::new();
+    let mut request = client
+        .post(&format!(
+            "{}/audio/transcriptions",
+            endpoint.trim_end_matches('/')
+        ))
+        .header(
+            "Content-Type",
+            &format!("multipart/form-data; boundary={}", BOUNDARY),
+        )
+        .body(body);
+
+    if let Some(api_key) = &config.api_key {
+        request = request.header("Authorization", &format!("Bearer {}", api_key));
+    }
+
+    let response = request.send().await?;
+    let status = response.status();
+    let text = response.text().await?;
+    if !status.is_success() {
+        return Err(format!("Transcription endpoint returned {}: {}", status, text).into());
+    }
+
+    let response: TranscriptionResponse = serde_json::from_str(&text)?;
+
+    Ok(response.text)
+}
+
+/// Transcribes a downloaded attachment and saves the transcript, errors are logged
+pub async fn transcribe_attachment(
+    attachment_id: u64,
+    message_id: u64,
+    path: &str,
+    db: &Client,
+) -> bool {
+    let text = match transcribe(path).await {
+        Ok(text) => text,
+        Err(e) => {
+            warn!("Failed to transcribe attachment {}: {}", attachment_id, e);
+            return false;
+        }
+    };
+
+    if let Err(e) = save_transcript(attachment_id, message_id, &text, db).await {
+        warn!(
+            "Failed to save the transcript of attachment {}: {}",
+            attachment_id, e
+        );
+        return false;
+    }
+
+    true
+}
+
+/// Transcribes the voice messages downloaded before transcription was enabled
+pub async fn backfill(limit: Option<usize>, db: &Client) -> BoxedResult<()> {
+    let config = config().ok_or("transcribe needs the transcription section of the config")?;
+
+    let mut after_id = 0;
+    let mut transcribed = 0;
+    let mut failed = 0;
+
+    loop {
+        maintenance::wait_for_window("transcription").await;
+
+        let audio = get_untranscribed_audio(
+            after_id,
+            config.all_audio,
+            VOICE_MESSAGE_FLAG,
+            BACKFILL_BATCH_SIZE,
+            db,
+        )
+        .await?;
+        let Some((last_id, _, _)) = audio.last() else {
+            break;
+        };
+        after_id = *last_id;
+
+        for (attachment_id, message_id, path) in audio {
+            if transcribe_attachment(attachment_id, message_id, &path, db).await {
+                transcribed += 1;
+            } else {
+                failed += 1;
+            }
+
+            if limit.is_some_and(|limit| transcribed >= limit) {
+                info!("Transcribed {} audio files", transcribed);
+                return Ok(());
+            }
+        }
+
+        info!("Transcribed {} audio files", transcribed);
+    }
+
+    info!("Transcription done, {} audio files failed", failed);
+
+    Ok(())
+}
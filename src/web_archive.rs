@@ -0,0 +1,375 @@
+use crate::BoxedResult;
+use crate::config::{Config, WebArchiveConfig};
+use crate::database::{WebArchive, get_unarchived_links, lock_db, save_web_archive};
+use crate::downloader;
+use log::{debug, error, info, warn};
+use rquest::Url;
+use rquest::redirect::Policy;
+use sha2::{Digest, Sha256};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use tokio_postgres::Client;
+
+const BATCH_SIZE: i64 = 100;
+// between two pages, sites aren't hammered when a message is full of links
+const REQUEST_DELAY: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_REDIRECTS: usize = 5;
+// folder of `download_dir` holding the snapshots
+pub const WEB_FOLDER: &str = "web";
+
+fn config() -> BoxedResult<&'static WebArchiveConfig> {
+    Config::get()
+        .web_archive
+        .as_ref()
+        .ok_or_else(|| "Link archiving is not configured, add a [web_archive] section".into())
+}
+
+/// Snapshots are named after the SHA-256 of their URL, so a URL is only saved once
+fn url_hash(url: &str) -> String {
+    format!("{:x}", Sha256::digest(url.as_bytes()))
+}
+
+fn snapshot_path(hash: &str, extension: &str) -> String {
+    format!(
+        "{}/{}/{}/{}.{}",
+        downloader::download_root(),
+        WEB_FOLDER,
+        &hash[..2],
+        hash,
+        extension
+    )
+}
+
+/// Archives up to `limit` links that were never archived, returns the amount of saved pages
+pub async fn archive_links(limit: i64, db: &Arc<Mutex<Client>>) -> BoxedResult<u64> {
+    let config = config()?;
+    let mut archived = 0;
+    let mut remaining = limit;
+
+    while remaining > 0 {
+        let urls = {
+            let db = lock_db(db).await;
+            get_unarchived_links(
+                &config.domains,
+                &config.exclude_domains,
+                remaining.min(BATCH_SIZE),
+                &db,
+            )
+            .await?
+        };
+        if urls.is_empty() {
+            break;
+        }
+        remaining -= urls.len() as i64;
+
+        for url in urls {
+            let archive = archive_url(config, &url).await;
+            match &archive.error {
+                Some(e) => warn!("Failed to archive {}: {}", url, e),
+                None => {
+                    debug!("Archived {}", url);
+                    archived += 1;
+                }
+            }
+
+            let db = lock_db(db).await;
+            save_web_archive(&archive, &db).await?;
+            drop(db);
+
+            tokio::time::sleep(REQUEST_DELAY).await;
+        }
+
+        info!("Archived {} links", archived);
+    }
+
+    Ok(archived)
+}
+
+async fn archive_url(config: &WebArchiveConfig, url: &str) -> WebArchive {
+    let hash = url_hash(url);
+    let mut archive = WebArchive {
+        url: url.to_string(),
+        url_hash: hash.clone(),
+        path: None,
+        status_code: None,
+        content_type: None,
+        error: None,
+    };
+
+    let result = match &config.command {
+        Some(command) => render(command, url, &snapshot_path(&hash, "html")).await,
+        None => fetch(config, url, &hash, &mut archive).await,
+    };
+    match result {
+        Ok(path) => archive.path = Some(path),
+        Err(e) => archive.error = Some(e.to_string()),
+    }
+
+    archive
+}
+
+// whether the address is reachable from the internet, links posted by users must not reach
+// the machine or its network
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || first == 0
+                // carrier-grade NAT, 100.64.0.0/10
+                || (first == 100 && second & 0xC0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ip(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // unique local, fc00::/7
+                    || first & 0xFE00 == 0xFC00
+                    // link local, fe80::/10
+                    || first & 0xFFC0 == 0xFE80)
+            }
+        },
+    }
+}
+
+/// Resolves the host of the URL, fails unless every address is public
+async fn public_address(url: &Url) -> BoxedResult<(String, SocketAddr)> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!("unsupported scheme {}", url.scheme()).into());
+    }
+    // IPv6 hosts are bracketed
+    let host = url
+        .host_str()
+        .ok_or("URL without host")?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), port))
+        .await?
+        .collect();
+    if let Some(address) = addresses.iter().find(|address| !is_public_ip(address.ip())) {
+        return Err(format!(
+            "{} resolves to the non-public address {}",
+            host,
+            address.ip()
+        )
+        .into());
+    }
+    let address = *addresses
+        .first()
+        .ok_or_else(|| format!("{} doesn't resolve", host))?;
+
+    Ok((host, address))
+}
+
+// redirects are followed by hand, so every hop is checked
+async fn get(url: &str) -> BoxedResult<rquest::Response> {
+    let mut url = Url::parse(url)?;
+
+    for _ in 0..=MAX_REDIRECTS {
+        let (host, address) = public_address(&url).await?;
+        // pinned to the checked address, resolving again could give another one
+        let client = rquest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .redirect(Policy::none())
+            .resolve(&host, address)
+            .build()?;
+        let response = client.get(url.clone()).send().await?;
+        if !response.status().is_redirection() {
+            return Ok(response);
+        }
+
+        let location = response
+            .headers()
+            .get("location")
+            .and_then(|value| value.to_str().ok())
+            .ok_or("redirect without location")?;
+        url = url.join(location)?;
+    }
+
+    Err(format!("more than {} redirects", MAX_REDIRECTS).into())
+}
+
+async fn fetch(
+    config: &WebArchiveConfig,
+    url: &str,
+    hash: &str,
+    archive: &mut WebArchive,
+) -> BoxedResult<String> {
+    let mut response = get(url).await?;
+
+    let status = response.status();
+    archive.status_code = Some(status.as_u16() as i32);
+    let content_type = response
+        .headers()
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .split(';')
+                .next()
+                .unwrap_or(value)
+                .trim()
+                .to_lowercase()
+        });
+    archive.content_type = content_type.clone();
+
+    if !status.is_success() {
+        return Err(format!("HTTP {}", status).into());
+    }
+    if response
+        .content_length()
+        .is_some_and(|length| length > config.max_bytes)
+    {
+        return Err(format!("bigger than {} bytes", config.max_bytes).into());
+    }
+
+    // the length isn't always announced, the download stops at the limit
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if (body.len() + chunk.len()) as u64 > config.max_bytes {
+            return Err(format!("bigger than {} bytes", config.max_bytes).into());
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    let extension = match content_type.as_deref() {
+        Some("text/html") => "html",
+        Some(content_type) => mime_guess::get_mime_extensions_str(content_type)
+            .and_then(|extensions| extensions.first())
+            .copied()
+            .unwrap_or("bin"),
+        None => "bin",
+    };
+    let path = snapshot_path(hash, extension);
+    write_snapshot(&path, &body)?;
+
+    Ok(path)
+}
+
+// the command either writes `{path}` itself, or prints the page. Only the link itself is
+// checked, the redirects the command follows are up to it.
+async fn render(command: &[String], url: &str, path: &str) -> BoxedResult<String> {
+    let (program, args) = command
+        .split_first()
+        .ok_or("web_archive.command is empty")?;
+    public_address(&Url::parse(url)?).await?;
+    if let Some(folder) = Path::new(path).parent() {
+        std::fs::create_dir_all(folder)?;
+    }
+
+    let output = Command::new(program)
+        .args(
+            args.iter()
+                .map(|arg| arg.replace("{url}", url).replace("{path}", path)),
+        )
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    if !Path::new(path).exists() {
+        if output.stdout.is_empty() {
+            return Err(format!("{} saved nothing", program).into());
+        }
+        write_snapshot(path, &output.stdout)?;
+    }
+
+    Ok(path.to_string())
+}
+
+fn write_snapshot(path: &str, content: &[u8]) -> std::io::Result<()> {
+    if let Some(folder) = Path::new(path).parent() {
+        std::fs::create_dir_all(folder)?;
+    }
+    std::fs::write(path, content)
+}
+
+/// Archives the new links periodically in sniff mode
+pub fn spawn_archive_task(db_client: Option<Arc<Mutex<Client>>>) {
+    let Some(config) = &Config::get().web_archive else {
+        return;
+    };
+    let Some(db_client) = db_client else {
+        return;
+    };
+    if config.interval == 0 {
+        return;
+    }
+
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(config.interval * 60)).await;
+
+            if let Err(e) = archive_links(i64::MAX, &db_client).await {
+                error!("Error archiving links: {}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_public(ip: &str) -> bool {
+        is_public_ip(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn public_addresses_are_allowed() {
+        for ip in ["1.1.1.1", "93.184.216.34", "100.128.0.1", "2606:4700::1111"] {
+            assert!(is_public(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn local_addresses_are_rejected() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "0.1.2.3",
+            "255.255.255.255",
+            "224.0.0.1",
+            "192.0.2.1",
+            "100.64.0.1",
+            "100.127.255.255",
+            "::1",
+            "::",
+            "fc00::1",
+            "fd12:3456::1",
+            "fe80::1",
+            "ff02::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+        ] {
+            assert!(!is_public(ip), "{}", ip);
+        }
+    }
+}
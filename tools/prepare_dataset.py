@@ -1,26 +1,183 @@
 import psycopg2
 import json
 import argparse
+import csv
+import hashlib
+import hmac
+import math
+import os
+import re
 import sys
 import random
+from collections import defaultdict
 from tqdm import tqdm
 
 MAX_INPUT_CHARS = 35000
 MAX_OUTPUT_CHARS = 5000
 MAX_CHAIN_LENGTH = 10
 MAX_CHAINS = 100
+MAX_PAIRS = 10000
+MAX_REPLIES = 3
+
+EMAIL_REGEX = re.compile(r"[\w.+-]+@[\w-]+(?:\.[\w-]+)+")
+PHONE_REGEX = re.compile(r"(?<!\w)\+?\d[\d ().-]{7,}\d(?!\w)")
+LINK_REGEX = re.compile(r"https?://\S+")
+
+class Anonymizer:
+    """Anonymization options. Pseudonyms come from an HMAC-SHA256 of the user id keyed with the salt,
+    the same as the ones of `slurpslurp export --anonymize` given the same salt"""
+
+    def __init__(self, pseudonymize: bool = False, strip_links: bool = False, scrub_pii: bool = False, salt: str = None):
+        self.pseudonymize = pseudonymize
+        self.strip_links = strip_links
+        self.scrub_pii = scrub_pii
+        self.salt = (salt or "").encode()
+
+    def pseudonym(self, user_id: str) -> str:
+        digest = hmac.new(self.salt, str(int(user_id)).encode(), hashlib.sha256).hexdigest()
+        return f"user{digest[:12]}"
+
+class NearDuplicateFilter:
+    """SimHash of word trigrams, a text within max_distance bits of an already seen one is a near-duplicate"""
+
+    # fingerprints within 3 bits share at least one of the 4 bands, which is used to find candidates
+    BANDS = 4
+    BAND_BITS = 16
+
+    def __init__(self, max_distance: int = 3, max_copies: int = 1, min_words: int = 8):
+        self.max_distance = max_distance
+        self.max_copies = max_copies
+        self.min_words = min_words
+        self.buckets = [defaultdict(list) for _ in range(self.BANDS)]
+        self.dropped = 0
+
+    @staticmethod
+    def fingerprint(words: list) -> int:
+        shingles = [" ".join(words[i:i + 3]) for i in range(max(len(words) - 2, 1))]
+        weights = [0] * 64
+        for shingle in shingles:
+            value = int.from_bytes(hashlib.blake2b(shingle.encode(), digest_size=8).digest(), "big")
+            for bit in range(64):
+                weights[bit] += 1 if value >> bit & 1 else -1
+        return sum(1 << bit for bit in range(64) if weights[bit] > 0)
+
+    def band(self, fingerprint: int, index: int) -> int:
+        return fingerprint >> (index * self.BAND_BITS) & ((1 << self.BAND_BITS) - 1)
+
+    def is_duplicate(self, text: str) -> bool:
+        """Counts the text, true once it was seen more than max_copies times"""
+        # short messages ("lol", "thanks") aren't copypasta
+        words = re.findall(r"\w+", text.lower())
+        if len(words) < self.min_words:
+            return False
+
+        fingerprint = self.fingerprint(words)
+        for index, bucket in enumerate(self.buckets):
+            for entry in bucket.get(self.band(fingerprint, index), []):
+                if bin(entry[0] ^ fingerprint).count("1") <= self.max_distance:
+                    entry[1] += 1
+                    if entry[1] > self.max_copies:
+                        self.dropped += 1
+                        return True
+                    return False
+
+        entry = [fingerprint, 1]
+        for index, bucket in enumerate(self.buckets):
+            bucket[self.band(fingerprint, index)].append(entry)
+        return False
+
+def load_tokenizer(name: str):
+    """Returns a function counting the tokens of a text: approx, tiktoken:<encoding> or hf:<model>"""
+    if name == "approx":
+        # about 4 characters per token for english text with most tokenizers
+        return lambda text: math.ceil(len(text) / 4)
+
+    kind, _, model = name.partition(":")
+    if kind == "tiktoken" and model:
+        import tiktoken
+        encoding = tiktoken.get_encoding(model)
+        return lambda text: len(encoding.encode(text))
+
+    if kind == "hf" and model:
+        from transformers import AutoTokenizer
+        tokenizer = AutoTokenizer.from_pretrained(model)
+        return lambda text: len(tokenizer.encode(text, add_special_tokens=False))
+
+    raise ValueError(f"unknown tokenizer {name}, expected approx, tiktoken:<encoding> or hf:<model>")
+
+class TokenBudget:
+    """Counts the tokens of the samples, and truncates them to max_tokens from the oldest turn"""
+
+    # role header and separators added by chat templates
+    MESSAGE_OVERHEAD = 4
+
+    def __init__(self, tokenizer: str = "approx", max_tokens: int = None):
+        self.count_tokens = load_tokenizer(tokenizer)
+        self.max_tokens = max_tokens
+        self.sample_tokens = []
+        self.truncated = 0
+        self.too_long = 0
+
+    def message_tokens(self, message: dict) -> int:
+        return self.count_tokens(message["content"]) + self.MESSAGE_OVERHEAD
+
+    def fit(self, messages: list) -> list:
+        """Drops the oldest turns until the sample fits, None when the last two turns don't"""
+        tokens = [self.message_tokens(msg) for msg in messages]
+        start = 0
+        while sum(tokens[start:]) > self.max_tokens and len(messages) - start > 2:
+            start += 1
+
+        if sum(tokens[start:]) > self.max_tokens:
+            self.too_long += 1
+            return None
+
+        if start > 0:
+            self.truncated += 1
+        return messages[start:]
+
+    def add_sample(self, record: dict):
+        self.sample_tokens.append(sum(self.message_tokens(msg) for msg in record["messages"]))
+
+    def report(self):
+        if not self.sample_tokens:
+            return
+
+        tokens = sorted(self.sample_tokens)
+        print(f"[+] Tokens: {sum(tokens)} in total, per sample {sum(tokens) / len(tokens):.0f} on average, "
+              f"{tokens[len(tokens) // 2]} median, {tokens[min(len(tokens) - 1, int(len(tokens) * 0.95))]} p95, "
+              f"{tokens[-1]} max.")
+        if self.max_tokens:
+            print(f"[+] {self.truncated} chains truncated and {self.too_long} skipped to fit {self.max_tokens} tokens.")
+
+def replace_usernames(text: str, username_to_role: dict) -> str:
+    # short names would replace parts of ordinary words
+    for username, role in username_to_role.items():
+        if username and len(username) >= 3:
+            text = re.sub(rf"(?<!\w)@?{re.escape(username)}(?!\w)", role, text, flags=re.IGNORECASE)
+    return text
+
+def scrub_pii(text: str) -> str:
+    text = EMAIL_REGEX.sub("[email]", text)
+
+    # 9 to 15 digits, fewer are usually dates or amounts
+    def replace_phone(match):
+        digits = sum(c.isdigit() for c in match.group(0))
+        return "[phone]" if 9 <= digits <= 15 else match.group(0)
 
-def preprocess_text(text: str, author_id_to_role: dict = None) -> str:
+    return PHONE_REGEX.sub(replace_phone, text)
+
+def preprocess_text(text: str, author_id_to_role: dict = None, anonymizer: Anonymizer = None, username_to_role: dict = None) -> str:
     if not isinstance(text, str):
         return ""
 
-    import re
-
     if author_id_to_role:
         def replace_mention(match):
             mentioned_id = match.group(1)
             if mentioned_id in author_id_to_role:
                 return f"@{author_id_to_role[mentioned_id]}"
+            elif anonymizer and anonymizer.pseudonymize:
+                return f"@{anonymizer.pseudonym(mentioned_id)}"
             else:
                 return ""
 
@@ -31,7 +188,12 @@ def preprocess_text(text: str, author_id_to_role: dict = None) -> str:
     text = re.sub(r"<@&\d+>", "@role", text)
     text = re.sub(r"<#\d+>", "#channel", text)
 
-    if re.search(r"https?://\S+", text):
+    if anonymizer and anonymizer.pseudonymize and username_to_role:
+        text = replace_usernames(text, username_to_role)
+
+    if anonymizer and anonymizer.strip_links:
+        text = LINK_REGEX.sub("", text)
+    elif LINK_REGEX.search(text):
         return ""
 
     if re.search(r"``````", text, flags=re.DOTALL):
@@ -39,6 +201,9 @@ def preprocess_text(text: str, author_id_to_role: dict = None) -> str:
 
     text = re.sub(r"<:[a-zA-Z0-9-_]{2,32}:\d+>", "", text)
 
+    if anonymizer and anonymizer.scrub_pii:
+        text = scrub_pii(text)
+
     text = re.sub(r"\s+", " ", text).strip()
 
     return text
@@ -60,19 +225,34 @@ def assign_last_speaker_as_assistant(messages):
 
     return messages
 
-def get_reply_chains(db_dsn: str, min_chain_length: int = 2) -> list:
+def message_text(alias: str) -> str:
+    """Content of a message, or the transcript of its voice message when it has no text"""
+    return f"""COALESCE(
+        NULLIF(trim({alias}.content), ''),
+        (SELECT string_agg(t.text, ' ' ORDER BY t.attachment_id)
+         FROM message_transcripts t WHERE t.message_id = {alias}.id)
+    )"""
+
+def not_toxic(alias: str) -> str:
+    """Excludes the messages scored above the max toxicity, given twice as a query parameter"""
+    return f"""(%s::REAL IS NULL OR NOT EXISTS (
+        SELECT 1 FROM message_scores s WHERE s.message_id = {alias}.id AND s.toxicity > %s
+    ))"""
+
+def get_reply_chains(db_dsn: str, min_chain_length: int = 2, tag: str = None, language: str = None, thread_context: bool = True,
+                     max_toxicity: float = None) -> list:
     print(f"[*] Connecting to PostgreSQL database...")
 
     try:
         with psycopg2.connect(db_dsn) as conn:
             with conn.cursor() as cursor:
-                query = """
+                query = f"""
                 WITH RECURSIVE reply_chains AS (
                     SELECT
                         m.id,
                         m.channel_id,
                         m.author_id,
-                        m.content,
+                        {message_text("m")} AS content,
                         u.username,
                         m.id as root_id,
                         1 as depth,
@@ -80,13 +260,15 @@ def get_reply_chains(db_dsn: str, min_chain_length: int = 2) -> list:
                         ARRAY[m.id] as msg_ids,
                         ARRAY[m.author_id] as author_ids,
                         ARRAY[u.username] as usernames,
-                        ARRAY[m.content] as contents
+                        ARRAY[{message_text("m")}] as contents
                     FROM messages m
                     JOIN users u ON m.author_id = u.id
                     WHERE m.referenced_message_id IS NULL
-                      AND m.content IS NOT NULL
-                      AND length(trim(m.content)) > 0
+                      AND length({message_text("m")}) > 0
                       AND m.deleted_at IS NULL
+                      AND (%s::TEXT IS NULL OR %s = ANY(m.tags))
+                      AND (%s::TEXT IS NULL OR m.language = %s)
+                      AND {not_toxic("m")}
 
                     UNION ALL
 
@@ -94,7 +276,7 @@ def get_reply_chains(db_dsn: str, min_chain_length: int = 2) -> list:
                         reply.id,
                         reply.channel_id,
                         reply.author_id,
-                        reply.content,
+                        {message_text("reply")},
                         reply_user.username,
                         rc.root_id,
                         rc.depth + 1,
@@ -102,31 +284,51 @@ def get_reply_chains(db_dsn: str, min_chain_length: int = 2) -> list:
                         rc.msg_ids || reply.id,
                         rc.author_ids || reply.author_id,
                         rc.usernames || reply_user.username,
-                        rc.contents || reply.content
+                        rc.contents || {message_text("reply")}
                     FROM messages reply
                     JOIN users reply_user ON reply.author_id = reply_user.id
                     JOIN reply_chains rc ON reply.referenced_message_id = rc.id
-                    WHERE reply.content IS NOT NULL
-                      AND length(trim(reply.content)) > 0
+                    WHERE length({message_text("reply")}) > 0
                       AND reply.deleted_at IS NULL
+                      AND {not_toxic("reply")}
                       AND rc.depth < %s
                       AND NOT (reply.id = ANY(rc.chain_path))
                 )
                 SELECT
-                    root_id,
-                    channel_id,
-                    depth,
-                    msg_ids,
-                    author_ids,
-                    usernames,
-                    contents
-                FROM reply_chains
-                WHERE depth >= %s  -- Use the min_chain_length parameter
-                ORDER BY root_id, depth DESC
+                    rc.root_id,
+                    rc.channel_id,
+                    rc.depth,
+                    rc.msg_ids,
+                    rc.author_ids,
+                    rc.usernames,
+                    rc.contents,
+                    starter.id,
+                    starter.author_id,
+                    starter_user.username,
+                    starter.content,
+                    root.guild_id
+                FROM reply_chains rc
+                JOIN messages root ON root.id = rc.root_id
+                -- threads share their id with the message they were started from
+                LEFT JOIN channels thread
+                       ON thread.id = rc.channel_id
+                      AND thread.type IN (10, 11, 12)
+                      AND %s
+                LEFT JOIN messages starter
+                       ON starter.id = thread.id
+                      AND starter.id <> rc.root_id
+                      AND starter.deleted_at IS NULL
+                      AND length(trim(starter.content)) > 0
+                LEFT JOIN users starter_user ON starter_user.id = starter.author_id
+                WHERE rc.depth >= %s  -- Use the min_chain_length parameter
+                ORDER BY rc.root_id, rc.depth DESC
                 LIMIT %s;
                 """
 
-                cursor.execute(query, (MAX_CHAIN_LENGTH, min_chain_length, MAX_CHAINS * 2))
+                cursor.execute(query, (
+                    tag, tag, language, language, max_toxicity, max_toxicity,
+                    max_toxicity, max_toxicity, MAX_CHAIN_LENGTH, thread_context, min_chain_length, MAX_CHAINS * 2
+                ))
                 chains = cursor.fetchall()
 
                 print(f"[+] {len(chains)} chains of at least {min_chain_length} messages found.")
@@ -136,9 +338,17 @@ def get_reply_chains(db_dsn: str, min_chain_length: int = 2) -> list:
         print(f"[ERROR] PostgreSQL error: {e}", file=sys.stderr)
         sys.exit(1)
 
-def create_conversation_record(chain_data: tuple) -> dict:
+def create_conversation_record(chain_data: tuple, anonymizer: Anonymizer = None, dedupe: NearDuplicateFilter = None, budget: TokenBudget = None) -> dict:
     try:
-        root_id, channel_id, depth, msg_ids, author_ids, usernames, contents = chain_data
+        root_id, channel_id, depth, msg_ids, author_ids, usernames, contents, \
+            starter_id, starter_author_id, starter_username, starter_content, guild_id = chain_data
+
+        # chains inside a thread start with the message the thread was started from
+        if starter_id is not None:
+            msg_ids = [starter_id] + list(msg_ids)
+            author_ids = [starter_author_id] + list(author_ids)
+            usernames = [starter_username] + list(usernames)
+            contents = [starter_content] + list(contents)
 
         messages = []
         person_mapping = {}
@@ -155,16 +365,20 @@ def create_conversation_record(chain_data: tuple) -> dict:
                 person_counter += 1
 
         author_id_to_role = {str(author_id): role for author_id, role in person_mapping.items()}
+        username_to_role = {usernames[i]: person_mapping[author_ids[i]] for i in range(len(msg_ids))}
 
         for i in range(len(msg_ids)):
             author_id = author_ids[i]
             username = usernames[i]
             content = contents[i]
 
-            processed_content = preprocess_text(content, author_id_to_role)
+            processed_content = preprocess_text(content, author_id_to_role, anonymizer, username_to_role)
             if not processed_content or len(processed_content.strip()) < 2:
                 continue
 
+            if dedupe and dedupe.is_duplicate(processed_content):
+                continue
+
             role = person_mapping[author_id]
 
             messages.append({
@@ -185,70 +399,298 @@ def create_conversation_record(chain_data: tuple) -> dict:
         if final_author_id_to_role != author_id_to_role:
             for i, msg in enumerate(messages):
                 original_content = contents[i]
-                reprocessed_content = preprocess_text(original_content, final_author_id_to_role)
+                reprocessed_content = preprocess_text(original_content, final_author_id_to_role, anonymizer, username_to_role)
                 if reprocessed_content and len(reprocessed_content.strip()) >= 2:
                     msg["content"] = reprocessed_content
 
-        total_length = sum(len(msg["content"]) for msg in messages)
-        if total_length > MAX_INPUT_CHARS:
-            return None
+        if budget and budget.max_tokens:
+            messages = budget.fit(messages)
+            if not messages:
+                return None
+        else:
+            total_length = sum(len(msg["content"]) for msg in messages)
+            if total_length > MAX_INPUT_CHARS:
+                return None
 
         return {"messages": messages}
 
     except Exception as e:
         return None
 
-def write_chains_to_jsonl(chains: list, output_filepath: str):
-    """Writes conversation chains to JSONL format"""
+def collect_records(chains: list, anonymizer: Anonymizer = None, dedupe: NearDuplicateFilter = None, budget: TokenBudget = None) -> list:
+    """Builds the conversation records of the chains, as (guild_id, record) tuples"""
+    records = []
+    unique_chains = set()
+
+    for chain_data in tqdm(chains, desc="Processing chains"):
+        try:
+            record = create_conversation_record(chain_data, anonymizer, dedupe, budget)
+            if record and len(record["messages"]) >= 2:
+                root_id = chain_data[0]
+                if root_id not in unique_chains:
+                    unique_chains.add(root_id)
+                    records.append((chain_data[-1], record))
+                    if budget:
+                        budget.add_sample(record)
+
+                    # Debug: display some examples
+                    if len(records) <= 3:
+                        print(f"[DEBUG] Example chain #{len(records)}:")
+                        for msg in record['messages']:
+                            print(f"  - {msg['role']}: {msg['content'][:50]}...")
+
+                    if len(records) >= MAX_CHAINS:
+                        break
+
+        except Exception as e:
+            continue
+
+    if dedupe:
+        print(f"[+] {dedupe.dropped} near-duplicate messages removed.")
+    if budget:
+        budget.report()
+
+    return records
+
+def split_records(records: list, validation_ratio: float, seed: int, stratify: bool = False) -> tuple:
+    """Shuffles the records with the seed and splits them, per guild when stratified"""
+    rng = random.Random(seed)
+    groups = defaultdict(list)
+    for guild_id, record in records:
+        groups[guild_id if stratify else None].append(record)
+
+    train, validation = [], []
+    for guild_id in sorted(groups, key=str):
+        group = groups[guild_id]
+        rng.shuffle(group)
+        validation_count = round(len(group) * validation_ratio)
+        validation += group[:validation_count]
+        train += group[validation_count:]
+
+    # stratified groups are concatenated, mix them again
+    rng.shuffle(train)
+    rng.shuffle(validation)
+    return train, validation
+
+def split_paths(output_filepath: str) -> tuple:
+    """data.jsonl -> data_train.jsonl, data_validation.jsonl"""
+    base, extension = os.path.splitext(output_filepath)
+    extension = extension or ".jsonl"
+    return f"{base}_train{extension}", f"{base}_validation{extension}"
+
+def write_records(records: list, output_filepath: str, kind: str = "valid chains"):
+    with open(output_filepath, "w", encoding="utf-8") as f:
+        for record in records:
+            f.write(json.dumps(record, ensure_ascii=False) + "\n")
+
+    print(f"[+] {len(records)} {kind} written to {output_filepath}.")
+
+def write_chains_to_jsonl(
+    chains: list,
+    output_filepath: str,
+    anonymizer: Anonymizer = None,
+    dedupe: NearDuplicateFilter = None,
+    validation_ratio: float = None,
+    seed: int = 42,
+    stratify: bool = False,
+    shuffle: bool = False,
+    budget: TokenBudget = None
+):
+    """Writes conversation chains to JSONL format, in a train and a validation file when split"""
     print(f"[*] Writing {len(chains)} chains to {output_filepath}...")
 
-    valid_records_count = 0
-    unique_chains = {}
+    records = collect_records(chains, anonymizer, dedupe, budget)
 
-    with open(output_filepath, "w", encoding="utf-8") as f:
-        for chain_data in tqdm(chains, desc="Processing chains"):
-            try:
-                record = create_conversation_record(chain_data)
-                if record and len(record["messages"]) >= 2:
-                    root_id = chain_data[0]
-                    if root_id not in unique_chains:
-                        unique_chains[root_id] = record
-                        f.write(json.dumps(record, ensure_ascii=False) + "\n")
-                        valid_records_count += 1
-
-                        # Debug: display some examples
-                        if valid_records_count <= 3:
-                            print(f"[DEBUG] Example chain #{valid_records_count}:")
-                            for msg in record['messages']:
-                                print(f"  - {msg['role']}: {msg['content'][:50]}...")
-
-                        if valid_records_count >= MAX_CHAINS:
-                            break
-
-            except Exception as e:
-                continue
+    if validation_ratio is None:
+        records = [record for _, record in records]
+        if shuffle:
+            random.Random(seed).shuffle(records)
+        write_records(records, output_filepath)
+        return
 
-    print(f"[+] {valid_records_count} valid chains written to {output_filepath}.")
+    train, validation = split_records(records, validation_ratio, seed, stratify)
+    train_path, validation_path = split_paths(output_filepath)
+    write_records(train, train_path)
+    write_records(validation, validation_path)
+
+def parse_split(value: str) -> float:
+    """'0.95/0.05' -> validation ratio of 0.05"""
+    try:
+        train, validation = (float(part) for part in value.split("/"))
+    except ValueError:
+        raise argparse.ArgumentTypeError(f"invalid split {value}, expected train/validation like 0.95/0.05")
+
+    if train <= 0 or validation < 0:
+        raise argparse.ArgumentTypeError(f"invalid split {value}, the ratios must be positive")
+
+    return validation / (train + validation)
 
 def generate_reply_chains_dataset(
     db_dsn: str,
     output_path: str,
     max_chains: int = MAX_CHAINS,
-    min_chain_length: int = 2
+    min_chain_length: int = 2,
+    tag: str = None,
+    language: str = None,
+    thread_context: bool = True,
+    anonymizer: Anonymizer = None,
+    dedupe: NearDuplicateFilter = None,
+    validation_ratio: float = None,
+    seed: int = 42,
+    stratify: bool = False,
+    shuffle: bool = False,
+    budget: TokenBudget = None,
+    max_toxicity: float = None
 ):
     global MAX_CHAINS
     MAX_CHAINS = max_chains
 
-    chains = get_reply_chains(db_dsn, min_chain_length)
+    chains = get_reply_chains(db_dsn, min_chain_length, tag, language, thread_context, max_toxicity)
 
     if not chains:
         print(f"[WARNING] No chains of at least {min_chain_length} messages found.")
         return
 
-    write_chains_to_jsonl(chains, output_path)
+    write_chains_to_jsonl(chains, output_path, anonymizer, dedupe, validation_ratio, seed, stratify, shuffle, budget)
     print(f"\n[SUCCESS] Dataset generated successfully: {output_path}")
     print(f"[INFO] Chains with at least {min_chain_length} messages")
 
+def get_image_pairs(db_dsn: str, max_pairs: int, max_replies: int, tag: str = None, language: str = None,
+                    min_nsfw_score: float = None, max_nsfw_score: float = None) -> list:
+    """Downloaded image attachments with the text of their message and its first replies"""
+    print(f"[*] Connecting to PostgreSQL database...")
+
+    try:
+        with psycopg2.connect(db_dsn) as conn:
+            with conn.cursor() as cursor:
+                query = """
+                SELECT
+                    a.id,
+                    a.message_id,
+                    a.path,
+                    a.content_type,
+                    m.content,
+                    COALESCE(replies.contents, ARRAY[]::TEXT[]),
+                    a.nsfw_score,
+                    m.guild_id
+                FROM attachments a
+                JOIN messages m ON m.id = a.message_id
+                LEFT JOIN LATERAL (
+                    SELECT array_agg(reply.content ORDER BY reply.id) AS contents
+                    FROM (
+                        SELECT id, content
+                        FROM messages
+                        WHERE referenced_message_id = m.id
+                          AND deleted_at IS NULL
+                          AND length(trim(content)) > 0
+                        ORDER BY id
+                        LIMIT %s
+                    ) reply
+                ) replies ON TRUE
+                WHERE a.content_type LIKE 'image/%%'
+                  AND a.path IS NOT NULL
+                  AND m.deleted_at IS NULL
+                  AND (%s::TEXT IS NULL OR %s = ANY(m.tags))
+                  AND (%s::TEXT IS NULL OR m.language = %s)
+                  AND (%s::REAL IS NULL OR a.nsfw_score >= %s)
+                  AND (%s::REAL IS NULL OR a.nsfw_score <= %s)
+                ORDER BY a.id
+                LIMIT %s;
+                """
+
+                cursor.execute(query, (
+                    max_replies, tag, tag, language, language,
+                    min_nsfw_score, min_nsfw_score, max_nsfw_score, max_nsfw_score, max_pairs
+                ))
+                pairs = cursor.fetchall()
+
+                print(f"[+] {len(pairs)} downloaded images found.")
+                return pairs
+
+    except psycopg2.Error as e:
+        print(f"[ERROR] PostgreSQL error: {e}", file=sys.stderr)
+        sys.exit(1)
+
+def create_image_record(pair: tuple, media_root: str, anonymizer: Anonymizer = None) -> dict:
+    attachment_id, message_id, path, content_type, content, replies, nsfw_score, _ = pair
+
+    # paths are relative to the folder slurpslurp runs in
+    if not os.path.isfile(os.path.join(media_root, path)):
+        return None
+
+    text = preprocess_text(content, anonymizer=anonymizer)
+    replies = [reply for reply in (preprocess_text(reply, anonymizer=anonymizer) for reply in replies) if len(reply) >= 2]
+
+    # an image alone has nothing to learn from
+    if len(text) < 2 and not replies:
+        return None
+
+    return {
+        "image": path,
+        "content_type": content_type,
+        "text": text,
+        "replies": replies,
+        "attachment_id": str(attachment_id),
+        "message_id": str(message_id),
+        "nsfw_score": nsfw_score,
+    }
+
+def write_manifest(records: list, output_filepath: str, output_format: str):
+    if output_format == "jsonl":
+        write_records(records, output_filepath, "image-text pairs")
+        return
+
+    with open(output_filepath, "w", encoding="utf-8", newline="") as f:
+        writer = csv.DictWriter(f, fieldnames=["image", "content_type", "text", "replies", "attachment_id", "message_id", "nsfw_score"])
+        writer.writeheader()
+        for record in records:
+            # one reply per line in a single cell
+            writer.writerow({**record, "replies": "\n".join(record["replies"])})
+
+    print(f"[+] {len(records)} image-text pairs written to {output_filepath}.")
+
+def generate_image_text_dataset(
+    db_dsn: str,
+    output_path: str,
+    output_format: str,
+    media_root: str,
+    max_pairs: int = MAX_PAIRS,
+    max_replies: int = MAX_REPLIES,
+    tag: str = None,
+    language: str = None,
+    anonymizer: Anonymizer = None,
+    validation_ratio: float = None,
+    seed: int = 42,
+    stratify: bool = False,
+    shuffle: bool = False,
+    min_nsfw_score: float = None,
+    max_nsfw_score: float = None
+):
+    pairs = get_image_pairs(db_dsn, max_pairs, max_replies, tag, language, min_nsfw_score, max_nsfw_score)
+
+    records = []
+    for pair in tqdm(pairs, desc="Processing images"):
+        record = create_image_record(pair, media_root, anonymizer)
+        if record:
+            records.append((pair[-1], record))
+
+    if not records:
+        print(f"[WARNING] No downloaded image with text found under {media_root}.")
+        return
+
+    if validation_ratio is None:
+        records = [record for _, record in records]
+        if shuffle:
+            random.Random(seed).shuffle(records)
+        write_manifest(records, output_path, output_format)
+    else:
+        train, validation = split_records(records, validation_ratio, seed, stratify)
+        train_path, validation_path = split_paths(output_path)
+        write_manifest(train, train_path, output_format)
+        write_manifest(validation, validation_path, output_format)
+
+    print(f"\n[SUCCESS] Dataset generated successfully: {output_path}")
+
 
 if __name__ == "__main__":
     parser = argparse.ArgumentParser(
@@ -263,7 +705,55 @@ if __name__ == "__main__":
 
     parser.add_argument(
         "output_file",
-        help="Path to the output JSONL file."
+        help="Path to the output JSONL file, or CSV for image-text manifests."
+    )
+
+    parser.add_argument(
+        "--mode",
+        choices=["chat", "image-text"],
+        default="chat",
+        help="chat: reply chains as conversations.\nimage-text: downloaded images paired with their message and replies,\nfor captioning or VLM fine-tuning (default: chat).",
+    )
+
+    parser.add_argument(
+        "--format",
+        choices=["jsonl", "csv"],
+        default=None,
+        help="Format of the image-text manifest (default: from the output file extension, jsonl otherwise).",
+    )
+
+    parser.add_argument(
+        "--media-root",
+        default="..",
+        help="Folder slurpslurp runs in, containing downloads/. Images missing from it are skipped (default: ..).",
+    )
+
+    parser.add_argument(
+        "--max-pairs",
+        type=int,
+        default=MAX_PAIRS,
+        help=f"Maximum number of image-text pairs to generate (default: {MAX_PAIRS}).",
+    )
+
+    parser.add_argument(
+        "--max-replies",
+        type=int,
+        default=MAX_REPLIES,
+        help=f"Maximum number of replies paired with an image (default: {MAX_REPLIES}).",
+    )
+
+    parser.add_argument(
+        "--max-nsfw-score",
+        type=float,
+        default=None,
+        help="Only use images classified with an NSFW score up to this value, e.g. 0.5.\nImages that weren't classified are skipped.",
+    )
+
+    parser.add_argument(
+        "--min-nsfw-score",
+        type=float,
+        default=None,
+        help="Only use images classified with an NSFW score of at least this value,\nto put explicit content in its own dataset.",
     )
 
     parser.add_argument(
@@ -287,8 +777,132 @@ if __name__ == "__main__":
         help="Minimum number of messages required in a chain (default: 2)."
     )
 
+    parser.add_argument(
+        "--tag",
+        default=None,
+        help="Only use chains whose first message carries this tag (see tag_rules in the config).",
+    )
+
+    parser.add_argument(
+        "--language",
+        default=None,
+        help="Only use chains whose first message is in this language, as an ISO 639-3 code (e.g. eng, fra).",
+    )
+
+    parser.add_argument(
+        "--max-toxicity",
+        type=float,
+        default=None,
+        help="Leave out messages whose toxicity score is above this value, e.g. 0.5,\nending their chain (see scoring in the config).",
+    )
+
+    parser.add_argument(
+        "--no-thread-context",
+        action="store_true",
+        help="Don't prepend the message a thread was started from to the chains of that thread.",
+    )
+
+    parser.add_argument(
+        "--pseudonymize",
+        action="store_true",
+        help="Replace usernames written in messages and mentions of users outside of a chain with stable pseudonyms.",
+    )
+
+    parser.add_argument(
+        "--strip-links",
+        action="store_true",
+        help="Remove links and attachment URLs from messages instead of skipping the messages containing them.",
+    )
+
+    parser.add_argument(
+        "--scrub-pii",
+        action="store_true",
+        help="Replace email addresses and phone numbers with [email] and [phone].",
+    )
+
+    parser.add_argument(
+        "--anonymize",
+        action="store_true",
+        help="Shortcut for --pseudonymize --strip-links --scrub-pii.",
+    )
+
+    parser.add_argument(
+        "--salt",
+        help="Secret keying the pseudonyms, required by --pseudonymize. The same salt gives the same pseudonyms as slurpslurp export --anonymize.",
+    )
+
+    parser.add_argument(
+        "--dedupe",
+        action="store_true",
+        help="Remove near-duplicate messages (copypasta, repeated bot output, crossposts) using SimHash.",
+    )
+
+    parser.add_argument(
+        "--dedupe-max-distance",
+        type=int,
+        default=3,
+        help="Max number of differing SimHash bits between near-duplicates, from 0 to 3 (default: 3).",
+    )
+
+    parser.add_argument(
+        "--dedupe-max-copies",
+        type=int,
+        default=1,
+        help="Number of copies of a message kept before the next ones are removed (default: 1).",
+    )
+
+    parser.add_argument(
+        "--dedupe-min-words",
+        type=int,
+        default=8,
+        help="Shorter messages are never considered duplicates (default: 8).",
+    )
+
+    parser.add_argument(
+        "--split",
+        type=parse_split,
+        default=None,
+        help="Split the chains into a train and a validation file, e.g. 0.95/0.05.\nThe files are named after the output file: data_train.jsonl and data_validation.jsonl.",
+    )
+
+    parser.add_argument(
+        "--seed",
+        type=int,
+        default=42,
+        help="Seed of the shuffling, the same seed and data give the same files (default: 42).",
+    )
+
+    parser.add_argument(
+        "--shuffle",
+        action="store_true",
+        help="Shuffle the chains, which are ordered by root message otherwise. Splits are always shuffled.",
+    )
+
+    parser.add_argument(
+        "--stratify-guild",
+        action="store_true",
+        help="Split every guild with the same ratio, so large guilds don't end up only in one of the files.",
+    )
+
+    parser.add_argument(
+        "--tokenizer",
+        default="approx",
+        help="Tokenizer used to count tokens: approx (4 characters per token), tiktoken:<encoding>\n(e.g. tiktoken:cl100k_base) or hf:<model> (e.g. hf:mistralai/Mistral-7B-v0.1) (default: approx).",
+    )
+
+    parser.add_argument(
+        "--max-tokens",
+        type=int,
+        default=None,
+        help=f"Max tokens of a sample, longer chains lose their oldest messages.\nWithout it, chains over {MAX_INPUT_CHARS} characters are skipped.",
+    )
+
     args = parser.parse_args()
 
+    if not 0 <= args.dedupe_max_distance < NearDuplicateFilter.BANDS:
+        print(f"[ERROR] dedupe-max-distance must be between 0 and {NearDuplicateFilter.BANDS - 1}", file=sys.stderr)
+        sys.exit(1)
+
     if args.max_chain_length:
         MAX_CHAIN_LENGTH = args.max_chain_length
 
@@ -300,9 +914,62 @@ if __name__ == "__main__":
         print(f"[ERROR] min-chain-length must be at least 2", file=sys.stderr)
         sys.exit(1)
 
+    if (args.pseudonymize or args.anonymize) and not args.salt:
+        print("[ERROR] --pseudonymize and --anonymize need a --salt", file=sys.stderr)
+        sys.exit(1)
+
+    anonymizer = Anonymizer(
+        pseudonymize=args.pseudonymize or args.anonymize,
+        strip_links=args.strip_links or args.anonymize,
+        scrub_pii=args.scrub_pii or args.anonymize,
+        salt=args.salt,
+    )
+
+    if args.mode == "image-text":
+        output_format = args.format or ("csv" if args.output_file.lower().endswith(".csv") else "jsonl")
+        generate_image_text_dataset(
+            args.db_dsn,
+            args.output_file,
+            output_format,
+            args.media_root,
+            args.max_pairs,
+            args.max_replies,
+            args.tag,
+            args.language,
+            anonymizer,
+            args.split,
+            args.seed,
+            args.stratify_guild,
+            args.shuffle,
+            args.min_nsfw_score,
+            args.max_nsfw_score
+        )
+        sys.exit(0)
+
+    try:
+        budget = TokenBudget(args.tokenizer, args.max_tokens)
+    except (ValueError, ImportError) as e:
+        print(f"[ERROR] Can't load the tokenizer: {e}", file=sys.stderr)
+        sys.exit(1)
+
     generate_reply_chains_dataset(
         args.db_dsn,
         args.output_file,
         args.max_chains,
-        args.min_chain_length
+        args.min_chain_length,
+        args.tag,
+        args.language,
+        not args.no_thread_context,
+        anonymizer,
+        NearDuplicateFilter(
+            max_distance=args.dedupe_max_distance,
+            max_copies=args.dedupe_max_copies,
+            min_words=args.dedupe_min_words,
+        ) if args.dedupe else None,
+        args.split,
+        args.seed,
+        args.stratify_guild,
+        args.shuffle,
+        budget,
+        args.max_toxicity
     )